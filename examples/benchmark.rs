@@ -23,31 +23,41 @@ fn main() -> anyhow::Result<()> {
 
     println!("Average span overhead: {} ns", (elapsed / N).as_nanos());
 
-    // Measure encoding time
-    let start = Instant::now();
-
-    let mut builder = TraceBuilder::new()?;
-
-    let encoded = builder
-        .process_thread_data(&ThreadTraceData::take_current_thread())
-        .encode_to_vec();
-
-    let elapsed = start.elapsed();
-
-    println!(
-        "Encode time: {} ms for {:0.1} MiB or {} ns per span",
-        elapsed.as_millis(),
-        encoded.len() as f64 / 1024_f64 / 1024_f64,
-        (elapsed / N).as_nanos()
-    );
+    // Measure encoding time, with and without packet defaults
+    let thread = ThreadTraceData::take_current_thread();
+
+    for packet_defaults in [false, true] {
+        let start = Instant::now();
+
+        let mut builder = TraceBuilder::new()?;
+
+        let encoded = builder
+            .set_packet_defaults(packet_defaults)
+            .process_thread_data(&thread)
+            .encode_to_vec();
+
+        let elapsed = start.elapsed();
+
+        println!(
+            "Encode time{}: {} ms for {:0.1} MiB or {} ns per span",
+            if packet_defaults {
+                " with packet defaults"
+            } else {
+                ""
+            },
+            elapsed.as_millis(),
+            encoded.len() as f64 / 1024_f64 / 1024_f64,
+            (elapsed / N).as_nanos()
+        );
+    }
 
     // Benchmark counter recording
     perfetto_recorder::current_thread_reserve(N_COUNTERS as usize * 2); // 2 events per counter
 
     let mut builder = TraceBuilder::new()?;
-    let mut counter_i64 =
+    let counter_i64 =
         builder.create_counter_track("test_counter_i64", CounterUnit::Count, 1, false);
-    let mut counter_f64 = builder.create_counter_track(
+    let counter_f64 = builder.create_counter_track(
         "test_counter_f64",
         CounterUnit::Custom("%".to_string()),
         1,
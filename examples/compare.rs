@@ -0,0 +1,19 @@
+//! Compares the spans in two traces written by this crate, e.g. from two builds of a program.
+//!
+//! Usage: cargo run --example compare -- before.pftrace after.pftrace
+
+use anyhow::Context;
+use anyhow::Result;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(before), Some(after)) = (args.next(), args.next()) else {
+        anyhow::bail!("Usage: compare <before.pftrace> <after.pftrace>");
+    };
+    let before = std::fs::read(&before).with_context(|| format!("Failed to read {before}"))?;
+    let after = std::fs::read(&after).with_context(|| format!("Failed to read {after}"))?;
+
+    let comparison = perfetto_recorder::compare_traces(&before, &after)?;
+    print!("{comparison}");
+    Ok(())
+}
@@ -17,21 +17,21 @@ fn main() -> Result<()> {
     let mut trace = TraceBuilder::new()?;
 
     // Create counter tracks for system metrics
-    let mut cpu_counter = trace.create_counter_track(
+    let cpu_counter = trace.create_counter_track(
         "CPU Usage",
         CounterUnit::Custom("%".to_string()),
         1,     // Unit multiplier
         false, // Not incremental (absolute values)
     );
 
-    let mut memory_counter = trace.create_counter_track(
+    let memory_counter = trace.create_counter_track(
         "Memory Usage",
         CounterUnit::SizeBytes,
         1024 * 1024, // Convert to MB
         false,       // Not incremental
     );
 
-    let mut fps_counter = trace.create_counter_track(
+    let fps_counter = trace.create_counter_track(
         "Frame Rate",
         CounterUnit::Custom("fps".to_string()),
         1,
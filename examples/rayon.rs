@@ -5,7 +5,6 @@ use perfetto_recorder::TraceBuilder;
 use perfetto_recorder::scope;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
-use std::sync::Mutex;
 use std::time::Duration;
 
 const N: u64 = 100;
@@ -55,18 +54,18 @@ fn main() -> anyhow::Result<()> {
     // Record data from the main thread.
     trace.process_thread_data(&ThreadTraceData::take_current_thread());
 
-    let trace = Mutex::new(trace);
-
-    rayon::in_place_scope(|scope| {
-        scope.spawn_broadcast(|_, _| {
-            let thread_trace = ThreadTraceData::take_current_thread();
-            trace.lock().unwrap().process_thread_data(&thread_trace);
-        });
+    // Each worker thread builds its own trace in parallel, then we merge them all together.
+    let thread_traces = rayon::broadcast(|_| {
+        let mut thread_trace = TraceBuilder::new()?;
+        thread_trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        Ok::<_, perfetto_recorder::Error>(thread_trace)
     });
 
+    for thread_trace in thread_traces {
+        trace.merge(thread_trace?);
+    }
+
     trace
-        .into_inner()
-        .unwrap()
         .write_to_file(&trace_file)
         .with_context(|| format!("Failed to write {trace_file}"))?;
 
@@ -10,11 +10,16 @@ pub struct TracePacket {
     pub timestamp: ::core::option::Option<u64>,
     #[prost(uint32, optional, tag = "58")]
     pub timestamp_clock_id: ::core::option::Option<u32>,
+    /// Values for fields that later packets on the same sequence leave unset.
+    #[prost(message, optional, tag = "59")]
+    pub trace_packet_defaults: ::core::option::Option<TracePacketDefaults>,
     #[prost(message, optional, tag = "12")]
     pub interned_data: ::core::option::Option<InternedData>,
     #[prost(uint32, optional, tag = "13")]
     pub sequence_flags: ::core::option::Option<u32>,
-    #[prost(oneof = "trace_packet::Data", tags = "11, 60")]
+    #[prost(uint32, optional, tag = "98")]
+    pub machine_id: ::core::option::Option<u32>,
+    #[prost(oneof = "trace_packet::Data", tags = "11, 60, 6, 1000")]
     pub data: ::core::option::Option<trace_packet::Data>,
     #[prost(oneof = "trace_packet::OptionalTrustedPacketSequenceId", tags = "10")]
     pub optional_trusted_packet_sequence_id: ::core::option::Option<
@@ -62,6 +67,12 @@ pub mod trace_packet {
         TrackEvent(super::TrackEvent),
         #[prost(message, tag = "60")]
         TrackDescriptor(super::TrackDescriptor),
+        #[prost(message, tag = "6")]
+        ClockSnapshot(super::ClockSnapshot),
+        /// Not part of the Perfetto schema, so ignored by Perfetto. Written by perfetto-recorder as the
+        /// last packet of a trace.
+        #[prost(message, tag = "1000")]
+        TraceFooter(super::TraceFooter),
     }
     #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Oneof)]
     pub enum OptionalTrustedPacketSequenceId {
@@ -69,6 +80,45 @@ pub mod trace_packet {
         TrustedPacketSequenceId(u32),
     }
 }
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TracePacketDefaults {
+    #[prost(uint32, optional, tag = "58")]
+    pub timestamp_clock_id: ::core::option::Option<u32>,
+    #[prost(message, optional, tag = "11")]
+    pub track_event_defaults: ::core::option::Option<TrackEventDefaults>,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TrackEventDefaults {
+    #[prost(uint64, optional, tag = "11")]
+    pub track_uuid: ::core::option::Option<u64>,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TraceFooter {
+    #[prost(uint64, optional, tag = "1")]
+    pub packet_count: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag = "2")]
+    pub span_count: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub dropped_event_count: ::core::option::Option<u64>,
+    /// CRC-32 of all bytes of the trace that precede the packet containing the footer.
+    #[prost(fixed32, optional, tag = "4")]
+    pub crc32: ::core::option::Option<u32>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClockSnapshot {
+    #[prost(message, repeated, tag = "1")]
+    pub clocks: ::prost::alloc::vec::Vec<clock_snapshot::Clock>,
+}
+/// Nested message and enum types in `ClockSnapshot`.
+pub mod clock_snapshot {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct Clock {
+        #[prost(uint32, optional, tag = "1")]
+        pub clock_id: ::core::option::Option<u32>,
+        #[prost(uint64, optional, tag = "2")]
+        pub timestamp: ::core::option::Option<u64>,
+    }
+}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TrackEvent {
     #[prost(enumeration = "track_event::Type", optional, tag = "9")]
@@ -77,6 +127,18 @@ pub struct TrackEvent {
     pub track_uuid: ::core::option::Option<u64>,
     #[prost(message, repeated, tag = "4")]
     pub debug_annotations: ::prost::alloc::vec::Vec<DebugAnnotation>,
+    #[prost(fixed64, repeated, packed = "false", tag = "47")]
+    pub flow_ids: ::prost::alloc::vec::Vec<u64>,
+    #[prost(uint64, repeated, packed = "false", tag = "3")]
+    pub category_iids: ::prost::alloc::vec::Vec<u64>,
+    #[prost(string, repeated, tag = "22")]
+    pub categories: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(uint64, optional, tag = "52")]
+    pub correlation_id: ::core::option::Option<u64>,
+    #[prost(message, optional, tag = "21")]
+    pub log_message: ::core::option::Option<LogMessage>,
+    #[prost(message, optional, tag = "6")]
+    pub legacy_event: ::core::option::Option<track_event::LegacyEvent>,
     #[prost(oneof = "track_event::NameField", tags = "10, 23")]
     pub name_field: ::core::option::Option<track_event::NameField>,
     #[prost(oneof = "track_event::SourceLocationField", tags = "33, 34")]
@@ -86,6 +148,18 @@ pub struct TrackEvent {
 }
 /// Nested message and enum types in `TrackEvent`.
 pub mod track_event {
+    /// Events in the format of the legacy JSON trace event API, for compatibility with tools that
+    /// predate typed track events.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct LegacyEvent {
+        /// The JSON event phase, e.g. 'b' and 'e' for the begin and end of a nestable async event.
+        #[prost(int32, optional, tag = "2")]
+        pub phase: ::core::option::Option<i32>,
+        /// Part of `oneof id` in the full definition. Async events with the same ID are on the same
+        /// process-scoped track.
+        #[prost(uint64, optional, tag = "6")]
+        pub unscoped_id: ::core::option::Option<u64>,
+    }
     #[derive(
         Clone,
         Copy,
@@ -101,6 +175,7 @@ pub mod track_event {
     pub enum Type {
         SliceBegin = 1,
         SliceEnd = 2,
+        Instant = 3,
         Counter = 4,
     }
     impl Type {
@@ -112,6 +187,7 @@ pub mod track_event {
             match self {
                 Self::SliceBegin => "TYPE_SLICE_BEGIN",
                 Self::SliceEnd => "TYPE_SLICE_END",
+                Self::Instant => "TYPE_INSTANT",
                 Self::Counter => "TYPE_COUNTER",
             }
         }
@@ -120,6 +196,7 @@ pub mod track_event {
             match value {
                 "TYPE_SLICE_BEGIN" => Some(Self::SliceBegin),
                 "TYPE_SLICE_END" => Some(Self::SliceEnd),
+                "TYPE_INSTANT" => Some(Self::Instant),
                 "TYPE_COUNTER" => Some(Self::Counter),
                 _ => None,
             }
@@ -147,6 +224,79 @@ pub mod track_event {
         DoubleCounterValue(f64),
     }
 }
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct LogMessage {
+    #[prost(uint64, optional, tag = "1")]
+    pub source_location_iid: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag = "2")]
+    pub body_iid: ::core::option::Option<u64>,
+    #[prost(enumeration = "log_message::Priority", optional, tag = "3")]
+    pub prio: ::core::option::Option<i32>,
+}
+/// Nested message and enum types in `LogMessage`.
+pub mod log_message {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum Priority {
+        PrioUnspecified = 0,
+        PrioUnused = 1,
+        PrioVerbose = 2,
+        PrioDebug = 3,
+        PrioInfo = 4,
+        PrioWarn = 5,
+        PrioError = 6,
+        PrioFatal = 7,
+    }
+    impl Priority {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::PrioUnspecified => "PRIO_UNSPECIFIED",
+                Self::PrioUnused => "PRIO_UNUSED",
+                Self::PrioVerbose => "PRIO_VERBOSE",
+                Self::PrioDebug => "PRIO_DEBUG",
+                Self::PrioInfo => "PRIO_INFO",
+                Self::PrioWarn => "PRIO_WARN",
+                Self::PrioError => "PRIO_ERROR",
+                Self::PrioFatal => "PRIO_FATAL",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "PRIO_UNSPECIFIED" => Some(Self::PrioUnspecified),
+                "PRIO_UNUSED" => Some(Self::PrioUnused),
+                "PRIO_VERBOSE" => Some(Self::PrioVerbose),
+                "PRIO_DEBUG" => Some(Self::PrioDebug),
+                "PRIO_INFO" => Some(Self::PrioInfo),
+                "PRIO_WARN" => Some(Self::PrioWarn),
+                "PRIO_ERROR" => Some(Self::PrioError),
+                "PRIO_FATAL" => Some(Self::PrioFatal),
+                _ => None,
+            }
+        }
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct LogMessageBody {
+    #[prost(uint64, optional, tag = "1")]
+    pub iid: ::core::option::Option<u64>,
+    #[prost(string, optional, tag = "2")]
+    pub body: ::core::option::Option<::prost::alloc::string::String>,
+}
 #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
 pub struct TrackDescriptor {
     #[prost(uint64, optional, tag = "1")]
@@ -159,6 +309,14 @@ pub struct TrackDescriptor {
     pub thread: ::core::option::Option<ThreadDescriptor>,
     #[prost(message, optional, tag = "8")]
     pub counter: ::core::option::Option<CounterDescriptor>,
+    #[prost(
+        enumeration = "track_descriptor::ChildTracksOrdering",
+        optional,
+        tag = "11"
+    )]
+    pub child_ordering: ::core::option::Option<i32>,
+    #[prost(int32, optional, tag = "12")]
+    pub sibling_order_rank: ::core::option::Option<i32>,
     #[prost(oneof = "track_descriptor::StaticOrDynamicName", tags = "2")]
     pub static_or_dynamic_name: ::core::option::Option<
         track_descriptor::StaticOrDynamicName,
@@ -171,6 +329,48 @@ pub mod track_descriptor {
         #[prost(string, tag = "2")]
         Name(::prost::alloc::string::String),
     }
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum ChildTracksOrdering {
+        Unknown = 0,
+        Lexicographic = 1,
+        Chronological = 2,
+        Explicit = 3,
+    }
+    impl ChildTracksOrdering {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Unknown => "UNKNOWN",
+                Self::Lexicographic => "LEXICOGRAPHIC",
+                Self::Chronological => "CHRONOLOGICAL",
+                Self::Explicit => "EXPLICIT",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "UNKNOWN" => Some(Self::Unknown),
+                "LEXICOGRAPHIC" => Some(Self::Lexicographic),
+                "CHRONOLOGICAL" => Some(Self::Chronological),
+                "EXPLICIT" => Some(Self::Explicit),
+                _ => None,
+            }
+        }
+    }
 }
 #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
 pub struct ProcessDescriptor {
@@ -192,14 +392,16 @@ pub struct ThreadDescriptor {
 }
 #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
 pub struct CounterDescriptor {
-    #[prost(enumeration = "counter_descriptor::Unit", optional, tag = "1")]
+    #[prost(enumeration = "counter_descriptor::Unit", optional, tag = "3")]
     pub unit: ::core::option::Option<i32>,
-    #[prost(string, optional, tag = "2")]
+    #[prost(string, optional, tag = "6")]
     pub unit_name: ::core::option::Option<::prost::alloc::string::String>,
-    #[prost(int64, optional, tag = "3")]
+    #[prost(int64, optional, tag = "4")]
     pub unit_multiplier: ::core::option::Option<i64>,
-    #[prost(bool, optional, tag = "4")]
+    #[prost(bool, optional, tag = "5")]
     pub is_incremental: ::core::option::Option<bool>,
+    #[prost(string, optional, tag = "7")]
+    pub y_axis_share_key: ::core::option::Option<::prost::alloc::string::String>,
 }
 /// Nested message and enum types in `CounterDescriptor`.
 pub mod counter_descriptor {
@@ -259,12 +461,16 @@ pub struct SourceLocation {
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct InternedData {
+    #[prost(message, repeated, tag = "1")]
+    pub event_categories: ::prost::alloc::vec::Vec<EventCategory>,
     #[prost(message, repeated, tag = "2")]
     pub event_names: ::prost::alloc::vec::Vec<EventName>,
     #[prost(message, repeated, tag = "3")]
     pub debug_annotation_names: ::prost::alloc::vec::Vec<DebugAnnotationName>,
     #[prost(message, repeated, tag = "4")]
     pub source_locations: ::prost::alloc::vec::Vec<SourceLocation>,
+    #[prost(message, repeated, tag = "20")]
+    pub log_message_body: ::prost::alloc::vec::Vec<LogMessageBody>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct DebugAnnotation {
@@ -272,6 +478,10 @@ pub struct DebugAnnotation {
     pub name_field: ::core::option::Option<debug_annotation::NameField>,
     #[prost(oneof = "debug_annotation::Value", tags = "2, 3, 4, 5, 6")]
     pub value: ::core::option::Option<debug_annotation::Value>,
+    #[prost(message, repeated, tag = "11")]
+    pub dict_entries: ::prost::alloc::vec::Vec<DebugAnnotation>,
+    #[prost(message, repeated, tag = "12")]
+    pub array_values: ::prost::alloc::vec::Vec<DebugAnnotation>,
 }
 /// Nested message and enum types in `DebugAnnotation`.
 pub mod debug_annotation {
@@ -310,3 +520,46 @@ pub struct EventName {
     #[prost(string, optional, tag = "2")]
     pub name: ::core::option::Option<::prost::alloc::string::String>,
 }
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct EventCategory {
+    #[prost(uint64, optional, tag = "1")]
+    pub iid: ::core::option::Option<u64>,
+    #[prost(string, optional, tag = "2")]
+    pub name: ::core::option::Option<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TraceConfig {
+    #[prost(message, repeated, tag = "1")]
+    pub buffers: ::prost::alloc::vec::Vec<trace_config::BufferConfig>,
+    #[prost(message, repeated, tag = "2")]
+    pub data_sources: ::prost::alloc::vec::Vec<trace_config::DataSource>,
+    #[prost(uint32, optional, tag = "3")]
+    pub duration_ms: ::core::option::Option<u32>,
+}
+/// Nested message and enum types in `TraceConfig`.
+pub mod trace_config {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct BufferConfig {
+        #[prost(uint32, optional, tag = "1")]
+        pub size_kb: ::core::option::Option<u32>,
+    }
+    #[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+    pub struct DataSource {
+        #[prost(message, optional, tag = "1")]
+        pub config: ::core::option::Option<super::DataSourceConfig>,
+    }
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct DataSourceConfig {
+    #[prost(string, optional, tag = "1")]
+    pub name: ::core::option::Option<::prost::alloc::string::String>,
+    #[prost(message, optional, tag = "113")]
+    pub track_event_config: ::core::option::Option<TrackEventConfig>,
+}
+#[derive(Clone, PartialEq, Eq, Hash, ::prost::Message)]
+pub struct TrackEventConfig {
+    #[prost(string, repeated, tag = "1")]
+    pub disabled_categories: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "2")]
+    pub enabled_categories: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
@@ -0,0 +1,64 @@
+//! Assertions on span durations in encoded traces, for enforcing performance budgets in tests.
+
+use crate::compare::SpanStats;
+use crate::compare::span_stats;
+use std::time::Duration;
+
+/// Called by [crate::assert_span_under]. Panics unless every span named `name` in `trace` took less
+/// than `limit`.
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_max_duration(trace: impl AsRef<[u8]>, name: &str, limit: Duration) {
+    let stats = stats_for(trace.as_ref(), name);
+    let longest = Duration::from_nanos(stats.max_nanos);
+    assert!(
+        longest < limit,
+        "Span `{name}` took {longest:?}, which isn't under the budget of {limit:?}"
+    );
+}
+
+/// Called by [crate::assert_total_under]. Panics unless the spans named `name` in `trace` took less
+/// than `limit` in total.
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_total_duration(trace: impl AsRef<[u8]>, name: &str, limit: Duration) {
+    let stats = stats_for(trace.as_ref(), name);
+    let total = Duration::from_nanos(stats.total_nanos);
+    assert!(
+        total < limit,
+        "{} spans named `{name}` took {total:?} in total, which isn't under the budget of {limit:?}",
+        stats.count
+    );
+}
+
+#[track_caller]
+fn stats_for(trace: &[u8], name: &str) -> SpanStats {
+    let mut stats = span_stats(trace).unwrap_or_else(|error| panic!("{error}"));
+    // A budget for a span that isn't in the trace would always pass, which is most likely a mistake
+    // such as a typo in the name.
+    stats
+        .remove(name)
+        .unwrap_or_else(|| panic!("No spans named `{name}` were found in the trace"))
+}
+
+/// Parses a duration written as a number followed by a unit, e.g. `500ms` or `1.5s`. The units
+/// `ns`, `us`, `ms` and `s` are supported.
+#[doc(hidden)]
+#[track_caller]
+pub fn parse_duration(text: &str) -> Duration {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.' && c != '_')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let nanos_per_unit = match unit {
+        "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        _ => panic!("Duration `{text}` should end with one of the units ns, us, ms or s"),
+    };
+    let Ok(number) = number.replace('_', "").parse::<f64>() else {
+        panic!("Duration `{text}` should start with a number");
+    };
+    Duration::from_nanos((number * nanos_per_unit).round() as u64)
+}
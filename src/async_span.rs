@@ -0,0 +1,194 @@
+//! Spans that can start on one thread and end on another.
+
+use crate::CallSite;
+use crate::Event;
+use crate::REDACTED;
+use crate::Redaction;
+use crate::ThreadTraceData;
+use crate::TraceBuilder;
+use crate::Uuid;
+#[cfg(feature = "enable")]
+use crate::ends_recorded;
+use crate::os;
+#[cfg(feature = "enable")]
+use crate::record_event;
+use crate::schema;
+#[cfg(feature = "enable")]
+use crate::time;
+#[cfg(feature = "enable")]
+use crate::unique_id;
+
+/// A span that isn't tied to a thread, created by [crate::start_async_span]. It can be moved to
+/// another thread, and the span ends when it's dropped, on whichever thread that happens. Each
+/// async span is shown on its own track, grouped under the process, so it doesn't need to nest
+/// with the spans of the threads that it passes through.
+#[must_use = "the span ends as soon as it is dropped"]
+pub struct OwnedSpan {
+    /// The call site of the span, or `None` if the start of the span wasn't recorded.
+    #[cfg(feature = "enable")]
+    call_site: Option<&'static CallSite>,
+    /// Identifies the track that the span is shown on.
+    #[cfg(feature = "enable")]
+    track: u64,
+}
+
+/// How a [TraceBuilder] represents async spans. See [TraceBuilder::set_async_span_mode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AsyncSpanMode {
+    /// Each span is shown on its own track, grouped under the process.
+    #[default]
+    Tracks,
+    /// Each span is written as a pair of legacy nestable async events, keyed by an ID on the
+    /// process's async track, as in the JSON trace event format.
+    Legacy,
+    /// Each span is written both on its own track and as legacy async events.
+    Both,
+}
+
+impl OwnedSpan {
+    /// Records the start of a span from `call_site`. The caller then records the span's arguments.
+    #[doc(hidden)]
+    #[allow(unused_variables)]
+    pub fn start(call_site: &'static CallSite) -> Self {
+        #[cfg(feature = "enable")]
+        {
+            let track = unique_id();
+            record_event(Event::AsyncStart {
+                id: call_site.id(),
+                track,
+            });
+            record_event(Event::Timestamp(time()));
+            Self {
+                call_site: Some(call_site),
+                track,
+            }
+        }
+        #[cfg(not(feature = "enable"))]
+        {
+            Self {}
+        }
+    }
+
+    /// Returns a span whose start wasn't recorded, so does nothing when it ends.
+    #[doc(hidden)]
+    pub fn not_recorded() -> Self {
+        Self {
+            #[cfg(feature = "enable")]
+            call_site: None,
+            #[cfg(feature = "enable")]
+            track: 0,
+        }
+    }
+
+    /// Ends the span. Equivalent to dropping it.
+    pub fn end(self) {}
+}
+
+impl Drop for OwnedSpan {
+    fn drop(&mut self) {
+        #[cfg(feature = "enable")]
+        if let Some(call_site) = self.call_site.take()
+            && ends_recorded()
+        {
+            record_event(Event::AsyncEnd {
+                id: call_site.id(),
+                track: self.track,
+            });
+            record_event(Event::Timestamp(time()));
+        }
+    }
+}
+
+impl TraceBuilder {
+    /// Sets how async spans, such as those started by [crate::start_async_span], are written.
+    /// Defaults to [AsyncSpanMode::Tracks]. Some viewers and converters, such as those based on
+    /// catapult, only understand the legacy async events of the JSON trace event format, which
+    /// [AsyncSpanMode::Legacy] and [AsyncSpanMode::Both] produce.
+    pub fn set_async_span_mode(&mut self, mode: AsyncSpanMode) -> &mut Self {
+        self.async_span_mode = mode;
+        self
+    }
+
+    /// Adds the start or end of the async span identified by `track`, as configured by
+    /// [TraceBuilder::set_async_span_mode].
+    pub(crate) fn emit_async_event(
+        &mut self,
+        call_site: &'static CallSite,
+        kind: schema::track_event::Type,
+        track: u64,
+        events: &mut std::slice::Iter<Event>,
+        thread: &ThreadTraceData,
+        thread_uuid: Uuid,
+    ) {
+        if self.async_span_mode != AsyncSpanMode::Legacy {
+            // If we're also adding a legacy event, it needs to read the same arguments.
+            let mut track_events = events.clone();
+            let track_uuid = self.async_track_uuid(track, call_site, thread.pid);
+            self.emit_track_event(
+                call_site,
+                kind,
+                &mut track_events,
+                thread,
+                track_uuid,
+                false,
+            );
+            if self.async_span_mode == AsyncSpanMode::Tracks {
+                *events = track_events;
+                return;
+            }
+        }
+
+        if !self.emit_track_event(call_site, kind, events, thread, thread_uuid, false) {
+            return;
+        }
+        let phase = if kind == schema::track_event::Type::SliceBegin {
+            b'b'
+        } else {
+            b'e'
+        };
+        if let Some(schema::trace_packet::Data::TrackEvent(track_event)) =
+            &mut self.trace.packet.last_mut().unwrap().data
+        {
+            // Legacy events take their type from their phase. They're placed on the async track of
+            // the thread's process with the same ID.
+            track_event.r#type = None;
+            track_event.legacy_event = Some(schema::track_event::LegacyEvent {
+                phase: Some(i32::from(phase)),
+                unscoped_id: Some(track),
+            });
+        }
+    }
+
+    /// Returns the UUID of the track for the async span identified by `track`, adding a descriptor
+    /// for the track if this is the first time we've seen it.
+    pub(crate) fn async_track_uuid(
+        &mut self,
+        track: u64,
+        call_site: &'static CallSite,
+        pid: os::Pid,
+    ) -> Uuid {
+        let name = match self.redaction_for(call_site.source.sensitivity) {
+            None => call_site.source.name,
+            Some(Redaction::Drop) => return Uuid(track),
+            Some(Redaction::Redact) => REDACTED,
+        };
+        if !self.async_tracks.insert(track) {
+            return Uuid(track);
+        }
+        let parent_uuid = self.process_uuid(pid).0;
+        self.add_packet(schema::TracePacket {
+            data: Some(schema::trace_packet::Data::TrackDescriptor(
+                schema::TrackDescriptor {
+                    uuid: Some(track),
+                    parent_uuid: Some(parent_uuid),
+                    static_or_dynamic_name: Some(
+                        schema::track_descriptor::StaticOrDynamicName::Name(name.to_owned()),
+                    ),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        });
+        Uuid(track)
+    }
+}
@@ -0,0 +1,86 @@
+//! A limit on the amount of memory used by events that have been recorded, but not yet taken.
+
+use crate::set_span_sample_interval;
+use crate::stop_recording;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// What to do when the memory budget set by [set_memory_budget] is exceeded.
+#[derive(Clone)]
+pub enum BudgetAction {
+    /// Call the supplied function. Typically this would arrange for each thread's events to be
+    /// taken and written out. The function is called on whichever thread exceeded the budget.
+    Callback(Arc<dyn Fn() + Send + Sync>),
+
+    /// Only record one in every `n` spans from then on. See [crate::set_span_sample_interval].
+    Sample(u32),
+
+    /// Stop recording. Recording can be resumed by calling [crate::start].
+    Stop,
+}
+
+/// The number of bytes allocated by all threads for recorded events that haven't yet been taken.
+static BUFFERED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of bytes above which we take action. `usize::MAX` if there's no budget.
+static BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+static ACTION: Mutex<Option<BudgetAction>> = Mutex::new(None);
+
+/// Sets a limit on the total memory used by recorded events across all threads. Each time the limit
+/// is exceeded, `action` is taken. Memory is counted from when a thread's recording buffer is
+/// allocated until the events are taken by [crate::ThreadTraceData::take_current_thread].
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::BudgetAction;
+///
+/// perfetto_recorder::set_memory_budget(512 * 1024 * 1024, BudgetAction::Sample(10));
+/// ```
+pub fn set_memory_budget(bytes: usize, action: BudgetAction) {
+    *ACTION.lock().unwrap() = Some(action);
+    BUDGET.store(bytes, Ordering::Relaxed);
+}
+
+/// Removes any limit previously set by [set_memory_budget].
+pub fn clear_memory_budget() {
+    BUDGET.store(usize::MAX, Ordering::Relaxed);
+    *ACTION.lock().unwrap() = None;
+}
+
+/// Returns the number of bytes currently allocated by all threads for recorded events that haven't
+/// yet been taken.
+pub fn buffered_bytes() -> usize {
+    BUFFERED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Records that a thread's recording buffer has grown by `bytes`.
+#[cold]
+pub(crate) fn grow(bytes: usize) {
+    let total = BUFFERED_BYTES.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    let budget = BUDGET.load(Ordering::Relaxed);
+    // Only act when we cross the budget, not every time the buffer grows while over it.
+    if total > budget && total - bytes <= budget {
+        exceeded();
+    }
+}
+
+/// Records that a thread's recording buffer of `bytes` has been taken.
+pub(crate) fn release(bytes: usize) {
+    BUFFERED_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+fn exceeded() {
+    // Clone the action so that we don't hold the lock while calling a user-provided callback.
+    let Some(action) = ACTION.lock().unwrap().clone() else {
+        return;
+    };
+    match action {
+        BudgetAction::Callback(callback) => callback(),
+        BudgetAction::Sample(interval) => set_span_sample_interval(interval),
+        BudgetAction::Stop => stop_recording(),
+    }
+}
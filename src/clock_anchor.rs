@@ -0,0 +1,66 @@
+//! Lining up our traces with traces written by other tracing crates in the same process.
+
+use crate::TimeAnchor;
+use crate::TraceBuilder;
+use crate::time;
+
+/// A single moment expressed both as nanoseconds since the unix epoch and in the timebase of
+/// another trace. Passing an anchor to [TraceBuilder::align_with] puts our trace on that other
+/// trace's timeline, so that the two can be merged.
+///
+/// Crates such as `tracing-chrome` write timestamps relative to when they were set up. Taking an
+/// anchor with [export_clock_anchor] at the same point gives the unix time of that trace's zero.
+/// Crates that write unix timestamps, such as `minitrace`, are already on the same timeline as
+/// our default [crate::BuiltinClock::Realtime] clock, so don't need an anchor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClockAnchor {
+    /// Nanoseconds since the unix epoch.
+    pub unix_nanos: u64,
+    /// The same moment in nanoseconds of the other trace's timebase.
+    pub local_nanos: u64,
+}
+
+/// Returns an anchor for the current time, at zero in the other trace's timebase. Other
+/// components can record the anchor's `unix_nanos` alongside their own trace, or the anchor can be
+/// adjusted with the other trace's time at this moment if it's not zero.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if start().is_ok() {
+/// // Set up another tracing crate, whose timestamps start from zero here.
+/// let anchor = export_clock_anchor();
+///
+/// scope!("work");
+/// let mut trace = TraceBuilder::new()?;
+/// trace
+///     .align_with(anchor)
+///     .process_thread_data(&ThreadTraceData::take_current_thread());
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn export_clock_anchor() -> ClockAnchor {
+    ClockAnchor {
+        unix_nanos: TimeAnchor::new().unix_nanos(time()),
+        local_nanos: 0,
+    }
+}
+
+impl TraceBuilder {
+    /// Expresses timestamps in the timebase of `anchor` when the trace is encoded, so that it lines
+    /// up with a trace written by another crate. As for [TraceBuilder::set_zero_based_timestamps],
+    /// the clock snapshot is omitted and all packets are assumed to use the same clock. Packets from
+    /// before `anchor` are moved to time zero. Takes precedence over zero-based timestamps.
+    pub fn align_with(&mut self, anchor: ClockAnchor) -> &mut Self {
+        self.clock_anchor = Some(anchor);
+        self
+    }
+
+    /// Returns the time, in the trace's clock, that corresponds to zero in the timebase of
+    /// `anchor`. This is negative if zero in that timebase is before zero in the trace's clock, e.g.
+    /// when the trace uses [crate::BuiltinClock::Boottime] and the other trace started long before.
+    pub(crate) fn anchor_origin(&self, anchor: ClockAnchor) -> i128 {
+        i128::from(self.unix_nanos_to_clock(anchor.unix_nanos)) - i128::from(anchor.local_nanos)
+    }
+}
@@ -0,0 +1,209 @@
+//! Comparing the spans in two traces, e.g. to look for performance regressions between builds.
+
+use crate::packet_defaults;
+use crate::schema;
+use crate::schema::trace_packet::Data;
+use crate::schema::trace_packet::OptionalTrustedPacketSequenceId;
+use crate::schema::track_event::NameField;
+use crate::schema::track_event::Type;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Statistics about the spans with a particular name in a single trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpanStats {
+    /// The number of spans that both started and ended in the trace.
+    pub count: u64,
+    /// The total duration of the spans, in nanoseconds.
+    pub total_nanos: u64,
+    /// The duration of the longest span, in nanoseconds.
+    pub max_nanos: u64,
+}
+
+/// How the spans with a particular name differ between two traces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanComparison {
+    pub name: String,
+    /// The spans in the first trace, or `None` if there weren't any.
+    pub before: Option<SpanStats>,
+    /// The spans in the second trace, or `None` if there weren't any.
+    pub after: Option<SpanStats>,
+}
+
+/// The result of [compare_traces]. Displaying it gives a table of the differences, with the largest
+/// changes in total duration first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceComparison {
+    /// A comparison for each span name that appears in either trace, ordered by name.
+    pub spans: Vec<SpanComparison>,
+}
+
+/// Returned by [compare_traces] if a trace couldn't be decoded.
+#[derive(Debug)]
+pub struct InvalidTrace;
+
+/// Compares the spans in two encoded traces, such as those written by [crate::TraceBuilder], by
+/// name. For each name, reports how the number of spans and their total duration changed, including
+/// names that only appear in one of the traces. This gives a quick overview of what got slower
+/// between two builds without needing to query the traces.
+///
+/// See `examples/compare.rs` for a command-line tool built on this.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if perfetto_recorder::is_enabled() {
+/// let before = TraceBuilder::new()?.encode_to_vec();
+/// let after = TraceBuilder::new()?.encode_to_vec();
+/// let comparison = compare_traces(&before, &after)?;
+/// println!("{comparison}");
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn compare_traces(before: &[u8], after: &[u8]) -> Result<TraceComparison, InvalidTrace> {
+    let mut before = span_stats(before)?;
+    let mut after = span_stats(after)?;
+
+    let mut names: Vec<String> = before.keys().chain(after.keys()).cloned().collect();
+    names.sort();
+    names.dedup();
+    let spans = names
+        .into_iter()
+        .map(|name| SpanComparison {
+            before: before.remove(&name),
+            after: after.remove(&name),
+            name,
+        })
+        .collect();
+
+    Ok(TraceComparison { spans })
+}
+
+/// Collects statistics for each span name in `trace`.
+pub(crate) fn span_stats(trace: &[u8]) -> Result<BTreeMap<String, SpanStats>, InvalidTrace> {
+    let mut trace = schema::Trace::decode(trace).map_err(|_| InvalidTrace)?;
+    packet_defaults::resolve_packet_defaults(&mut trace.packet);
+
+    let mut names: HashMap<Option<OptionalTrustedPacketSequenceId>, HashMap<u64, String>> =
+        HashMap::new();
+    // The name and start time of each open span, by track.
+    let mut open: HashMap<u64, Vec<(Option<String>, u64)>> = HashMap::new();
+    let mut stats: BTreeMap<String, SpanStats> = BTreeMap::new();
+
+    for packet in &trace.packet {
+        let sequence = packet.optional_trusted_packet_sequence_id;
+        let incremental_state_cleared =
+            schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32;
+        if packet.sequence_flags.unwrap_or_default() & incremental_state_cleared != 0 {
+            names.remove(&sequence);
+        }
+        if let Some(interned) = &packet.interned_data {
+            let sequence_names = names.entry(sequence).or_default();
+            for event_name in &interned.event_names {
+                if let (Some(iid), Some(name)) = (event_name.iid, &event_name.name) {
+                    sequence_names.insert(iid, name.clone());
+                }
+            }
+        }
+
+        let Some(Data::TrackEvent(event)) = &packet.data else {
+            continue;
+        };
+        let timestamp = packet.timestamp.unwrap_or_default();
+        let track_spans = open
+            .entry(event.track_uuid.unwrap_or_default())
+            .or_default();
+        match event.r#type.and_then(|kind| Type::try_from(kind).ok()) {
+            Some(Type::SliceBegin) => {
+                let name = match &event.name_field {
+                    Some(NameField::Name(name)) => Some(name.clone()),
+                    Some(NameField::NameIid(iid)) => names
+                        .get(&sequence)
+                        .and_then(|sequence_names| sequence_names.get(iid))
+                        .cloned(),
+                    None => None,
+                };
+                track_spans.push((name, timestamp));
+            }
+            Some(Type::SliceEnd) => {
+                if let Some((Some(name), start)) = track_spans.pop() {
+                    let entry = stats.entry(name).or_default();
+                    let duration = timestamp.saturating_sub(start);
+                    entry.count += 1;
+                    entry.total_nanos += duration;
+                    entry.max_nanos = entry.max_nanos.max(duration);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(stats)
+}
+
+impl SpanStats {
+    /// Returns the mean duration of the spans, in nanoseconds.
+    pub fn mean_nanos(&self) -> u64 {
+        self.total_nanos.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+impl SpanComparison {
+    /// Returns how many more spans there were in the second trace than in the first.
+    pub fn count_delta(&self) -> i64 {
+        let count = |stats: Option<SpanStats>| stats.map_or(0, |stats| stats.count as i64);
+        count(self.after) - count(self.before)
+    }
+
+    /// Returns how much longer, in nanoseconds, the spans took in total in the second trace than in
+    /// the first.
+    pub fn total_nanos_delta(&self) -> i64 {
+        let total = |stats: Option<SpanStats>| stats.map_or(0, |stats| stats.total_nanos as i64);
+        total(self.after) - total(self.before)
+    }
+}
+
+impl Display for TraceComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut spans: Vec<&SpanComparison> = self.spans.iter().collect();
+        spans.sort_by_key(|span| std::cmp::Reverse(span.total_nanos_delta().unsigned_abs()));
+
+        writeln!(
+            f,
+            "{:<40} {:>10} {:>10} {:>14} {:>14} {:>14}",
+            "span", "count", "Δcount", "total ns", "Δtotal ns", "Δmean ns"
+        )?;
+        for span in spans {
+            let after = span.after.unwrap_or_default();
+            let status = match (span.before, span.after) {
+                (None, Some(_)) => " (new)",
+                (Some(_), None) => " (removed)",
+                _ => "",
+            };
+            let mean_delta =
+                after.mean_nanos() as i64 - span.before.unwrap_or_default().mean_nanos() as i64;
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>+10} {:>14} {:>+14} {:>+14}{status}",
+                span.name,
+                after.count,
+                span.count_delta(),
+                after.total_nanos,
+                span.total_nanos_delta(),
+                mean_delta,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidTrace {}
+
+impl Display for InvalidTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Trace couldn't be decoded")
+    }
+}
@@ -0,0 +1,170 @@
+//! Counter tracks showing how many spans with a particular name are open at once.
+
+use std::collections::HashMap;
+
+use crate::CounterTrack;
+use crate::CounterUnit;
+use crate::TraceBuilder;
+use crate::schema;
+use crate::schema::TracePacket;
+
+/// A counter track derived from the spans with a particular name. See
+/// [TraceBuilder::add_concurrency_counter].
+#[derive(Debug, Clone)]
+pub(crate) struct ConcurrencyCounter {
+    span_name: String,
+    track: CounterTrack,
+    /// How many of the spans were open at the start of the current segment.
+    initial_level: i64,
+    /// The timestamp of each span's begin (+1) and end (-1) in the current segment, in the order
+    /// that threads were processed.
+    changes: Vec<(u64, i64)>,
+}
+
+impl TraceBuilder {
+    /// Adds a counter track that shows how many spans named `span_name` are open at each moment,
+    /// across all threads, e.g. how many "compile TU" spans are running at once. For hundreds of
+    /// short spans spread over many threads, this is much easier to read than the spans themselves.
+    /// The counter is derived when the trace is written, from the spans that have been processed
+    /// by then. Returns the counter's track, e.g. so that its scale can be shared with another
+    /// counter via [TraceBuilder::set_counter_y_axis_share_key].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if start().is_ok() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// trace.add_concurrency_counter("compile TU");
+    /// let threads: Vec<_> = (0..4)
+    ///     .map(|_| {
+    ///         std::thread::spawn(|| {
+    ///             scope!("compile TU");
+    ///             ThreadTraceData::take_current_thread()
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for thread in threads {
+    ///     trace.process_thread_data(&thread.join().unwrap());
+    /// }
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn add_concurrency_counter(&mut self, span_name: impl Into<String>) -> CounterTrack {
+        let span_name = span_name.into();
+        let track = self.create_counter_track(
+            format!("{span_name} (concurrent)"),
+            CounterUnit::Unspecified,
+            1,
+            false,
+        );
+        self.concurrency_counters.push(ConcurrencyCounter {
+            span_name,
+            track,
+            initial_level: 0,
+            changes: Vec::new(),
+        });
+        track
+    }
+
+    /// Records that a span named `span_name` began (`change` of 1) or ended (-1) in the packet at
+    /// `index`.
+    pub(crate) fn record_concurrency_change(&mut self, span_name: &str, index: usize, change: i64) {
+        if self.concurrency_counters.is_empty() {
+            return;
+        }
+        let timestamp = self.trace.packet[index].timestamp();
+        for counter in &mut self.concurrency_counters {
+            if counter.span_name == span_name {
+                counter.changes.push((timestamp, change));
+            }
+        }
+    }
+
+    /// Adds the spans counted by `other`, which come from a builder on the same machine, to our
+    /// counters with the same span names. Counters that we don't have are taken as they are. The
+    /// tracks of the counters that we already have are added to `uuid_remap`.
+    pub(crate) fn merge_concurrency_counters(
+        &mut self,
+        other: Vec<ConcurrencyCounter>,
+        uuid_remap: &mut HashMap<u64, u64>,
+    ) {
+        for theirs in other {
+            match self
+                .concurrency_counters
+                .iter_mut()
+                .find(|counter| counter.span_name == theirs.span_name)
+            {
+                Some(mine) => {
+                    mine.initial_level += theirs.initial_level;
+                    mine.changes.extend(theirs.changes);
+                    uuid_remap.insert(theirs.track.uuid, mine.track.uuid);
+                }
+                None => self.concurrency_counters.push(theirs),
+            }
+        }
+    }
+
+    /// Carries the level of each concurrency counter over to the next segment.
+    pub(crate) fn start_concurrency_segment(&mut self) {
+        for counter in &mut self.concurrency_counters {
+            counter.initial_level = level_after(counter.initial_level, sorted(&counter.changes));
+            counter.changes.clear();
+        }
+    }
+
+    /// Returns the counter values of the concurrency counters for the current segment.
+    pub(crate) fn concurrency_packets(&self) -> Vec<TracePacket> {
+        let mut packets = Vec::new();
+        for counter in &self.concurrency_counters {
+            let changes = sorted(&counter.changes);
+            let mut level = counter.initial_level;
+            for (position, (timestamp, change)) in changes.iter().enumerate() {
+                // The end of a span whose begin was discarded, e.g. by a ring buffer, mustn't take
+                // the count below zero.
+                level = (level + change).max(0);
+                // Only the last of several changes at the same time is visible.
+                if changes
+                    .get(position + 1)
+                    .is_some_and(|(next, _)| next == timestamp)
+                {
+                    continue;
+                }
+                packets.push(TracePacket {
+                    timestamp: Some(*timestamp),
+                    timestamp_clock_id: Some(self.clock as u32),
+                    data: Some(schema::trace_packet::Data::TrackEvent(schema::TrackEvent {
+                        track_uuid: Some(counter.track.uuid),
+                        r#type: Some(schema::track_event::Type::Counter as i32),
+                        counter_value_field: Some(
+                            schema::track_event::CounterValueField::CounterValue(level),
+                        ),
+                        ..Default::default()
+                    })),
+                    optional_trusted_packet_sequence_id: Some(
+                        schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                            self.sequence_id,
+                        ),
+                    ),
+                    machine_id: self.machine_id,
+                    ..Default::default()
+                });
+            }
+        }
+        packets
+    }
+}
+
+/// Returns `changes` in time order, with ends before begins at the same time, so that a span that
+/// starts as another ends doesn't briefly count as concurrent.
+fn sorted(changes: &[(u64, i64)]) -> Vec<(u64, i64)> {
+    let mut changes = changes.to_vec();
+    changes.sort_unstable();
+    changes
+}
+
+fn level_after(initial_level: i64, changes: Vec<(u64, i64)>) -> i64 {
+    changes
+        .into_iter()
+        .fold(initial_level, |level, (_, change)| (level + change).max(0))
+}
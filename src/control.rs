@@ -0,0 +1,102 @@
+//! A handle for reconfiguring recording while the program runs.
+
+use crate::Error;
+use crate::TraceBuilder;
+use crate::TraceBuilderOptions;
+use crate::session;
+use crate::set_span_sample_interval;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The options used by [Control::trace_builder]. `None` if they haven't been set.
+static TRACE_OPTIONS: Mutex<Option<TraceBuilderOptions>> = Mutex::new(None);
+
+/// Where [Control::write] writes traces.
+static OUTPUT_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// A handle, returned by [control], that changes how recording is done while the program runs. This
+/// allows long-running services to be tuned, e.g. from an admin interface, without restarting them.
+///
+/// Changes are applied at safe points, so never affect a span that has already started. Sampling
+/// and category changes apply from the next span started on each thread. Trace options and the
+/// output path apply to traces built after the change.
+#[derive(Debug, Clone, Copy)]
+pub struct Control {
+    _private: (),
+}
+
+/// Returns a handle for reconfiguring recording while the program runs.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if perfetto_recorder::is_enabled() {
+/// let control = perfetto_recorder::control();
+/// control
+///     .set_sample_interval(10)
+///     .set_categories(&["db*"], &["db.verbose"])
+///     .set_output_path(std::env::temp_dir().join("service.pftrace"));
+///
+/// // Later, e.g. when a snapshot is requested.
+/// let mut trace = control.trace_builder()?;
+/// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+/// control.write(&trace)?;
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn control() -> Control {
+    Control { _private: () }
+}
+
+impl Control {
+    /// Only records one in every `interval` spans on each thread. See
+    /// [crate::set_span_sample_interval].
+    pub fn set_sample_interval(&self, interval: u32) -> &Self {
+        set_span_sample_interval(interval);
+        self
+    }
+
+    /// Replaces the enabled and disabled categories, including any set by
+    /// [crate::start_with_config]. Patterns are as for [crate::SessionConfig::enable_category].
+    pub fn set_categories(&self, enabled: &[&str], disabled: &[&str]) -> &Self {
+        let to_owned = |patterns: &[&str]| patterns.iter().map(|p| (*p).to_owned()).collect();
+        session::set_categories(to_owned(enabled), to_owned(disabled));
+        self
+    }
+
+    /// Sets the options, such as the maximum sensitivity, used by [Control::trace_builder].
+    pub fn set_trace_options(&self, options: TraceBuilderOptions) -> &Self {
+        *TRACE_OPTIONS.lock().unwrap() = Some(options);
+        self
+    }
+
+    /// Returns the options used by [Control::trace_builder].
+    pub fn trace_options(&self) -> TraceBuilderOptions {
+        TRACE_OPTIONS.lock().unwrap().clone().unwrap_or_default()
+    }
+
+    /// Sets where [Control::write] writes traces.
+    pub fn set_output_path(&self, path: impl Into<PathBuf>) -> &Self {
+        *OUTPUT_PATH.lock().unwrap() = Some(path.into());
+        self
+    }
+
+    /// Returns where [Control::write] writes traces, if a path has been set.
+    pub fn output_path(&self) -> Option<PathBuf> {
+        OUTPUT_PATH.lock().unwrap().clone()
+    }
+
+    /// Creates a [TraceBuilder] using the current trace options.
+    pub fn trace_builder(&self) -> Result<TraceBuilder, Error> {
+        TraceBuilder::with_options(&self.trace_options())
+    }
+
+    /// Writes `trace` to the current output path. Returns an error if no output path has been set.
+    pub fn write(&self, trace: &TraceBuilder) -> Result<(), Error> {
+        let Some(path) = self.output_path() else {
+            return Err(Error::NoOutputPath);
+        };
+        trace.write_to_file(path)
+    }
+}
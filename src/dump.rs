@@ -0,0 +1,224 @@
+//! Writing a trace when the process receives a signal, without stopping or restarting it.
+
+use crate::EVENTS;
+use crate::Event;
+use crate::FIRST_EVENT_TIME;
+use crate::STRINGS;
+use crate::ThreadTraceData;
+use crate::Timestamp;
+use crate::budget;
+use crate::exit;
+use crate::os;
+use crate::ring_buffer;
+use crate::session;
+use crate::time;
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// How long a dump waits for running threads to hand over their events. Threads that don't record
+/// an event within this time, e.g. because they're blocked, are left out of the dump.
+const HANDOVER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Where dumps are written. `None` if [install_dump_handler] hasn't been called.
+static DUMP_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Incremented for each dump, so that each thread hands over its events once per dump.
+static DUMP_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// How many threads have handed over their events for the current dump.
+static HANDED_OVER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The last dump for which the current thread handed over its events.
+    static HANDED_OVER_GENERATION: Cell<u64> = const { Cell::new(0) };
+
+    /// Set while the current thread's events are temporarily replaced, so must stay where they are.
+    static HANDOVER_PAUSED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A signal that makes the process write a trace. See [install_dump_handler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DumpSignal {
+    /// SIGUSR1.
+    Usr1,
+    /// SIGUSR2.
+    Usr2,
+}
+
+/// Arranges for a trace to be written to `path` each time the process receives `signal`, e.g.
+/// from `kill -USR1 <pid>`. This allows a trace to be taken from a misbehaving production process
+/// without restarting it.
+///
+/// Each running thread hands over its events the next time that it records one. Threads that don't
+/// record anything for a short while, e.g. because they're blocked, are left out. The trace also
+/// includes the data of threads that have exited, events recorded by
+/// [crate::signal_safe_instant] and any samples that haven't been processed. Everything included
+/// is removed, so the next dump only has what was recorded after this one. Each dump replaces the
+/// previous file at `path`. The trace is built using the options set by
+/// [crate::Control::set_trace_options].
+///
+/// This replaces any existing handler for `signal`. Calling this again changes the path, and can
+/// add another signal. Only supported on unix.
+///
+/// # Example
+///
+/// ```no_run
+/// use perfetto_recorder::DumpSignal;
+///
+/// perfetto_recorder::start()?;
+/// perfetto_recorder::install_dump_handler(DumpSignal::Usr1, "/var/log/service-dump.pftrace")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn install_dump_handler(
+    signal: DumpSignal,
+    path: impl Into<PathBuf>,
+) -> Result<(), std::io::Error> {
+    let mut dump_path = DUMP_PATH.lock().unwrap();
+    os::on_dump_signal(signal, write_dump)?;
+    *dump_path = Some(path.into());
+    Ok(())
+}
+
+/// Collects the events of running threads and writes them to the dump path.
+fn write_dump() {
+    let Some(path) = DUMP_PATH.lock().unwrap().clone() else {
+        return;
+    };
+    collect_running_threads();
+    exit::write_stashed_trace(&path);
+}
+
+/// Asks each running thread that has events buffered to hand them over, waiting until they all
+/// have or for at most [HANDOVER_TIMEOUT]. The events are stashed with those of threads that have
+/// exited.
+pub(crate) fn collect_running_threads() {
+    request_handovers();
+    // The calling thread won't record anything while it waits, so if it's one of the threads that
+    // we're waiting for, it hands over its events now.
+    if EVENTS.with(|events| {
+        events
+            .try_borrow()
+            .is_ok_and(|events| events.capacity() != 0)
+    }) {
+        hand_over_current_thread();
+    }
+    let deadline = std::time::Instant::now() + HANDOVER_TIMEOUT;
+    while HANDED_OVER.load(Ordering::Relaxed) < exit::buffering_threads()
+        && std::time::Instant::now() < deadline
+    {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    ring_buffer::request_handover(false);
+}
+
+/// Asks each running thread to hand over its events the next time that it records one, until the
+/// request is withdrawn with [ring_buffer::request_handover].
+pub(crate) fn request_handovers() {
+    HANDED_OVER.store(0, Ordering::Relaxed);
+    DUMP_GENERATION.fetch_add(1, Ordering::Relaxed);
+    ring_buffer::request_handover(true);
+}
+
+/// Sets whether the current thread's events must stay where they are, returning the previous
+/// setting.
+pub(crate) fn pause_handover(paused: bool) -> bool {
+    HANDOVER_PAUSED.replace(paused)
+}
+
+/// Moves the current thread's complete events to where the dump will find them, unless it has
+/// already done so for the current dump.
+pub(crate) fn hand_over_current_thread() {
+    let generation = DUMP_GENERATION.load(Ordering::Relaxed);
+    if HANDOVER_PAUSED.get() || HANDED_OVER_GENERATION.get() == generation {
+        return;
+    }
+    // A thread that's dropping its newest events can't tell where the event that it's recording
+    // starts, so it keeps its events until they're taken.
+    if !ring_buffer::dropping_newest() {
+        let split = EVENTS.with(|events| {
+            STRINGS.with(|strings| {
+                // Panicking here would lose the thread's events, so if the buffers are already
+                // borrowed, we try again when the next event is recorded.
+                let (Ok(mut events), Ok(mut strings)) =
+                    (events.try_borrow_mut(), strings.try_borrow_mut())
+                else {
+                    return None;
+                };
+                // Until the event that's being recorded has its timestamp, the end of the one
+                // before it would be mistaken for its start, so we also try again later.
+                if !ring_buffer::is_timestamp(events.last()) {
+                    return None;
+                }
+                let handed = split_complete_records(&mut events, &mut strings);
+                let new_bytes = events.capacity() * size_of::<Event>() + strings.capacity();
+                Some((handed, new_bytes, events.is_empty()))
+            })
+        });
+        let Some((handed, new_bytes, remaining_empty)) = split else {
+            return;
+        };
+        // The event that's being recorded is all that's left, so belongs to the current session.
+        let session = session::take_buffer_session();
+
+        if let Some((mut events, strings)) = handed {
+            session::lifetime_events_taken(&events);
+            // The handed over buffers still count towards the memory budget until they're
+            // processed, so only the new buffers need adding.
+            if new_bytes != 0 {
+                budget::grow(new_bytes);
+            }
+            let first_event_time = if remaining_empty {
+                FIRST_EVENT_TIME.take()
+            } else {
+                FIRST_EVENT_TIME.replace(Some(time()))
+            };
+            let drops = ring_buffer::take_drops(&mut events);
+            exit::stash(ThreadTraceData {
+                events,
+                strings,
+                pid: os::getpid(),
+                tid: os::gettid(),
+                thread_name: std::thread::current().name().map(str::to_owned),
+                first_event_time: first_event_time.map(Timestamp::Instant),
+                taken_time: Timestamp::Instant(time()),
+                dropped_events: drops.events,
+                first_drop_time: drops.first_time.map(Timestamp::Instant),
+                thread_started: exit::take_thread_started(),
+                thread_exited: false,
+                session,
+            });
+        }
+    }
+    HANDED_OVER_GENERATION.set(generation);
+    HANDED_OVER.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Removes all complete records from `events`, returning them together with the strings that they
+/// refer to, or `None` if there aren't any. The event that's being recorded, which might still be
+/// missing some of its arguments, is left in `events`, with its strings moved to a fresh `strings`.
+pub(crate) fn split_complete_records(
+    events: &mut Vec<Event>,
+    strings: &mut String,
+) -> Option<(Vec<Event>, String)> {
+    // Only top-level events are followed by a timestamp, so the last of those starts the event
+    // that's being recorded.
+    let cut = (1..events.len())
+        .rev()
+        .find(|index| ring_buffer::is_timestamp(events.get(index + 1)))?;
+    let remaining = events.split_off(cut);
+    let handed_events = std::mem::replace(events, remaining);
+    let handed_strings = std::mem::take(strings);
+    for event in events {
+        if let Event::Str { offset, len } = event {
+            let start = strings.len();
+            strings.push_str(&handed_strings[*offset..*offset + *len]);
+            *offset = start;
+        }
+    }
+    Some((handed_events, handed_strings))
+}
@@ -0,0 +1,121 @@
+//! An error type that covers the ways that creating, loading and writing a trace can fail.
+
+use crate::ClockUnavailable;
+use crate::InvalidFooter;
+use crate::InvalidTrace;
+use crate::InvalidTraceConfig;
+use crate::TracingDisabled;
+use crate::TracingDisabledAtBuildTime;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+/// An error returned by [crate::TraceBuilder] and related types. Functions that can only fail in
+/// one way, such as [crate::start] and [crate::verify_footer], return a more specific error, which
+/// converts into this type, so that `?` can be used to handle all of them together.
+///
+/// # Example
+///
+/// ```no_run
+/// use perfetto_recorder::TraceBuilder;
+///
+/// fn write_trace() -> Result<(), perfetto_recorder::Error> {
+///     perfetto_recorder::start()?;
+///     TraceBuilder::new()?.write_to_file("trace.pftrace")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Recording hasn't been started.
+    Disabled(TracingDisabled),
+
+    /// The "enable" feature of this crate isn't active.
+    DisabledAtBuildTime(TracingDisabledAtBuildTime),
+
+    /// The requested clock isn't available on this platform.
+    ClockUnavailable(ClockUnavailable),
+
+    /// A trace couldn't be decoded.
+    InvalidTrace(InvalidTrace),
+
+    /// A trace's footer was missing or didn't match the trace.
+    InvalidFooter(InvalidFooter),
+
+    /// A Perfetto `TraceConfig` couldn't be decoded.
+    InvalidTraceConfig(InvalidTraceConfig),
+
+    /// Writing the trace to `path` failed.
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// [crate::Control::write] was called before an output path was set.
+    NoOutputPath,
+
+    /// A [crate::TraceOutput] or the output of a [crate::TraceWriter] failed to write the trace.
+    Output(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Write { source, .. } => Some(source),
+            Error::Output(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Disabled(error) => error.fmt(f),
+            Error::DisabledAtBuildTime(error) => error.fmt(f),
+            Error::ClockUnavailable(error) => error.fmt(f),
+            Error::InvalidTrace(error) => error.fmt(f),
+            Error::InvalidFooter(error) => error.fmt(f),
+            Error::InvalidTraceConfig(error) => error.fmt(f),
+            Error::Write { path, .. } => write!(f, "Failed to write trace to `{}`", path.display()),
+            Error::NoOutputPath => write!(f, "No output path set"),
+            Error::Output(_) => write!(f, "Failed to write trace to output"),
+        }
+    }
+}
+
+impl From<TracingDisabled> for Error {
+    fn from(error: TracingDisabled) -> Self {
+        Error::Disabled(error)
+    }
+}
+
+impl From<TracingDisabledAtBuildTime> for Error {
+    fn from(error: TracingDisabledAtBuildTime) -> Self {
+        Error::DisabledAtBuildTime(error)
+    }
+}
+
+impl From<ClockUnavailable> for Error {
+    fn from(error: ClockUnavailable) -> Self {
+        Error::ClockUnavailable(error)
+    }
+}
+
+impl From<InvalidTrace> for Error {
+    fn from(error: InvalidTrace) -> Self {
+        Error::InvalidTrace(error)
+    }
+}
+
+impl From<InvalidFooter> for Error {
+    fn from(error: InvalidFooter) -> Self {
+        Error::InvalidFooter(error)
+    }
+}
+
+impl From<InvalidTraceConfig> for Error {
+    fn from(error: InvalidTraceConfig) -> Self {
+        Error::InvalidTraceConfig(error)
+    }
+}
@@ -0,0 +1,294 @@
+//! Keeping the events of threads that exit, and writing a trace when the process exits.
+
+use crate::EVENTS;
+use crate::FIRST_EVENT_TIME;
+use crate::STRINGS;
+use crate::ThreadTraceData;
+use crate::Timestamp;
+use crate::TraceBuilder;
+use crate::budget;
+use crate::control;
+use crate::dump;
+use crate::os;
+use crate::ring_buffer;
+use crate::session;
+use crate::time;
+use std::cell::Cell;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Where the trace is written when the process exits. `None` if [install_exit_handler] hasn't been
+/// called.
+static EXIT_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Data from threads that exited without their events being taken. The memory it uses still counts
+/// towards the memory budget until it's taken.
+static EXITED_THREADS: Mutex<Vec<ThreadTraceData>> = Mutex::new(Vec::new());
+
+/// Set once [install_exit_handler] has installed its signal handlers.
+static SIGNALS_HANDLED: AtomicBool = AtomicBool::new(false);
+
+/// The number of threads that have allocated a buffer for their events that hasn't been taken.
+static BUFFERING_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the exit trace has been written, so that we only write it once.
+static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Stashes a thread's remaining events when the thread exits, so that they're not lost.
+struct ExitCapture {
+    thread_name: Option<String>,
+}
+
+thread_local! {
+    static EXIT_CAPTURE: ExitCapture = {
+        THREAD_STARTED.set(true);
+        ExitCapture {
+            thread_name: std::thread::current().name().map(str::to_owned),
+        }
+    };
+
+    /// Set when the current thread first records events, until its data is next taken.
+    static THREAD_STARTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Arranges for a trace to be written to `path` when the process exits, including when it's
+/// terminated by SIGTERM or SIGINT, as is done by most orchestration systems. This means that
+/// daemons that are never shut down cleanly still produce a trace.
+///
+/// The trace includes the data of threads that exited before the process, events recorded by
+/// [crate::signal_safe_instant] and any samples that haven't been processed. It's built using the
+/// options set by [crate::Control::set_trace_options]. If the process exits normally, the data of
+/// the thread that exits the process, usually the main thread, is included too. Threads that are
+/// still running hand over their data if they record an event shortly after the process starts to
+/// exit, as for [crate::install_dump_handler]. Other threads that are still running should pass
+/// their data to a [crate::TraceBuilder] themselves.
+///
+/// On unix, this replaces any existing SIGTERM and SIGINT handlers. Once the trace has been
+/// written, the signal is raised again with its default handler, so the process terminates as it
+/// would have otherwise. On Windows, only normal exits are handled. Calling this again changes the
+/// path.
+///
+/// # Example
+///
+/// ```no_run
+/// perfetto_recorder::start()?;
+/// perfetto_recorder::install_exit_handler("/var/log/service.pftrace")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn install_exit_handler(path: impl Into<PathBuf>) -> Result<(), std::io::Error> {
+    write_on_exit(path)?;
+    if SIGNALS_HANDLED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    os::on_termination_signal(write_exit_trace)
+}
+
+/// Arranges for a trace to be written to `path` when the process exits normally, i.e. by returning
+/// from `main` or calling [std::process::exit]. This saves collecting the data of each thread at
+/// every exit path, which is easy to forget in programs such as CLIs that can exit from many
+/// places. The trace includes the same data as for [install_exit_handler], but signal handlers
+/// are left alone, so a process that's killed doesn't write a trace. Calling this again changes
+/// the path.
+///
+/// # Example
+///
+/// ```no_run
+/// perfetto_recorder::start()?;
+/// perfetto_recorder::write_on_exit("out.pftrace")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn write_on_exit(path: impl Into<PathBuf>) -> Result<(), std::io::Error> {
+    let previous = EXIT_PATH.lock().unwrap().replace(path.into());
+    if previous.is_some() {
+        return Ok(());
+    }
+
+    // SAFETY: `write_at_exit` is safe to call at any point during exit.
+    if unsafe { libc::atexit(write_at_exit) } != 0 {
+        return Err(std::io::Error::other("Failed to register exit handler"));
+    }
+    Ok(())
+}
+
+/// Called when the current thread records its first events, so that they can be stashed if the
+/// thread exits.
+pub(crate) fn register_thread() {
+    // Thread locals are destroyed in the reverse order to that in which they were first used, so we
+    // make sure that the events are still around when our capture is destroyed.
+    STRINGS.with(|_| {});
+    EVENTS.with(|_| {});
+    EXIT_CAPTURE.with(|_| {});
+    BUFFERING_THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns whether the current thread's data hasn't been taken since it first recorded events,
+/// clearing the flag.
+pub(crate) fn take_thread_started() -> bool {
+    // Thread locals might already have been destroyed if the thread is exiting.
+    THREAD_STARTED.try_with(Cell::take).unwrap_or(false)
+}
+
+/// Called when the current thread's buffer of events, which had `capacity`, is taken or freed.
+pub(crate) fn buffer_taken(capacity: usize) {
+    if capacity != 0 {
+        let _ = BUFFERING_THREADS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            count.checked_sub(1)
+        });
+    }
+}
+
+impl Drop for ExitCapture {
+    fn drop(&mut self) {
+        // `std::thread::current` isn't available while thread locals are being destroyed, so we
+        // can't use `ThreadTraceData::take_current_thread`.
+        let (Ok(mut events), Ok(strings)) = (
+            EVENTS.try_with(|e| e.take()),
+            STRINGS.try_with(|s| s.take()),
+        ) else {
+            return;
+        };
+        buffer_taken(events.capacity());
+        if events.is_empty() {
+            budget::release(events.capacity() * size_of::<crate::Event>() + strings.capacity());
+            return;
+        }
+        let drops = ring_buffer::take_drops(&mut events);
+        stash(ThreadTraceData {
+            events,
+            strings,
+            pid: os::getpid(),
+            tid: os::gettid(),
+            thread_name: self.thread_name.take(),
+            first_event_time: FIRST_EVENT_TIME
+                .try_with(|time| time.take())
+                .ok()
+                .flatten()
+                .map(Timestamp::Instant),
+            taken_time: Timestamp::Instant(time()),
+            dropped_events: drops.events,
+            first_drop_time: drops.first_time.map(Timestamp::Instant),
+            thread_started: take_thread_started(),
+            thread_exited: true,
+            session: session::take_buffer_session(),
+        });
+    }
+}
+
+/// Returns the number of threads that have a buffer of events that hasn't been taken.
+pub(crate) fn buffering_threads() -> usize {
+    BUFFERING_THREADS.load(Ordering::Relaxed)
+}
+
+/// Keeps `thread` until it's added to a trace by [TraceBuilder::process_exited_threads]. The memory
+/// that it uses still counts towards the memory budget until then.
+pub(crate) fn stash(thread: ThreadTraceData) {
+    EXITED_THREADS.lock().unwrap().push(thread);
+}
+
+/// Removes and returns the data that has been stashed, which then no longer counts towards the
+/// memory budget.
+pub(crate) fn take_stashed() -> Vec<ThreadTraceData> {
+    take_stashed_if(|_| true)
+}
+
+/// Returns a copy of the data that has been stashed, leaving it stashed.
+pub(crate) fn copy_stashed() -> Vec<ThreadTraceData> {
+    EXITED_THREADS.lock().unwrap().clone()
+}
+
+/// Removes and returns the stashed data for which `take` returns true. The rest stays stashed.
+pub(crate) fn take_stashed_if(take: impl Fn(&ThreadTraceData) -> bool) -> Vec<ThreadTraceData> {
+    let mut stashed = EXITED_THREADS.lock().unwrap();
+    let (threads, kept) = std::mem::take(&mut *stashed)
+        .into_iter()
+        .partition(|thread| take(thread));
+    *stashed = kept;
+    for thread in &threads {
+        budget::release(
+            thread.events.capacity() * size_of::<crate::Event>() + thread.strings.capacity(),
+        );
+    }
+    threads
+}
+
+extern "C" fn write_at_exit() {
+    // Unwinding out of an `extern "C"` function would abort the process.
+    let _ = std::panic::catch_unwind(write_exit_trace);
+}
+
+/// Writes the exit trace, unless it's already been written.
+pub(crate) fn write_exit_trace() {
+    let Some(path) = claim_exit_trace() else {
+        return;
+    };
+    dump::collect_running_threads();
+    write_stashed_trace(&path);
+}
+
+/// Returns where the exit trace should be written, or `None` if it's already been claimed or there
+/// isn't an exit path.
+pub(crate) fn claim_exit_trace() -> Option<PathBuf> {
+    if WRITTEN.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    EXIT_PATH.lock().unwrap().clone()
+}
+
+/// Writes a trace to `path` of the data that has been stashed, signal-safe events and samples,
+/// reporting any error to stderr, since there's no caller to return it to.
+pub(crate) fn write_stashed_trace(path: &Path) {
+    write_trace(path, |trace| {
+        trace
+            .process_exited_threads()
+            .process_signal_events()
+            .process_samples();
+    });
+}
+
+/// Writes a trace to `path` of the data that `add` adds to it, reporting any error to stderr.
+pub(crate) fn write_trace(path: &Path, add: impl FnOnce(&mut TraceBuilder)) {
+    let Ok(mut trace) = control().trace_builder() else {
+        return;
+    };
+
+    add(&mut trace);
+    if let Err(error) = trace.write_to_file(path) {
+        match std::error::Error::source(&error) {
+            Some(source) => eprintln!("{error}: {source}"),
+            None => eprintln!("{error}"),
+        }
+    }
+}
+
+impl TraceBuilder {
+    /// Adds the data of all threads that have exited since the last call to this function without
+    /// their events having been taken by [ThreadTraceData::take_current_thread]. Without this,
+    /// spans recorded by short-lived threads that the caller doesn't control, such as those of a
+    /// thread pool that shrinks, would be lost. Also adds any data that running threads handed over
+    /// too late to be included in a dump written by [crate::install_dump_handler].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if start().is_ok() {
+    /// std::thread::spawn(|| {
+    ///     scope!("short-lived");
+    /// })
+    /// .join()
+    /// .unwrap();
+    ///
+    /// let mut trace = TraceBuilder::new()?;
+    /// trace.process_exited_threads();
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn process_exited_threads(&mut self) -> &mut Self {
+        self.process_all(take_stashed());
+        self
+    }
+}
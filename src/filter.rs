@@ -0,0 +1,151 @@
+//! Loading previously written traces and removing parts of them.
+
+use crate::Error;
+use crate::InvalidTrace;
+use crate::TraceBuilder;
+use crate::TraceBuilderOptions;
+use crate::packet_defaults;
+use crate::schema;
+use crate::schema::trace_packet::Data;
+use crate::schema::trace_packet::OptionalTrustedPacketSequenceId;
+use crate::schema::track_event::NameField;
+use crate::schema::track_event::Type;
+use prost::Message;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+impl TraceBuilder {
+    /// Loads an encoded trace, such as one written earlier by a [TraceBuilder], so that parts of it
+    /// can be removed, e.g. with [TraceBuilder::restrict_to_timestamps],
+    /// [TraceBuilder::retain_spans] or [TraceBuilder::retain_threads], before it's written again.
+    /// This is useful for cutting a shareable excerpt out of a large archived trace. Unlike
+    /// [TraceBuilder::new], this doesn't require recording to be enabled. Any footer is dropped,
+    /// since it won't match the trace once it's been changed.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use perfetto_recorder::TraceBuilder;
+    ///
+    /// let bytes = std::fs::read("archived.pftrace")?;
+    /// let mut trace = TraceBuilder::import(&bytes)?;
+    /// trace
+    ///     .retain_spans(|name| !name.starts_with("poll"))
+    ///     .retain_threads(|_pid, _tid, name| name == Some("main"));
+    /// trace.write_to_file("excerpt.pftrace")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn import(trace: &[u8]) -> Result<TraceBuilder, Error> {
+        let mut trace = schema::Trace::decode(trace).map_err(|_| InvalidTrace)?;
+        packet_defaults::resolve_packet_defaults(&mut trace.packet);
+        let mut builder = Self::build(&TraceBuilderOptions::new(), false);
+        // The imported trace has its own clock snapshot, so we replace the packets that the builder
+        // started with rather than adding to them.
+        builder.trace.packet = trace
+            .packet
+            .into_iter()
+            .filter(|packet| !matches!(packet.data, Some(Data::TraceFooter(_))))
+            .collect();
+        Ok(builder)
+    }
+
+    /// Removes the spans whose names `keep` returns false for. Spans that were nested within a
+    /// removed span are kept.
+    pub fn retain_spans(&mut self, mut keep: impl FnMut(&str) -> bool) -> &mut Self {
+        let mut names: HashMap<Option<OptionalTrustedPacketSequenceId>, HashMap<u64, String>> =
+            HashMap::new();
+        // Whether each open span is being kept, by track.
+        let mut open: HashMap<u64, Vec<bool>> = HashMap::new();
+        let mut keep_packets = vec![true; self.trace.packet.len()];
+
+        for (index, packet) in self.trace.packet.iter().enumerate() {
+            let sequence = packet.optional_trusted_packet_sequence_id;
+            let incremental_state_cleared =
+                schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32;
+            if packet.sequence_flags.unwrap_or_default() & incremental_state_cleared != 0 {
+                names.remove(&sequence);
+            }
+            if let Some(interned) = &packet.interned_data {
+                let sequence_names = names.entry(sequence).or_default();
+                for event_name in &interned.event_names {
+                    if let (Some(iid), Some(name)) = (event_name.iid, &event_name.name) {
+                        sequence_names.insert(iid, name.clone());
+                    }
+                }
+            }
+
+            let Some(Data::TrackEvent(event)) = &packet.data else {
+                continue;
+            };
+            let track_spans = open
+                .entry(event.track_uuid.unwrap_or_default())
+                .or_default();
+            match event.r#type.and_then(|kind| Type::try_from(kind).ok()) {
+                Some(Type::SliceBegin) => {
+                    let name = match &event.name_field {
+                        Some(NameField::Name(name)) => Some(name.as_str()),
+                        Some(NameField::NameIid(iid)) => names
+                            .get(&sequence)
+                            .and_then(|sequence_names| sequence_names.get(iid))
+                            .map(String::as_str),
+                        None => None,
+                    };
+                    let kept = name.is_none_or(&mut keep);
+                    keep_packets[index] = kept;
+                    track_spans.push(kept);
+                }
+                Some(Type::SliceEnd) => {
+                    keep_packets[index] = track_spans.pop().unwrap_or(true);
+                }
+                _ => {}
+            }
+        }
+
+        self.retain_packets(keep_packets)
+    }
+
+    /// Removes the threads that `keep` returns false for, together with everything recorded on
+    /// them. `keep` is passed the process ID, thread ID and name of each thread.
+    pub fn retain_threads(
+        &mut self,
+        mut keep: impl FnMut(i32, i32, Option<&str>) -> bool,
+    ) -> &mut Self {
+        let removed: HashSet<u64> = self
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(Data::TrackDescriptor(descriptor)) => {
+                    let thread = descriptor.thread.as_ref()?;
+                    let kept = keep(
+                        thread.pid.unwrap_or_default(),
+                        thread.tid.unwrap_or_default(),
+                        thread.thread_name.as_deref(),
+                    );
+                    (!kept).then_some(descriptor.uuid?)
+                }
+                _ => None,
+            })
+            .collect();
+        if removed.is_empty() {
+            return self;
+        }
+
+        let track_uuid = |data: &Option<Data>| match data {
+            Some(Data::TrackDescriptor(descriptor)) => descriptor.uuid,
+            Some(Data::TrackEvent(event)) => event.track_uuid,
+            _ => None,
+        };
+        let keep_packets = self
+            .trace
+            .packet
+            .iter()
+            .map(|packet| track_uuid(&packet.data).is_none_or(|uuid| !removed.contains(&uuid)))
+            .collect();
+        self.descriptors
+            .retain(|descriptor| descriptor.uuid.is_none_or(|uuid| !removed.contains(&uuid)));
+        self.thread_uuids
+            .retain(|_, uuid| !removed.contains(&uuid.0));
+        self.retain_packets(keep_packets)
+    }
+}
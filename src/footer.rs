@@ -0,0 +1,212 @@
+//! A footer that can be appended to a trace, allowing it to be checked for completeness.
+
+use crate::TraceBuilder;
+use crate::schema;
+use crate::schema::trace_packet::Data;
+use crate::signal;
+use prost::Message;
+use prost::encoding::WireType;
+
+/// The field number of `packet` in the `Trace` message.
+const PACKET_FIELD: u32 = 1;
+
+/// Statistics read from a trace's footer by [verify_footer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FooterStats {
+    /// The number of packets in the trace, not counting the footer.
+    pub packet_count: u64,
+    /// The number of spans that were started in the trace.
+    pub span_count: u64,
+    /// The number of events that were discarded when they were recorded, e.g. because too many
+    /// signal-safe events were pending or a thread exceeded the limit of its [crate::BufferMode].
+    pub dropped_event_count: u64,
+}
+
+/// Why [verify_footer] rejected a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidFooter {
+    /// The trace couldn't be parsed, e.g. because it was truncated part-way through a packet.
+    Malformed,
+    /// The last packet of the trace isn't a footer.
+    Missing,
+    /// The footer's checksum doesn't match the rest of the trace.
+    ChecksumMismatch,
+}
+
+impl TraceBuilder {
+    /// Sets whether a footer packet is appended to the trace when it's encoded. The footer records
+    /// the number of packets, spans and dropped events together with a checksum of the rest of the
+    /// trace, so that pipelines that collect traces can use [verify_footer] to check that a trace
+    /// was written in full before archiving it. Perfetto ignores the footer.
+    pub fn set_footer(&mut self, enabled: bool) -> &mut Self {
+        self.footer = enabled;
+        self
+    }
+
+    /// Appends a footer packet for `trace`, which has been encoded into `bytes`.
+    pub(crate) fn append_footer(&self, trace: &schema::Trace, bytes: &mut Vec<u8>) {
+        let mut state = FooterState::default();
+        state.add(trace, bytes);
+        self.append_footer_for(&state, bytes);
+    }
+
+    /// Appends a footer packet with the totals in `state` to `bytes`.
+    pub(crate) fn append_footer_for(&self, state: &FooterState, bytes: &mut Vec<u8>) {
+        let footer = schema::TraceFooter {
+            packet_count: Some(state.packet_count),
+            span_count: Some(state.span_count),
+            dropped_event_count: Some(signal::discarded_events() + self.dropped_events),
+            crc32: Some(!state.crc),
+        };
+        let footer_trace = schema::Trace {
+            packet: vec![schema::TracePacket {
+                data: Some(Data::TraceFooter(footer)),
+                optional_trusted_packet_sequence_id: Some(
+                    schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                        self.sequence_id,
+                    ),
+                ),
+                ..Default::default()
+            }],
+        };
+        footer_trace.encode(bytes).unwrap();
+    }
+}
+
+/// The totals recorded in a footer, accumulated over the parts of a trace that are encoded
+/// separately, e.g. by a [crate::TraceWriter].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FooterState {
+    packet_count: u64,
+    span_count: u64,
+    /// The CRC-32 of the bytes so far, before its final inversion.
+    crc: u32,
+}
+
+impl Default for FooterState {
+    fn default() -> Self {
+        Self {
+            packet_count: 0,
+            span_count: 0,
+            crc: !0,
+        }
+    }
+}
+
+impl FooterState {
+    /// Adds `trace`, which has been encoded into `bytes`, to the totals.
+    pub(crate) fn add(&mut self, trace: &schema::Trace, bytes: &[u8]) {
+        let span_count = trace
+            .packet
+            .iter()
+            .filter(|packet| match &packet.data {
+                Some(Data::TrackEvent(event)) => {
+                    event.r#type() == schema::track_event::Type::SliceBegin
+                }
+                _ => false,
+            })
+            .count();
+        self.packet_count += trace.packet.len() as u64;
+        self.span_count += span_count as u64;
+        self.crc = crc32_update(self.crc, bytes);
+    }
+}
+
+/// Checks that `trace`, an encoded trace, ends with a footer written by a [TraceBuilder] with
+/// [TraceBuilder::set_footer] enabled and that the rest of the trace matches the footer's checksum.
+/// Returns the statistics recorded in the footer.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if perfetto_recorder::is_enabled() {
+/// let mut trace = TraceBuilder::new()?;
+/// trace.set_footer(true);
+/// let bytes = trace.encode_to_vec();
+/// assert!(verify_footer(&bytes).is_ok());
+/// assert!(verify_footer(&bytes[..bytes.len() - 1]).is_err());
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn verify_footer(trace: &[u8]) -> Result<FooterStats, InvalidFooter> {
+    // Find where the last packet starts, without decoding any of the packets.
+    let mut last_packet_start = None;
+    let mut remaining = trace;
+    while !remaining.is_empty() {
+        let start = trace.len() - remaining.len();
+        let (field, wire_type) =
+            prost::encoding::decode_key(&mut remaining).map_err(|_| InvalidFooter::Malformed)?;
+        if field != PACKET_FIELD || wire_type != WireType::LengthDelimited {
+            return Err(InvalidFooter::Malformed);
+        }
+        let len =
+            prost::encoding::decode_varint(&mut remaining).map_err(|_| InvalidFooter::Malformed)?;
+        let len = usize::try_from(len).map_err(|_| InvalidFooter::Malformed)?;
+        remaining = remaining.get(len..).ok_or(InvalidFooter::Malformed)?;
+        last_packet_start = Some(start);
+    }
+
+    let start = last_packet_start.ok_or(InvalidFooter::Missing)?;
+    let last = schema::Trace::decode(&trace[start..]).map_err(|_| InvalidFooter::Malformed)?;
+    let Some(Data::TraceFooter(footer)) = last.packet.into_iter().next().and_then(|p| p.data)
+    else {
+        return Err(InvalidFooter::Missing);
+    };
+    if footer.crc32 != Some(crc32(&trace[..start])) {
+        return Err(InvalidFooter::ChecksumMismatch);
+    }
+
+    Ok(FooterStats {
+        packet_count: footer.packet_count.unwrap_or_default(),
+        span_count: footer.span_count.unwrap_or_default(),
+        dropped_event_count: footer.dropped_event_count.unwrap_or_default(),
+    })
+}
+
+/// Lookup table for [crc32], with an entry for each possible byte value.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the standard CRC-32 (as used by zlib) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Continues computing a CRC-32 from `crc` over `bytes`, without the final inversion.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl std::error::Error for InvalidFooter {}
+
+impl std::fmt::Display for InvalidFooter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidFooter::Malformed => write!(f, "Trace is malformed"),
+            InvalidFooter::Missing => write!(f, "Trace doesn't end with a footer"),
+            InvalidFooter::ChecksumMismatch => {
+                write!(f, "Trace doesn't match its footer's checksum")
+            }
+        }
+    }
+}
@@ -9,11 +9,20 @@ use crate::schema::TrackDescriptor;
 use prost::Message;
 use rand::RngCore;
 use rand::rngs::ThreadRng;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 #[cfg(unix)]
 #[path = "os_unix.rs"]
@@ -29,8 +38,123 @@ type Instant = fastant::Instant;
 #[cfg(not(feature = "fastant"))]
 type Instant = std::time::SystemTime;
 
+mod assertions;
+mod async_span;
+mod budget;
+mod clock_anchor;
+mod compare;
+mod concurrency;
+mod control;
+mod dump;
+mod error;
+mod exit;
+mod filter;
+mod footer;
+mod log_event;
+mod measure;
+mod options;
+mod output;
+mod packet_defaults;
+mod panic_hook;
+mod ring_buffer;
+mod sampler;
+mod serialize;
+mod session;
+mod shard;
+mod signal;
+#[cfg(feature = "span-stack")]
+mod span_stack;
+mod spawn;
+mod tail_sampling;
+mod units;
+mod validate;
+mod watchpoint;
+mod window;
+mod writer;
+
+/// The generated protobuf types for the subset of the Perfetto trace format that this crate uses.
+#[cfg(feature = "raw-schema")]
+pub mod schema;
+
+#[cfg(not(feature = "raw-schema"))]
 mod schema;
 
+#[doc(hidden)]
+pub use assertions::assert_max_duration;
+#[doc(hidden)]
+pub use assertions::assert_total_duration;
+#[doc(hidden)]
+pub use assertions::parse_duration;
+pub use async_span::AsyncSpanMode;
+pub use async_span::OwnedSpan;
+pub use budget::BudgetAction;
+pub use budget::buffered_bytes;
+pub use budget::clear_memory_budget;
+pub use budget::set_memory_budget;
+pub use clock_anchor::ClockAnchor;
+pub use clock_anchor::export_clock_anchor;
+pub use compare::InvalidTrace;
+pub use compare::SpanComparison;
+pub use compare::SpanStats;
+pub use compare::TraceComparison;
+pub use compare::compare_traces;
+pub use control::Control;
+pub use control::control;
+pub use dump::DumpSignal;
+pub use dump::install_dump_handler;
+pub use error::Error;
+pub use exit::install_exit_handler;
+pub use exit::write_on_exit;
+pub use footer::FooterStats;
+pub use footer::InvalidFooter;
+pub use footer::verify_footer;
+pub use log_event::LogLevel;
+pub use measure::clear_measure_sink;
+pub use measure::measure;
+pub use measure::set_measure_sink;
+pub use options::TraceBuilderOptions;
+pub use output::FileOutput;
+pub use output::TraceOutput;
+pub use panic_hook::install_panic_hook;
+pub use ring_buffer::BufferMode;
+pub use ring_buffer::OverflowPolicy;
+pub use ring_buffer::set_buffer_mode;
+pub use sampler::Sampler;
+pub use sampler::register_gauge;
+pub use sampler::sample_now;
+pub use sampler::start_sampler;
+pub use session::InvalidTraceConfig;
+pub use session::SessionConfig;
+pub use session::SessionData;
+pub use session::end_session;
+pub use session::start_session;
+pub use session::start_with_config;
+pub use shard::TraceShard;
+pub use signal::SIGNAL_EVENT_CAPACITY;
+#[doc(hidden)]
+pub use signal::record_instant_signal_safe;
+#[cfg(feature = "span-stack")]
+pub use span_stack::current_span_depth;
+#[cfg(feature = "span-stack")]
+pub use span_stack::current_span_name;
+pub use spawn::spawn;
+pub use spawn::spawn_with;
+pub use tail_sampling::clear_tail_sampling;
+pub use tail_sampling::set_tail_sampling;
+pub use tail_sampling::tail_sampling_counts;
+pub use units::Bytes;
+pub use units::Micros;
+pub use units::Percent;
+#[doc(hidden)]
+pub use units::Unit;
+pub use validate::ValidationIssue;
+pub use validate::ValidationReport;
+pub use watchpoint::Threshold;
+pub use watchpoint::WatchpointAction;
+pub use watchpoint::add_watchpoint;
+pub use watchpoint::clear_watchpoints;
+pub use writer::TraceWriter;
+
 /// Begins a time span that ends when the current scope ends.
 ///
 /// Example usage:
@@ -50,6 +174,38 @@ macro_rules! scope {
     };
 }
 
+/// Evaluates an expression within a span, returning the expression's value. The span's name,
+/// options and arguments are as for [start_span] and are separated from the expression by a `;`.
+/// The span ends once the expression has been evaluated, including if it panics or returns early.
+/// This is convenient for small expressions and within closures, e.g. in iterator chains.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::with_span;
+///
+/// let lengths: Vec<usize> = ["a.rs", "b.rs"]
+///     .iter()
+///     .map(|&file| with_span!("measure", file; file.len()))
+///     .collect();
+/// ```
+///
+/// See also the [with_span](fn@with_span) function, which takes a closure.
+#[macro_export]
+macro_rules! with_span {
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $name:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        ; $body:expr
+    ) => {{
+        let _guard = $crate::start_span!(
+            $(@ $option $(= $option_arg)?)* $name
+            $(, $($arg_name $(: $arg_sensitivity)? $( = $arg_value)?),*)?
+        );
+        $body
+    }};
+}
+
 /// Begins a timing span, returning a guard, that when dropped will end the span.
 ///
 /// Example usage:
@@ -63,26 +219,124 @@ macro_rules! scope {
 /// ```
 ///
 /// If you don't need the span to outlive the scope in which it's created.
+///
+/// Argument values are only evaluated if the span is recorded, so expensive arguments cost nothing
+/// when recording is disabled or the span is dropped by sampling. See also [lazy].
+///
+/// Arguments after `@end` are attached to the end of the span rather than the start, which is
+/// useful for recording results. Their values are evaluated when the span ends, so anything they
+/// refer to is borrowed until then.
+///
+/// ```
+/// use perfetto_recorder::start_span;
+///
+/// let input = vec![3, 1, 2];
+/// let mut output = Vec::new();
+/// {
+///     let num_output = std::cell::Cell::new(0);
+///     let _span = start_span!(
+///         "Filter",
+///         input_len = input.len(),
+///         @end output_len = num_output.get()
+///     );
+///     output.extend(input.iter().copied().filter(|v| *v > 1));
+///     num_output.set(output.len());
+/// }
+/// ```
+///
+/// Spans and arguments can be marked as `internal` or `secret`, which allows a [TraceBuilder] to
+/// drop or redact them when producing a trace that is to be shared more widely. See
+/// [TraceBuilder::set_max_sensitivity]. Unmarked spans and arguments are `public`. An argument is
+/// treated as at least as sensitive as its span.
+///
+/// ```
+/// use perfetto_recorder::start_span;
+///
+/// let user = "alice";
+/// let span_guard = start_span!(@internal "Load profile", user: secret = user, cached = false);
+/// drop(span_guard);
+/// ```
+///
+/// Spans marked with `@process` are shown on a track that belongs to the process rather than on the
+/// track of the thread that recorded them. This is useful for spans that represent something
+/// process-wide, such as holding a global lock, that would otherwise be buried inside whichever
+/// thread happened to record them. Spans on the process track must not overlap unless they're
+/// nested, so this is best suited to things that only one thread can be doing at a time.
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// scope!(@process "Compact heap");
+/// ```
+///
+/// Spans can be shown on a track of their own with `@track = "..."`, which names a track created by
+/// [TraceBuilder::create_track]. The track is created when the trace is built if it doesn't already
+/// exist. As for `@process`, spans on the same track must not overlap unless they're nested.
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// scope!(@track = "GC" "Mark");
+/// ```
+///
+/// Spans can be given a category with `@category = "..."`. When recording is started with
+/// [start_with_config], only spans in the enabled categories are recorded. Spans without a category
+/// are always recorded. Each call site caches whether its category is enabled until the filter next
+/// changes, so checking it usually costs just a couple of atomic loads.
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// scope!(@category = "db" "Query");
+/// ```
+///
+/// Spans marked with `@collapse_recursion` don't nest when they directly recurse. Instead, each
+/// outermost call is shown as a single slice, annotated with `recursion_max_depth`, the deepest
+/// level of recursion, and `recursion_calls`, the number of calls including the outermost one. The
+/// arguments of the recursive calls are dropped. Recursion through other spans isn't collapsed, so
+/// spans in between still show their full detail.
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// fn visit(depth: u32) {
+///     scope!(@collapse_recursion "visit", depth);
+///     if depth < 10 {
+///         visit(depth + 1);
+///     }
+/// }
+/// visit(0);
+/// ```
+///
+/// A span's name can be any constant expression of type `&'static str`, so can be built with
+/// `concat!` and `stringify!`, e.g. in a macro that's expanded for several types. Within a generic
+/// function, however, the name is shared by all instantiations, since `stringify!(T)` gives `"T"`
+/// and [std::any::type_name] isn't a constant. Use [start_span_dynamic] to give each instantiation
+/// its own name.
+///
+/// ```
+/// use perfetto_recorder::scope;
+/// use perfetto_recorder::start_span_dynamic;
+///
+/// macro_rules! parse_fn {
+///     ($name:ident, $ty:ty) => {
+///         fn $name(input: &str) -> Option<$ty> {
+///             scope!(concat!("parse ", stringify!($ty)));
+///             input.parse().ok()
+///         }
+///     };
+/// }
+/// parse_fn!(parse_u32, u32);
+/// parse_fn!(parse_f64, f64);
+///
+/// fn decode<T: Default>() -> T {
+///     let _guard = start_span_dynamic!(std::any::type_name::<T>());
+///     T::default()
+/// }
+/// decode::<Vec<u8>>();
+/// ```
 #[macro_export]
 macro_rules! start_span {
-    ($name:expr $(, $($arg_name:ident $( = $arg_value:expr)?),*)?) => {{
-        const SOURCE_INFO: $crate::SourceInfo = $crate::SourceInfo {
-            name: $name,
-            file: file!(),
-            line: line!(),
-            arg_names: &[$($(stringify!($arg_name)),*)?],
-        };
-        if $crate::is_enabled() {
-            $crate::record_event($crate::Event::StartSpan(&SOURCE_INFO));
-                $crate::record_event($crate::Event::Timestamp($crate::time()));
-            $($($crate::RecordArg::record_arg(
-                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
-            );)*)?
-        }
-
-        $crate::SpanGuard::new(&SOURCE_INFO)
-    }};
-
     (@arg_value $name:ident) => {
         $name
     };
@@ -90,790 +344,5049 @@ macro_rules! start_span {
     (@arg_value $name:ident $value:expr) => {
         $value
     };
-}
-
-/// A guard that when dropped will end a span.
-///
-/// Created by the [start_span] macro.
-pub struct SpanGuard {
-    #[cfg(feature = "enable")]
-    pub source: &'static SourceInfo,
-}
 
-/// Trace events that occurred on a single thread.
-pub struct ThreadTraceData {
-    events: Vec<Event>,
-    pid: os::Pid,
-    tid: os::Pid,
-    thread_name: Option<String>,
-}
+    (@sensitivity) => {
+        $crate::Sensitivity::Public
+    };
 
-impl ThreadTraceData {
-    pub fn take_current_thread() -> Self {
-        let thread = std::thread::current();
-        Self {
-            events: EVENTS.take(),
-            pid: os::getpid(),
-            tid: os::gettid(),
-            thread_name: thread.name().map(str::to_owned),
-        }
-    }
-}
+    (@sensitivity public) => {
+        $crate::Sensitivity::Public
+    };
 
-/// The number of events consumed by each span.
-pub const EVENTS_PER_SPAN: usize = 4;
+    (@sensitivity internal) => {
+        $crate::Sensitivity::Internal
+    };
 
-/// The number of events consumed by each argument.
-pub const EVENTS_PER_ARG: usize = 1;
+    (@sensitivity secret) => {
+        $crate::Sensitivity::Secret
+    };
 
-/// The number of events consumed by each counter value.
-pub const EVENTS_PER_COUNTER: usize = 2;
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $name:expr,
+        $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?,)*
+        @end $($end_arg_name:ident $(: $end_arg_sensitivity:ident)? $( = $end_arg_value:expr)?),+
+        $(,)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $name,
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion:
+                $crate::SpanOptions::new()$(.$option($($option_arg)?))*.collapse_recursion,
+            dynamic_name: false,
+            arg_names: &[$(stringify!($arg_name)),*],
+            arg_sensitivities: &[$($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*],
+            end_arg_names: &[$(stringify!($end_arg_name)),+],
+            end_arg_sensitivities: &[
+                $($crate::start_span!(@sensitivity $($end_arg_sensitivity)?)),+
+            ],
+        });
+        let enabled = $crate::is_enabled() && CALL_SITE.category_enabled();
+        let start = if enabled && $crate::sample_span() {
+            $crate::record_event($crate::Event::StartSpan(CALL_SITE.id()));
+            let start = $crate::time();
+            $crate::record_event($crate::Event::Timestamp(start));
+            $($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*
+            Some(start)
+        } else {
+            None
+        };
 
-/// Reserve capacity on the current thread for additional spans and their arguments.
-///
-/// See constants [EVENTS_PER_SPAN], [EVENTS_PER_ARG], and [EVENTS_PER_COUNTER] to aid in working
-/// out what a reasonable value might be. Note that string slices will consume additional capacity
-/// for each multiple of 15 in size. Calling this is entirely optional, but might make recording
-/// spans and counters more consistent by reducing the need to reallocate the recording for the
-/// current thread.
-pub fn current_thread_reserve(additional: usize) {
-    EVENTS.with_borrow_mut(|events| events.reserve(additional))
-}
+        $crate::SpanGuardWithEndArgs::new(&CALL_SITE, enabled, start, || {
+            $($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $end_arg_name $($end_arg_value)?)
+            );)+
+        })
+    }};
 
-/// Types that implement this trait can be used as arguments to the [span] macro.
-pub trait RecordArg {
-    fn record_arg(self);
-}
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $name:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $name,
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion:
+                $crate::SpanOptions::new()$(.$option($($option_arg)?))*.collapse_recursion,
+            dynamic_name: false,
+            arg_names: &[$($(stringify!($arg_name)),*)?],
+            arg_sensitivities: &[
+                $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+            ],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        let enabled = $crate::is_enabled() && CALL_SITE.category_enabled();
+        let start = if enabled && $crate::sample_span() {
+            $crate::record_event($crate::Event::StartSpan(CALL_SITE.id()));
+            let start = $crate::time();
+            $crate::record_event($crate::Event::Timestamp(start));
+            $($($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*)?
+            Some(start)
+        } else {
+            None
+        };
 
-impl RecordArg for bool {
-    fn record_arg(self) {
-        record_event(Event::Bool(self));
-    }
+        $crate::SpanGuard::new(&CALL_SITE, enabled, start)
+    }};
 }
 
-impl RecordArg for f64 {
-    fn record_arg(self) {
-        record_event(Event::F64(self));
-    }
+/// Like [start_span], but the span's name is computed at runtime, e.g. with [format]. The name
+/// can be a `String` or a `&str` and is only evaluated if the span is recorded. Each distinct name
+/// is interned in the trace, so names should come from a limited set, e.g. the names of the files
+/// being compiled, rather than being unique to each span. Arguments can't be attached to the end of
+/// a span with a dynamic name.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::start_span_dynamic;
+///
+/// for unit in ["main.rs", "lib.rs"] {
+///     let _guard = start_span_dynamic!(format!("compile {unit}"), unit);
+/// }
+/// ```
+#[macro_export]
+macro_rules! start_span_dynamic {
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $name:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        $(,)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: stringify!($name),
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion:
+                $crate::SpanOptions::new()$(.$option($($option_arg)?))*.collapse_recursion,
+            dynamic_name: true,
+            arg_names: &[$($(stringify!($arg_name)),*)?],
+            arg_sensitivities: &[
+                $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+            ],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        let enabled = $crate::is_enabled() && CALL_SITE.category_enabled();
+        if enabled && $crate::sample_span() {
+            $crate::record_event($crate::Event::StartSpan(CALL_SITE.id()));
+            let start = $crate::time();
+            $crate::record_event($crate::Event::Timestamp(start));
+            let name = &$name;
+            let name = ::core::convert::AsRef::<str>::as_ref(name);
+            $crate::RecordArg::record_arg(name);
+            $($($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*)?
+            $crate::SpanGuard::new_named(&CALL_SITE, name, enabled, Some(start))
+        } else {
+            $crate::SpanGuard::new(&CALL_SITE, enabled, None)
+        }
+    }};
 }
 
-impl RecordArg for u64 {
-    fn record_arg(self) {
-        record_event(Event::U64(self));
-    }
-}
+/// Like [start_span], but the span starts at `time`, given in nanoseconds, rather than at the
+/// current time, and returns a [SimulatedSpan] that must be ended with [SimulatedSpan::end_at].
+/// This lets discrete-event simulators record spans in simulated time, while still using this
+/// crate's buffering and encoding. The times are written to the trace unchanged, so spans in
+/// simulated time are best kept in their own trace rather than mixed with spans in real time.
+/// Spans started with this macro aren't subject to sampling.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::start_span_at;
+///
+/// let mut sim_time = 0;
+/// for entity in 0..3_u64 {
+///     let step = start_span_at!(sim_time, "step", entity);
+///     sim_time += 1_000;
+///     step.end_at(sim_time);
+/// }
+/// ```
+#[macro_export]
+macro_rules! start_span_at {
+    (
+        $time:expr, $(@ $option:ident $(= $option_arg:literal)?)* $name:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        $(,)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $name,
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion:
+                $crate::SpanOptions::new()$(.$option($($option_arg)?))*.collapse_recursion,
+            dynamic_name: false,
+            arg_names: &[$($(stringify!($arg_name)),*)?],
+            arg_sensitivities: &[
+                $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+            ],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        let time: u64 = $time;
+        let recorded = $crate::is_enabled() && CALL_SITE.category_enabled();
+        if recorded {
+            $crate::record_event($crate::Event::StartSpan(CALL_SITE.id()));
+            $crate::record_event($crate::Event::SimulatedTimestamp(time));
+            $($($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*)?
+        }
 
-impl RecordArg for u32 {
-    fn record_arg(self) {
-        record_event(Event::U64(self.into()));
-    }
+        $crate::SimulatedSpan::new(&CALL_SITE, recorded, time)
+    }};
 }
 
-impl RecordArg for u16 {
-    fn record_arg(self) {
-        record_event(Event::U64(self.into()));
-    }
+/// Starts a span that can end on a different thread from the one that started it, returning an
+/// [OwnedSpan] that ends the span when dropped. This is useful for work that migrates between
+/// threads, such as a request that is handled by a pool of workers or an async task. Each async
+/// span is shown on its own track, so it doesn't need to nest with the spans of the threads that it
+/// passes through. Options and arguments are as for [start_span], except that arguments can't be
+/// attached to the end of the span.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::start_async_span;
+///
+/// let request = start_async_span!("request", id = 42_u64);
+/// std::thread::spawn(move || {
+///     // The span ends on this thread.
+///     drop(request);
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[macro_export]
+macro_rules! start_async_span {
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $name:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        $(,)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $name,
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion:
+                $crate::SpanOptions::new()$(.$option($($option_arg)?))*.collapse_recursion,
+            dynamic_name: false,
+            arg_names: &[$($(stringify!($arg_name)),*)?],
+            arg_sensitivities: &[
+                $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+            ],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        if $crate::is_enabled() && CALL_SITE.category_enabled() {
+            let span = $crate::OwnedSpan::start(&CALL_SITE);
+            $($($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*)?
+            span
+        } else {
+            $crate::OwnedSpan::not_recorded()
+        }
+    }};
 }
 
-impl RecordArg for u8 {
-    fn record_arg(self) {
-        record_event(Event::U64(self.into()));
-    }
+/// Checks a condition and, if it doesn't hold, records an instant event named after the failed
+/// expression, e.g. `assertion failed: queue.len() < 100`, on the current thread's track. Unlike
+/// [assert], this doesn't panic, so it's suitable for soft invariants that should be investigated
+/// without taking down the process. The failure shows up on the timeline at exactly the point where
+/// it happened. Evaluates to whether the condition held.
+///
+/// Options and arguments are as for [start_span]. Arguments are only evaluated if the condition
+/// doesn't hold. Use `@panic` before any other options to also panic, as [assert] would, once the
+/// failure has been recorded. In that case, the macro evaluates to `()`.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::assert_traced;
+///
+/// let queue_len = 3_u64;
+/// assert_traced!(queue_len < 100, queue_len);
+/// ```
+#[macro_export]
+macro_rules! assert_traced {
+    (@panic $($args:tt)*) => {
+        if !$crate::assert_traced!($($args)*) {
+            $crate::assert_traced!(@fail $($args)*);
+        }
+    };
+    (@fail $(@ $option:ident $(= $option_arg:literal)?)* $cond:expr $(, $($rest:tt)*)?) => {
+        panic!(concat!("assertion failed: ", stringify!($cond)))
+    };
+    (
+        $(@ $option:ident $(= $option_arg:literal)?)* $cond:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        $(,)?
+    ) => {{
+        let holds: bool = $cond;
+        if !holds {
+            static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+                name: concat!("assertion failed: ", stringify!($cond)),
+                file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+                line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+                sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+                process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+                category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+                track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+                collapse_recursion: false,
+                dynamic_name: false,
+                arg_names: &[$($(stringify!($arg_name)),*)?],
+                arg_sensitivities: &[
+                    $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+                ],
+                end_arg_names: &[],
+                end_arg_sensitivities: &[],
+            });
+            if $crate::is_enabled() && CALL_SITE.category_enabled() {
+                $crate::record_event($crate::Event::FailedAssertion(CALL_SITE.id()));
+                $crate::record_event($crate::Event::Timestamp($crate::time()));
+                $($($crate::RecordArg::record_arg(
+                    $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+                );)*)?
+            }
+        }
+        holds
+    }};
 }
 
-impl RecordArg for usize {
-    fn record_arg(self) {
-        record_event(Event::U64(self as u64));
-    }
+/// Like [scope], but only records the span in builds with debug assertions enabled. Useful for very
+/// fine-grained spans that shouldn't cost anything in release builds. In other builds, the span is
+/// compiled out entirely, so unlike with [debug_assert], its arguments aren't type-checked and
+/// variables that are only used by it may be reported as unused.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::debug_scope;
+///
+/// debug_scope!("Parse token", index = 3_u64);
+/// ```
+#[macro_export]
+macro_rules! debug_scope {
+    ($($args:tt)*) => {
+        #[cfg(debug_assertions)]
+        let _guard = $crate::start_span!($($args)*);
+    };
 }
 
-impl RecordArg for i64 {
-    fn record_arg(self) {
-        record_event(Event::I64(self));
-    }
+/// Like [start_span], but only records the span in builds with debug assertions enabled. Returns
+/// `None` in other builds, in which the span is compiled out as for [debug_scope].
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::debug_span;
+///
+/// let span_guard = debug_span!("Parse token");
+/// // Do some work.
+/// drop(span_guard);
+/// ```
+#[macro_export]
+macro_rules! debug_span {
+    ($($args:tt)*) => {{
+        #[cfg(debug_assertions)]
+        let guard = Some($crate::start_span!($($args)*));
+        #[cfg(not(debug_assertions))]
+        let guard: Option<$crate::SpanGuard> = None;
+        guard
+    }};
 }
 
-impl RecordArg for i32 {
-    fn record_arg(self) {
-        record_event(Event::I64(self.into()));
-    }
+/// Records an instant event in a way that is safe to call from a signal handler. Unlike spans, the
+/// event doesn't allocate or take any locks. Events are added to the trace by
+/// [TraceBuilder::process_signal_events].
+///
+/// At most [SIGNAL_EVENT_CAPACITY] events can be pending at once. Any further events are discarded.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::signal_safe_instant;
+///
+/// extern "C" fn on_sigterm(_signal: i32) {
+///     signal_safe_instant!("SIGTERM received");
+/// }
+/// ```
+#[macro_export]
+macro_rules! signal_safe_instant {
+    ($name:expr) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $name,
+            file: if $crate::SOURCE_LOCATIONS {
+                file!()
+            } else {
+                ""
+            },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::Sensitivity::Public,
+            process_track: false,
+            category: None,
+            track: None,
+            collapse_recursion: false,
+            dynamic_name: false,
+            arg_names: &[],
+            arg_sensitivities: &[],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        $crate::record_instant_signal_safe(&CALL_SITE);
+    }};
 }
 
-impl RecordArg for i16 {
-    fn record_arg(self) {
-        record_event(Event::I64(self.into()));
-    }
+/// Records a log message with severity `level`, a [LogLevel], at the current time. The message is
+/// shown as an instant event on the thread's track, interleaved with its spans, and is also
+/// written as a Perfetto log message, so it appears in the UI's log panel with its severity and
+/// source location. The message must be a `&'static str`, which is interned in the trace, so
+/// details that vary should be passed as arguments. Options and arguments are as for [start_span].
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::LogLevel;
+/// use perfetto_recorder::log_event;
+///
+/// let free_bytes = 1024_u64;
+/// log_event!(LogLevel::Warn, "Disk nearly full", free_bytes);
+/// ```
+#[macro_export]
+macro_rules! log_event {
+    (
+        $level:expr, $(@ $option:ident $(= $option_arg:literal)?)* $message:expr
+        $(, $($arg_name:ident $(: $arg_sensitivity:ident)? $( = $arg_value:expr)?),*)?
+        $(,)?
+    ) => {{
+        static CALL_SITE: $crate::CallSite = $crate::CallSite::new($crate::SourceInfo {
+            name: $message,
+            file: if $crate::SOURCE_LOCATIONS { file!() } else { "" },
+            line: if $crate::SOURCE_LOCATIONS { line!() } else { 0 },
+            sensitivity: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.sensitivity,
+            process_track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.process_track,
+            category: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.category,
+            track: $crate::SpanOptions::new()$(.$option($($option_arg)?))*.track,
+            collapse_recursion: false,
+            dynamic_name: false,
+            arg_names: &[$($(stringify!($arg_name)),*)?],
+            arg_sensitivities: &[
+                $($($crate::start_span!(@sensitivity $($arg_sensitivity)?)),*)?
+            ],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+        if $crate::is_enabled() && CALL_SITE.category_enabled() {
+            let level: $crate::LogLevel = $level;
+            $crate::record_event($crate::Event::Log {
+                id: CALL_SITE.id(),
+                level,
+            });
+            $crate::record_event($crate::Event::Timestamp($crate::time()));
+            $($($crate::RecordArg::record_arg(
+                $crate::start_span!(@arg_value $arg_name $($arg_value)?)
+            );)*)?
+        }
+    }};
 }
 
-impl RecordArg for i8 {
-    fn record_arg(self) {
-        record_event(Event::I64(self.into()));
-    }
+/// Counts how many times it's executed, without recording any events. Useful for call sites that
+/// are too hot to record spans for. The count is stored in a [StaticCounter] named `name`, so it's
+/// recorded by [TraceBuilder::flush_static_counters] or a [Sampler], like other static counters.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::count_only;
+///
+/// for _ in 0..1000 {
+///     count_only!("fast path");
+/// }
+/// ```
+#[macro_export]
+macro_rules! count_only {
+    ($name:expr) => {{
+        static COUNTER: $crate::StaticCounter = $crate::StaticCounter::new($name);
+        if $crate::is_enabled() {
+            COUNTER.increment();
+        }
+    }};
 }
 
-impl RecordArg for String {
-    fn record_arg(self) {
-        record_event(Event::String(self));
-    }
+/// Records a value on a counter track named `name`, e.g. `counter!("queue depth", queue.len())`.
+/// The track is created the first time the counter is recorded, so unlike a [CounterTrack], it
+/// doesn't need to be passed around. The value can be any integer or floating-point number and is
+/// recorded in the current thread's trace data with the current time, in the same way as spans.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::counter;
+///
+/// let queue = vec![1, 2, 3];
+/// counter!("queue depth", queue.len());
+/// counter!("load", 0.75);
+/// ```
+#[macro_export]
+macro_rules! counter {
+    ($name:expr, $value:expr $(,)?) => {{
+        static COUNTER: $crate::CounterSite = $crate::CounterSite::new($name);
+        if $crate::is_enabled() {
+            $crate::CounterValue::record_counter($value, COUNTER.track());
+        }
+    }};
 }
 
-impl RecordArg for isize {
-    fn record_arg(self) {
-        record_event(Event::I64(self as i64));
-    }
+/// Asserts that every span with the given name in an encoded trace took less than a duration. This
+/// allows performance budgets to be enforced by tests. The duration can be written as a number
+/// followed by one of the units `ns`, `us`, `ms` or `s`, or be any expression that evaluates to a
+/// [std::time::Duration]. Panics if the trace contains no spans with the name, since that most likely
+/// means that the name is wrong.
+///
+/// See also [assert_total_under], for budgets on the total time spent in spans with a name.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::*;
+///
+/// # if start().is_ok() {
+/// {
+///     scope!("link objects");
+/// }
+/// let mut trace = TraceBuilder::new()?;
+/// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+/// let trace = trace.encode_to_vec();
+///
+/// assert_span_under!(trace, "link objects", 500ms);
+/// assert_span_under!(trace, "link objects", std::time::Duration::from_secs(1));
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[macro_export]
+macro_rules! assert_span_under {
+    ($trace:expr, $name:expr, $limit:literal) => {
+        $crate::assert_max_duration(&$trace, $name, $crate::parse_duration(stringify!($limit)))
+    };
+
+    ($trace:expr, $name:expr, $limit:expr) => {
+        $crate::assert_max_duration(&$trace, $name, $limit)
+    };
 }
 
-impl RecordArg for &str {
-    fn record_arg(self) {
-        let mut pending: &[u8] = &[];
-        for chunk in self.as_bytes().chunks(STR_PART_LEN) {
-            if let Some(part_bytes) = pending.first_chunk::<STR_PART_LEN>() {
-                record_event(Event::StrPart(*part_bytes));
-            }
-            pending = chunk;
-        }
-        let mut padded_bytes = [0; STR_PART_LEN];
-        padded_bytes[..pending.len()].copy_from_slice(pending);
-        record_event(Event::StrEnd {
-            len: pending.len() as u8,
-            bytes: padded_bytes,
-        });
-    }
+/// Asserts that the spans with the given name in an encoded trace took less than a duration in
+/// total. The duration is written as for [assert_span_under].
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::*;
+///
+/// # if start().is_ok() {
+/// for _ in 0..10 {
+///     scope!("hash chunk");
+/// }
+/// let mut trace = TraceBuilder::new()?;
+/// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+///
+/// assert_total_under!(trace.encode_to_vec(), "hash chunk", 2s);
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[macro_export]
+macro_rules! assert_total_under {
+    ($trace:expr, $name:expr, $limit:literal) => {
+        $crate::assert_total_duration(&$trace, $name, $crate::parse_duration(stringify!($limit)))
+    };
+
+    ($trace:expr, $name:expr, $limit:expr) => {
+        $crate::assert_total_duration(&$trace, $name, $limit)
+    };
 }
 
-#[doc(hidden)]
-#[derive(Debug)]
-pub enum Event {
-    /// The start of a span. Must be followed by a timestamp.
-    StartSpan(&'static SourceInfo),
+/// A guard that when dropped will end a span.
+///
+/// Created by the [start_span] macro.
+#[must_use = "the span ends as soon as the guard is dropped"]
+pub struct SpanGuard {
+    /// The call site of the span, or `None` if the start of the span wasn't recorded.
+    #[cfg(feature = "enable")]
+    call_site: Option<&'static CallSite>,
+    /// Whether the span was dropped by sampling, in which case so are any spans nested within it.
+    #[cfg(feature = "enable")]
+    suppressed: bool,
+    /// Whether the span is the outermost recorded span on its thread, so owns the trace ID.
+    #[cfg(feature = "enable")]
+    root: bool,
+    /// When the span started, if its start was recorded.
+    #[cfg(feature = "enable")]
+    start: Option<Instant>,
+}
 
-    /// The end of a span. Must be followed by a timestamp.
-    EndSpan(&'static SourceInfo),
+/// Trace events that occurred on a single thread.
+#[derive(Clone)]
+pub struct ThreadTraceData {
+    events: Vec<Event>,
+    /// The contents of the str slices referred to by `events`.
+    strings: String,
+    pid: os::Pid,
+    tid: os::Pid,
+    thread_name: Option<String>,
+    /// When the thread first recorded an event, if it recorded any.
+    first_event_time: Option<Timestamp>,
+    taken_time: Timestamp,
+    /// How many events the thread dropped because of its [BufferMode].
+    dropped_events: u64,
+    /// When the thread first dropped an event, if it dropped any.
+    first_drop_time: Option<Timestamp>,
+    /// Whether this is the first data taken from the thread, so `first_event_time` is when it
+    /// started recording.
+    thread_started: bool,
+    /// Whether the data was stashed when the thread exited, so `taken_time` is when it exited.
+    thread_exited: bool,
+    /// The session started by [start_session] during which the events were recorded. This only
+    /// means something within the process that recorded them, so isn't serialized.
+    session: u32,
+}
 
-    /// The time at which the preceding start/end span occurred.
-    Timestamp(Instant),
+impl ThreadTraceData {
+    pub fn take_current_thread() -> Self {
+        session::end_lifetime_span();
+        let thread = std::thread::current();
+        let mut events = EVENTS.take();
+        let strings = STRINGS.take();
+        budget::release(events.capacity() * size_of::<Event>() + strings.capacity());
+        exit::buffer_taken(events.capacity());
+        let drops = ring_buffer::take_drops(&mut events);
+        Self {
+            events,
+            strings,
+            pid: os::getpid(),
+            tid: os::gettid(),
+            thread_name: thread.name().map(str::to_owned),
+            first_event_time: FIRST_EVENT_TIME.take().map(Timestamp::Instant),
+            taken_time: Timestamp::Instant(time()),
+            dropped_events: drops.events,
+            first_drop_time: drops.first_time.map(Timestamp::Instant),
+            thread_started: exit::take_thread_started(),
+            thread_exited: false,
+            session: session::take_buffer_session(),
+        }
+    }
+}
 
-    Bool(bool),
-    U64(u64),
-    I64(i64),
-    F64(f64),
-    String(String),
+/// A summary of what was added to a trace from a single thread's data. Returned by
+/// [TraceBuilder::process_all].
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    pub tid: i32,
+    pub thread_name: Option<String>,
+    /// The number of events that the thread recorded.
+    pub num_events: usize,
+    /// The number of spans that were started on the thread.
+    pub num_spans: usize,
+    /// The number of packets that were added to the trace.
+    pub num_packets: usize,
+    /// The number of events that the thread dropped because of its [BufferMode].
+    pub dropped_events: u64,
+    /// Possible problems with the thread's data, such as spans that look like their guard was
+    /// dropped as soon as it was created. Only checked in builds with debug assertions enabled.
+    pub warnings: Vec<String>,
+}
 
-    /// Part of a str slice. Must be followed by either another [Event::StrPart] or a
-    /// [Event::StrEnd].
-    StrPart([u8; STR_PART_LEN]),
+/// The number of events consumed by each span.
+pub const EVENTS_PER_SPAN: usize = 4;
 
-    /// The end of a str slice.
-    StrEnd {
-        len: u8,
-        bytes: [u8; STR_PART_LEN],
-    },
+/// The number of events consumed by each argument.
+pub const EVENTS_PER_ARG: usize = 1;
 
-    /// An integer counter value. Must be followed by a timestamp.
-    CounterI64 {
-        uuid: u64,
-        value: i64,
-    },
+/// The number of events consumed by each counter value.
+pub const EVENTS_PER_COUNTER: usize = 2;
 
-    /// A floating-point counter value. Must be followed by a timestamp.
-    CounterF64 {
-        uuid: u64,
-        value: f64,
-    },
+/// Reserve capacity on the current thread for additional spans and their arguments.
+///
+/// See constants [EVENTS_PER_SPAN], [EVENTS_PER_ARG], and [EVENTS_PER_COUNTER] to aid in working
+/// out what a reasonable value might be. The contents of string slices are stored separately, so
+/// don't need to be accounted for. Calling this is entirely optional, but might make recording
+/// spans and counters more consistent by reducing the need to reallocate the recording for the
+/// current thread.
+pub fn current_thread_reserve(additional: usize) {
+    with_events(|events| events.reserve(additional))
 }
 
-/// The maximum number of bytes we can fit in an [Event::StrPart].
-const STR_PART_LEN: usize = 15;
-
-#[doc(hidden)]
-#[derive(Debug)]
-pub struct SourceInfo {
-    pub name: &'static str,
-    pub file: &'static str,
-    pub line: u32,
-    pub arg_names: &'static [&'static str],
+/// Releases any capacity of the current thread's recording that isn't being used, e.g. after a
+/// burst of activity has been recorded and taken, or capacity reserved by [current_thread_reserve]
+/// turned out not to be needed. Returns the number of bytes released, which are also deducted from
+/// [buffered_bytes]. The recording of a thread is freed entirely when it's taken by
+/// [ThreadTraceData::take_current_thread], so this is only needed for threads that hold on to
+/// events.
+pub fn current_thread_shrink_to_fit() -> usize {
+    let events_released = EVENTS.with_borrow_mut(|events| {
+        let capacity = events.capacity();
+        events.shrink_to_fit();
+        if events.capacity() == 0 {
+            exit::buffer_taken(capacity);
+        }
+        (capacity - events.capacity()) * size_of::<Event>()
+    });
+    let strings_released = STRINGS.with_borrow_mut(|strings| {
+        let capacity = strings.capacity();
+        strings.shrink_to_fit();
+        capacity - strings.capacity()
+    });
+    let released = events_released + strings_released;
+    budget::release(released);
+    released
 }
 
-#[doc(hidden)]
-#[inline(always)]
-pub fn record_event(event: Event) {
-    EVENTS.with_borrow_mut(|events| events.push(event));
+/// Types that implement this trait can be used as arguments to the [span] macro.
+pub trait RecordArg {
+    fn record_arg(self);
 }
 
-thread_local! {
-    static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+impl RecordArg for bool {
+    fn record_arg(self) {
+        record_event(Event::Bool(self));
+    }
 }
 
-thread_local! {
-    static RNG: RefCell<ThreadRng> = RefCell::new(ThreadRng::default());
+impl RecordArg for f64 {
+    fn record_arg(self) {
+        record_event(Event::F64(self));
+    }
 }
 
-#[doc(hidden)]
-#[inline(always)]
-pub fn time() -> Instant {
-    Instant::now()
+impl RecordArg for u64 {
+    fn record_arg(self) {
+        record_event(Event::U64(self));
+    }
 }
 
-impl Drop for SpanGuard {
-    fn drop(&mut self) {
-        #[cfg(feature = "enable")]
-        if is_enabled() {
-            record_event(Event::EndSpan(self.source));
-            record_event(Event::Timestamp(time()));
-        }
+impl RecordArg for u32 {
+    fn record_arg(self) {
+        record_event(Event::U64(self.into()));
     }
 }
 
-impl SpanGuard {
-    #[doc(hidden)]
-    #[allow(unused_variables)]
-    pub fn new(source: &'static SourceInfo) -> Self {
-        #[cfg(feature = "enable")]
-        {
-            Self { source }
-        }
-        #[cfg(not(feature = "enable"))]
-        {
-            Self {}
-        }
+impl RecordArg for u16 {
+    fn record_arg(self) {
+        record_event(Event::U64(self.into()));
     }
 }
 
-const CLOCK_ID: u32 = 6;
+impl RecordArg for u8 {
+    fn record_arg(self) {
+        record_event(Event::U64(self.into()));
+    }
+}
 
-static RUNTIME_ENABLED: AtomicBool = AtomicBool::new(false);
+impl RecordArg for usize {
+    fn record_arg(self) {
+        record_event(Event::U64(self as u64));
+    }
+}
 
-/// Enable recording. Can be called multiple times. Any spans emitted prior to the first call will
-/// be discarded.
-pub fn start() -> Result<(), TracingDisabledAtBuildTime> {
-    if !cfg!(feature = "enable") {
-        return Err(TracingDisabledAtBuildTime);
+impl RecordArg for i64 {
+    fn record_arg(self) {
+        record_event(Event::I64(self));
     }
+}
 
-    RUNTIME_ENABLED.store(true, Ordering::Relaxed);
-    Ok(())
+impl RecordArg for i32 {
+    fn record_arg(self) {
+        record_event(Event::I64(self.into()));
+    }
 }
 
-/// Returns whether recording is enabled.
-pub fn is_enabled() -> bool {
-    cfg!(feature = "enable") && RUNTIME_ENABLED.load(Ordering::Relaxed)
+impl RecordArg for i16 {
+    fn record_arg(self) {
+        record_event(Event::I64(self.into()));
+    }
 }
 
-/// An error that is produced if [enable] is called when the "enable" feature of this crate is not
-/// active.
-#[derive(Debug)]
-pub struct TracingDisabledAtBuildTime;
+impl RecordArg for i8 {
+    fn record_arg(self) {
+        record_event(Event::I64(self.into()));
+    }
+}
 
-/// An error that is produced if [enable] has not been called, but we're trying to build a trace.
-#[derive(Debug)]
-pub struct TracingDisabled;
+impl RecordArg for String {
+    fn record_arg(self) {
+        record_event(Event::String(self));
+    }
+}
 
-/// Used to build a trace file.
+impl RecordArg for isize {
+    fn record_arg(self) {
+        record_event(Event::I64(self as i64));
+    }
+}
+
+impl RecordArg for &str {
+    fn record_arg(self) {
+        record_str(|strings| strings.push_str(self));
+    }
+}
+
+/// Formats the arguments directly into the current thread's strings, so unlike [format], doesn't
+/// allocate a [String] for each value. e.g. `scope!("Resize", size = format_args!("{w}x{h}"))`.
+impl RecordArg for std::fmt::Arguments<'_> {
+    fn record_arg(self) {
+        if let Some(literal) = self.as_str() {
+            literal.record_arg();
+            return;
+        }
+        // Formatting runs user code, which might record events of its own, e.g. if a `Debug`
+        // implementation starts a span, so we don't hold the thread's strings while it runs. Those
+        // events would be mixed up with the arguments that we're recording, so they're discarded.
+        // Nested calls find the buffer already taken and use a new one.
+        let mut buffer = FORMAT_BUFFER.take();
+        let events_len = EVENTS.with_borrow(Vec::len);
+        let discarded = ring_buffer::discarded_oldest();
+        let handover_paused = dump::pause_handover(true);
+        // Writing to a `String` only fails if a `Display` implementation returns an error. In that
+        // case, we keep whatever was written before the error rather than panicking as `format`
+        // would.
+        let _ = std::fmt::Write::write_fmt(&mut buffer, self);
+        dump::pause_handover(handover_paused);
+        // Any events that were discarded to stay within the buffer limit came from the start.
+        let kept =
+            events_len.saturating_sub((ring_buffer::discarded_oldest() - discarded) as usize);
+        EVENTS.with_borrow_mut(|events| events.truncate(kept));
+        record_str(|strings| strings.push_str(&buffer));
+        buffer.clear();
+        FORMAT_BUFFER.set(buffer);
+    }
+}
+
+/// A span argument that is recorded as a string using the value's [Debug](std::fmt::Debug)
+/// implementation. The value is formatted directly into the current thread's trace data, and only
+/// if the span is recorded.
 ///
 /// Example usage:
-/// ```
-/// # use perfetto_recorder::*;
-///
-/// # if perfetto_recorder::is_enabled() {
-///
-/// TraceBuilder::new()?
-///     .process_thread_data(&ThreadTraceData::take_current_thread())
-///     .write_to_file("a.pftrace")?;
 ///
-/// # }
+/// ```
+/// use perfetto_recorder::DebugArg;
+/// use perfetto_recorder::DisplayArg;
+/// use perfetto_recorder::scope;
 ///
-/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// let path = std::path::Path::new("/tmp/input.txt");
+/// scope!("open", path = DisplayArg(path.display()), mode = DebugArg(Some("read")));
 /// ```
-pub struct TraceBuilder {
-    trace: schema::Trace,
-    pending_interned: Option<schema::InternedData>,
-    name_ids: HashMap<&'static str, u64>,
-    debug_annotation_name_ids: HashMap<&'static str, u64>,
-    source_location_ids: HashMap<(&'static str, u32), u64>,
-    thread_uuids: HashMap<os::Pid, Uuid>,
-    sequence_id: u32,
-    #[cfg(feature = "fastant")]
-    time_anchor: fastant::Anchor,
-}
-
-impl TraceBuilder {
-    pub fn new() -> Result<TraceBuilder, TracingDisabled> {
-        if !is_enabled() {
-            return Err(TracingDisabled);
-        }
-
-        let sequence_id = RNG.with_borrow_mut(|rng| rng.next_u32());
+#[derive(Debug, Clone, Copy)]
+pub struct DebugArg<T: std::fmt::Debug>(pub T);
 
-        let mut builder = TraceBuilder {
-            sequence_id,
-            trace: Default::default(),
-            pending_interned: Default::default(),
-            name_ids: Default::default(),
-            source_location_ids: Default::default(),
-            debug_annotation_name_ids: Default::default(),
-            thread_uuids: Default::default(),
-            #[cfg(feature = "fastant")]
-            time_anchor: fastant::Anchor::new(),
-        };
+/// A span argument that is recorded as a string using the value's [Display](std::fmt::Display)
+/// implementation. See [DebugArg].
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayArg<T: std::fmt::Display>(pub T);
 
-        builder.add_packet(TracePacket {
-            sequence_flags: Some(
-                schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32,
-            ),
-            ..Default::default()
-        });
+impl<T: std::fmt::Debug> RecordArg for DebugArg<T> {
+    fn record_arg(self) {
+        format_args!("{:?}", self.0).record_arg();
+    }
+}
 
-        Ok(builder)
+impl<T: std::fmt::Display> RecordArg for DisplayArg<T> {
+    fn record_arg(self) {
+        format_args!("{}", self.0).record_arg();
     }
+}
 
-    /// Merges trace data captured from a thread into the trace.
-    pub fn process_thread_data(&mut self, thread: &ThreadTraceData) -> &mut Self {
-        let thread_uuid = self.thread_uuid(thread);
+/// A span argument whose value is computed by a closure, which is only called if the span is
+/// recorded. Created by [lazy].
+#[derive(Debug, Clone, Copy)]
+pub struct LazyArg<F>(pub F);
 
-        let mut events = thread.events.iter();
+/// Defers computing an argument until it's recorded, so that expensive values, such as formatted
+/// descriptions, cost nothing when recording is disabled or the span is dropped by sampling.
+/// Argument expressions passed directly to [start_span] are already only evaluated when the span is
+/// recorded, so this is mostly useful for arguments that are built elsewhere, e.g. by a helper that
+/// returns an argument for several spans, or that are nested in an [ArrayArg] or [StructArg].
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::lazy;
+/// use perfetto_recorder::scope;
+///
+/// fn describe(items: &[u32]) -> String {
+///     format!("{items:?}")
+/// }
+///
+/// let items = [3, 1, 2];
+/// let detail = lazy(|| describe(&items));
+/// scope!("sort", detail);
+/// ```
+pub fn lazy<T: RecordArg, F: FnOnce() -> T>(compute: F) -> LazyArg<F> {
+    LazyArg(compute)
+}
 
-        while let Some(event) = events.next() {
-            match event {
-                Event::StartSpan(source_info) => {
-                    self.emit_track_event(
-                        source_info,
-                        schema::track_event::Type::SliceBegin,
-                        &mut events,
-                        thread_uuid,
-                    );
-                }
-                Event::EndSpan(source_info) => {
-                    self.emit_track_event(
-                        source_info,
-                        schema::track_event::Type::SliceEnd,
-                        &mut events,
-                        thread_uuid,
-                    );
-                }
-                Event::CounterI64 { uuid, value } => {
-                    self.emit_counter_event(
-                        *uuid,
-                        &mut events,
-                        schema::track_event::CounterValueField::CounterValue(*value),
-                    );
-                }
-                Event::CounterF64 { uuid, value } => {
-                    self.emit_counter_event(
-                        *uuid,
-                        &mut events,
-                        schema::track_event::CounterValueField::DoubleCounterValue(*value),
-                    );
-                }
-                other => panic!("Internal error: Unexpected event {other:?}"),
-            }
-        }
+impl<T: RecordArg, F: FnOnce() -> T> RecordArg for LazyArg<F> {
+    fn record_arg(self) {
+        (self.0)().record_arg();
+    }
+}
 
-        self
+/// Recorded as a string of lowercase hex digits, e.g. for hashes and keys.
+impl RecordArg for &[u8] {
+    fn record_arg(self) {
+        record_str(|strings| {
+            const DIGITS: &[u8; 16] = b"0123456789abcdef";
+            strings.reserve(self.len() * 2);
+            for byte in self {
+                strings.push(DIGITS[usize::from(byte >> 4)] as char);
+                strings.push(DIGITS[usize::from(byte & 0xf)] as char);
+            }
+        });
     }
+}
 
-    // Encode the Perfetto trace as bytes.
-    pub fn encode_to_vec(&self) -> Vec<u8> {
-        self.trace.encode_to_vec()
+/// Recorded as a string of lowercase hex digits. See the implementation for `&[u8]`.
+impl RecordArg for Vec<u8> {
+    fn record_arg(self) {
+        self.as_slice().record_arg();
     }
+}
 
-    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
-        std::fs::write(path, self.encode_to_vec())
+/// Recorded as a string. Any parts that aren't valid UTF-8 are replaced with U+FFFD.
+impl RecordArg for &std::ffi::OsStr {
+    fn record_arg(self) {
+        record_str(|strings| strings.push_str(&self.to_string_lossy()));
     }
+}
 
-    fn name_id(&mut self, name: &'static str) -> u64 {
-        let next_id = self.name_ids.len() as u64 + 1;
-        *self.name_ids.entry(name).or_insert_with(|| {
-            self.pending_interned
-                .get_or_insert_default()
-                .event_names
-                .push(schema::EventName {
-                    iid: Some(next_id),
-                    name: Some(name.to_owned()),
-                });
-            next_id
-        })
+/// Recorded as a string. See the implementation for `&OsStr`.
+impl RecordArg for &Path {
+    fn record_arg(self) {
+        self.as_os_str().record_arg();
     }
+}
 
-    fn debug_annotation_name_id(&mut self, name: &'static str) -> u64 {
-        let next_id = self.debug_annotation_name_ids.len() as u64 + 1;
-        *self
-            .debug_annotation_name_ids
-            .entry(name)
-            .or_insert_with(|| {
-                self.pending_interned
-                    .get_or_insert_default()
-                    .debug_annotation_names
-                    .push(schema::DebugAnnotationName {
-                        iid: Some(next_id),
-                        name: Some(name.to_owned()),
-                    });
-                next_id
-            })
+/// Recorded as a string. See the implementation for `&OsStr`.
+impl RecordArg for std::path::PathBuf {
+    fn record_arg(self) {
+        self.as_path().record_arg();
     }
+}
 
-    fn source_location_id(&mut self, source_location: &'static SourceInfo) -> u64 {
-        let next_id = self.source_location_ids.len() as u64 + 1;
-        *self
-            .source_location_ids
-            .entry((source_location.file, source_location.line))
-            .or_insert_with(|| {
-                self.pending_interned
-                    .get_or_insert_default()
-                    .source_locations
-                    .push(schema::SourceLocation {
-                        iid: Some(next_id),
-                        file_name: Some(source_location.file.to_owned()),
-                        function_name: None,
-                        line_number: Some(source_location.line),
-                    });
-                next_id
-            })
+/// Records a string argument whose contents are appended to the current thread's strings by
+/// `write`.
+fn record_str(write: impl FnOnce(&mut String)) {
+    let (offset, len, grown_by) = STRINGS.with_borrow_mut(|strings| {
+        let capacity = strings.capacity();
+        let offset = strings.len();
+        write(strings);
+        (
+            offset,
+            strings.len() - offset,
+            strings.capacity() - capacity,
+        )
+    });
+    record_event(Event::Str { offset, len });
+    // This must happen after we've recorded the event that refers to the string, since it might
+    // call a user-provided callback that takes the events and strings of the current thread.
+    if grown_by != 0 {
+        budget::grow(grown_by);
     }
+}
 
-    fn emit_track_event(
-        &mut self,
-        source_info: &'static SourceInfo,
-        kind: schema::track_event::Type,
-        events: &mut std::slice::Iter<Event>,
-        thread_uuid: Uuid,
-    ) {
-        let Some(Event::Timestamp(timestamp)) = events.next() else {
-            panic!("Internal error: Timestamp must follow top-level events");
-        };
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The start of a span, identified by the ID of its [CallSite]. Must be followed by a
+    /// timestamp.
+    StartSpan(u32),
 
-        let name_id = self.name_id(source_info.name);
-        let source_location_id = self.source_location_id(source_info);
-        let mut track_event = schema::TrackEvent::default();
-        track_event.set_type(kind);
-        track_event.name_field = Some(schema::track_event::NameField::NameIid(name_id));
-        track_event.source_location_field = Some(
-            schema::track_event::SourceLocationField::SourceLocationIid(source_location_id),
-        );
-        track_event.track_uuid = Some(thread_uuid.0);
+    /// The end of a span, identified by the ID of its [CallSite]. Must be followed by a timestamp.
+    EndSpan(u32),
 
-        if kind == schema::track_event::Type::SliceBegin && !source_info.arg_names.is_empty() {
-            track_event.debug_annotations = source_info
-                .arg_names
-                .iter()
-                .map(|arg_name| {
-                    let value = convert_next_arg(events);
-                    DebugAnnotation {
-                        name_field: Some(schema::debug_annotation::NameField::NameIid(
-                            self.debug_annotation_name_id(arg_name),
-                        )),
-                        value: Some(value),
-                    }
-                })
-                .collect();
-        }
+    /// The time at which the preceding start/end span occurred.
+    Timestamp(Instant),
 
-        let packet = TracePacket {
-            timestamp: Some(self.get_unix_nanos(*timestamp)),
-            timestamp_clock_id: Some(CLOCK_ID),
-            data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
-            interned_data: self.pending_interned.take(),
-            ..Default::default()
-        };
+    /// Used in place of [Event::Timestamp] in events that were deserialized. Nanoseconds since the
+    /// unix epoch.
+    UnixTimestamp(u64),
 
-        self.add_packet(packet);
-    }
+    /// Used in place of [Event::Timestamp] by spans started with [start_span_at]. Nanoseconds in
+    /// the trace's clock, which are written to the trace unchanged.
+    SimulatedTimestamp(u64),
 
-    fn emit_counter_event(
-        &mut self,
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    String(String),
+
+    /// A str slice, stored in the current thread's string arena.
+    Str {
+        offset: usize,
+        len: usize,
+    },
+
+    /// An integer counter value. Must be followed by a timestamp.
+    CounterI64 {
         uuid: u64,
-        events: &mut std::slice::Iter<Event>,
-        counter_value_field: schema::track_event::CounterValueField,
-    ) {
-        let Some(Event::Timestamp(timestamp)) = events.next() else {
-            panic!("Internal error: Counter event must be followed by Timestamp");
-        };
+        value: i64,
+    },
 
-        let packet = TracePacket {
-            timestamp: Some(self.get_unix_nanos(*timestamp)),
-            timestamp_clock_id: Some(CLOCK_ID),
-            data: Some(schema::trace_packet::Data::TrackEvent(schema::TrackEvent {
-                track_uuid: Some(uuid),
-                r#type: Some(schema::track_event::Type::Counter as i32),
-                counter_value_field: Some(counter_value_field),
-                ..Default::default()
-            })),
-            ..Default::default()
-        };
+    /// A floating-point counter value. Must be followed by a timestamp.
+    CounterF64 {
+        uuid: u64,
+        value: f64,
+    },
 
-        self.add_packet(packet);
-    }
+    /// An argument that links the span to a flow.
+    Flow(u64),
 
-    fn thread_uuid(&mut self, thread: &ThreadTraceData) -> Uuid {
-        if let Some(uuid) = self.thread_uuids.get(&thread.tid) {
-            return *uuid;
-        }
+    /// An argument that links the span to a named flow. Must be followed by the name as a string.
+    NamedFlow(u64),
 
-        let uuid = Uuid::new();
+    /// A [CorrelationId] argument.
+    CorrelationId(u64),
 
-        self.add_packet(TracePacket {
-            data: Some(schema::trace_packet::Data::TrackDescriptor(
-                TrackDescriptor {
-                    uuid: Some(uuid.0),
-                    thread: Some(ThreadDescriptor {
-                        pid: Some(thread.pid.as_i32()),
-                        tid: Some(thread.tid.as_i32()),
-                        thread_name: thread.thread_name.clone(),
-                    }),
-                    ..Default::default()
-                },
-            )),
-            ..Default::default()
-        });
+    /// The start of an argument made up of entries with dynamic names. Each entry is a string name
+    /// followed by a value, which may itself be a dictionary or array. Must be followed by
+    /// [Event::DictEnd] after the last entry.
+    DictStart,
 
-        self.thread_uuids.insert(thread.tid, uuid);
+    /// The start of an argument made up of a list of values, each of which may itself be a
+    /// dictionary or array. Must be followed by [Event::DictEnd] after the last value.
+    ArrayStart,
 
-        uuid
-    }
+    /// The end of an argument started by [Event::DictStart] or [Event::ArrayStart], or of the
+    /// annotations started by [Event::Annotations].
+    DictEnd,
 
-    fn add_packet(&mut self, mut packet: TracePacket) {
-        packet.optional_trusted_packet_sequence_id = Some(
-            schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
-                self.sequence_id,
-            ),
-        );
-        self.trace.packet.push(packet);
+    /// Annotations added by [SpanGuard::record_all] to the innermost open span from the call site
+    /// with this ID. Followed by a string name and a value for each annotation, then
+    /// [Event::DictEnd].
+    Annotations(u32),
+
+    /// Like [Event::Annotations], but added by [SpanGuard::record_arg] to the end of the span.
+    EndAnnotations(u32),
+
+    /// Like [Event::Annotations], but added by [current_trace_id] to the outermost open span, which
+    /// is from the call site with this ID. A recursive span's inner calls share its call site, so
+    /// can't be told apart from it by ID alone.
+    RootAnnotations(u32),
+
+    /// Another thread was spawned by [spawn]. The innermost open span is the start of a flow with
+    /// this ID.
+    SpawnedThread(u64),
+
+    /// The current thread was spawned by [spawn]. The next span to start continues the flow with
+    /// this ID.
+    SpawnedBy(u64),
+
+    /// The unit of the numeric argument value that follows.
+    Unit(Unit),
+
+    /// The start of a span from [start_async_span] with call site `id`, shown on the track
+    /// identified by `track`. Must be followed by a timestamp, then the span's arguments.
+    AsyncStart {
+        id: u32,
+        track: u64,
+    },
+
+    /// The end of a span started by [Event::AsyncStart]. Must be followed by a timestamp.
+    AsyncEnd {
+        id: u32,
+        track: u64,
+    },
+
+    /// A failed assertion from [assert_traced] with call site `id`. Must be followed by a timestamp,
+    /// then the assertion's arguments.
+    FailedAssertion(u32),
+
+    /// A message from [log_event] with call site `id`. Must be followed by a timestamp, then the
+    /// message's arguments.
+    Log {
+        id: u32,
+        level: LogLevel,
+    },
+}
+
+/// Spans shorter than this, in nanoseconds, might have had their guard dropped immediately.
+const SHORT_SPAN_NANOS: u64 = 1_000;
+
+/// How many spans from a call site need to be recorded before we warn that its guard might be
+/// dropped immediately. Legitimate spans are occasionally short, but rarely always.
+const MIN_SHORT_SPANS_TO_WARN: usize = 100;
+
+/// The percentage of a call site's spans that need to be short before we warn. Spans whose guard is
+/// dropped immediately are occasionally long, e.g. if recording the end of the span grew the
+/// thread's event buffer.
+const SHORT_SPAN_WARNING_PERCENT: usize = 95;
+
+/// What redacted span names and argument values are replaced with.
+const REDACTED: &str = "[redacted]";
+
+/// The names of the instant events added by [TraceBuilder::set_thread_lifecycle_events].
+const THREAD_STARTED_EVENT_NAME: &str = "thread started";
+const THREAD_EXITED_EVENT_NAME: &str = "thread exited";
+
+/// The name of the instant event that marks where a thread started dropping events because of its
+/// [BufferMode].
+const DATA_LOST_EVENT_NAME: &str = "data lost";
+
+/// Whether call sites capture their file and line. Disabled by the `no-source-locations` feature.
+#[doc(hidden)]
+pub const SOURCE_LOCATIONS: bool = !cfg!(feature = "no-source-locations");
+
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct SourceInfo {
+    pub name: &'static str,
+    /// Empty if source locations aren't being captured.
+    pub file: &'static str,
+    pub line: u32,
+    pub sensitivity: Sensitivity,
+    /// Whether the span should be shown on the process track rather than the thread track.
+    pub process_track: bool,
+    pub category: Option<&'static str>,
+    /// The name of the track created by [TraceBuilder::create_track] that the span should be shown
+    /// on.
+    pub track: Option<&'static str>,
+    /// Whether direct recursion of the span is collapsed into a single slice.
+    pub collapse_recursion: bool,
+    /// Whether the span's name is recorded when it starts, before its arguments. If so, `name` is
+    /// only used when we don't have the recorded name, e.g. by [current_span_name].
+    pub dynamic_name: bool,
+    pub arg_names: &'static [&'static str],
+    pub arg_sensitivities: &'static [Sensitivity],
+    pub end_arg_names: &'static [&'static str],
+    pub end_arg_sensitivities: &'static [Sensitivity],
+}
+
+/// The options that can be given before a span's name, e.g. `@secret`. Each option is a method.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub struct SpanOptions {
+    pub sensitivity: Sensitivity,
+    pub process_track: bool,
+    pub category: Option<&'static str>,
+    pub track: Option<&'static str>,
+    pub collapse_recursion: bool,
+}
+
+impl SpanOptions {
+    pub const fn new() -> Self {
+        Self {
+            sensitivity: Sensitivity::Public,
+            process_track: false,
+            category: None,
+            track: None,
+            collapse_recursion: false,
+        }
     }
 
-    #[cfg(feature = "fastant")]
-    fn get_unix_nanos(&self, timestamp: Instant) -> u64 {
-        timestamp.as_unix_nanos(&self.time_anchor)
+    pub const fn public(self) -> Self {
+        Self {
+            sensitivity: Sensitivity::Public,
+            ..self
+        }
     }
 
-    #[cfg(not(feature = "fastant"))]
-    fn get_unix_nanos(&self, timestamp: Instant) -> u64 {
-        timestamp
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64
+    pub const fn internal(self) -> Self {
+        Self {
+            sensitivity: Sensitivity::Internal,
+            ..self
+        }
     }
-}
 
-/// Reads the next argument from `events`.
-fn convert_next_arg(events: &mut std::slice::Iter<'_, Event>) -> schema::debug_annotation::Value {
-    let event = events.next().expect("Internal error: missing arg value");
+    pub const fn secret(self) -> Self {
+        Self {
+            sensitivity: Sensitivity::Secret,
+            ..self
+        }
+    }
 
-    use schema::debug_annotation::Value;
-    match event {
-        Event::StartSpan(_) => panic!("Internal error: Unexpected StartSpan"),
-        Event::EndSpan(_) => panic!("Internal error: Unexpected EndSpan"),
-        Event::Timestamp(_) => panic!("Internal error: Unexpected Timestamp"),
-        Event::CounterI64 { .. } => panic!("Internal error: Unexpected CounterI64"),
-        Event::CounterF64 { .. } => panic!("Internal error: Unexpected CounterF64"),
-        Event::Bool(value) => Value::BoolValue(*value),
-        Event::U64(value) => Value::UintValue(*value),
-        Event::I64(value) => Value::IntValue(*value),
-        Event::F64(value) => Value::DoubleValue(*value),
-        Event::String(value) => Value::StringValue(value.clone()),
-        Event::StrPart(bytes) => {
-            let mut merged_bytes = Vec::new();
-            merged_bytes.extend_from_slice(bytes);
-            loop {
-                match events.next() {
-                    Some(Event::StrPart(bytes)) => {
-                        merged_bytes.extend_from_slice(bytes);
-                    }
-                    Some(Event::StrEnd { len, bytes }) => {
-                        merged_bytes.extend_from_slice(&bytes[..*len as usize]);
-                        // The string started out as valid UTF-8 &str, so it should still be valid.
-                        break Value::StringValue(String::from_utf8(merged_bytes).unwrap());
-                    }
-                    other => panic!(
-                        "Internal error: Unexpected event {other:?} while looking for StrEnd"
-                    ),
-                }
-            }
+    pub const fn process(self) -> Self {
+        Self {
+            process_track: true,
+            ..self
         }
-        Event::StrEnd { len, bytes } => {
-            Value::StringValue(str::from_utf8(&bytes[..*len as usize]).unwrap().to_owned())
+    }
+
+    pub const fn category(self, category: &'static str) -> Self {
+        Self {
+            category: Some(category),
+            ..self
         }
     }
-}
 
-impl Uuid {
-    fn new() -> Uuid {
-        Uuid(RNG.with_borrow_mut(|rng| rng.next_u64()))
+    pub const fn track(self, track: &'static str) -> Self {
+        Self {
+            track: Some(track),
+            ..self
+        }
     }
-}
 
-impl std::error::Error for TracingDisabledAtBuildTime {}
+    pub const fn collapse_recursion(self) -> Self {
+        Self {
+            collapse_recursion: true,
+            ..self
+        }
+    }
+}
 
-impl std::fmt::Display for TracingDisabledAtBuildTime {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "The \"enable\" feature of perfetto-recorder is not active"
-        )
+impl Default for SpanOptions {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl std::error::Error for TracingDisabled {}
+/// How sensitive a span or argument is. Used by [TraceBuilder::set_max_sensitivity] to decide what
+/// to include in a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Sensitivity {
+    /// Fine to share with anyone.
+    #[default]
+    Public,
 
-impl std::fmt::Display for TracingDisabled {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "The `perfetto_recorder::start()` was not called")
-    }
+    /// Useful for debugging, but not for sharing outside the team that produced the trace.
+    Internal,
+
+    /// Shouldn't be included in traces that anyone other than the producer of the trace will see.
+    Secret,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Uuid(u64);
+/// What a [TraceBuilder] does with spans and arguments that are more sensitive than it allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    /// Leaves them out of the trace entirely.
+    Drop,
 
-/// Units for counter tracks.
-#[derive(Debug, Clone)]
-pub enum CounterUnit {
-    /// Unspecified unit.
-    Unspecified,
-    /// Time in nanoseconds.
-    TimeNs,
-    /// Generic count.
-    Count,
-    /// Size in bytes.
-    SizeBytes,
-    /// Custom unit with a name (e.g., "%", "fps", etc.).
-    Custom(String),
+    /// Keeps their timing and structure, but replaces names and values with a placeholder.
+    Redact,
 }
 
-impl CounterUnit {
-    fn to_proto_unit(&self) -> Option<i32> {
-        Some(match self {
-            CounterUnit::Unspecified => schema::counter_descriptor::Unit::Unspecified,
-            CounterUnit::TimeNs => schema::counter_descriptor::Unit::TimeNs,
-            CounterUnit::Count => schema::counter_descriptor::Unit::Count,
-            CounterUnit::SizeBytes => schema::counter_descriptor::Unit::SizeBytes,
-            CounterUnit::Custom(_) => schema::counter_descriptor::Unit::Unspecified,
-        } as i32)
+/// A span's [SourceInfo] together with a small ID that is assigned the first time the span is
+/// recorded. Events refer to call sites by ID, which allows the [TraceBuilder] to look up what it
+/// has already interned for the call site without hashing.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct CallSite {
+    source: SourceInfo,
+    id: AtomicU32,
+    /// Whether the call site's category is enabled, cached for a particular category filter. See
+    /// `session::category_enabled`.
+    category_state: AtomicU32,
+}
+
+/// All call sites that have been recorded at least once. The call site with ID `n` is at index
+/// `n - 1`.
+static CALL_SITES: Mutex<Vec<&'static CallSite>> = Mutex::new(Vec::new());
+
+impl CallSite {
+    pub const fn new(source: SourceInfo) -> Self {
+        Self {
+            source,
+            id: AtomicU32::new(0),
+            category_state: AtomicU32::new(0),
+        }
     }
 
-    fn to_proto_unit_name(&self) -> Option<String> {
-        if let Self::Custom(name) = self {
-            Some(name.clone())
-        } else {
-            None
+    /// Returns whether spans from this call site should be recorded in the current session, based
+    /// on their category.
+    #[inline(always)]
+    pub fn category_enabled(&'static self) -> bool {
+        let Some(category) = self.source.category else {
+            return true;
+        };
+        session::category_enabled(category, &self.category_state)
+    }
+
+    /// Returns the ID of this call site, registering it if this is the first time it's been used.
+    #[inline(always)]
+    pub fn id(&'static self) -> u32 {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        self.register()
+    }
+
+    #[cold]
+    fn register(&'static self) -> u32 {
+        let mut call_sites = CALL_SITES.lock().unwrap();
+        // Another thread may have registered this call site while we were waiting for the lock.
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
         }
+        call_sites.push(self);
+        let id = call_sites.len() as u32;
+        self.id.store(id, Ordering::Relaxed);
+        id
     }
 }
 
-/// A handle to a counter track that can be used to record counter values.
-#[derive(Debug, Clone, Copy)]
-pub struct CounterTrack {
-    uuid: u64,
+#[doc(hidden)]
+#[inline(always)]
+pub fn record_event(event: Event) {
+    with_events(|events| events.push(event));
 }
 
-impl CounterTrack {
-    /// Records an integer counter value at a specific timestamp.
-    ///
-    /// This function stores the counter value in thread-local storage. The value will be
-    /// converted to the trace format when `TraceBuilder::process_thread_data()` is called.
-    ///
-    /// # Arguments
-    ///
-    /// * `timestamp` - The timestamp for this value
-    /// * `value` - The counter value
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// # use perfetto_recorder::*;
-    /// # if perfetto_recorder::is_enabled() {
-    /// start()?;
-    /// let mut trace = TraceBuilder::new()?;
-    /// let mut counter = trace.create_counter_track("Memory", CounterUnit::SizeBytes, 1, false);
-    /// counter.record_i64(perfetto_recorder::time(), 1024);
-    /// # }
-    /// # Ok::<(), Box<dyn std::error::Error>>(())
-    /// ```
-    #[inline(always)]
-    pub fn record_i64(&mut self, timestamp: Instant, value: i64) {
-        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
-            return;
+/// Runs `f` with the current thread's events, accounting for any memory that it allocates.
+#[inline(always)]
+fn with_events<R>(f: impl FnOnce(&mut Vec<Event>) -> R) -> R {
+    let (result, previous_capacity, grown_by, hand_over) = EVENTS.with_borrow_mut(|events| {
+        let capacity = events.capacity();
+        let result = f(events);
+        let hand_over = ring_buffer::enforce_limit(events);
+        (result, capacity, events.capacity() - capacity, hand_over)
+    });
+    // This must happen after we've released the borrow of `EVENTS`, since it might call a
+    // user-provided callback that takes the events of the current thread.
+    if grown_by != 0 {
+        if previous_capacity == 0 {
+            FIRST_EVENT_TIME.set(Some(time()));
+            session::start_buffer();
+            exit::register_thread();
         }
-        record_event(Event::CounterI64 {
-            uuid: self.uuid,
-            value,
-        });
-        record_event(Event::Timestamp(timestamp));
+        budget::grow(grown_by * size_of::<Event>());
+    }
+    if hand_over {
+        dump::hand_over_current_thread();
     }
+    result
+}
 
-    /// Records a floating-point counter value at a specific timestamp.
-    ///
-    /// This function stores the counter value in thread-local storage. The value will be
-    /// converted to the trace format when `TraceBuilder::process_thread_data()` is called.
-    ///
-    /// # Arguments
-    ///
-    /// * `timestamp` - The timestamp for this value
-    /// * `value` - The counter value
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// # use perfetto_recorder::*;
-    /// # if perfetto_recorder::is_enabled() {
-    /// start()?;
-    /// let mut trace = TraceBuilder::new()?;
-    /// let mut counter = trace.create_counter_track("CPU %", CounterUnit::Custom("%".to_string()), 1, false);
-    /// counter.record_f64(perfetto_recorder::time(), 42.5);
-    /// # }
-    /// # Ok::<(), Box<dyn std::error::Error>>(())
-    /// ```
-    #[inline(always)]
-    pub fn record_f64(&mut self, timestamp: Instant, value: f64) {
-        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
-            return;
+/// Runs `record` with empty event and string buffers, returning what it recorded. The current
+/// thread's own events are left as they were, even if `record` panics. The limit set by
+/// [set_buffer_mode] doesn't apply to what's recorded, since it needs to be complete.
+fn capture_events(record: impl FnOnce()) -> (Vec<Event>, String) {
+    let mut saved = SavedEvents::take();
+    record();
+    saved.restore()
+}
+
+/// The current thread's events and the state that goes with them, put aside by [capture_events].
+/// They're restored when this is dropped, if they haven't been already.
+struct SavedEvents {
+    events: Vec<Event>,
+    strings: String,
+    first_event_time: Option<Instant>,
+    drops: ring_buffer::Drops,
+    handover_paused: bool,
+    limit_suspended: bool,
+    restored: bool,
+}
+
+impl SavedEvents {
+    fn take() -> SavedEvents {
+        SavedEvents {
+            events: EVENTS.take(),
+            strings: STRINGS.take(),
+            first_event_time: FIRST_EVENT_TIME.take(),
+            drops: ring_buffer::replace_drops(Default::default()),
+            handover_paused: dump::pause_handover(true),
+            limit_suspended: ring_buffer::suspend_limit(true),
+            restored: false,
         }
-        record_event(Event::CounterF64 {
-            uuid: self.uuid,
-            value,
-        });
-        record_event(Event::Timestamp(timestamp));
+    }
+
+    /// Puts back the saved events, returning those that were recorded since they were taken.
+    fn restore(&mut self) -> (Vec<Event>, String) {
+        self.restored = true;
+        let captured_events = EVENTS.replace(std::mem::take(&mut self.events));
+        let captured_strings = STRINGS.replace(std::mem::take(&mut self.strings));
+        exit::buffer_taken(captured_events.capacity());
+        FIRST_EVENT_TIME.set(self.first_event_time);
+        ring_buffer::replace_drops(self.drops);
+        dump::pause_handover(self.handover_paused);
+        ring_buffer::suspend_limit(self.limit_suspended);
+        budget::release(
+            captured_events.capacity() * size_of::<Event>() + captured_strings.capacity(),
+        );
+        (captured_events, captured_strings)
     }
 }
 
-impl TraceBuilder {
-    /// Creates a new counter track.
-    ///
-    /// Counter tracks display time-series data like CPU usage, memory usage, etc.
-    ///
-    /// # Arguments
-    ///
-    /// * `name` - The name of the counter track
-    /// * `unit` - The unit for the counter values
-    /// * `unit_multiplier` - Multiplier for the values (e.g., 1024*1024 to convert bytes to MB)
-    /// * `is_incremental` - Whether values are incremental (delta) or absolute
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// # use perfetto_recorder::*;
-    /// # if perfetto_recorder::is_enabled() {
-    /// let mut trace = TraceBuilder::new()?;
-    /// let cpu_counter = trace.create_counter_track(
-    ///     "CPU Usage",
-    ///     CounterUnit::Custom("%".to_string()),
-    ///     1,
-    ///     false,
-    /// );
-    /// # }
-    /// # Ok::<(), Box<dyn std::error::Error>>(())
-    /// ```
-    pub fn create_counter_track(
-        &mut self,
-        name: impl Into<String>,
-        unit: CounterUnit,
-        unit_multiplier: i64,
-        is_incremental: bool,
-    ) -> CounterTrack {
-        let uuid = Uuid::new();
+impl Drop for SavedEvents {
+    fn drop(&mut self) {
+        if !self.restored {
+            self.restore();
+        }
+    }
+}
 
+thread_local! {
+    static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+}
+
+thread_local! {
+    /// The contents of the str slices recorded by the current thread, referred to by [Event::Str].
+    static STRINGS: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+thread_local! {
+    /// Where [std::fmt::Arguments] are formatted before they're copied to [STRINGS]. Kept so that
+    /// its allocation can be reused.
+    static FORMAT_BUFFER: Cell<String> = const { Cell::new(String::new()) };
+}
+
+thread_local! {
+    /// When the current thread first recorded an event since its events were last taken.
+    static FIRST_EVENT_TIME: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+thread_local! {
+    static RNG: RefCell<ThreadRng> = RefCell::new(ThreadRng::default());
+}
+
+#[doc(hidden)]
+#[inline(always)]
+pub fn time() -> Instant {
+    #[cfg(not(feature = "fastant"))]
+    if COARSE_TIMESTAMPS.load(Ordering::Relaxed)
+        && let Some(nanos) = os::coarse_realtime_nanos()
+    {
+        return std::time::UNIX_EPOCH + std::time::Duration::from_nanos(nanos);
+    }
+    Instant::now()
+}
+
+static COARSE_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether events are timestamped using a coarse clock, which is cheaper to read, but only
+/// updated every few milliseconds. This reduces the overhead of recording events at the expense of
+/// precision. Events that occur close together may end up with the same timestamp.
+///
+/// Coarse timestamps are only available on Linux and Android. On other platforms, and when the
+/// `fastant` feature is enabled, this has no effect.
+pub fn set_coarse_timestamps(coarse: bool) {
+    COARSE_TIMESTAMPS.store(coarse, Ordering::Relaxed);
+}
+
+static RECORD_CPU: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether each span is annotated with `cpu`, the CPU that its thread was running on when the
+/// span started. This makes it possible to spot threads migrating between cores and NUMA effects in
+/// compute-heavy workloads. Reading the CPU costs a little at the start of each span, so this is
+/// off by default.
+///
+/// The CPU is only available on Linux, Android and Windows. On other platforms, this has no effect.
+pub fn set_record_cpu(record: bool) {
+    RECORD_CPU.store(record, Ordering::Relaxed);
+}
+
+/// Annotates the span that has just started at `call_site` with the current CPU.
+#[cfg(feature = "enable")]
+#[cold]
+fn record_cpu(call_site: &'static CallSite) {
+    if let Some(cpu) = os::current_cpu() {
+        record_event(Event::Annotations(call_site.id()));
+        "cpu".record_arg();
+        u64::from(cpu).record_arg();
+        record_event(Event::DictEnd);
+    }
+}
+
+#[cfg(feature = "enable")]
+thread_local! {
+    /// The call site of the outermost recorded span that is open on the current thread, if any,
+    /// together with its trace ID, or zero if it hasn't been given one yet.
+    static ROOT_SPAN: Cell<Option<(&'static CallSite, u64)>> = const { Cell::new(None) };
+}
+
+/// Returns the trace ID of the outermost span that is open on the current thread, or `None` if no
+/// span is being recorded. Each outermost span is given a new ID the first time this is called
+/// within it, which is recorded as the span's `trace_id` annotation. Including the ID in log lines
+/// allows the logs to be joined with the trace afterwards.
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::current_trace_id;
+/// use perfetto_recorder::scope;
+///
+/// scope!("Handle request");
+/// if let Some(trace_id) = current_trace_id() {
+///     eprintln!("trace_id={trace_id:x} handling request");
+/// }
+/// ```
+pub fn current_trace_id() -> Option<u64> {
+    #[cfg(feature = "enable")]
+    {
+        let (call_site, id) = ROOT_SPAN.get()?;
+        if id != 0 {
+            return Some(id);
+        }
+        let id = unique_id();
+        ROOT_SPAN.set(Some((call_site, id)));
+        if is_enabled() {
+            record_event(Event::RootAnnotations(call_site.id()));
+            "trace_id".record_arg();
+            id.record_arg();
+            record_event(Event::DictEnd);
+        }
+        Some(id)
+    }
+    #[cfg(not(feature = "enable"))]
+    {
+        None
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Returns the time from `start` to `end`, or zero if `end` is earlier.
+#[cfg(feature = "enable")]
+fn elapsed(start: Instant, end: Instant) -> Duration {
+    #[cfg(not(feature = "fastant"))]
+    return end.duration_since(start).unwrap_or_default();
+    #[cfg(feature = "fastant")]
+    return end.duration_since(start);
+}
+
+impl SpanGuard {
+    #[doc(hidden)]
+    pub fn new(call_site: &'static CallSite, enabled: bool, start: Option<Instant>) -> Self {
+        Self::start(call_site, None, enabled, start)
+    }
+
+    /// Like [SpanGuard::new], but for a span whose name was only known at runtime, so isn't the
+    /// name of its call site.
+    #[doc(hidden)]
+    pub fn new_named(
+        call_site: &'static CallSite,
+        name: &str,
+        enabled: bool,
+        start: Option<Instant>,
+    ) -> Self {
+        Self::start(call_site, Some(name), enabled, start)
+    }
+
+    #[allow(unused_variables)]
+    fn start(
+        call_site: &'static CallSite,
+        name: Option<&str>,
+        enabled: bool,
+        start: Option<Instant>,
+    ) -> Self {
+        #[cfg(feature = "enable")]
+        {
+            let recorded = start.is_some();
+            #[cfg(feature = "span-stack")]
+            if recorded {
+                span_stack::push(name.map_or(call_site.source.name, span_stack::intern));
+            }
+            if recorded && RECORD_CPU.load(Ordering::Relaxed) {
+                record_cpu(call_site);
+            }
+            let root = recorded && ROOT_SPAN.get().is_none();
+            if root {
+                ROOT_SPAN.set(Some((call_site, 0)));
+            }
+            Self {
+                call_site: recorded.then_some(call_site),
+                suppressed: enabled && !recorded,
+                root,
+                start,
+            }
+        }
+        #[cfg(not(feature = "enable"))]
+        {
+            Self {}
+        }
+    }
+
+    /// Adds annotations to the span. This is useful when the annotations are computed in a loop or
+    /// come from a map, so can't be passed to [start_span]. The annotations are shown alongside
+    /// the span's arguments. Does nothing if the start of the span wasn't recorded.
+    ///
+    /// Example usage:
+    ///
+    /// ```
+    /// use perfetto_recorder::start_span;
+    ///
+    /// let span_guard = start_span!("Query");
+    /// span_guard.record_all(&[("rows", 10_u64.into()), ("cache", true.into())]);
+    /// ```
+    #[allow(unused_variables)]
+    pub fn record_all(&self, annotations: &[(&str, AnnotationValue)]) {
+        #[cfg(feature = "enable")]
+        if let Some(call_site) = self.call_site
+            && is_enabled()
+        {
+            record_event(Event::Annotations(call_site.id()));
+            for (name, value) in annotations {
+                name.record_arg();
+                (*value).record_arg();
+            }
+            record_event(Event::DictEnd);
+        }
+    }
+
+    /// Adds an annotation to the end of the span. This is useful for values that are only known
+    /// once the work is underway, such as the size of a result or whether a cache was hit, and so
+    /// can't be passed to [start_span]. Does nothing if the start of the span wasn't recorded.
+    ///
+    /// Example usage:
+    ///
+    /// ```
+    /// use perfetto_recorder::start_span;
+    ///
+    /// let span_guard = start_span!("Lookup");
+    /// let hit = true;
+    /// span_guard.record_arg("hit", hit);
+    /// ```
+    #[allow(unused_variables)]
+    pub fn record_arg<'a>(&self, name: &str, value: impl Into<AnnotationValue<'a>>) {
+        #[cfg(feature = "enable")]
+        if let Some(call_site) = self.call_site
+            && is_enabled()
+        {
+            record_event(Event::EndAnnotations(call_site.id()));
+            name.record_arg();
+            value.into().record_arg();
+            record_event(Event::DictEnd);
+        }
+    }
+
+    /// Ends the span now, rather than when the guard is dropped, and returns how long it lasted.
+    /// This allows the duration to also be used for other purposes, such as logging or metrics,
+    /// without measuring it separately. The duration is returned even if recording was paused or
+    /// disabled while the span was running. Returns `None` if the start of the span wasn't
+    /// recorded: because the `enable` feature is off, recording wasn't enabled when the span
+    /// started, the span's category was filtered out or the span was sampled out.
+    ///
+    /// Example usage:
+    ///
+    /// ```
+    /// use perfetto_recorder::start_span;
+    ///
+    /// let span_guard = start_span!("Compile");
+    /// if let Some(duration) = span_guard.end() {
+    ///     println!("Compiled in {duration:?}");
+    /// }
+    /// ```
+    pub fn end(mut self) -> Option<Duration> {
+        self.finish().0
+    }
+
+    /// Records the end of the span if its start was recorded. Returns how long the span lasted if
+    /// that's known, and whether the end was recorded. Subsequent calls do nothing.
+    fn finish(&mut self) -> (Option<Duration>, bool) {
+        #[cfg(feature = "enable")]
+        if std::mem::take(&mut self.suppressed) {
+            end_suppressed_span();
+        }
+        #[cfg(feature = "enable")]
+        if let Some(call_site) = self.call_site.take() {
+            #[cfg(feature = "span-stack")]
+            span_stack::pop();
+            if self.root {
+                ROOT_SPAN.set(None);
+            }
+            let now = time();
+            let duration = self.start.map(|start| elapsed(start, now));
+            if ends_recorded() {
+                if tail_sampling::drop_if_short(call_site.id(), now) {
+                    return (duration, false);
+                }
+                record_event(Event::EndSpan(call_site.id()));
+                record_event(Event::Timestamp(now));
+                return (duration, true);
+            }
+            return (duration, false);
+        }
+        (None, false)
+    }
+}
+
+/// A span whose start and end times were provided by the caller rather than read from a clock.
+///
+/// Created by the [start_span_at] macro.
+#[must_use = "the span ends at its start time unless `end_at` is called"]
+pub struct SimulatedSpan {
+    /// The call site of the span, or `None` if the start of the span wasn't recorded.
+    #[cfg(feature = "enable")]
+    call_site: Option<&'static CallSite>,
+    #[cfg(feature = "enable")]
+    start: u64,
+}
+
+impl SimulatedSpan {
+    #[doc(hidden)]
+    #[allow(unused_variables)]
+    pub fn new(call_site: &'static CallSite, recorded: bool, start: u64) -> Self {
+        #[cfg(feature = "enable")]
+        {
+            Self {
+                call_site: recorded.then_some(call_site),
+                start,
+            }
+        }
+        #[cfg(not(feature = "enable"))]
+        {
+            Self {}
+        }
+    }
+
+    /// Ends the span at `time`, in the same units as the time that it was started at.
+    pub fn end_at(mut self, time: u64) {
+        self.end(time);
+    }
+
+    #[allow(unused_variables)]
+    fn end(&mut self, time: u64) {
+        #[cfg(feature = "enable")]
+        if let Some(call_site) = self.call_site.take()
+            && ends_recorded()
+        {
+            record_event(Event::EndSpan(call_site.id()));
+            record_event(Event::SimulatedTimestamp(time));
+        }
+    }
+}
+
+impl Drop for SimulatedSpan {
+    fn drop(&mut self) {
+        #[cfg(feature = "enable")]
+        self.end(self.start);
+    }
+}
+
+/// A guard that when dropped will end a span and record the arguments that are attached to the end
+/// of the span.
+///
+/// Created by the [start_span] macro when arguments are given after `@end`.
+#[must_use = "the span ends as soon as the guard is dropped"]
+pub struct SpanGuardWithEndArgs<F: FnOnce()> {
+    guard: SpanGuard,
+    record_end_args: Option<F>,
+}
+
+impl<F: FnOnce()> SpanGuardWithEndArgs<F> {
+    #[doc(hidden)]
+    pub fn new(
+        call_site: &'static CallSite,
+        enabled: bool,
+        start: Option<Instant>,
+        record_end_args: F,
+    ) -> Self {
+        Self {
+            guard: SpanGuard::new(call_site, enabled, start),
+            record_end_args: Some(record_end_args),
+        }
+    }
+}
+
+impl<F: FnOnce()> SpanGuardWithEndArgs<F> {
+    /// Ends the span now and returns how long it lasted. See [SpanGuard::end].
+    pub fn end(mut self) -> Option<Duration> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Option<Duration> {
+        let (duration, recorded) = self.guard.finish();
+        if recorded && let Some(record_end_args) = self.record_end_args.take() {
+            record_end_args();
+        }
+        duration
+    }
+
+    /// Adds annotations to the span. See [SpanGuard::record_all].
+    pub fn record_all(&self, annotations: &[(&str, AnnotationValue)]) {
+        self.guard.record_all(annotations);
+    }
+
+    /// Adds an annotation to the end of the span. See [SpanGuard::record_arg].
+    pub fn record_arg<'a>(&self, name: &str, value: impl Into<AnnotationValue<'a>>) {
+        self.guard.record_arg(name, value);
+    }
+}
+
+impl<F: FnOnce()> Drop for SpanGuardWithEndArgs<F> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The value of an annotation added by [SpanGuard::record_all].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationValue<'a> {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(&'a str),
+}
+
+impl RecordArg for AnnotationValue<'_> {
+    fn record_arg(self) {
+        match self {
+            AnnotationValue::Bool(value) => value.record_arg(),
+            AnnotationValue::U64(value) => value.record_arg(),
+            AnnotationValue::I64(value) => value.record_arg(),
+            AnnotationValue::F64(value) => value.record_arg(),
+            AnnotationValue::Str(value) => value.record_arg(),
+        }
+    }
+}
+
+impl From<bool> for AnnotationValue<'_> {
+    fn from(value: bool) -> Self {
+        AnnotationValue::Bool(value)
+    }
+}
+
+impl From<u64> for AnnotationValue<'_> {
+    fn from(value: u64) -> Self {
+        AnnotationValue::U64(value)
+    }
+}
+
+impl From<u32> for AnnotationValue<'_> {
+    fn from(value: u32) -> Self {
+        AnnotationValue::U64(value.into())
+    }
+}
+
+impl From<usize> for AnnotationValue<'_> {
+    fn from(value: usize) -> Self {
+        AnnotationValue::U64(value as u64)
+    }
+}
+
+impl From<i64> for AnnotationValue<'_> {
+    fn from(value: i64) -> Self {
+        AnnotationValue::I64(value)
+    }
+}
+
+impl From<i32> for AnnotationValue<'_> {
+    fn from(value: i32) -> Self {
+        AnnotationValue::I64(value.into())
+    }
+}
+
+impl From<f64> for AnnotationValue<'_> {
+    fn from(value: f64) -> Self {
+        AnnotationValue::F64(value)
+    }
+}
+
+impl<'a> From<&'a str> for AnnotationValue<'a> {
+    fn from(value: &'a str) -> Self {
+        AnnotationValue::Str(value)
+    }
+}
+
+impl<'a> From<&'a String> for AnnotationValue<'a> {
+    fn from(value: &'a String) -> Self {
+        AnnotationValue::Str(value)
+    }
+}
+
+/// An argument made up of entries whose names aren't known at compile time, such as the headers of
+/// an HTTP request. Shown in the trace as a single argument with an entry for each name. The names
+/// are interned by the [TraceBuilder], so repeating the same names doesn't make the trace larger.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::DynamicArgs;
+/// use perfetto_recorder::scope;
+///
+/// let headers = [("content-type", "text/html"), ("x-request-id", "42")];
+/// scope!("handle request", headers = DynamicArgs(headers.iter().copied()));
+/// ```
+pub struct DynamicArgs<I>(pub I);
+
+impl<I, K, V> RecordArg for DynamicArgs<I>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: RecordArg,
+{
+    fn record_arg(self) {
+        record_event(Event::DictStart);
+        for (name, value) in self.0 {
+            name.as_ref().record_arg();
+            value.record_arg();
+        }
+        record_event(Event::DictEnd);
+    }
+}
+
+/// An argument made up of a list of values, shown in the trace as an array. The values can be
+/// anything that can be passed as an argument, including further arrays and [DynamicArgs].
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::ArrayArg;
+/// use perfetto_recorder::scope;
+///
+/// let shard_sizes = [3_u64, 7, 2];
+/// scope!("merge shards", sizes = ArrayArg(shard_sizes));
+/// ```
+pub struct ArrayArg<I>(pub I);
+
+impl<I> RecordArg for ArrayArg<I>
+where
+    I: IntoIterator,
+    I::Item: RecordArg,
+{
+    fn record_arg(self) {
+        record_event(Event::ArrayStart);
+        for value in self.0 {
+            value.record_arg();
+        }
+        record_event(Event::DictEnd);
+    }
+}
+
+/// An argument made up of named entries whose values can have different types, such as the fields
+/// of a request. The closure adds the entries with [StructEntries::entry]. Values can themselves be
+/// structured, so implementing [RecordArg] for a type with a [StructArg] lets it be nested within
+/// other arguments.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::ArrayArg;
+/// use perfetto_recorder::RecordArg;
+/// use perfetto_recorder::StructArg;
+/// use perfetto_recorder::scope;
+///
+/// struct Request {
+///     method: &'static str,
+///     path: String,
+///     retries: u32,
+///     tags: Vec<&'static str>,
+/// }
+///
+/// impl RecordArg for &Request {
+///     fn record_arg(self) {
+///         StructArg(|entries| {
+///             entries
+///                 .entry("method", self.method)
+///                 .entry("path", self.path.as_str())
+///                 .entry("retries", self.retries)
+///                 .entry("tags", ArrayArg(self.tags.iter().copied()));
+///         })
+///         .record_arg();
+///     }
+/// }
+///
+/// let request = Request {
+///     method: "GET",
+///     path: "/index.html".to_owned(),
+///     retries: 0,
+///     tags: vec!["static"],
+/// };
+/// scope!("handle request", request = &request);
+/// ```
+pub struct StructArg<F: FnOnce(&mut StructEntries)>(pub F);
+
+impl<F: FnOnce(&mut StructEntries)> RecordArg for StructArg<F> {
+    fn record_arg(self) {
+        record_event(Event::DictStart);
+        (self.0)(&mut StructEntries { _private: () });
+        record_event(Event::DictEnd);
+    }
+}
+
+/// Adds entries to a [StructArg].
+pub struct StructEntries {
+    _private: (),
+}
+
+impl StructEntries {
+    /// Adds an entry named `name`. Entry names are interned by the [TraceBuilder] in the same way as
+    /// the names of [DynamicArgs].
+    pub fn entry(&mut self, name: &str, value: impl RecordArg) -> &mut Self {
+        name.record_arg();
+        value.record_arg();
+        self
+    }
+}
+
+/// Calls `f` within a span named `name`, returning its result. The span ends when `f` returns,
+/// including if it panics. Since the name isn't known at compile time, it's recorded each time, as
+/// for [start_span_dynamic]. Every call shares one call site, so the span has no source location.
+/// For spans with a source location, options or arguments, use the [with_span](macro@with_span)
+/// macro.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::with_span;
+///
+/// let total: u64 = with_span("sum", || (1..=10).sum());
+/// ```
+pub fn with_span<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let _guard = start_function_span(name);
+    f()
+}
+
+/// The call site of spans started by functions such as [with_span], rather than by macros. All of
+/// their calls share it, so it has no source location, since that would point into this crate rather
+/// than at the caller.
+static FUNCTION_CALL_SITE: CallSite = CallSite::new(SourceInfo {
+    name: "name",
+    file: "",
+    line: 0,
+    sensitivity: Sensitivity::Public,
+    process_track: false,
+    category: None,
+    track: None,
+    collapse_recursion: false,
+    dynamic_name: true,
+    arg_names: &[],
+    arg_sensitivities: &[],
+    end_arg_names: &[],
+    end_arg_sensitivities: &[],
+});
+
+/// Starts a span named `name` at [FUNCTION_CALL_SITE], as [start_span_dynamic] would.
+pub(crate) fn start_function_span(name: &str) -> SpanGuard {
+    let enabled = is_enabled() && FUNCTION_CALL_SITE.category_enabled();
+    let start = if enabled && sample_span() {
+        record_event(Event::StartSpan(FUNCTION_CALL_SITE.id()));
+        let start = time();
+        record_event(Event::Timestamp(start));
+        name.record_arg();
+        Some(start)
+    } else {
+        None
+    };
+    SpanGuard::new_named(&FUNCTION_CALL_SITE, name, enabled, start)
+}
+
+/// Links spans, possibly on different threads, so that the Perfetto UI draws arrows between them.
+/// A flow is attached to a span by passing it as an argument.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::Flow;
+/// use perfetto_recorder::scope;
+///
+/// let flow = Flow::named("request 42");
+/// {
+///     scope!("enqueue", flow = &flow);
+/// }
+/// // Possibly on another thread.
+/// {
+///     scope!("handle", flow);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flow {
+    id: u64,
+    name: Option<String>,
+}
+
+impl Flow {
+    /// Creates a new flow with a unique ID.
+    pub fn new() -> Self {
+        Self {
+            id: unique_id(),
+            name: None,
+        }
+    }
+
+    /// Creates a flow identified by `name`. Each span that participates in the flow is annotated with
+    /// the name, so that spans can be grouped by correlation key when querying the trace. Flows
+    /// created with the same name are the same flow, so the producer and consumer don't need to share
+    /// a [Flow] value.
+    pub fn named(name: impl Into<String>) -> Self {
+        use std::hash::Hash;
+        use std::hash::Hasher;
+
+        let name = name.into();
+        let mut hasher = std::hash::DefaultHasher::new();
+        name.hash(&mut hasher);
+        Self {
+            id: hasher.finish(),
+            name: Some(name),
+        }
+    }
+
+    /// Creates a flow with an ID that the caller already has, e.g. a request or message ID that is
+    /// sent along with work handed between threads. Flows created with the same ID are the same
+    /// flow, so a queue's producer and consumer can each create their own [Flow] from the ID of the
+    /// item being passed.
+    ///
+    /// ```
+    /// use perfetto_recorder::Flow;
+    /// use perfetto_recorder::scope;
+    ///
+    /// let message_id = 17;
+    /// {
+    ///     scope!("enqueue", flow = Flow::from_id(message_id));
+    /// }
+    /// // On a worker thread.
+    /// {
+    ///     scope!("handle", flow = Flow::from_id(message_id));
+    /// }
+    /// ```
+    pub fn from_id(id: u64) -> Self {
+        Self { id, name: None }
+    }
+
+    /// Returns the ID that identifies this flow in the trace.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An ID, such as a request ID, that is recorded as the span's correlation ID as well as an
+/// argument. Unlike other arguments, the correlation ID is stored in a dedicated field of the
+/// slice, so trace processor and other tools can group the spans that belong to the same operation
+/// without parsing debug annotations, even across millions of spans. A span only has one
+/// correlation ID, so if several are given, the last one is used.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::CorrelationId;
+/// use perfetto_recorder::scope;
+///
+/// let request_id = 42;
+/// scope!("handle", request_id = CorrelationId(request_id));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(pub u64);
+
+impl RecordArg for CorrelationId {
+    fn record_arg(self) {
+        record_event(Event::CorrelationId(self.0));
+    }
+}
+
+impl RecordArg for &Flow {
+    fn record_arg(self) {
+        match &self.name {
+            Some(name) => {
+                record_event(Event::NamedFlow(self.id));
+                name.as_str().record_arg();
+            }
+            None => record_event(Event::Flow(self.id)),
+        }
+    }
+}
+
+impl RecordArg for Flow {
+    fn record_arg(self) {
+        (&self).record_arg();
+    }
+}
+
+/// Clocks that Perfetto knows about. Timestamps in the trace are expressed relative to one of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinClock {
+    /// Wall-clock time, i.e. nanoseconds since the unix epoch.
+    Realtime = 1,
+    /// Time since some unspecified point, not including time that the system was suspended.
+    Monotonic = 3,
+    /// Time since boot, including time that the system was suspended. This is the clock used by
+    /// system-level Perfetto traces. Only available on Linux and Android.
+    Boottime = 6,
+}
+
+impl BuiltinClock {
+    const ALL: [BuiltinClock; 3] = [
+        BuiltinClock::Realtime,
+        BuiltinClock::Monotonic,
+        BuiltinClock::Boottime,
+    ];
+}
+
+static RUNTIME_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable recording. Can be called multiple times. Any spans emitted prior to the first call will
+/// be discarded.
+pub fn start() -> Result<(), TracingDisabledAtBuildTime> {
+    if !cfg!(feature = "enable") {
+        return Err(TracingDisabledAtBuildTime);
+    }
+
+    PAUSED.store(false, Ordering::Relaxed);
+    RUNTIME_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Set while recording is suspended by [pause].
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Temporarily suspends recording, e.g. during a phase that's known to be noisy, until [resume] is
+/// called. Unlike stopping, nothing that has already been recorded is discarded and the same
+/// [TraceBuilder] can carry on being used afterwards. Spans that were started before the pause still
+/// record their end, so aren't left open. Does nothing if recording isn't enabled.
+///
+/// # Example
+///
+/// ```
+/// # if perfetto_recorder::start().is_ok() {
+/// perfetto_recorder::pause();
+/// assert!(!perfetto_recorder::is_enabled());
+/// {
+///     perfetto_recorder::scope!("not recorded");
+/// }
+/// perfetto_recorder::resume();
+/// assert!(perfetto_recorder::is_enabled());
+/// # }
+/// ```
+pub fn pause() {
+    if !is_enabled() {
+        return;
+    }
+    // Spans that end while we're pausing check whether we're paused after checking whether we're
+    // enabled, so the flags are changed in the opposite order.
+    PAUSED.store(true, Ordering::Relaxed);
+    if !RUNTIME_ENABLED.swap(false, Ordering::Relaxed) {
+        PAUSED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Resumes recording that was suspended by [pause]. Does nothing if recording isn't paused, e.g.
+/// because it was stopped by a memory budget or the end of a session while paused.
+pub fn resume() {
+    if PAUSED.swap(false, Ordering::Relaxed) {
+        RUNTIME_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Returns whether recording is suspended by [pause].
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Returns whether spans whose start was recorded should record their end. They do while recording
+/// is paused, so that they aren't left open.
+#[cfg(feature = "enable")]
+pub(crate) fn ends_recorded() -> bool {
+    is_enabled() || is_paused()
+}
+
+/// Disables recording, including any that's paused, so that [resume] doesn't re-enable it.
+pub(crate) fn stop_recording() {
+    PAUSED.store(false, Ordering::Relaxed);
+    RUNTIME_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Like [start], but returns whether recording was enabled rather than an error. Useful for programs
+/// that don't care whether they were built with the "enable" feature.
+pub fn start_if_enabled() -> bool {
+    start().is_ok()
+}
+
+/// Returns whether recording is enabled.
+pub fn is_enabled() -> bool {
+    cfg!(feature = "enable") && RUNTIME_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Only one in every `SPAN_SAMPLE_INTERVAL` spans is recorded on each thread.
+static SPAN_SAMPLE_INTERVAL: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static SPANS_SINCE_SAMPLE: Cell<u32> = const { Cell::new(0) };
+
+    /// The number of spans on this thread that have been dropped by sampling and haven't yet ended.
+    /// While this is non-zero, all spans are dropped, so that we don't record spans without their
+    /// parents.
+    static SUPPRESSED_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Only record one in every `interval` spans on each thread. Counters are unaffected. An interval
+/// of 1, which is the default, records all spans. When a span isn't recorded, neither are any spans
+/// nested within it.
+pub fn set_span_sample_interval(interval: u32) {
+    SPAN_SAMPLE_INTERVAL.store(interval.max(1), Ordering::Relaxed);
+}
+
+/// Returns whether the span that is about to start should be recorded. If it shouldn't, then the
+/// span's guard must call `end_suppressed_span` when it's dropped.
+#[doc(hidden)]
+#[inline(always)]
+pub fn sample_span() -> bool {
+    let interval = SPAN_SAMPLE_INTERVAL.load(Ordering::Relaxed);
+    interval == 1 || sample_span_slow(interval)
+}
+
+#[cold]
+fn sample_span_slow(interval: u32) -> bool {
+    let depth = SUPPRESSED_DEPTH.get();
+    if depth > 0 {
+        SUPPRESSED_DEPTH.set(depth + 1);
+        return false;
+    }
+    let sample = SPANS_SINCE_SAMPLE.with(|count| {
+        let next = count.get() + 1;
+        let sample = next >= interval;
+        count.set(if sample { 0 } else { next });
+        sample
+    });
+    if !sample {
+        SUPPRESSED_DEPTH.set(1);
+    }
+    sample
+}
+
+/// Records that a span that was dropped by sampling has ended.
+#[cfg(feature = "enable")]
+fn end_suppressed_span() {
+    SUPPRESSED_DEPTH.set(SUPPRESSED_DEPTH.get().saturating_sub(1));
+}
+
+/// An error that is produced if [enable] is called when the "enable" feature of this crate is not
+/// active.
+#[derive(Debug)]
+pub struct TracingDisabledAtBuildTime;
+
+/// An error that is produced if [enable] has not been called, but we're trying to build a trace.
+#[derive(Debug)]
+pub struct TracingDisabled;
+
+/// An error that is produced if [TraceBuilder::set_clock] is called with a clock that isn't
+/// available on the current platform.
+#[derive(Debug)]
+pub struct ClockUnavailable(pub BuiltinClock);
+
+/// Used to build a trace file.
+///
+/// Example usage:
+/// ```
+/// # use perfetto_recorder::*;
+///
+/// # if perfetto_recorder::is_enabled() {
+///
+/// TraceBuilder::new()?
+///     .process_thread_data(&ThreadTraceData::take_current_thread())
+///     .write_to_file("a.pftrace")?;
+///
+/// # }
+///
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct TraceBuilder {
+    trace: schema::Trace,
+    pending_interned: Option<schema::InternedData>,
+    name_ids: HashMap<Cow<'static, str>, u64>,
+    debug_annotation_name_ids: HashMap<Cow<'static, str>, u64>,
+    category_ids: HashMap<&'static str, u64>,
+    source_location_ids: HashMap<(&'static str, u32), u64>,
+    log_message_body_ids: HashMap<&'static str, u64>,
+    /// The interned name and source location IDs of each call site, indexed by call site ID - 1.
+    call_site_iids: Vec<Option<(u64, Option<u64>)>>,
+    thread_uuids: HashMap<os::Pid, Uuid>,
+    process_uuids: HashMap<os::Pid, Uuid>,
+    static_counter_tracks: HashMap<usize, CounterTrack>,
+    static_counter_rate_tracks: HashMap<usize, CounterTrack>,
+    /// The timestamp and value of the most recent sample of each static counter. Only populated if
+    /// we're recording rates.
+    static_counter_previous: HashMap<usize, (u64, i64)>,
+    record_static_counter_rates: bool,
+    thread_lifetime_slices: bool,
+    thread_lifecycle_events: bool,
+    /// How deeply nested the spans of the main thread can be and still be copied to its process's
+    /// "Phases" track, or `None` if there's no such track.
+    phases_depth: Option<usize>,
+    /// The "Phases" track of each process.
+    phase_tracks: HashMap<os::Pid, Uuid>,
+    group_threads: bool,
+    /// Patterns, optionally ending in `*`, that assign matching threads to the named group.
+    thread_group_patterns: Vec<(String, String)>,
+    /// The sort index shared by the threads of each group, in the order that groups were first seen.
+    thread_group_ranks: HashMap<String, i32>,
+    /// The tracks created by [TraceBuilder::create_track], by name.
+    named_tracks: HashMap<String, TrackHandle>,
+    /// Patterns added by [TraceBuilder::route_spans] and the tracks that matching spans are moved to.
+    span_routes: Vec<(String, TrackHandle)>,
+    footer: bool,
+    packet_defaults: bool,
+    /// Stamped on each packet, so that traces from several machines can be told apart once merged.
+    machine_id: Option<u32>,
+    max_sensitivity: Sensitivity,
+    redaction: Redaction,
+    gauge_tracks: HashMap<usize, CounterTrack>,
+    /// How many of [COUNTER_SITES] have had their tracks added to the trace.
+    num_counter_site_tracks: usize,
+    sequence_id: u32,
+    clock: BuiltinClock,
+    zero_based_timestamps: bool,
+    /// The timestamp that zero-based timestamps are relative to, once it's been fixed by the first
+    /// call to [TraceBuilder::take_packets].
+    timestamp_origin: Option<u64>,
+    /// The anchor set by [TraceBuilder::align_with], if any.
+    clock_anchor: Option<ClockAnchor>,
+    /// The offset of each available clock relative to [BuiltinClock::Realtime], measured when the
+    /// builder was created.
+    clock_offsets: HashMap<BuiltinClock, i64>,
+    /// The reading of each available clock taken when the builder was created.
+    clock_snapshot: schema::ClockSnapshot,
+    /// The tracks of async spans that we've added descriptors for.
+    async_tracks: HashSet<u64>,
+    async_span_mode: AsyncSpanMode,
+    /// Every track descriptor that has been added, so that they can be replayed at the start of
+    /// each segment.
+    descriptors: Vec<TrackDescriptor>,
+    time_anchor: TimeAnchor,
+    /// The counters added by [TraceBuilder::add_concurrency_counter].
+    concurrency_counters: Vec<concurrency::ConcurrencyCounter>,
+    /// The total number of events that the threads we've processed dropped because of their
+    /// [BufferMode].
+    dropped_events: u64,
+    /// Set for builders created by [TraceBuilder::try_new] when recording isn't enabled. Such
+    /// builders ignore thread data and don't write anything.
+    noop: bool,
+}
+
+impl TraceBuilder {
+    pub fn new() -> Result<TraceBuilder, Error> {
+        Self::with_options(&TraceBuilderOptions::new())
+    }
+
+    /// Like [TraceBuilder::new], but if recording isn't enabled, returns a builder that ignores any
+    /// data that it's given and doesn't write anything, rather than an error. This allows the same
+    /// code to be used regardless of whether the "enable" feature is active.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use perfetto_recorder::*;
+    ///
+    /// start_if_enabled();
+    /// let mut trace = TraceBuilder::try_new();
+    /// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    /// trace.write_to_file(std::env::temp_dir().join("maybe.pftrace"))?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn try_new() -> TraceBuilder {
+        Self::build(&TraceBuilderOptions::new(), !is_enabled())
+    }
+
+    /// Creates a builder configured by `options`.
+    pub fn with_options(options: &TraceBuilderOptions) -> Result<TraceBuilder, Error> {
+        if !is_enabled() {
+            return Err(TracingDisabled.into());
+        }
+        Ok(Self::build(options, false))
+    }
+
+    fn build(options: &TraceBuilderOptions, noop: bool) -> TraceBuilder {
+        let sequence_id = next_sequence_id();
+
+        let clock_readings: Vec<(BuiltinClock, u64)> = BuiltinClock::ALL
+            .into_iter()
+            .filter_map(|clock| Some((clock, os::clock_nanos(clock)?)))
+            .collect();
+        let realtime = clock_readings
+            .iter()
+            .find(|(clock, _)| *clock == BuiltinClock::Realtime)
+            .map_or(0, |(_, nanos)| *nanos);
+        let clock_offsets = clock_readings
+            .iter()
+            .map(|(clock, nanos)| (*clock, nanos.wrapping_sub(realtime) as i64))
+            .collect();
+
+        let mut builder = TraceBuilder {
+            sequence_id,
+            trace: Default::default(),
+            pending_interned: Default::default(),
+            name_ids: Default::default(),
+            source_location_ids: Default::default(),
+            log_message_body_ids: Default::default(),
+            call_site_iids: Default::default(),
+            debug_annotation_name_ids: Default::default(),
+            category_ids: Default::default(),
+            thread_uuids: Default::default(),
+            process_uuids: Default::default(),
+            clock: options.clock,
+            zero_based_timestamps: options.zero_based_timestamps,
+            timestamp_origin: None,
+            clock_anchor: options.clock_anchor,
+            clock_offsets,
+            clock_snapshot: schema::ClockSnapshot {
+                clocks: clock_readings
+                    .iter()
+                    .map(|(clock, nanos)| schema::clock_snapshot::Clock {
+                        clock_id: Some(*clock as u32),
+                        timestamp: Some(*nanos),
+                    })
+                    .collect(),
+            },
+            async_tracks: Default::default(),
+            async_span_mode: options.async_span_mode,
+            descriptors: Default::default(),
+            static_counter_tracks: Default::default(),
+            static_counter_rate_tracks: Default::default(),
+            static_counter_previous: Default::default(),
+            record_static_counter_rates: options.record_static_counter_rates,
+            thread_lifetime_slices: options.thread_lifetime_slices,
+            thread_lifecycle_events: options.thread_lifecycle_events,
+            phases_depth: options.phases_depth,
+            phase_tracks: Default::default(),
+            group_threads: options.group_threads,
+            thread_group_patterns: options.thread_group_patterns.clone(),
+            thread_group_ranks: Default::default(),
+            named_tracks: Default::default(),
+            span_routes: Default::default(),
+            footer: options.footer,
+            packet_defaults: options.packet_defaults,
+            machine_id: options.machine_id,
+            max_sensitivity: options.max_sensitivity,
+            redaction: options.redaction,
+            gauge_tracks: Default::default(),
+            num_counter_site_tracks: 0,
+            time_anchor: TimeAnchor::new(),
+            concurrency_counters: Vec::new(),
+            dropped_events: 0,
+            noop,
+        };
+
+        builder.start_segment();
+        for span_name in &options.concurrency_counters {
+            builder.add_concurrency_counter(span_name.clone());
+        }
+
+        builder
+    }
+
+    /// Adds the packets that each segment of the trace starts with, so that it can be read without
+    /// any earlier segments.
+    fn start_segment(&mut self) {
+        self.add_packet(TracePacket {
+            sequence_flags: Some(
+                schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32,
+            ),
+            ..Default::default()
+        });
+
+        // Record all clocks at the same moment, so that Perfetto can convert between them. This
+        // allows our trace to be lined up with traces that use a different clock.
+        self.add_packet(TracePacket {
+            data: Some(schema::trace_packet::Data::ClockSnapshot(
+                self.clock_snapshot.clone(),
+            )),
+            ..Default::default()
+        });
+
+        // Adding the descriptors registers them again.
+        for descriptor in std::mem::take(&mut self.descriptors) {
+            self.add_packet(TracePacket {
+                data: Some(schema::trace_packet::Data::TrackDescriptor(descriptor)),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Encodes the trace so far, then clears it so that the builder can be used to build the next
+    /// segment. Each segment is a complete trace: it starts with the descriptors of all tracks that
+    /// the builder has created, including those of threads, counters and track groups from earlier
+    /// segments, and strings are interned afresh. This allows a long-running process to write its
+    /// trace out periodically, e.g. to a new file each time, without holding on to all the data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if start().is_ok() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// for segment in 0..3 {
+    ///     scope!("work", segment);
+    ///     trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    ///     let bytes = trace.take_segment();
+    ///     std::fs::write(std::env::temp_dir().join(format!("segment-{segment}.pftrace")), bytes)?;
+    /// }
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn take_segment(&mut self) -> Vec<u8> {
+        let bytes = self.encode_to_vec();
+        self.trace.packet.clear();
+        self.pending_interned = None;
+        self.name_ids.clear();
+        self.debug_annotation_name_ids.clear();
+        self.category_ids.clear();
+        self.source_location_ids.clear();
+        self.log_message_body_ids.clear();
+        self.call_site_iids.clear();
+        self.start_concurrency_segment();
+        self.start_segment();
+        bytes
+    }
+
+    /// Releases memory held by the builder that it no longer needs. [TraceBuilder::take_segment]
+    /// keeps the capacity that was allocated for the previous segment, on the assumption that the
+    /// next segment will be similar in size. Calling this afterwards returns that memory to the
+    /// allocator, which is worthwhile for services that only trace intermittently.
+    pub fn shrink_to_fit(&mut self) -> &mut Self {
+        self.trace.packet.shrink_to_fit();
+        self.name_ids.shrink_to_fit();
+        self.debug_annotation_name_ids.shrink_to_fit();
+        self.category_ids.shrink_to_fit();
+        self.source_location_ids.shrink_to_fit();
+        self.log_message_body_ids.shrink_to_fit();
+        self.call_site_iids.shrink_to_fit();
+        self.descriptors.shrink_to_fit();
+        self
+    }
+
+    /// Sets the clock in which timestamps of subsequently added packets are expressed. Defaults to
+    /// [BuiltinClock::Realtime]. Returns an error if the clock isn't available on this platform.
+    pub fn set_clock(&mut self, clock: BuiltinClock) -> Result<&mut Self, Error> {
+        if !self.clock_offsets.contains_key(&clock) {
+            return Err(ClockUnavailable(clock).into());
+        }
+        self.clock = clock;
+        Ok(self)
+    }
+
+    /// Merges trace data captured from a thread into the trace.
+    pub fn process_thread_data(&mut self, thread: &ThreadTraceData) -> &mut Self {
+        self.process_thread(thread);
+        self
+    }
+
+    /// Merges trace data captured from a thread into the trace, returning any warnings about the
+    /// data.
+    fn process_thread(&mut self, thread: &ThreadTraceData) -> Vec<String> {
+        if self.noop {
+            return Vec::new();
+        }
+        let thread_uuid = self.thread_uuid(thread);
+        let call_sites = CALL_SITES.lock().unwrap().clone();
+        self.add_counter_site_tracks();
+        let phases_depth = self
+            .phases_depth
+            .filter(|_| thread.thread_name.as_deref() == Some("main"));
+
+        let mut events = thread.events.iter();
+        // The call site ID of each span that is currently open, together with the index of its
+        // begin packet, or `None` if the span was dropped, how it has recursed and the annotations
+        // to add to its end.
+        let mut open_spans: Vec<(u32, Option<usize>, Recursion, Annotations)> = Vec::new();
+        let mut spawned_by = None;
+        // For each call site, how many of its spans were suspiciously short and how many spans it
+        // had in total.
+        let mut span_durations: HashMap<u32, (usize, usize)> = HashMap::new();
+
+        while let Some(event) = events.next() {
+            match event {
+                Event::StartSpan(id) => {
+                    let call_site = call_sites[*id as usize - 1];
+                    if call_site.source.collapse_recursion
+                        && let Some((open, _, recursion, _)) = open_spans.last_mut()
+                        && open == id
+                    {
+                        skip_span_event(
+                            &call_site.source,
+                            schema::track_event::Type::SliceBegin,
+                            &mut events,
+                            &thread.strings,
+                        );
+                        recursion.depth += 1;
+                        recursion.max_depth = recursion.max_depth.max(recursion.depth);
+                        recursion.calls += 1;
+                        continue;
+                    }
+                    let added = self.emit_track_event(
+                        call_site,
+                        schema::track_event::Type::SliceBegin,
+                        &mut events,
+                        thread,
+                        thread_uuid,
+                        true,
+                    );
+                    let index = added.then(|| self.trace.packet.len() - 1);
+                    if let Some(index) = index {
+                        self.record_concurrency_change(call_site.source.name, index, 1);
+                    }
+                    if let Some(index) = index
+                        && phases_depth.is_some_and(|depth| open_spans.len() <= depth)
+                    {
+                        self.copy_to_phases_track(index, thread.pid);
+                    }
+                    if let Some(index) = index
+                        && let Some(flow_id) = spawned_by.take()
+                    {
+                        self.add_flow_id(index, flow_id);
+                    }
+                    open_spans.push((*id, index, Recursion::default(), Vec::new()));
+                }
+                Event::EndSpan(id) => {
+                    let call_site = call_sites[*id as usize - 1];
+                    if let Some((open, _, recursion, _)) = open_spans.last_mut()
+                        && open == id
+                        && recursion.depth > 0
+                    {
+                        skip_span_event(
+                            &call_site.source,
+                            schema::track_event::Type::SliceEnd,
+                            &mut events,
+                            &thread.strings,
+                        );
+                        recursion.depth -= 1;
+                        continue;
+                    }
+                    let added = self.emit_track_event(
+                        call_site,
+                        schema::track_event::Type::SliceEnd,
+                        &mut events,
+                        thread,
+                        thread_uuid,
+                        false,
+                    );
+                    if added {
+                        self.record_concurrency_change(
+                            call_site.source.name,
+                            self.trace.packet.len() - 1,
+                            -1,
+                        );
+                    }
+                    if let Some(position) = open_spans.iter().rposition(|(open, ..)| open == id) {
+                        let (_, begin, recursion, end_annotations) = open_spans.remove(position);
+                        // The end must be on the same track as the begin, which might have been moved.
+                        if added
+                            && let Some(begin) = begin
+                            && let Some(schema::trace_packet::Data::TrackEvent(begin_event)) =
+                                &self.trace.packet[begin].data
+                        {
+                            let track_uuid = begin_event.track_uuid;
+                            if let Some(schema::trace_packet::Data::TrackEvent(end_event)) =
+                                &mut self.trace.packet.last_mut().unwrap().data
+                            {
+                                end_event.track_uuid = track_uuid;
+                            }
+                        }
+                        if added && !end_annotations.is_empty() {
+                            self.add_annotations(
+                                self.trace.packet.len() - 1,
+                                call_site,
+                                end_annotations,
+                            );
+                        }
+                        if added
+                            && begin.is_some()
+                            && phases_depth.is_some_and(|depth| position <= depth)
+                        {
+                            self.copy_to_phases_track(self.trace.packet.len() - 1, thread.pid);
+                        }
+                        if recursion.calls > 0
+                            && let Some(begin) = begin
+                        {
+                            self.add_annotations(
+                                begin,
+                                call_site,
+                                vec![
+                                    (
+                                        "recursion_max_depth".to_owned(),
+                                        schema::debug_annotation::Value::UintValue(
+                                            u64::from(recursion.max_depth) + 1,
+                                        ),
+                                    ),
+                                    (
+                                        "recursion_calls".to_owned(),
+                                        schema::debug_annotation::Value::UintValue(
+                                            u64::from(recursion.calls) + 1,
+                                        ),
+                                    ),
+                                ],
+                            );
+                        }
+                        if cfg!(debug_assertions)
+                            && added
+                            && let Some(begin) = begin
+                        {
+                            let packets = &self.trace.packet;
+                            let duration = packets[packets.len() - 1]
+                                .timestamp()
+                                .saturating_sub(packets[begin].timestamp());
+                            let (short, total) = span_durations.entry(*id).or_default();
+                            *total += 1;
+                            if duration < SHORT_SPAN_NANOS {
+                                *short += 1;
+                            }
+                        }
+                    }
+                }
+                Event::Annotations(id) => {
+                    let annotations = read_annotations(&mut events, &thread.strings);
+                    if let Some((_, Some(index), ..)) =
+                        open_spans.iter().rev().find(|(open, ..)| open == id)
+                    {
+                        self.add_annotations(*index, call_sites[*id as usize - 1], annotations);
+                    }
+                }
+                Event::RootAnnotations(id) => {
+                    let annotations = read_annotations(&mut events, &thread.strings);
+                    if let Some((open, Some(index), ..)) = open_spans.first()
+                        && open == id
+                    {
+                        self.add_annotations(*index, call_sites[*id as usize - 1], annotations);
+                    }
+                }
+                Event::EndAnnotations(id) => {
+                    let annotations = read_annotations(&mut events, &thread.strings);
+                    if let Some((.., end_annotations)) =
+                        open_spans.iter_mut().rev().find(|(open, ..)| open == id)
+                    {
+                        end_annotations.extend(annotations);
+                    }
+                }
+                Event::SpawnedThread(flow_id) => {
+                    if let Some((_, Some(index), ..)) = open_spans.last() {
+                        self.add_flow_id(*index, *flow_id);
+                    }
+                }
+                Event::SpawnedBy(flow_id) => spawned_by = Some(*flow_id),
+                Event::AsyncStart { id, track } | Event::AsyncEnd { id, track } => {
+                    let call_site = call_sites[*id as usize - 1];
+                    let kind = if matches!(event, Event::AsyncStart { .. }) {
+                        schema::track_event::Type::SliceBegin
+                    } else {
+                        schema::track_event::Type::SliceEnd
+                    };
+                    self.emit_async_event(
+                        call_site,
+                        kind,
+                        *track,
+                        &mut events,
+                        thread,
+                        thread_uuid,
+                    );
+                }
+                Event::FailedAssertion(id) => {
+                    self.emit_track_event(
+                        call_sites[*id as usize - 1],
+                        schema::track_event::Type::Instant,
+                        &mut events,
+                        thread,
+                        thread_uuid,
+                        false,
+                    );
+                }
+                Event::Log { id, level } => {
+                    let call_site = call_sites[*id as usize - 1];
+                    let added = self.emit_track_event(
+                        call_site,
+                        schema::track_event::Type::Instant,
+                        &mut events,
+                        thread,
+                        thread_uuid,
+                        true,
+                    );
+                    if added {
+                        self.add_log_message(self.trace.packet.len() - 1, call_site, *level);
+                    }
+                }
+                Event::CounterI64 { uuid, value } => {
+                    self.emit_counter_event(
+                        *uuid,
+                        &mut events,
+                        schema::track_event::CounterValueField::CounterValue(*value),
+                    );
+                }
+                Event::CounterF64 { uuid, value } => {
+                    self.emit_counter_event(
+                        *uuid,
+                        &mut events,
+                        schema::track_event::CounterValueField::DoubleCounterValue(*value),
+                    );
+                }
+                other => panic!("Internal error: Unexpected event {other:?}"),
+            }
+        }
+
+        if self.thread_lifetime_slices
+            && let Some(first_event_time) = thread.first_event_time
+        {
+            let name = match &thread.thread_name {
+                Some(name) => name.clone(),
+                None => format!("Thread {}", thread.tid.as_i32()),
+            };
+            let name_id = self.name_id(Cow::Owned(name));
+            for (kind, timestamp) in [
+                (schema::track_event::Type::SliceBegin, first_event_time),
+                (schema::track_event::Type::SliceEnd, thread.taken_time),
+            ] {
+                let mut track_event = schema::TrackEvent {
+                    track_uuid: Some(thread_uuid.0),
+                    name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+                    ..Default::default()
+                };
+                track_event.set_type(kind);
+                self.add_packet(TracePacket {
+                    timestamp: Some(self.timestamp_nanos(timestamp)),
+                    timestamp_clock_id: Some(self.clock as u32),
+                    data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+                    ..Default::default()
+                });
+            }
+        }
+
+        if self.thread_lifecycle_events {
+            let lifecycle_events = [
+                thread
+                    .first_event_time
+                    .filter(|_| thread.thread_started)
+                    .map(|time| (THREAD_STARTED_EVENT_NAME, time)),
+                thread
+                    .thread_exited
+                    .then_some((THREAD_EXITED_EVENT_NAME, thread.taken_time)),
+            ];
+            for (name, timestamp) in lifecycle_events.into_iter().flatten() {
+                self.add_thread_lifecycle_event(thread, name, timestamp);
+            }
+        }
+
+        if thread.dropped_events > 0 {
+            self.dropped_events += thread.dropped_events;
+            let name_id = self.name_id(Cow::Borrowed(DATA_LOST_EVENT_NAME));
+            let annotation = DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(Cow::Borrowed("dropped_events")),
+                )),
+                value: Some(schema::debug_annotation::Value::UintValue(
+                    thread.dropped_events,
+                )),
+                ..Default::default()
+            };
+            let mut track_event = schema::TrackEvent {
+                track_uuid: Some(thread_uuid.0),
+                name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+                debug_annotations: vec![annotation],
+                ..Default::default()
+            };
+            track_event.set_type(schema::track_event::Type::Instant);
+            let timestamp = thread.first_drop_time.unwrap_or(thread.taken_time);
+            self.add_packet(TracePacket {
+                timestamp: Some(self.timestamp_nanos(timestamp)),
+                timestamp_clock_id: Some(self.clock as u32),
+                data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+                ..Default::default()
+            });
+        }
+
+        let mut short_call_sites: Vec<u32> = span_durations
+            .into_iter()
+            .filter(|(_, (short, total))| {
+                *total >= MIN_SHORT_SPANS_TO_WARN
+                    && short * 100 >= total * SHORT_SPAN_WARNING_PERCENT
+            })
+            .map(|(id, _)| id)
+            .collect();
+        short_call_sites.sort_unstable();
+        short_call_sites
+            .into_iter()
+            .map(|id| {
+                let source = &call_sites[id as usize - 1].source;
+                let location = if source.file.is_empty() {
+                    String::new()
+                } else {
+                    format!(" at {}:{}", source.file, source.line)
+                };
+                format!(
+                    "Almost all spans named `{}`{location} were shorter than {SHORT_SPAN_NANOS}ns. \
+                     The span guard might be dropped immediately, e.g. by `let _ = start_span!(...)`",
+                    source.name
+                )
+            })
+            .collect()
+    }
+
+    /// Merges trace data from each of `threads` into the trace, returning a report for each thread.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let (sender, receiver) = std::sync::mpsc::channel();
+    /// for _ in 0..4 {
+    ///     let sender = sender.clone();
+    ///     std::thread::spawn(move || {
+    ///         scope!("work");
+    ///         sender.send(ThreadTraceData::take_current_thread()).unwrap();
+    ///     });
+    /// }
+    /// drop(sender);
+    ///
+    /// let mut trace = TraceBuilder::new()?;
+    /// for report in trace.process_all(receiver) {
+    ///     println!("{report:?}");
+    /// }
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn process_all(
+        &mut self,
+        threads: impl IntoIterator<Item = ThreadTraceData>,
+    ) -> Vec<BuildReport> {
+        threads
+            .into_iter()
+            .map(|thread| {
+                let num_packets_before = self.trace.packet.len();
+                let warnings = self.process_thread(&thread);
+                BuildReport {
+                    tid: thread.tid.as_i32(),
+                    num_events: thread.events.len(),
+                    num_spans: thread
+                        .events
+                        .iter()
+                        .filter(|event| matches!(event, Event::StartSpan(_)))
+                        .count(),
+                    num_packets: self.trace.packet.len() - num_packets_before,
+                    dropped_events: thread.dropped_events,
+                    warnings,
+                    thread_name: thread.thread_name,
+                }
+            })
+            .collect()
+    }
+
+    /// When enabled, instant events are added to the process track when each thread started
+    /// recording and when it exited, annotated with the thread's name and ID. This shows when
+    /// threads were created and torn down, e.g. as a thread pool warms up or shuts down. A thread's
+    /// start is shown when its first data is processed and its exit is shown when the data stashed
+    /// by [TraceBuilder::process_exited_threads] is processed. Threads whose data is taken before
+    /// they exit, with nothing recorded since, don't show an exit.
+    pub fn set_thread_lifecycle_events(&mut self, enabled: bool) -> &mut Self {
+        self.thread_lifecycle_events = enabled;
+        self
+    }
+
+    /// Adds an instant event named `name` at `timestamp` to the track of `thread`'s process.
+    fn add_thread_lifecycle_event(
+        &mut self,
+        thread: &ThreadTraceData,
+        name: &'static str,
+        timestamp: Timestamp,
+    ) {
+        let process_uuid = self.process_uuid(thread.pid);
+        let name_id = self.name_id(Cow::Borrowed(name));
+        let thread_name = match &thread.thread_name {
+            Some(name) => name.clone(),
+            None => format!("Thread {}", thread.tid.as_i32()),
+        };
+        let debug_annotations = vec![
+            DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(Cow::Borrowed("thread_name")),
+                )),
+                value: Some(schema::debug_annotation::Value::StringValue(thread_name)),
+                ..Default::default()
+            },
+            DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(Cow::Borrowed("tid")),
+                )),
+                value: Some(schema::debug_annotation::Value::IntValue(
+                    thread.tid.as_i32().into(),
+                )),
+                ..Default::default()
+            },
+        ];
+        let mut track_event = schema::TrackEvent {
+            track_uuid: Some(process_uuid.0),
+            name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+            debug_annotations,
+            ..Default::default()
+        };
+        track_event.set_type(schema::track_event::Type::Instant);
+        self.add_packet(TracePacket {
+            timestamp: Some(self.timestamp_nanos(timestamp)),
+            timestamp_clock_id: Some(self.clock as u32),
+            data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+            ..Default::default()
+        });
+    }
+
+    /// When enabled, each call to [TraceBuilder::process_thread_data] adds a slice named after the
+    /// thread that spans from when the thread first recorded an event to when its events were
+    /// taken. The slice encloses the thread's other spans, giving an overview of when each thread
+    /// was active.
+    pub fn set_thread_lifetime_slices(&mut self, enabled: bool) -> &mut Self {
+        self.thread_lifetime_slices = enabled;
+        self
+    }
+
+    /// Copies the spans of the main thread that are nested at most `max_depth` deep onto a separate
+    /// "Phases" track, where 0 means only the outermost spans. This gives tools with a few
+    /// distinct phases, such as parsing, layout and writing output, a clean overview at the top of
+    /// the trace, while the full nesting stays on the thread's own track. The main thread is
+    /// identified by its name, "main", which is what Rust names it.
+    pub fn set_phases_track(&mut self, max_depth: usize) -> &mut Self {
+        self.phases_depth = Some(max_depth);
+        self
+    }
+
+    /// When enabled, threads that belong to the same pool, such as `rayon-worker-0` to
+    /// `rayon-worker-63`, are given a shared sort index, so that they're shown next to each other
+    /// beneath their process rather than interleaved with unrelated threads. A thread's group is
+    /// its name with any numeric suffix removed, unless it matches a pattern passed to
+    /// [TraceBuilder::add_thread_group]. Groups are ordered by when their first thread was added to
+    /// the trace. Perfetto doesn't allow thread tracks to be nested within other tracks, so groups
+    /// don't get a track of their own.
+    pub fn set_group_threads(&mut self, enabled: bool) -> &mut Self {
+        self.group_threads = enabled;
+        self
+    }
+
+    /// Assigns threads whose names match `pattern` to the group `name`, overriding the group that
+    /// would be inferred from their names. `pattern` either matches a name exactly or, if it ends
+    /// in `*`, matches names that start with the rest of it. Earlier patterns take precedence.
+    /// Enables [TraceBuilder::set_group_threads].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// trace
+    ///     .add_thread_group("tokio-runtime-worker*", "tokio")
+    ///     .add_thread_group("tokio-blocking*", "tokio");
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn add_thread_group(&mut self, pattern: &str, name: &str) -> &mut Self {
+        self.group_threads = true;
+        self.thread_group_patterns
+            .push((pattern.to_owned(), name.to_owned()));
+        self
+    }
+
+    /// Moves all packets from `other` into this trace. `other` keeps its own packet sequence, so
+    /// builders can be populated independently, e.g. one per thread, then merged at the end.
+    ///
+    /// If both builders have tracks for the same thread, static counter or gauge, then the tracks
+    /// from `other` are replaced by those from this builder. This only applies to builders with the
+    /// same machine ID. Packets keep the machine ID of the builder that added them. Concurrency
+    /// counters for the same span name are combined, and dropped event counts are added together.
+    pub fn merge(&mut self, mut other: TraceBuilder) -> &mut Self {
+        let mut uuid_remap = HashMap::new();
+        self.num_counter_site_tracks = self
+            .num_counter_site_tracks
+            .max(other.num_counter_site_tracks);
+        self.dropped_events += other.dropped_events;
+        // Groups that only `other` has seen are ordered after ours.
+        let mut other_groups: Vec<(String, i32)> = std::mem::take(&mut other.thread_group_ranks)
+            .into_iter()
+            .collect();
+        other_groups.sort_by_key(|(_, rank)| *rank);
+        for (group, _) in other_groups {
+            let next_rank = self.thread_group_ranks.len() as i32 + 1;
+            self.thread_group_ranks.entry(group).or_insert(next_rank);
+        }
+        // Tracks on different machines are different, even if their threads, processes or names are
+        // the same, so `other` keeps its own tracks and we don't reuse them.
+        if self.machine_id == other.machine_id {
+            for (mine, theirs) in [
+                (&mut self.thread_uuids, other.thread_uuids),
+                (&mut self.process_uuids, other.process_uuids),
+                (&mut self.phase_tracks, other.phase_tracks),
+            ] {
+                for (id, uuid) in theirs {
+                    match mine.get(&id) {
+                        Some(existing) => {
+                            uuid_remap.insert(uuid.0, existing.0);
+                        }
+                        None => {
+                            mine.insert(id, uuid);
+                        }
+                    }
+                }
+            }
+            for (name, track) in other.named_tracks {
+                match self.named_tracks.get(&name) {
+                    Some(existing) => {
+                        uuid_remap.insert(track.uuid, existing.uuid);
+                    }
+                    None => {
+                        self.named_tracks.insert(name, track);
+                    }
+                }
+            }
+            for (mine, theirs) in [
+                (&mut self.static_counter_tracks, other.static_counter_tracks),
+                (
+                    &mut self.static_counter_rate_tracks,
+                    other.static_counter_rate_tracks,
+                ),
+                (&mut self.gauge_tracks, other.gauge_tracks),
+            ] {
+                for (key, track) in theirs {
+                    match mine.get(&key) {
+                        Some(existing) => {
+                            uuid_remap.insert(track.uuid, existing.uuid);
+                        }
+                        None => {
+                            mine.insert(key, track);
+                        }
+                    }
+                }
+            }
+            self.merge_concurrency_counters(
+                std::mem::take(&mut other.concurrency_counters),
+                &mut uuid_remap,
+            );
+        } else {
+            // Spans on other machines don't count towards our counters, so `other`'s counters are
+            // finished as they are.
+            let concurrency_packets = other.concurrency_packets();
+            other.trace.packet.extend(concurrency_packets);
+        }
+
+        if !uuid_remap.is_empty() {
+            other.trace.packet.retain(|packet| {
+                !matches!(
+                    &packet.data,
+                    Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                        if descriptor.uuid.is_some_and(|uuid| uuid_remap.contains_key(&uuid))
+                )
+            });
+            // Tracks that `other` kept may be children of tracks that were replaced, e.g. its phases
+            // track of a process that we've also seen.
+            let remap_parent = |descriptor: &mut TrackDescriptor| {
+                if let Some(uuid) = descriptor
+                    .parent_uuid
+                    .and_then(|uuid| uuid_remap.get(&uuid))
+                {
+                    descriptor.parent_uuid = Some(*uuid);
+                }
+            };
+            for packet in &mut other.trace.packet {
+                match &mut packet.data {
+                    Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                        if let Some(uuid) = event.track_uuid.and_then(|uuid| uuid_remap.get(&uuid))
+                        {
+                            event.track_uuid = Some(*uuid);
+                        }
+                    }
+                    Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                        remap_parent(descriptor);
+                    }
+                    _ => {}
+                }
+            }
+            other.descriptors.iter_mut().for_each(remap_parent);
+        }
+
+        other.descriptors.retain(|descriptor| {
+            descriptor
+                .uuid
+                .is_none_or(|uuid| !uuid_remap.contains_key(&uuid))
+        });
+        self.descriptors.append(&mut other.descriptors);
+        self.async_tracks.extend(other.async_tracks);
+        self.trace.packet.append(&mut other.trace.packet);
+        self
+    }
+
+    /// Stamps each packet of the trace with `machine_id`, including packets that have already been
+    /// added, but not packets merged in from other builders. This identifies the machine or
+    /// container that the trace came from, so that traces collected from several machines can be
+    /// merged, e.g. with [TraceBuilder::merge], and still be grouped by origin. Builders with
+    /// different machine IDs never share tracks when merged.
+    pub fn set_machine_id(&mut self, machine_id: u32) -> &mut Self {
+        self.machine_id = Some(machine_id);
+        let own_sequence = Some(
+            schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                self.sequence_id,
+            ),
+        );
+        for packet in &mut self.trace.packet {
+            if packet.optional_trusted_packet_sequence_id == own_sequence {
+                packet.machine_id = Some(machine_id);
+            }
+        }
+        self
+    }
+
+    /// When enabled, timestamps are shifted when the trace is encoded such that the earliest packet
+    /// is at time zero. This makes it easier to compare traces from different runs and avoids
+    /// revealing when the trace was recorded. The clock snapshot is omitted, so the trace can't be
+    /// lined up with traces from other sources. Assumes that all packets use the same clock.
+    pub fn set_zero_based_timestamps(&mut self, zero_based: bool) -> &mut Self {
+        self.zero_based_timestamps = zero_based;
+        self
+    }
+
+    // Encode the Perfetto trace as bytes.
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let trace = self.finished_trace();
+        let mut bytes = self.encode_packets(&trace);
+        if self.footer {
+            self.append_footer(&trace, &mut bytes);
+        }
+        bytes
+    }
+
+    /// Encodes `trace`, which is the result of [TraceBuilder::finished_trace], without a footer.
+    fn encode_packets(&self, trace: &schema::Trace) -> Vec<u8> {
+        if self.packet_defaults {
+            packet_defaults::encode_with_packet_defaults(trace)
+        } else {
+            trace.encode_to_vec()
+        }
+    }
+
+    /// Encodes the packets that have been added since this was last called, without a footer,
+    /// then removes them, adding them to `totals`. Unlike
+    /// [TraceBuilder::take_segment], tracks and interned strings are kept, so the packets continue
+    /// those that were taken before.
+    pub(crate) fn take_packets(&mut self, totals: &mut footer::FooterState) -> Vec<u8> {
+        // Later packets need to be shifted by the same amount as the first ones.
+        if self.zero_based_timestamps && self.timestamp_origin.is_none() {
+            self.timestamp_origin = self
+                .trace
+                .packet
+                .iter()
+                .filter_map(|packet| packet.timestamp)
+                .min();
+        }
+        let trace = self.finished_trace();
+        let bytes = self.encode_packets(&trace);
+        totals.add(&trace, &bytes);
+        drop(trace);
+        self.trace.packet.clear();
+        self.start_concurrency_segment();
+        bytes
+    }
+
+    /// Returns the trace with any transformations that are deferred until encoding applied.
+    fn finished_trace(&self) -> Cow<'_, schema::Trace> {
+        let concurrency_packets = self.concurrency_packets();
+        if !self.zero_based_timestamps
+            && self.clock_anchor.is_none()
+            && concurrency_packets.is_empty()
+        {
+            return Cow::Borrowed(&self.trace);
+        }
+
+        let mut trace = self.trace.clone();
+        trace.packet.extend(concurrency_packets);
+        if !self.zero_based_timestamps && self.clock_anchor.is_none() {
+            return Cow::Owned(trace);
+        }
+        trace.packet.retain(|packet| {
+            !matches!(
+                packet.data,
+                Some(schema::trace_packet::Data::ClockSnapshot(_))
+            )
+        });
+        let start = match (self.clock_anchor, self.timestamp_origin) {
+            (Some(anchor), _) => self.anchor_origin(anchor),
+            (None, Some(origin)) => origin.into(),
+            (None, None) => trace
+                .packet
+                .iter()
+                .filter_map(|packet| packet.timestamp)
+                .min()
+                .unwrap_or(0)
+                .into(),
+        };
+        for packet in &mut trace.packet {
+            if let Some(timestamp) = &mut packet.timestamp {
+                *timestamp =
+                    u64::try_from((i128::from(*timestamp) - start).max(0)).unwrap_or(u64::MAX);
+                // Without a clock snapshot, timestamps need to be in the default trace clock.
+                packet.timestamp_clock_id = Some(BuiltinClock::Boottime as u32);
+            }
+        }
+        Cow::Owned(trace)
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.write_to(&mut FileOutput::new(path.as_ref()))
+    }
+
+    /// Interns `name` as an event name, returning its interning ID. The ID can be used as the
+    /// `name_iid` of track events in packets added via `add_raw_packet`. The interned data is
+    /// attached to the next packet added to the trace.
+    pub fn intern_event_name(&mut self, name: &str) -> u64 {
+        self.name_id(Cow::Owned(name.to_owned()))
+    }
+
+    /// Interns `name` as a debug annotation name, returning its interning ID. The ID can be used as
+    /// the `name_iid` of debug annotations in packets added via `add_raw_packet`. The interned data
+    /// is attached to the next packet added to the trace.
+    pub fn intern_annotation_name(&mut self, name: &str) -> u64 {
+        self.debug_annotation_name_id(Cow::Owned(name.to_owned()))
+    }
+
+    fn name_id(&mut self, name: Cow<'static, str>) -> u64 {
+        if let Some(id) = self.name_ids.get(name.as_ref()) {
+            return *id;
+        }
+        let id = self.name_ids.len() as u64 + 1;
+        self.pending_interned
+            .get_or_insert_default()
+            .event_names
+            .push(schema::EventName {
+                iid: Some(id),
+                name: Some(name.clone().into_owned()),
+            });
+        self.name_ids.insert(name, id);
+        id
+    }
+
+    fn debug_annotation_name_id(&mut self, name: Cow<'static, str>) -> u64 {
+        if let Some(id) = self.debug_annotation_name_ids.get(name.as_ref()) {
+            return *id;
+        }
+        let id = self.debug_annotation_name_ids.len() as u64 + 1;
+        self.pending_interned
+            .get_or_insert_default()
+            .debug_annotation_names
+            .push(schema::DebugAnnotationName {
+                iid: Some(id),
+                name: Some(name.clone().into_owned()),
+            });
+        self.debug_annotation_name_ids.insert(name, id);
+        id
+    }
+
+    /// Sets the value of `annotation` from `value`, interning the names of any dictionary entries.
+    fn set_annotation_value(&mut self, annotation: &mut DebugAnnotation, value: ArgValue) {
+        match value {
+            ArgValue::Value(value) => annotation.value = Some(value),
+            ArgValue::Dict(entries) => {
+                annotation.dict_entries = entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let mut entry = DebugAnnotation {
+                            name_field: Some(schema::debug_annotation::NameField::NameIid(
+                                self.debug_annotation_name_id(Cow::Owned(key)),
+                            )),
+                            ..Default::default()
+                        };
+                        self.set_annotation_value(&mut entry, value);
+                        entry
+                    })
+                    .collect();
+            }
+            ArgValue::Array(values) => {
+                annotation.array_values = values
+                    .into_iter()
+                    .map(|value| {
+                        let mut entry = DebugAnnotation::default();
+                        self.set_annotation_value(&mut entry, value);
+                        entry
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    fn category_id(&mut self, category: &'static str) -> u64 {
+        let next_id = self.category_ids.len() as u64 + 1;
+        *self.category_ids.entry(category).or_insert_with(|| {
+            self.pending_interned
+                .get_or_insert_default()
+                .event_categories
+                .push(schema::EventCategory {
+                    iid: Some(next_id),
+                    name: Some(category.to_owned()),
+                });
+            next_id
+        })
+    }
+
+    fn source_location_id(&mut self, source_location: &'static SourceInfo) -> u64 {
+        let next_id = self.source_location_ids.len() as u64 + 1;
+        *self
+            .source_location_ids
+            .entry((source_location.file, source_location.line))
+            .or_insert_with(|| {
+                self.pending_interned
+                    .get_or_insert_default()
+                    .source_locations
+                    .push(schema::SourceLocation {
+                        iid: Some(next_id),
+                        file_name: Some(source_location.file.to_owned()),
+                        function_name: None,
+                        line_number: Some(source_location.line),
+                    });
+                next_id
+            })
+    }
+
+    /// Returns the interned name and source location IDs for `call_site`, interning them if this
+    /// is the first time we've seen it. The source location ID is `None` if the call site didn't
+    /// capture its location.
+    fn call_site_iids(&mut self, call_site: &'static CallSite) -> (u64, Option<u64>) {
+        let index = call_site.id.load(Ordering::Relaxed) as usize - 1;
+        if let Some(Some(iids)) = self.call_site_iids.get(index) {
+            return *iids;
+        }
+        let iids = (
+            self.name_id(Cow::Borrowed(call_site.source.name)),
+            (!call_site.source.file.is_empty()).then(|| self.source_location_id(&call_site.source)),
+        );
+        if self.call_site_iids.len() <= index {
+            self.call_site_iids.resize(index + 1, None);
+        }
+        self.call_site_iids[index] = Some(iids);
+        iids
+    }
+
+    /// Interns the names and source locations of all spans that have been recorded so far. This is
+    /// optional, but means that the interned data is emitted up-front in a single packet rather
+    /// than being spread across the first event of each span.
+    pub fn intern_call_sites(&mut self) -> &mut Self {
+        let call_sites = CALL_SITES.lock().unwrap().clone();
+        for call_site in call_sites {
+            if self.redaction_for(call_site.source.sensitivity).is_none() {
+                self.call_site_iids(call_site);
+            }
+        }
+        self
+    }
+
+    /// Sets the most sensitive spans and arguments that will be included in the trace. Anything
+    /// more sensitive is handled according to `redaction`. By default, everything is included.
+    /// This allows the same instrumentation to produce both detailed traces for internal debugging
+    /// and traces that can be shared externally.
+    ///
+    /// This only affects threads that are processed after it's called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// trace
+    ///     .set_max_sensitivity(Sensitivity::Public, Redaction::Redact)
+    ///     .process_thread_data(&ThreadTraceData::take_current_thread());
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_max_sensitivity(&mut self, max: Sensitivity, redaction: Redaction) -> &mut Self {
+        self.max_sensitivity = max;
+        self.redaction = redaction;
+        self
+    }
+
+    /// Returns how data with the supplied sensitivity should be redacted, or `None` if it should be
+    /// included as-is.
+    fn redaction_for(&self, sensitivity: Sensitivity) -> Option<Redaction> {
+        (sensitivity > self.max_sensitivity).then_some(self.redaction)
+    }
+
+    /// Adds a track event for the start or end of a span. Returns whether an event was added, which
+    /// it won't be if the span was dropped due to its sensitivity. If `custom_tracks` is set, the
+    /// event is added to the track created by [TraceBuilder::create_track] that the span has been
+    /// moved to, if any.
+    fn emit_track_event(
+        &mut self,
+        call_site: &'static CallSite,
+        kind: schema::track_event::Type,
+        events: &mut std::slice::Iter<Event>,
+        thread: &ThreadTraceData,
+        thread_uuid: Uuid,
+        custom_tracks: bool,
+    ) -> bool {
+        let Some(timestamp) = next_timestamp(events) else {
+            panic!("Internal error: Timestamp must follow top-level events");
+        };
+
+        let source_info = &call_site.source;
+        // We need to read all the arguments, even if we end up dropping the span, so that we're
+        // positioned at the next event.
+        let dynamic_name = read_dynamic_name(source_info, kind, events, &thread.strings);
+        let args = read_span_args(source_info, kind, events, &thread.strings);
+        let custom_track = if custom_tracks {
+            let name = dynamic_name.as_deref().unwrap_or(source_info.name);
+            self.custom_track(source_info, name)
+        } else {
+            None
+        };
+
+        let mut track_event = schema::TrackEvent::default();
+        track_event.set_type(kind);
+        match self.redaction_for(source_info.sensitivity) {
+            None => {
+                let (mut name_id, source_location_id) = self.call_site_iids(call_site);
+                if let Some(name) = dynamic_name {
+                    name_id = self.name_id(Cow::Owned(name));
+                }
+                track_event.name_field = Some(schema::track_event::NameField::NameIid(name_id));
+                track_event.source_location_field = source_location_id
+                    .map(schema::track_event::SourceLocationField::SourceLocationIid);
+            }
+            Some(Redaction::Drop) => return false,
+            Some(Redaction::Redact) => {
+                let name_id = self.name_id(Cow::Borrowed(REDACTED));
+                track_event.name_field = Some(schema::track_event::NameField::NameIid(name_id));
+            }
+        }
+        let track_uuid = if let Some(track) = custom_track {
+            Uuid(track.uuid)
+        } else if source_info.process_track {
+            self.process_uuid(thread.pid)
+        } else {
+            thread_uuid
+        };
+        track_event.track_uuid = Some(track_uuid.0);
+        if kind != schema::track_event::Type::SliceEnd
+            && let Some(category) = source_info.category
+        {
+            track_event.category_iids.push(self.category_id(category));
+        }
+
+        for (arg_name, arg_sensitivity, links, value) in args {
+            let value = match self.redaction_for(arg_sensitivity) {
+                None => {
+                    if links.correlation_id.is_some() {
+                        track_event.correlation_id = links.correlation_id;
+                    }
+                    value
+                }
+                Some(Redaction::Drop) => continue,
+                Some(Redaction::Redact) => ArgValue::Value(
+                    schema::debug_annotation::Value::StringValue(REDACTED.to_owned()),
+                ),
+            };
+            track_event.flow_ids.extend(links.flow_ids);
+            let mut annotation = DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(arg_name),
+                )),
+                ..Default::default()
+            };
+            self.set_annotation_value(&mut annotation, value);
+            track_event.debug_annotations.push(annotation);
+        }
+
+        let packet = TracePacket {
+            timestamp: Some(self.timestamp_nanos(timestamp)),
+            timestamp_clock_id: Some(self.clock as u32),
+            data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+            ..Default::default()
+        };
+
+        self.add_packet(packet);
+        true
+    }
+
+    /// Adds a span named `name` to `track`, running from `start` to `end`. This allows timings that
+    /// were measured elsewhere, such as by GPU timestamp queries, kernel events or another profiler,
+    /// to be included in the trace alongside the spans recorded by this crate. Arguments are added by
+    /// `args` in the same way as for a [StructArg]. Spans on the same track should either be nested
+    /// or not overlap.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// let gpu = trace.create_track_group("GPU");
+    /// let start = perfetto_recorder::time();
+    /// let end = perfetto_recorder::time();
+    /// trace.record_span_at("draw", gpu, start, end, |args| {
+    ///     args.entry("pass", "shadows").entry("triangles", 12_000u64);
+    /// });
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn record_span_at(
+        &mut self,
+        name: &str,
+        track: TrackHandle,
+        start: Instant,
+        end: Instant,
+        args: impl FnOnce(&mut StructEntries),
+    ) -> &mut Self {
+        if self.noop {
+            return self;
+        }
+        let (events, strings) = capture_events(|| StructArg(args).record_arg());
+        let mut links = ArgLinks::default();
+        let ArgValue::Dict(entries) = read_structured_arg(&mut events.iter(), &strings, &mut links)
+        else {
+            panic!("Internal error: Struct argument must be a dictionary");
+        };
+
+        let name_id = self.name_id(Cow::Owned(name.to_owned()));
+        let mut begin = schema::TrackEvent {
+            track_uuid: Some(track.uuid),
+            name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+            flow_ids: links.flow_ids,
+            correlation_id: links.correlation_id,
+            ..Default::default()
+        };
+        begin.set_type(schema::track_event::Type::SliceBegin);
+        for (arg_name, value) in entries {
+            let mut annotation = DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(Cow::Owned(arg_name)),
+                )),
+                ..Default::default()
+            };
+            self.set_annotation_value(&mut annotation, value);
+            begin.debug_annotations.push(annotation);
+        }
+        let mut end_event = schema::TrackEvent {
+            track_uuid: Some(track.uuid),
+            ..Default::default()
+        };
+        end_event.set_type(schema::track_event::Type::SliceEnd);
+
+        for (timestamp, track_event) in [(start, begin), (end, end_event)] {
+            self.add_packet(TracePacket {
+                timestamp: Some(self.clock_nanos(timestamp)),
+                timestamp_clock_id: Some(self.clock as u32),
+                data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+                ..Default::default()
+            });
+        }
+        self
+    }
+
+    /// Adds annotations recorded by [SpanGuard::record_all] or [SpanGuard::record_arg] to the begin
+    /// or end event of a span from `call_site`, which is in the packet at `index`.
+    fn add_annotations(
+        &mut self,
+        index: usize,
+        call_site: &'static CallSite,
+        annotations: Annotations,
+    ) {
+        let redaction = self.redaction_for(call_site.source.sensitivity);
+        let annotations: Vec<DebugAnnotation> = annotations
+            .into_iter()
+            .map(|(name, value)| DebugAnnotation {
+                name_field: Some(schema::debug_annotation::NameField::NameIid(
+                    self.debug_annotation_name_id(Cow::Owned(name)),
+                )),
+                value: Some(match redaction {
+                    None => value,
+                    Some(_) => schema::debug_annotation::Value::StringValue(REDACTED.to_owned()),
+                }),
+                ..Default::default()
+            })
+            .collect();
+        if let Some(schema::trace_packet::Data::TrackEvent(track_event)) =
+            &mut self.trace.packet[index].data
+        {
+            track_event.debug_annotations.extend(annotations);
+        }
+        self.attach_pending_interned(index);
+    }
+
+    /// Links the track event in the packet at `index` to the flow with the supplied ID.
+    fn add_flow_id(&mut self, index: usize, flow_id: u64) {
+        if let Some(schema::trace_packet::Data::TrackEvent(track_event)) =
+            &mut self.trace.packet[index].data
+        {
+            track_event.flow_ids.push(flow_id);
+        }
+    }
+
+    fn emit_counter_event(
+        &mut self,
+        uuid: u64,
+        events: &mut std::slice::Iter<Event>,
+        counter_value_field: schema::track_event::CounterValueField,
+    ) {
+        let Some(timestamp) = next_timestamp(events) else {
+            panic!("Internal error: Counter event must be followed by Timestamp");
+        };
+
+        let timestamp = self.timestamp_nanos(timestamp);
+        self.emit_counter_value(uuid, timestamp, counter_value_field);
+    }
+
+    fn emit_counter_value(
+        &mut self,
+        uuid: u64,
+        timestamp: u64,
+        counter_value_field: schema::track_event::CounterValueField,
+    ) {
+        let packet = TracePacket {
+            timestamp: Some(timestamp),
+            timestamp_clock_id: Some(self.clock as u32),
+            data: Some(schema::trace_packet::Data::TrackEvent(schema::TrackEvent {
+                track_uuid: Some(uuid),
+                r#type: Some(schema::track_event::Type::Counter as i32),
+                counter_value_field: Some(counter_value_field),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+
+        self.add_packet(packet);
+    }
+
+    fn thread_uuid(&mut self, thread: &ThreadTraceData) -> Uuid {
+        self.thread_uuid_for(thread.pid, thread.tid, thread.thread_name.as_deref())
+    }
+
+    fn thread_uuid_for(&mut self, pid: os::Pid, tid: os::Pid, thread_name: Option<&str>) -> Uuid {
+        if let Some(uuid) = self.thread_uuids.get(&tid) {
+            return *uuid;
+        }
+
+        let uuid = Uuid::new();
+        let sibling_order_rank = if self.group_threads {
+            // The ranks only take effect if the process track asks for explicit ordering.
+            self.process_uuid(pid);
+            Some(self.thread_group_rank(thread_name))
+        } else {
+            None
+        };
+
+        self.add_packet(TracePacket {
+            data: Some(schema::trace_packet::Data::TrackDescriptor(
+                TrackDescriptor {
+                    uuid: Some(uuid.0),
+                    thread: Some(ThreadDescriptor {
+                        pid: Some(pid.as_i32()),
+                        tid: Some(tid.as_i32()),
+                        thread_name: thread_name.map(str::to_owned),
+                    }),
+                    sibling_order_rank,
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        });
+
+        self.thread_uuids.insert(tid, uuid);
+
+        uuid
+    }
+
+    /// Returns the sort index of the group that a thread named `thread_name` belongs to, allocating
+    /// one if this is the first thread in its group.
+    fn thread_group_rank(&mut self, thread_name: Option<&str>) -> i32 {
+        let name = thread_name.unwrap_or_default();
+        let group = self
+            .thread_group_patterns
+            .iter()
+            .find(|(pattern, _)| pattern_matches(pattern, name))
+            .map_or_else(|| thread_group_from_name(name), |(_, group)| group.as_str());
+        if let Some(rank) = self.thread_group_ranks.get(group) {
+            return *rank;
+        }
+        // Start at 1, so that tracks without a rank, such as the "Phases" track, sort first.
+        let rank = self.thread_group_ranks.len() as i32 + 1;
+        self.thread_group_ranks.insert(group.to_owned(), rank);
+        rank
+    }
+
+    /// Adds a copy of the track event at `index` to the "Phases" track of process `pid`.
+    fn copy_to_phases_track(&mut self, index: usize, pid: os::Pid) {
+        let track_uuid = match self.phase_tracks.get(&pid) {
+            Some(uuid) => *uuid,
+            None => {
+                let uuid = Uuid::new();
+                let parent_uuid = self.process_uuid(pid).0;
+                self.add_packet(TracePacket {
+                    data: Some(schema::trace_packet::Data::TrackDescriptor(
+                        TrackDescriptor {
+                            uuid: Some(uuid.0),
+                            parent_uuid: Some(parent_uuid),
+                            static_or_dynamic_name: Some(
+                                schema::track_descriptor::StaticOrDynamicName::Name(
+                                    "Phases".to_owned(),
+                                ),
+                            ),
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                });
+                self.phase_tracks.insert(pid, uuid);
+                uuid
+            }
+        };
+        let packet = &self.trace.packet[index];
+        let Some(schema::trace_packet::Data::TrackEvent(event)) = &packet.data else {
+            return;
+        };
+        self.add_packet(TracePacket {
+            timestamp: packet.timestamp,
+            timestamp_clock_id: packet.timestamp_clock_id,
+            // Flows and correlation IDs belong to the original event, which is the one that they
+            // should lead to.
+            data: Some(schema::trace_packet::Data::TrackEvent(schema::TrackEvent {
+                track_uuid: Some(track_uuid.0),
+                flow_ids: Vec::new(),
+                correlation_id: None,
+                ..event.clone()
+            })),
+            ..Default::default()
+        });
+    }
+
+    fn process_uuid(&mut self, pid: os::Pid) -> Uuid {
+        if let Some(uuid) = self.process_uuids.get(&pid) {
+            return *uuid;
+        }
+
+        let uuid = Uuid::new();
+
+        self.add_packet(TracePacket {
+            data: Some(schema::trace_packet::Data::TrackDescriptor(
+                TrackDescriptor {
+                    uuid: Some(uuid.0),
+                    process: Some(schema::ProcessDescriptor {
+                        pid: Some(pid.as_i32()),
+                        ..Default::default()
+                    }),
+                    child_ordering: self
+                        .group_threads
+                        .then_some(schema::track_descriptor::ChildTracksOrdering::Explicit as i32),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        });
+
+        self.process_uuids.insert(pid, uuid);
+
+        uuid
+    }
+
+    /// Adds a packet that was constructed by the caller to the trace. This allows use of Perfetto
+    /// features that this crate doesn't otherwise support. The packet's sequence ID is overwritten
+    /// with that of this builder.
+    #[cfg(feature = "raw-schema")]
+    pub fn add_raw_packet(&mut self, packet: TracePacket) -> &mut Self {
+        self.add_packet(packet);
+        self
+    }
+
+    /// Creates a builder that starts with `packets`, e.g. from a trace that was written earlier and
+    /// decoded with [schema::Trace]. Unlike [TraceBuilder::add_raw_packet], the packets keep their
+    /// sequence IDs, so their interned data stays valid. Together with [TraceBuilder::into_trace],
+    /// this allows post-processing, such as filtering or rebasing timestamps, to be written as
+    /// transformations of the packets. The packets replace those that a new builder starts with, so
+    /// they should include their own clock snapshot, and recording doesn't need to be enabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let trace = TraceBuilder::new()?.into_trace();
+    /// // Shift everything a second later.
+    /// let packets = trace.packet.into_iter().map(|mut packet| {
+    ///     packet.timestamp = packet.timestamp.map(|timestamp| timestamp + 1_000_000_000);
+    ///     packet
+    /// });
+    /// let shifted = TraceBuilder::from_packets(packets)?;
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[cfg(feature = "raw-schema")]
+    pub fn from_packets(
+        packets: impl IntoIterator<Item = TracePacket>,
+    ) -> Result<TraceBuilder, Error> {
+        let mut builder = Self::build(&TraceBuilderOptions::new(), false);
+        // As for [TraceBuilder::import], the packets have their own clock snapshot, so they replace
+        // the packets that the builder started with.
+        builder.trace.packet = packets.into_iter().collect();
+        Ok(builder)
+    }
+
+    /// Returns the packets that have been added to the trace so far.
+    #[cfg(feature = "raw-schema")]
+    pub fn packets(&self) -> &[TracePacket] {
+        &self.trace.packet
+    }
+
+    /// Consumes the builder, returning the trace that [TraceBuilder::encode_to_vec] would encode,
+    /// apart from any footer.
+    #[cfg(feature = "raw-schema")]
+    pub fn into_trace(self) -> schema::Trace {
+        let packet_defaults = self.packet_defaults;
+        let mut trace = match self.finished_trace() {
+            Cow::Borrowed(_) => self.trace,
+            Cow::Owned(trace) => trace,
+        };
+        if packet_defaults {
+            packet_defaults::apply_packet_defaults(&mut trace.packet);
+        }
+        trace
+    }
+
+    /// Attaches any pending interned data to the packet at `index`, which has already been added.
+    /// Used when that packet is changed to refer to data that's only just been interned, since
+    /// otherwise it would be defined by the next packet, or not at all if there isn't one.
+    fn attach_pending_interned(&mut self, index: usize) {
+        if let Some(pending) = self.pending_interned.take() {
+            merge_interned(&mut self.trace.packet[index].interned_data, pending);
+        }
+    }
+
+    fn add_packet(&mut self, mut packet: TracePacket) {
+        if let Some(pending) = self.pending_interned.take() {
+            merge_interned(&mut packet.interned_data, pending);
+        }
+        if let Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) = &packet.data {
+            self.descriptors.push(descriptor.clone());
+        }
+        packet.optional_trusted_packet_sequence_id = Some(
+            schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                self.sequence_id,
+            ),
+        );
+        packet.machine_id = self.machine_id;
+        self.trace.packet.push(packet);
+    }
+
+    /// Converts `timestamp` to nanoseconds in the clock that we're using.
+    fn clock_nanos(&self, timestamp: Instant) -> u64 {
+        self.unix_nanos_to_clock(self.get_unix_nanos(timestamp))
+    }
+
+    fn unix_nanos_to_clock(&self, unix_nanos: u64) -> u64 {
+        unix_nanos.wrapping_add_signed(self.clock_offsets[&self.clock])
+    }
+
+    fn timestamp_nanos(&self, timestamp: Timestamp) -> u64 {
+        match timestamp {
+            Timestamp::Instant(instant) => self.clock_nanos(instant),
+            Timestamp::UnixNanos(nanos) => self.unix_nanos_to_clock(nanos),
+            Timestamp::Simulated(nanos) => nanos,
+        }
+    }
+
+    fn get_unix_nanos(&self, timestamp: Instant) -> u64 {
+        self.time_anchor.unix_nanos(timestamp)
+    }
+}
+
+/// Adds the interned data from `from` into `into`.
+fn merge_interned(into: &mut Option<schema::InternedData>, from: schema::InternedData) {
+    match into {
+        Some(interned) => {
+            interned.event_categories.extend(from.event_categories);
+            interned.event_names.extend(from.event_names);
+            interned
+                .debug_annotation_names
+                .extend(from.debug_annotation_names);
+            interned.source_locations.extend(from.source_locations);
+            interned.log_message_body.extend(from.log_message_body);
+        }
+        None => *into = Some(from),
+    }
+}
+
+/// Returns whether `name` matches `pattern`, which either matches a name exactly or, if it ends in
+/// `*`, matches names that start with the rest of it.
+pub(crate) fn pattern_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+/// Infers the group of a thread from its name by removing any numeric suffix, so that
+/// `rayon-worker-12` and `rayon-worker-3` are both in group `rayon-worker`. Threads that share a
+/// name, such as Tokio's `tokio-runtime-worker` threads, are in the same group.
+fn thread_group_from_name(name: &str) -> &str {
+    let stem = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if stem.len() == name.len() {
+        return name;
+    }
+    match stem.trim_end_matches(['-', '_', '.', '#', ' ']) {
+        "" => name,
+        stem => stem,
+    }
+}
+
+/// A point in time, either as recorded in this process or as read from serialized trace data.
+#[derive(Debug, Clone, Copy)]
+enum Timestamp {
+    Instant(Instant),
+    UnixNanos(u64),
+    /// A time provided by the caller, already in the trace's clock.
+    Simulated(u64),
+}
+
+/// Reads the timestamp that follows a top-level event.
+fn next_timestamp(events: &mut std::slice::Iter<'_, Event>) -> Option<Timestamp> {
+    match events.next()? {
+        Event::Timestamp(instant) => Some(Timestamp::Instant(*instant)),
+        Event::UnixTimestamp(nanos) => Some(Timestamp::UnixNanos(*nanos)),
+        Event::SimulatedTimestamp(nanos) => Some(Timestamp::Simulated(*nanos)),
+        _ => None,
+    }
+}
+
+/// Converts [Instant]s to nanoseconds since the unix epoch.
+struct TimeAnchor {
+    #[cfg(feature = "fastant")]
+    anchor: fastant::Anchor,
+}
+
+impl TimeAnchor {
+    fn new() -> Self {
+        Self {
+            #[cfg(feature = "fastant")]
+            anchor: fastant::Anchor::new(),
+        }
+    }
+
+    #[cfg(feature = "fastant")]
+    fn unix_nanos(&self, timestamp: Instant) -> u64 {
+        timestamp.as_unix_nanos(&self.anchor)
+    }
+
+    #[cfg(not(feature = "fastant"))]
+    fn unix_nanos(&self, timestamp: Instant) -> u64 {
+        timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+}
+
+/// Reads the arguments that follow the start or end of a span, returning the name, sensitivity,
+/// linked flows and value of each.
+fn read_span_args(
+    source_info: &SourceInfo,
+    kind: schema::track_event::Type,
+    events: &mut std::slice::Iter<Event>,
+    strings: &str,
+) -> Vec<(Cow<'static, str>, Sensitivity, ArgLinks, ArgValue)> {
+    let (arg_names, arg_sensitivities) = if kind != schema::track_event::Type::SliceEnd {
+        (source_info.arg_names, source_info.arg_sensitivities)
+    } else {
+        (source_info.end_arg_names, source_info.end_arg_sensitivities)
+    };
+    arg_names
+        .iter()
+        .zip(arg_sensitivities)
+        .map(|(arg_name, arg_sensitivity)| {
+            let mut links = ArgLinks::default();
+            let arg_name = take_unit(events, Cow::Borrowed(*arg_name));
+            let value = read_structured_arg(events, strings, &mut links);
+            (
+                arg_name,
+                (*arg_sensitivity).max(source_info.sensitivity),
+                links,
+                value,
+            )
+        })
+        .collect()
+}
+
+/// Reads past the start or end of a span without adding it to the trace.
+fn skip_span_event(
+    source_info: &SourceInfo,
+    kind: schema::track_event::Type,
+    events: &mut std::slice::Iter<Event>,
+    strings: &str,
+) {
+    if next_timestamp(events).is_none() {
+        panic!("Internal error: Timestamp must follow top-level events");
+    }
+    read_dynamic_name(source_info, kind, events, strings);
+    read_span_args(source_info, kind, events, strings);
+}
+
+/// Reads the name of a span from [start_span_dynamic], which is recorded before its arguments.
+/// Returns `None` for other spans and for the ends of spans.
+fn read_dynamic_name(
+    source_info: &SourceInfo,
+    kind: schema::track_event::Type,
+    events: &mut std::slice::Iter<Event>,
+    strings: &str,
+) -> Option<String> {
+    if kind != schema::track_event::Type::SliceBegin || !source_info.dynamic_name {
+        return None;
+    }
+    let schema::debug_annotation::Value::StringValue(name) = convert_next_arg(events, strings)
+    else {
+        panic!("Internal error: Dynamic span name must be a string");
+    };
+    Some(name)
+}
+
+/// Annotations that have been read from a thread's events, with their names.
+type Annotations = Vec<(String, schema::debug_annotation::Value)>;
+
+/// How a span marked with `@collapse_recursion` has directly recursed.
+#[derive(Default)]
+struct Recursion {
+    /// How many recursive calls are currently open within the span.
+    depth: u32,
+    /// The maximum value that `depth` has reached.
+    max_depth: u32,
+    /// The number of recursive calls, not including the span itself.
+    calls: u32,
+}
+
+/// An argument that has been read from a thread's events, but not yet added to a packet.
+enum ArgValue {
+    Value(schema::debug_annotation::Value),
+
+    /// The entries of a [DynamicArgs] or [StructArg]. The names haven't been interned yet, since
+    /// the argument might end up being dropped.
+    Dict(Vec<(String, ArgValue)>),
+
+    /// The values of an [ArrayArg].
+    Array(Vec<ArgValue>),
+}
+
+/// What an argument links its span to, in addition to the argument's value.
+#[derive(Default)]
+struct ArgLinks {
+    /// The IDs of the flows that the argument links the span to.
+    flow_ids: Vec<u64>,
+    /// The value of a [CorrelationId] argument.
+    correlation_id: Option<u64>,
+}
+
+/// Reads the next argument from `events`, including the contents of dictionaries and arrays,
+/// adding anything that it links the span to to `links`.
+fn read_structured_arg(
+    events: &mut std::slice::Iter<'_, Event>,
+    strings: &str,
+    links: &mut ArgLinks,
+) -> ArgValue {
+    match events.as_slice().first() {
+        Some(Event::DictStart) => {
+            events.next();
+            let mut entries = Vec::new();
+            while !matches!(events.as_slice().first(), Some(Event::DictEnd)) {
+                let schema::debug_annotation::Value::StringValue(key) =
+                    convert_next_arg(events, strings)
+                else {
+                    panic!("Internal error: Dynamic argument name must be a string");
+                };
+                let key = take_unit(events, Cow::Owned(key)).into_owned();
+                entries.push((key, read_structured_arg(events, strings, links)));
+            }
+            events.next();
+            ArgValue::Dict(entries)
+        }
+        Some(Event::ArrayStart) => {
+            events.next();
+            let mut values = Vec::new();
+            while !matches!(events.as_slice().first(), Some(Event::DictEnd)) {
+                values.push(read_structured_arg(events, strings, links));
+            }
+            events.next();
+            ArgValue::Array(values)
+        }
+        _ => ArgValue::Value(read_arg(events, strings, links)),
+    }
+}
+
+/// Reads the next argument from `events`, adding anything that it links the span to to `links`.
+fn read_arg(
+    events: &mut std::slice::Iter<'_, Event>,
+    strings: &str,
+    links: &mut ArgLinks,
+) -> schema::debug_annotation::Value {
+    match events.as_slice().first() {
+        Some(Event::Flow(id)) => {
+            events.next();
+            links.flow_ids.push(*id);
+            schema::debug_annotation::Value::UintValue(*id)
+        }
+        Some(Event::NamedFlow(id)) => {
+            events.next();
+            links.flow_ids.push(*id);
+            convert_next_arg(events, strings)
+        }
+        Some(Event::CorrelationId(id)) => {
+            events.next();
+            links.correlation_id = Some(*id);
+            schema::debug_annotation::Value::UintValue(*id)
+        }
+        _ => convert_next_arg(events, strings),
+    }
+}
+
+/// Reads the name-value pairs that follow an [Event::Annotations], up to and including the
+/// terminating [Event::DictEnd].
+fn read_annotations(events: &mut std::slice::Iter<'_, Event>, strings: &str) -> Annotations {
+    let mut annotations = Vec::new();
+    while !matches!(events.as_slice().first(), Some(Event::DictEnd)) {
+        let schema::debug_annotation::Value::StringValue(name) = convert_next_arg(events, strings)
+        else {
+            panic!("Internal error: Annotation name must be a string");
+        };
+        let name = take_unit(events, Cow::Owned(name)).into_owned();
+        annotations.push((name, convert_next_arg(events, strings)));
+    }
+    events.next();
+    annotations
+}
+
+/// If the next argument in `events` has a unit, skips past it and returns the name of an argument
+/// called `name` in that unit. Otherwise returns `name`.
+fn take_unit(
+    events: &mut std::slice::Iter<'_, Event>,
+    name: Cow<'static, str>,
+) -> Cow<'static, str> {
+    match events.as_slice().first() {
+        Some(Event::Unit(unit)) => {
+            events.next();
+            Cow::Owned(unit.annotation_name(&name))
+        }
+        _ => name,
+    }
+}
+
+/// Reads the next argument from `events`. `strings` is the string arena of the thread that
+/// recorded the events.
+fn convert_next_arg(
+    events: &mut std::slice::Iter<'_, Event>,
+    strings: &str,
+) -> schema::debug_annotation::Value {
+    let event = events.next().expect("Internal error: missing arg value");
+
+    use schema::debug_annotation::Value;
+    match event {
+        Event::StartSpan(_) => panic!("Internal error: Unexpected StartSpan"),
+        Event::EndSpan(_) => panic!("Internal error: Unexpected EndSpan"),
+        Event::Timestamp(_) | Event::UnixTimestamp(_) | Event::SimulatedTimestamp(_) => {
+            panic!("Internal error: Unexpected Timestamp")
+        }
+        Event::CounterI64 { .. } => panic!("Internal error: Unexpected CounterI64"),
+        Event::CounterF64 { .. } => panic!("Internal error: Unexpected CounterF64"),
+        Event::Flow(_) | Event::NamedFlow(_) => panic!("Internal error: Unexpected flow"),
+        Event::CorrelationId(_) => panic!("Internal error: Unexpected correlation ID"),
+        Event::DictStart | Event::ArrayStart | Event::DictEnd => {
+            panic!("Internal error: Unexpected dynamic arguments")
+        }
+        Event::SpawnedThread(_) | Event::SpawnedBy(_) => {
+            panic!("Internal error: Unexpected spawn")
+        }
+        Event::Annotations(_) | Event::EndAnnotations(_) | Event::RootAnnotations(_) => {
+            panic!("Internal error: Unexpected annotations")
+        }
+        Event::AsyncStart { .. } | Event::AsyncEnd { .. } => {
+            panic!("Internal error: Unexpected async span")
+        }
+        Event::FailedAssertion(_) => panic!("Internal error: Unexpected failed assertion"),
+        Event::Log { .. } => panic!("Internal error: Unexpected log message"),
+        // Where the argument has a name, the unit has already been added to it by [take_unit].
+        Event::Unit(_) => match convert_next_arg(events, strings) {
+            value @ (Value::UintValue(_) | Value::IntValue(_) | Value::DoubleValue(_)) => value,
+            _ => panic!("Internal error: Unit must be followed by a number"),
+        },
+        Event::Bool(value) => Value::BoolValue(*value),
+        Event::U64(value) => Value::UintValue(*value),
+        Event::I64(value) => Value::IntValue(*value),
+        Event::F64(value) => Value::DoubleValue(*value),
+        Event::String(value) => Value::StringValue(value.clone()),
+        Event::Str { offset, len } => {
+            Value::StringValue(strings[*offset..*offset + len].to_owned())
+        }
+    }
+}
+
+impl Uuid {
+    fn new() -> Uuid {
+        Uuid(unique_id())
+    }
+}
+
+/// A random value from which this process's unique IDs are allocated. Random rather than derived
+/// from the process ID, since processes in separate containers or on separate machines often have
+/// the same process ID, yet their traces may still be merged.
+static UNIQUE_ID_BASE: OnceLock<u64> = OnceLock::new();
+
+/// How many IDs [unique_id] has returned in this process. 64 bits, so that it doesn't wrap even in
+/// long-running processes.
+static NEXT_UNIQUE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns an ID that differs from all others returned by this function in this process and is
+/// very unlikely to be returned in any other process, so that traces from multiple builders,
+/// processes and machines can be merged without track UUIDs colliding. Never returns zero.
+fn unique_id() -> u64 {
+    let base = *UNIQUE_ID_BASE.get_or_init(|| RNG.with_borrow_mut(|rng| rng.next_u64()));
+    loop {
+        let id = base.wrapping_add(NEXT_UNIQUE_ID.fetch_add(1, Ordering::Relaxed));
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// A random value from which this process's packet sequence IDs are allocated. Random, so that
+/// sequence IDs from different processes are unlikely to collide.
+static SEQUENCE_ID_BASE: OnceLock<u32> = OnceLock::new();
+
+static NEXT_SEQUENCE_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Returns a packet sequence ID that differs from those of all other builders in this process.
+fn next_sequence_id() -> u32 {
+    // Leave the top bit clear so that we don't wrap around to the invalid sequence ID 0.
+    let base = *SEQUENCE_ID_BASE.get_or_init(|| RNG.with_borrow_mut(|rng| rng.next_u32()) >> 1 | 1);
+    base.wrapping_add(NEXT_SEQUENCE_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+impl std::error::Error for TracingDisabledAtBuildTime {}
+
+impl std::fmt::Display for TracingDisabledAtBuildTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The \"enable\" feature of perfetto-recorder is not active"
+        )
+    }
+}
+
+impl std::error::Error for ClockUnavailable {}
+
+impl std::fmt::Display for ClockUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The clock {:?} is not available on this platform",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TracingDisabled {}
+
+impl std::fmt::Display for TracingDisabled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The `perfetto_recorder::start()` was not called")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Uuid(u64);
+
+/// Units for counter tracks.
+#[derive(Debug, Clone)]
+pub enum CounterUnit {
+    /// Unspecified unit.
+    Unspecified,
+    /// Time in nanoseconds.
+    TimeNs,
+    /// Generic count.
+    Count,
+    /// Size in bytes.
+    SizeBytes,
+    /// Custom unit with a name (e.g., "%", "fps", etc.).
+    Custom(String),
+}
+
+impl CounterUnit {
+    fn to_proto_unit(&self) -> Option<i32> {
+        Some(match self {
+            CounterUnit::Unspecified => schema::counter_descriptor::Unit::Unspecified,
+            CounterUnit::TimeNs => schema::counter_descriptor::Unit::TimeNs,
+            CounterUnit::Count => schema::counter_descriptor::Unit::Count,
+            CounterUnit::SizeBytes => schema::counter_descriptor::Unit::SizeBytes,
+            CounterUnit::Custom(_) => schema::counter_descriptor::Unit::Unspecified,
+        } as i32)
+    }
+
+    fn to_proto_unit_name(&self) -> Option<String> {
+        if let Self::Custom(name) = self {
+            Some(name.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// A handle to a track that groups other tracks. Created by [TraceBuilder::create_track_group] or
+/// [TraceBuilder::create_track]. Spans can also be added to the track itself, e.g. with
+/// [TraceBuilder::record_span_at].
+#[derive(Debug, Clone, Copy)]
+pub struct TrackHandle {
+    uuid: u64,
+}
+
+/// A handle to a counter track that can be used to record counter values. Values are recorded in
+/// the current thread's trace data, in the same way as spans, so recording doesn't need access to
+/// the [TraceBuilder] that created the track. The handle can be freely copied and shared between
+/// threads, so no locking is needed to record values from several threads. As with spans, the
+/// trace data of each thread that records values needs to be processed.
+#[derive(Debug, Clone, Copy)]
+pub struct CounterTrack {
+    uuid: u64,
+}
+
+impl CounterTrack {
+    /// Records an integer counter value at a specific timestamp.
+    ///
+    /// This function stores the counter value in thread-local storage. The value will be
+    /// converted to the trace format when `TraceBuilder::process_thread_data()` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The timestamp for this value
+    /// * `value` - The counter value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// start()?;
+    /// let mut trace = TraceBuilder::new()?;
+    /// let counter = trace.create_counter_track("Memory", CounterUnit::SizeBytes, 1, false);
+    /// counter.record_i64(perfetto_recorder::time(), 1024);
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[inline(always)]
+    pub fn record_i64(&self, timestamp: Instant, value: i64) {
+        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        record_event(Event::CounterI64 {
+            uuid: self.uuid,
+            value,
+        });
+        record_event(Event::Timestamp(timestamp));
+    }
+
+    /// Records a floating-point counter value at a specific timestamp.
+    ///
+    /// This function stores the counter value in thread-local storage. The value will be
+    /// converted to the trace format when `TraceBuilder::process_thread_data()` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The timestamp for this value
+    /// * `value` - The counter value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// start()?;
+    /// let mut trace = TraceBuilder::new()?;
+    /// let counter = trace.create_counter_track("CPU %", CounterUnit::Custom("%".to_string()), 1, false);
+    /// counter.record_f64(perfetto_recorder::time(), 42.5);
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[inline(always)]
+    pub fn record_f64(&self, timestamp: Instant, value: f64) {
+        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        record_event(Event::CounterF64 {
+            uuid: self.uuid,
+            value,
+        });
+        record_event(Event::Timestamp(timestamp));
+    }
+
+    /// Records multiple integer counter values, each with its own timestamp. This is equivalent to
+    /// calling [CounterTrack::record_i64] for each sample, but only accesses the thread-local
+    /// recording once.
+    pub fn record_many_i64(&self, samples: &[(Instant, i64)]) {
+        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        with_events(|events| {
+            events.reserve(samples.len() * EVENTS_PER_COUNTER);
+            for (timestamp, value) in samples {
+                events.push(Event::CounterI64 {
+                    uuid: self.uuid,
+                    value: *value,
+                });
+                events.push(Event::Timestamp(*timestamp));
+            }
+        });
+    }
+
+    /// Records multiple floating-point counter values, each with its own timestamp. This is
+    /// equivalent to calling [CounterTrack::record_f64] for each sample, but only accesses the
+    /// thread-local recording once.
+    pub fn record_many_f64(&self, samples: &[(Instant, f64)]) {
+        if !RUNTIME_ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        with_events(|events| {
+            events.reserve(samples.len() * EVENTS_PER_COUNTER);
+            for (timestamp, value) in samples {
+                events.push(Event::CounterF64 {
+                    uuid: self.uuid,
+                    value: *value,
+                });
+                events.push(Event::Timestamp(*timestamp));
+            }
+        });
+    }
+}
+
+/// A counter whose value is the number of currently live [GaugeGuard]s that were created from it.
+/// Useful for things like "in-flight requests" or "active workers".
+///
+/// The gauge can be shared between threads. Each change in value is recorded on the thread that
+/// made the change, so as with other counter values, you need to process the trace data from all
+/// threads that use the gauge.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if perfetto_recorder::is_enabled() {
+/// let mut trace = TraceBuilder::new()?;
+/// let workers = Gauge::new(trace.create_counter_track("Active workers", CounterUnit::Count, 1, false));
+/// {
+///     let _guard = GaugeGuard::enter(&workers);
+///     // Do some work.
+/// }
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct Gauge {
+    track: CounterTrack,
+    #[cfg(feature = "enable")]
+    value: AtomicI64,
+}
+
+impl Gauge {
+    /// Creates a gauge that records its value to `track`. The initial value is zero.
+    pub fn new(track: CounterTrack) -> Self {
+        Self {
+            track,
+            #[cfg(feature = "enable")]
+            value: AtomicI64::new(0),
+        }
+    }
+
+    /// Returns the counter track to which this gauge records.
+    pub fn track(&self) -> CounterTrack {
+        self.track
+    }
+
+    #[cfg(feature = "enable")]
+    fn add(&self, delta: i64) {
+        let value = self.value.fetch_add(delta, Ordering::Relaxed) + delta;
+        if is_enabled() {
+            record_event(Event::CounterI64 {
+                uuid: self.track.uuid,
+                value,
+            });
+            record_event(Event::Timestamp(time()));
+        }
+    }
+}
+
+/// A guard that increments a [Gauge] when created and decrements it when dropped.
+#[must_use]
+pub struct GaugeGuard<'a> {
+    #[cfg(feature = "enable")]
+    gauge: &'a Gauge,
+    #[cfg(not(feature = "enable"))]
+    _gauge: std::marker::PhantomData<&'a Gauge>,
+}
+
+impl<'a> GaugeGuard<'a> {
+    /// Increments `gauge`, returning a guard that will decrement it again when dropped.
+    #[allow(unused_variables)]
+    pub fn enter(gauge: &'a Gauge) -> Self {
+        #[cfg(feature = "enable")]
+        {
+            gauge.add(1);
+            Self { gauge }
+        }
+        #[cfg(not(feature = "enable"))]
+        {
+            Self {
+                _gauge: std::marker::PhantomData,
+            }
+        }
+    }
+}
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "enable")]
+        self.gauge.add(-1);
+    }
+}
+
+/// A process-wide counter that can be declared as a static and updated from any thread without
+/// coordination. Updates are relaxed atomic operations and don't record any events. Instead, the
+/// values of all counters that have been used are sampled into counter tracks by
+/// [TraceBuilder::flush_static_counters], or periodically by a [Sampler].
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::StaticCounter;
+///
+/// static CACHE_HITS: StaticCounter = StaticCounter::new("cache hits");
+///
+/// CACHE_HITS.increment();
+/// ```
+#[derive(Debug)]
+pub struct StaticCounter {
+    name: &'static str,
+    value: AtomicI64,
+    registered: AtomicBool,
+}
+
+/// All static counters that have been used at least once.
+static STATIC_COUNTERS: Mutex<Vec<&'static StaticCounter>> = Mutex::new(Vec::new());
+
+impl StaticCounter {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicI64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Adds `delta` to the counter.
+    #[inline(always)]
+    pub fn add(&'static self, delta: i64) {
+        if !self.registered.load(Ordering::Relaxed) {
+            self.register();
+        }
+        self.value.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Adds one to the counter.
+    #[inline(always)]
+    pub fn increment(&'static self) {
+        self.add(1);
+    }
+
+    /// Sets the counter to `value`.
+    #[inline(always)]
+    pub fn set(&'static self, value: i64) {
+        if !self.registered.load(Ordering::Relaxed) {
+            self.register();
+        }
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    pub fn value(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[cold]
+    fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            STATIC_COUNTERS.lock().unwrap().push(self);
+        }
+    }
+}
+
+/// The counter track of a [counter] call site, which is allocated the first time it's used.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct CounterSite {
+    name: &'static str,
+    /// The UUID of the track, or zero if it hasn't been allocated yet.
+    uuid: AtomicU64,
+}
+
+/// All counter sites that have been used at least once, in the order that they were first used.
+static COUNTER_SITES: Mutex<Vec<&'static CounterSite>> = Mutex::new(Vec::new());
+
+impl CounterSite {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            uuid: AtomicU64::new(0),
+        }
+    }
+
+    #[inline(always)]
+    pub fn track(&'static self) -> CounterTrack {
+        let uuid = self.uuid.load(Ordering::Relaxed);
+        if uuid != 0 {
+            return CounterTrack { uuid };
+        }
+        self.register()
+    }
+
+    #[cold]
+    fn register(&'static self) -> CounterTrack {
+        let mut sites = COUNTER_SITES.lock().unwrap();
+        if self.uuid.load(Ordering::Relaxed) == 0 {
+            self.uuid.store(Uuid::new().0, Ordering::Relaxed);
+            sites.push(self);
+        }
+        CounterTrack {
+            uuid: self.uuid.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Types that can be recorded by the [counter] macro.
+#[doc(hidden)]
+pub trait CounterValue {
+    fn record_counter(self, track: CounterTrack);
+}
+
+macro_rules! impl_integer_counter_value {
+    ($($ty:ty),*) => {
+        $(impl CounterValue for $ty {
+            fn record_counter(self, track: CounterTrack) {
+                // Values too large for the trace's counters are clamped rather than wrapping.
+                track.record_i64(time(), i64::try_from(self).unwrap_or(i64::MAX));
+            }
+        })*
+    };
+}
+
+impl_integer_counter_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl CounterValue for f64 {
+    fn record_counter(self, track: CounterTrack) {
+        track.record_f64(time(), self);
+    }
+}
+
+impl CounterValue for f32 {
+    fn record_counter(self, track: CounterTrack) {
+        track.record_f64(time(), self.into());
+    }
+}
+
+impl TraceBuilder {
+    /// Creates a new counter track.
+    ///
+    /// Counter tracks display time-series data like CPU usage, memory usage, etc.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the counter track
+    /// * `unit` - The unit for the counter values
+    /// * `unit_multiplier` - Multiplier for the values (e.g., 1024*1024 to convert bytes to MB)
+    /// * `is_incremental` - Whether values are incremental (delta) or absolute
+    ///
+    /// Incremental values are summed to give the value shown, starting from zero at the beginning
+    /// of the trace. Perfetto only supports this when all the values are in a single trace from one
+    /// builder. If the counter didn't start at zero, use
+    /// [TraceBuilder::record_initial_counter_value] to avoid showing a ramp up from zero.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// let cpu_counter = trace.create_counter_track(
+    ///     "CPU Usage",
+    ///     CounterUnit::Custom("%".to_string()),
+    ///     1,
+    ///     false,
+    /// );
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn create_counter_track(
+        &mut self,
+        name: impl Into<String>,
+        unit: CounterUnit,
+        unit_multiplier: i64,
+        is_incremental: bool,
+    ) -> CounterTrack {
+        self.add_counter_track(
+            Uuid::new(),
+            None,
+            name.into(),
+            unit,
+            unit_multiplier,
+            is_incremental,
+        )
+    }
+
+    /// Creates a new counter track that is nested under `parent`. See
+    /// [TraceBuilder::create_counter_track] for a description of the other arguments.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// let shards = trace.create_track_group("Shard queue lengths");
+    /// for i in 0..16 {
+    ///     let shard =
+    ///         trace.create_counter_track_in(shards, format!("Shard {i}"), CounterUnit::Count, 1, false);
+    ///     shard.record_i64(perfetto_recorder::time(), 0);
+    /// }
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn create_counter_track_in(
+        &mut self,
+        parent: TrackHandle,
+        name: impl Into<String>,
+        unit: CounterUnit,
+        unit_multiplier: i64,
+        is_incremental: bool,
+    ) -> CounterTrack {
+        self.add_counter_track(
+            Uuid::new(),
+            Some(parent.uuid),
+            name.into(),
+            unit,
+            unit_multiplier,
+            is_incremental,
+        )
+    }
+
+    /// Creates a track named `name` under which other tracks can be grouped.
+    pub fn create_track_group(&mut self, name: impl Into<String>) -> TrackHandle {
+        self.add_track_group(None, name.into())
+    }
+
+    /// Creates a track group that is nested under `parent`.
+    pub fn create_track_group_in(
+        &mut self,
+        parent: TrackHandle,
+        name: impl Into<String>,
+    ) -> TrackHandle {
+        self.add_track_group(Some(parent.uuid), name.into())
+    }
+
+    /// Returns the track named `name`, creating it if it doesn't exist yet. Spans can be shown on the
+    /// track rather than on the track of the thread that recorded them, either by passing
+    /// `@track = "name"` when they're started, or by calling [TraceBuilder::route_spans]. This keeps
+    /// activities such as garbage collection or network requests together and out of the way of
+    /// the thread's other spans. Tracks that are only named by spans are created when the first such
+    /// span is added. Spans on the same track must not overlap unless they're nested, even if they
+    /// were recorded by different threads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if start().is_ok() {
+    /// let mut trace = TraceBuilder::new()?;
+    /// let network = trace.create_track("Network");
+    /// trace.route_spans("fetch*", network);
+    /// {
+    ///     scope!("fetch index");
+    ///     scope!(@track = "GC" "mark");
+    /// }
+    /// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn create_track(&mut self, name: &str) -> TrackHandle {
+        if let Some(track) = self.named_tracks.get(name) {
+            return *track;
+        }
+        let track = self.add_track_group(None, name.to_owned());
+        self.named_tracks.insert(name.to_owned(), track);
+        track
+    }
+
+    /// Moves spans whose names match `pattern` onto `track` as they're added to the trace. `pattern`
+    /// either matches a name exactly or, if it ends in `*`, matches names that start with the rest of
+    /// it. Earlier patterns take precedence, and spans started with a `@track` option aren't moved.
+    pub fn route_spans(&mut self, pattern: &str, track: TrackHandle) -> &mut Self {
+        self.span_routes.push((pattern.to_owned(), track));
+        self
+    }
+
+    /// Returns the track that a span from `source_info` named `name` should be shown on, if it has
+    /// been moved off its thread's track by a `@track` option or [TraceBuilder::route_spans].
+    fn custom_track(&mut self, source_info: &SourceInfo, name: &str) -> Option<TrackHandle> {
+        if let Some(track) = source_info.track {
+            return Some(self.create_track(track));
+        }
+        self.span_routes
+            .iter()
+            .find(|(pattern, _)| pattern_matches(pattern, name))
+            .map(|(_, track)| *track)
+    }
+
+    fn add_track_group(&mut self, parent_uuid: Option<u64>, name: String) -> TrackHandle {
+        let uuid = Uuid::new();
+
+        self.add_packet(TracePacket {
+            data: Some(schema::trace_packet::Data::TrackDescriptor(
+                TrackDescriptor {
+                    uuid: Some(uuid.0),
+                    parent_uuid,
+                    static_or_dynamic_name: Some(
+                        schema::track_descriptor::StaticOrDynamicName::Name(name),
+                    ),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        });
+
+        TrackHandle { uuid: uuid.0 }
+    }
+
+    fn add_counter_track(
+        &mut self,
+        uuid: Uuid,
+        parent_uuid: Option<u64>,
+        name: String,
+        unit: CounterUnit,
+        unit_multiplier: i64,
+        is_incremental: bool,
+    ) -> CounterTrack {
         self.add_packet(TracePacket {
             data: Some(schema::trace_packet::Data::TrackDescriptor(
                 TrackDescriptor {
                     uuid: Some(uuid.0),
-                    parent_uuid: None,
+                    parent_uuid,
                     process: None,
                     thread: None,
                     counter: Some(schema::CounterDescriptor {
@@ -881,77 +5394,3072 @@ impl TraceBuilder {
                         unit_name: unit.to_proto_unit_name(),
                         unit_multiplier: Some(unit_multiplier),
                         is_incremental: Some(is_incremental),
+                        y_axis_share_key: None,
                     }),
                     static_or_dynamic_name: Some(
-                        schema::track_descriptor::StaticOrDynamicName::Name(name.into()),
+                        schema::track_descriptor::StaticOrDynamicName::Name(name),
                     ),
+                    child_ordering: None,
+                    sibling_order_rank: None,
                 },
             )),
             ..Default::default()
         });
 
-        CounterTrack { uuid: uuid.0 }
+        CounterTrack { uuid: uuid.0 }
+    }
+
+    /// Makes `track` share its y-axis scale with every other counter track that has the same `key`,
+    /// so that their values can be compared visually.
+    pub fn set_counter_y_axis_share_key(
+        &mut self,
+        track: CounterTrack,
+        key: impl Into<String>,
+    ) -> &mut Self {
+        let key = key.into();
+        let packet_descriptors =
+            self.trace
+                .packet
+                .iter_mut()
+                .filter_map(|packet| match &mut packet.data {
+                    Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                        Some(descriptor)
+                    }
+                    _ => None,
+                });
+        for descriptor in packet_descriptors.chain(&mut self.descriptors) {
+            if descriptor.uuid == Some(track.uuid)
+                && let Some(counter) = &mut descriptor.counter
+            {
+                counter.y_axis_share_key = Some(key.clone());
+            }
+        }
+        self
+    }
+
+    /// Records `value` on `track` at the current time. This is intended to be called when the track
+    /// is created, to give the counter's value at the start of the trace. Incremental counters
+    /// otherwise start from zero, so would appear to ramp up from zero to their real value.
+    pub fn record_initial_counter_value(&mut self, track: CounterTrack, value: i64) -> &mut Self {
+        let timestamp = self.clock_nanos(time());
+        self.emit_counter_value(
+            track.uuid,
+            timestamp,
+            schema::track_event::CounterValueField::CounterValue(value),
+        );
+        self
+    }
+
+    /// Adds the tracks of any [counter] call sites that have been used since we last checked.
+    fn add_counter_site_tracks(&mut self) {
+        let sites = COUNTER_SITES.lock().unwrap()[self.num_counter_site_tracks..].to_vec();
+        self.num_counter_site_tracks += sites.len();
+        for site in sites {
+            self.add_counter_track(
+                Uuid(site.uuid.load(Ordering::Relaxed)),
+                None,
+                site.name.to_owned(),
+                CounterUnit::Unspecified,
+                1,
+                false,
+            );
+        }
+    }
+
+    /// Records the current value of every [StaticCounter] that has been used so far. The first time
+    /// a counter is flushed, a counter track with the counter's name is created for it.
+    ///
+    /// This can be called as many times as you like, with each call adding one sample per counter.
+    pub fn flush_static_counters(&mut self) -> &mut Self {
+        let counters = STATIC_COUNTERS.lock().unwrap().clone();
+        let timestamp = self.clock_nanos(time());
+
+        for counter in counters {
+            self.emit_static_counter_value(
+                counter,
+                timestamp,
+                schema::track_event::CounterValueField::CounterValue(counter.value()),
+            );
+        }
+
+        self
+    }
+
+    /// When enabled, each time a [StaticCounter] is recorded, the rate at which it changed since it
+    /// was previously recorded by this builder is also recorded, on a separate track.
+    pub fn set_record_static_counter_rates(&mut self, enabled: bool) -> &mut Self {
+        self.record_static_counter_rates = enabled;
+        self
+    }
+
+    fn emit_static_counter_value(
+        &mut self,
+        counter: &'static StaticCounter,
+        timestamp: u64,
+        value: schema::track_event::CounterValueField,
+    ) {
+        let track = self.static_counter_track(counter);
+        self.emit_counter_value(track.uuid, timestamp, value);
+
+        if !self.record_static_counter_rates {
+            return;
+        }
+        let schema::track_event::CounterValueField::CounterValue(value) = value else {
+            return;
+        };
+        let key = std::ptr::from_ref(counter) as usize;
+        let Some((previous_timestamp, previous_value)) =
+            self.static_counter_previous.insert(key, (timestamp, value))
+        else {
+            return;
+        };
+        if timestamp <= previous_timestamp {
+            return;
+        }
+        let rate = (value - previous_value) as f64 * 1e9 / (timestamp - previous_timestamp) as f64;
+        let track = match self.static_counter_rate_tracks.get(&key) {
+            Some(track) => *track,
+            None => {
+                let track = self.create_counter_track(
+                    format!("{} rate", counter.name),
+                    CounterUnit::Custom("/s".to_owned()),
+                    1,
+                    false,
+                );
+                self.static_counter_rate_tracks.insert(key, track);
+                track
+            }
+        };
+        self.emit_counter_value(
+            track.uuid,
+            timestamp,
+            schema::track_event::CounterValueField::DoubleCounterValue(rate),
+        );
+    }
+
+    fn static_counter_track(&mut self, counter: &'static StaticCounter) -> CounterTrack {
+        let key = std::ptr::from_ref(counter) as usize;
+        if let Some(track) = self.static_counter_tracks.get(&key) {
+            return *track;
+        }
+        let track = self.create_counter_track(counter.name, CounterUnit::Count, 1, false);
+        self.static_counter_tracks.insert(key, track);
+        track
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the track events that have been added to `trace`.
+    #[cfg(feature = "enable")]
+    fn track_events(trace: &TraceBuilder) -> Vec<&schema::TrackEvent> {
+        trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event)) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the values of the debug annotations of all of the track events in `trace`.
+    #[cfg(feature = "enable")]
+    fn annotation_values(trace: &TraceBuilder) -> Vec<&schema::debug_annotation::Value> {
+        track_events(trace)
+            .into_iter()
+            .flat_map(|event| &event.debug_annotations)
+            .filter_map(|annotation| annotation.value.as_ref())
+            .collect()
+    }
+
+    /// Returns the string values of the debug annotations of all of the track events in `trace`.
+    #[cfg(feature = "enable")]
+    fn annotation_strings(trace: &TraceBuilder) -> Vec<String> {
+        annotation_values(trace)
+            .into_iter()
+            .filter_map(|value| match value {
+                schema::debug_annotation::Value::StringValue(value) => Some(value.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_basic_usage() {
+        start().unwrap();
+        {
+            scope!(
+                "foo",
+                value = 1_u64,
+                foo = 2_i64,
+                baz = "baz",
+                baz_owned = "baz".to_owned()
+            );
+            scope!("bar");
+        }
+
+        let num_events = EVENTS.with_borrow(|events| events.len());
+        assert_eq!(num_events, 12);
+
+        TraceBuilder::new()
+            .unwrap()
+            .process_thread_data(&ThreadTraceData::take_current_thread())
+            .encode_to_vec();
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_end_args() {
+        start().unwrap();
+
+        let input = 5_u64;
+        let output = std::cell::Cell::new(0_u64);
+        {
+            scope!("op", input, @end output = output.get());
+            output.set(input * 2);
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let annotations = |kind| {
+            trace
+                .trace
+                .packet
+                .iter()
+                .find_map(|packet| match &packet.data {
+                    Some(schema::trace_packet::Data::TrackEvent(event))
+                        if event.r#type() == kind =>
+                    {
+                        Some(
+                            event
+                                .debug_annotations
+                                .iter()
+                                .map(|annotation| annotation.value.clone())
+                                .collect::<Vec<_>>(),
+                        )
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        use schema::debug_annotation::Value;
+        assert_eq!(
+            annotations(schema::track_event::Type::SliceBegin),
+            [Some(Value::UintValue(5))]
+        );
+        assert_eq!(
+            annotations(schema::track_event::Type::SliceEnd),
+            [Some(Value::UintValue(10))]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_sensitivity() {
+        start().unwrap();
+
+        {
+            scope!("open", user: secret = "bob", count = 3_u64);
+        }
+        {
+            scope!(@internal "internal", id = 7_u64);
+        }
+        let thread_data = ThreadTraceData::take_current_thread();
+
+        use schema::debug_annotation::Value;
+        let span_starts = |redaction| {
+            let mut trace = TraceBuilder::new().unwrap();
+            trace
+                .set_max_sensitivity(Sensitivity::Public, redaction)
+                .process_thread_data(&thread_data);
+            let name_ids: HashMap<u64, String> = trace
+                .trace
+                .packet
+                .iter()
+                .filter_map(|packet| packet.interned_data.as_ref())
+                .flat_map(|interned| &interned.event_names)
+                .map(|name| (name.iid.unwrap(), name.name.clone().unwrap()))
+                .collect();
+            trace
+                .trace
+                .packet
+                .iter()
+                .filter_map(|packet| match &packet.data {
+                    Some(schema::trace_packet::Data::TrackEvent(event))
+                        if event.r#type() == schema::track_event::Type::SliceBegin =>
+                    {
+                        let Some(schema::track_event::NameField::NameIid(iid)) = event.name_field
+                        else {
+                            return None;
+                        };
+                        Some((
+                            name_ids[&iid].clone(),
+                            event
+                                .debug_annotations
+                                .iter()
+                                .map(|annotation| annotation.value.clone().unwrap())
+                                .collect::<Vec<_>>(),
+                        ))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let redacted = || Value::StringValue(REDACTED.to_owned());
+        assert_eq!(
+            span_starts(Redaction::Redact),
+            [
+                ("open".to_owned(), vec![redacted(), Value::UintValue(3)]),
+                (REDACTED.to_owned(), vec![redacted()]),
+            ]
+        );
+        assert_eq!(
+            span_starts(Redaction::Drop),
+            [("open".to_owned(), vec![Value::UintValue(3)])]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_serialize_thread_data() {
+        start().unwrap();
+        {
+            scope!(
+                "serialized",
+                flag = true,
+                count = 3_u64,
+                text = "a string slice",
+                owned = "owned".to_owned(),
+                flow = Flow::named("flow")
+            );
+        }
+
+        let thread_data = ThreadTraceData::take_current_thread();
+        let mut bytes = Vec::new();
+        thread_data.serialize(&mut bytes).unwrap();
+        let deserialized = ThreadTraceData::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(deserialized.events.len(), thread_data.events.len());
+        assert_eq!(deserialized.thread_name, thread_data.thread_name);
+
+        let annotations = |thread_data: &ThreadTraceData| {
+            let mut trace = TraceBuilder::new().unwrap();
+            trace.process_thread_data(thread_data);
+            trace
+                .trace
+                .packet
+                .into_iter()
+                .filter_map(|packet| match packet.data {
+                    Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                        Some((event.debug_annotations, event.flow_ids))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(annotations(&deserialized), annotations(&thread_data));
+
+        assert!(ThreadTraceData::deserialize(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(ThreadTraceData::deserialize(&mut b"nope".as_slice()).is_err());
+
+        // Data that would fail to process is rejected, e.g. a span without its timestamp or with
+        // an argument missing.
+        for removed in [1, 2] {
+            {
+                scope!("serialized", flag = true, count = 3_u64);
+            }
+            let mut thread_data = ThreadTraceData::take_current_thread();
+            thread_data.events.remove(removed);
+            let mut bytes = Vec::new();
+            thread_data.serialize(&mut bytes).unwrap();
+            assert!(ThreadTraceData::deserialize(&mut bytes.as_slice()).is_err());
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_restrict_to() {
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(2));
+        start().unwrap();
+
+        {
+            scope!("before");
+        }
+        pause();
+        let crossing = start_span!("crossing");
+        pause();
+        let window_start = time();
+        {
+            scope!("inside");
+        }
+        drop(crossing);
+        pause();
+        let window_end = time();
+        pause();
+        {
+            scope!("after");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .process_thread_data(&ThreadTraceData::take_current_thread())
+            .restrict_to(window_start, window_end);
+
+        let name_ids: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.event_names)
+            .map(|name| (name.iid.unwrap(), name.name.as_deref().unwrap()))
+            .collect();
+        let events: Vec<(&str, u64, bool)> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                    let Some(schema::track_event::NameField::NameIid(iid)) = event.name_field
+                    else {
+                        return None;
+                    };
+                    Some((
+                        name_ids[&iid],
+                        packet.timestamp.unwrap(),
+                        !event.debug_annotations.is_empty(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let window_start = trace.clock_nanos(window_start);
+        assert_eq!(
+            events.iter().map(|(name, _, _)| *name).collect::<Vec<_>>(),
+            ["crossing", "inside", "inside", "crossing"]
+        );
+        assert_eq!(events[0].1, window_start);
+        assert!(events[0].2, "crossing span should be marked as clipped");
+        assert!(!events[1].2);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_packet_defaults() {
+        start().unwrap();
+        let threads: Vec<ThreadTraceData> = (0..2)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..3 {
+                        scope!("work");
+                    }
+                    ThreadTraceData::take_current_thread()
+                })
+                .join()
+                .unwrap()
+            })
+            .collect();
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_all(threads);
+        let plain = trace.encode_to_vec();
+        let with_defaults = trace.set_packet_defaults(true).encode_to_vec();
+        assert!(with_defaults.len() < plain.len());
+
+        // Encoding without copying the trace gives the same result as changing it.
+        let mut applied = schema::Trace::decode(plain.as_slice()).unwrap();
+        packet_defaults::apply_packet_defaults(&mut applied.packet);
+        assert_eq!(applied.encode_to_vec(), with_defaults);
+
+        let mut decoded = schema::Trace::decode(with_defaults.as_slice()).unwrap();
+        let num_defaults = decoded
+            .packet
+            .iter()
+            .filter(|packet| packet.trace_packet_defaults.is_some())
+            .count();
+        // The first event sets the clock and its thread's track, then the other thread's track.
+        assert_eq!(num_defaults, 2);
+        packet_defaults::resolve_packet_defaults(&mut decoded.packet);
+        assert_eq!(decoded, schema::Trace::decode(plain.as_slice()).unwrap());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_trace_writer() {
+        start().unwrap();
+        let threads: Vec<ThreadTraceData> = (0..2)
+            .map(|index| {
+                std::thread::spawn(move || {
+                    {
+                        scope!("streamed", index);
+                    }
+                    ThreadTraceData::take_current_thread()
+                })
+                .join()
+                .unwrap()
+            })
+            .collect();
+        let mut builder = TraceBuilder::new().unwrap();
+        builder.set_footer(true);
+        let mut writer = TraceWriter::with_builder(builder, Vec::new());
+        for thread in &threads {
+            writer.write_thread_data(thread).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let stats = verify_footer(&bytes).unwrap();
+        assert_eq!(stats.span_count, 2);
+        let decoded = schema::Trace::decode(bytes.as_slice()).unwrap();
+        assert_eq!(stats.packet_count, decoded.packet.len() as u64 - 1);
+        // The second thread's spans refer to the name interned for the first thread's.
+        let interned = decoded
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.event_names)
+            .filter(|name| name.name.as_deref() == Some("streamed"))
+            .count();
+        assert_eq!(interned, 1);
+        assert_eq!(compare::span_stats(&bytes).unwrap()["streamed"].count, 2);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_footer() {
+        start().unwrap();
+        {
+            scope!("a");
+            scope!("b");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let without_footer = trace.encode_to_vec();
+        let mut bytes = trace.set_footer(true).encode_to_vec();
+
+        let stats = verify_footer(&bytes).unwrap();
+        assert_eq!(stats.packet_count, trace.trace.packet.len() as u64);
+        assert_eq!(stats.span_count, 2);
+        assert!(bytes.starts_with(&without_footer));
+
+        assert_eq!(verify_footer(&without_footer), Err(InvalidFooter::Missing));
+        assert_eq!(
+            verify_footer(&bytes[..bytes.len() - 1]),
+            Err(InvalidFooter::Malformed)
+        );
+        let middle = without_footer.len() / 2;
+        bytes[middle] ^= 1;
+        assert_eq!(verify_footer(&bytes), Err(InvalidFooter::ChecksumMismatch));
+    }
+
+    #[test]
+    fn test_filter_generation_wraps() {
+        assert_eq!(session::next_filter_generation(0), 1);
+        let max = session::MAX_FILTER_GENERATION;
+        assert_eq!(session::next_filter_generation(max - 1), max);
+        // The generation must still fit in a call site's state once shifted to make room for the
+        // cached answer, and must never become zero, which would mean that there's no filter.
+        assert_eq!((max << 1) >> 1, max);
+        assert_eq!(session::next_filter_generation(max), 1);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_session_categories() {
+        let trace_config = schema::TraceConfig {
+            data_sources: vec![schema::trace_config::DataSource {
+                config: Some(schema::DataSourceConfig {
+                    name: Some("track_event".to_owned()),
+                    track_event_config: Some(schema::TrackEventConfig {
+                        enabled_categories: vec!["test.session*".to_owned()],
+                        disabled_categories: vec!["test.session.verbose".to_owned()],
+                    }),
+                }),
+            }],
+            ..Default::default()
+        };
+        let config = SessionConfig::from_trace_config(&trace_config.encode_to_vec()).unwrap();
+        start_with_config(&config).unwrap();
+
+        {
+            scope!(@category = "test.session.db" "enabled");
+            scope!(@category = "test.session.verbose" "disabled");
+            scope!(@category = "test.other" "not enabled");
+            scope!("uncategorized");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let category_names: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.event_categories)
+            .map(|category| (category.iid.unwrap(), category.name.as_deref().unwrap()))
+            .collect();
+        let categories: Vec<Vec<&str>> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::SliceBegin =>
+                {
+                    assert!(event.categories.is_empty());
+                    Some(
+                        event
+                            .category_iids
+                            .iter()
+                            .map(|iid| category_names[iid])
+                            .collect(),
+                    )
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(categories, [vec!["test.session.db"], Vec::new()]);
+
+        assert!(SessionConfig::from_trace_config(&[0xff]).is_err());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_lifetime_span() {
+        fn ended_spans(data: &ThreadTraceData) -> usize {
+            data.events
+                .iter()
+                .filter(|event| matches!(event, Event::EndSpan(_)))
+                .count()
+        }
+
+        start().unwrap();
+        session::start_lifetime_span();
+        {
+            scope!("inner");
+        }
+        let open = start_span!("open");
+
+        // Ending the lifetime span now would end it before "open", so it's left open.
+        let first = ThreadTraceData::take_current_thread();
+        assert_eq!(ended_spans(&first), 1);
+        drop(open);
+        let second = ThreadTraceData::take_current_thread();
+        assert_eq!(ended_spans(&second), 2);
+        assert!(ThreadTraceData::take_current_thread().events.is_empty());
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&first);
+        trace.process_thread_data(&second);
+        let events = track_events(&trace);
+        let lifetime_name = trace.name_ids["process lifetime"];
+        assert_eq!(
+            events[0].name_field,
+            Some(schema::track_event::NameField::NameIid(lifetime_name))
+        );
+        assert_eq!(
+            events[events.len() - 1].r#type(),
+            schema::track_event::Type::SliceEnd
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_process_all() {
+        start().unwrap();
+
+        // Keep all threads alive until they've all taken their data, so that they have distinct
+        // thread IDs.
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(3));
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..i {
+                        scope!("work");
+                    }
+                    let data = ThreadTraceData::take_current_thread();
+                    barrier.wait();
+                    data
+                })
+            })
+            .collect();
+        let threads = handles.into_iter().map(|handle| handle.join().unwrap());
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let reports = trace.process_all(threads);
+        let num_spans: Vec<usize> = reports.iter().map(|report| report.num_spans).collect();
+        assert_eq!(num_spans, [0, 1, 2]);
+        assert_eq!(reports[2].num_events, 2 * EVENTS_PER_SPAN);
+        // One thread descriptor, plus a begin and an end packet for each span.
+        assert_eq!(reports[2].num_packets, 5);
+    }
+
+    #[cfg(all(feature = "enable", debug_assertions))]
+    #[test]
+    fn test_immediately_dropped_span_warning() {
+        start().unwrap();
+        for _ in 0..MIN_SHORT_SPANS_TO_WARN {
+            let _ = start_span!("dropped immediately");
+        }
+        for _ in 0..MIN_SHORT_SPANS_TO_WARN {
+            scope!("sleeps");
+            std::thread::sleep(std::time::Duration::from_micros(10));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let reports = trace.process_all([ThreadTraceData::take_current_thread()]);
+        let [warning] = reports[0].warnings.as_slice() else {
+            panic!("Expected a single warning, got {:?}", reports[0].warnings);
+        };
+        assert!(warning.contains("dropped immediately"));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_phases_track() {
+        start().unwrap();
+        let main = std::thread::Builder::new()
+            .name("main".to_owned())
+            .spawn(|| {
+                let flow = Flow::new();
+                for phase in ["parse", "layout"] {
+                    let _phase = start_span!("phase", phase, flow = &flow, id = CorrelationId(1));
+                    scope!("step");
+                    scope!("detail");
+                }
+                ThreadTraceData::take_current_thread()
+            })
+            .unwrap();
+        let main = main.join().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.set_phases_track(1).process_thread_data(&main);
+
+        let phases_uuid = trace.phase_tracks[&main.pid];
+        let names: HashMap<u64, &str> = trace
+            .name_ids
+            .iter()
+            .map(|(name, id)| (*id, name.as_ref()))
+            .collect();
+        let phases: Vec<(schema::track_event::Type, &str)> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.track_uuid == Some(phases_uuid.0) =>
+                {
+                    assert!(event.flow_ids.is_empty());
+                    assert_eq!(event.correlation_id, None);
+                    let Some(schema::track_event::NameField::NameIid(name_id)) = event.name_field
+                    else {
+                        panic!("Expected an interned name");
+                    };
+                    Some((event.r#type(), names[&name_id]))
+                }
+                _ => None,
+            })
+            .collect();
+        use schema::track_event::Type;
+        assert_eq!(
+            phases,
+            [
+                (Type::SliceBegin, "phase"),
+                (Type::SliceBegin, "step"),
+                (Type::SliceEnd, "step"),
+                (Type::SliceEnd, "phase"),
+            ]
+            .repeat(2)
+        );
+
+        // Other threads don't have their spans copied.
+        {
+            scope!("not main");
+        }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .set_phases_track(1)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+        assert!(trace.phase_tracks.is_empty());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_merge_phases_track() {
+        start().unwrap();
+        let main = std::thread::Builder::new()
+            .name("main".to_owned())
+            .spawn(|| {
+                {
+                    scope!("phase");
+                }
+                ThreadTraceData::take_current_thread()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        {
+            scope!("other thread");
+        }
+
+        // Both builders have a track for the process, but only `other` has a phases track.
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .set_group_threads(true)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+        let process_uuid = trace.process_uuids[&main.pid].0;
+        let mut other = TraceBuilder::new().unwrap();
+        other.set_phases_track(0).process_thread_data(&main);
+        let phases_uuid = other.phase_tracks[&main.pid].0;
+        trace.merge(other);
+
+        let descriptors: HashMap<u64, &TrackDescriptor> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                    Some((descriptor.uuid.unwrap(), descriptor))
+                }
+                _ => None,
+            })
+            .collect();
+        let parent = descriptors[&phases_uuid].parent_uuid.unwrap();
+        assert_eq!(parent, process_uuid);
+        assert!(descriptors.contains_key(&parent));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_record_span_at() {
+        start().unwrap();
+        ThreadTraceData::take_current_thread();
+        {
+            scope!("local");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let gpu = trace.create_track_group("GPU");
+        let start = time();
+        let end = time();
+        trace.record_span_at("draw", gpu, start, end, |args| {
+            args.entry("pass", "shadows")
+                .entry("request", CorrelationId(7));
+        });
+
+        // Recording the arguments mustn't disturb the events of the current thread.
+        let thread = ThreadTraceData::take_current_thread();
+        assert_eq!(thread.events.len(), EVENTS_PER_SPAN);
+
+        let events = track_events(&trace);
+        let [begin, end_event] = events.as_slice() else {
+            panic!("Expected a begin and an end event, got {events:?}");
+        };
+        assert_eq!(begin.r#type(), schema::track_event::Type::SliceBegin);
+        assert_eq!(end_event.r#type(), schema::track_event::Type::SliceEnd);
+        assert_eq!(begin.track_uuid, Some(gpu.uuid));
+        assert_eq!(end_event.track_uuid, Some(gpu.uuid));
+        assert_eq!(begin.correlation_id, Some(7));
+        let values: Vec<_> = begin
+            .debug_annotations
+            .iter()
+            .map(|annotation| annotation.value.clone().unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                schema::debug_annotation::Value::StringValue("shadows".to_owned()),
+                schema::debug_annotation::Value::UintValue(7),
+            ]
+        );
+
+        // Nor must a panic while recording them.
+        {
+            scope!("local");
+        }
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            trace.record_span_at("draw", gpu, start, end, |_| {
+                panic!("Failed to get arguments")
+            });
+        }));
+        assert!(result.is_err());
+        assert!(!dump::pause_handover(false));
+        let thread = ThreadTraceData::take_current_thread();
+        assert_eq!(thread.events.len(), EVENTS_PER_SPAN);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_thread_groups() {
+        start().unwrap();
+        let names = [
+            "rayon-worker-0",
+            "io",
+            "rayon-worker-12",
+            "tokio-runtime-worker",
+            "tokio-blocking-1",
+            "tokio-runtime-worker",
+        ];
+        let threads: Vec<ThreadTraceData> = names
+            .into_iter()
+            .map(|name| {
+                std::thread::Builder::new()
+                    .name(name.to_owned())
+                    .spawn(|| {
+                        scope!("work");
+                        ThreadTraceData::take_current_thread()
+                    })
+                    .unwrap()
+                    .join()
+                    .unwrap()
+            })
+            .collect();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .add_thread_group("tokio-blocking*", "tokio-runtime-worker")
+            .process_all(threads);
+
+        let mut process_ordering = None;
+        let mut ranks = Vec::new();
+        for packet in &trace.trace.packet {
+            let Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) = &packet.data else {
+                continue;
+            };
+            if descriptor.process.is_some() {
+                process_ordering = descriptor.child_ordering;
+            }
+            if let Some(thread) = &descriptor.thread {
+                ranks.push((
+                    thread.thread_name.clone().unwrap(),
+                    descriptor.sibling_order_rank.unwrap(),
+                ));
+            }
+        }
+        assert_eq!(
+            process_ordering,
+            Some(schema::track_descriptor::ChildTracksOrdering::Explicit as i32)
+        );
+        let expected: Vec<(String, i32)> = names
+            .into_iter()
+            .zip([1, 2, 1, 3, 3, 3])
+            .map(|(name, rank)| (name.to_owned(), rank))
+            .collect();
+        assert_eq!(ranks, expected);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_thread_lifetime_slices() {
+        start().unwrap();
+        {
+            scope!("work");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .set_thread_lifetime_slices(true)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let thread_name = std::thread::current().name().unwrap().to_owned();
+        let lifetime_name_id = trace.name_ids[thread_name.as_str()];
+        let lifetime_timestamps: Vec<u64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.name_field
+                        == Some(schema::track_event::NameField::NameIid(lifetime_name_id)) =>
+                {
+                    packet.timestamp
+                }
+                _ => None,
+            })
+            .collect();
+        let all_timestamps: Vec<u64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.timestamp)
+            .collect();
+        assert_eq!(lifetime_timestamps.len(), 2);
+        assert_eq!(
+            lifetime_timestamps[0],
+            *all_timestamps.iter().min().unwrap()
+        );
+        assert_eq!(
+            lifetime_timestamps[1],
+            *all_timestamps.iter().max().unwrap()
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_debug_scope() {
+        start().unwrap();
+        {
+            debug_scope!("fine-grained", value = 1_u64);
+        }
+        let guard = debug_span!("fine-grained span");
+        assert_eq!(guard.is_some(), cfg!(debug_assertions));
+        drop(guard);
+
+        let num_events = ThreadTraceData::take_current_thread().events.len();
+        let expected = if cfg!(debug_assertions) {
+            2 * EVENTS_PER_SPAN + EVENTS_PER_ARG
+        } else {
+            0
+        };
+        assert_eq!(num_events, expected);
+    }
+
+    #[cfg(not(feature = "enable"))]
+    #[test]
+    fn test_no_execution_when_disabled() {
+        fn do_not_run() -> u32 {
+            panic!("This should not be called");
+        }
+
+        scope!("foo", value = do_not_run());
+    }
+
+    #[cfg(not(feature = "enable"))]
+    #[test]
+    fn test_measure_sink() {
+        let measured = std::sync::Arc::new(Mutex::new(Vec::new()));
+        set_measure_sink({
+            let measured = measured.clone();
+            move |name, duration| measured.lock().unwrap().push((name.to_owned(), duration))
+        });
+        let result = measure("sleep", || {
+            std::thread::sleep(Duration::from_millis(2));
+            7
+        });
+        clear_measure_sink();
+        measure("after clear", || {});
+
+        assert_eq!(result, 7);
+        let measured = measured.lock().unwrap();
+        assert_eq!(measured.len(), 1);
+        assert_eq!(measured[0].0, "sleep");
+        assert!(measured[0].1 >= Duration::from_millis(2));
+    }
+
+    /// Try different lengths of string slices to make sure we're able to store them in the string
+    /// arena and read them back again.
+    #[test]
+    fn str_encoding() {
+        for l in 0..100 {
+            let string: String = (0..l)
+                .map(|i| char::from_u32('A' as u32 + i).unwrap())
+                .collect();
+            let str_slice = string.as_str();
+            RecordArg::record_arg(str_slice);
+            let events = EVENTS.take();
+            let strings = STRINGS.take();
+            let mut events = events.iter();
+            match convert_next_arg(&mut events, &strings) {
+                schema::debug_annotation::Value::StringValue(actual) => {
+                    assert_eq!(actual, string);
+                }
+                other => panic!("Unexpected event: {other:?}"),
+            }
+            assert!(events.next().is_none());
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_without_source_location() {
+        static CALL_SITE: CallSite = CallSite::new(SourceInfo {
+            name: "no location",
+            file: "",
+            line: 0,
+            sensitivity: Sensitivity::Public,
+            process_track: false,
+            category: None,
+            track: None,
+            collapse_recursion: false,
+            dynamic_name: false,
+            arg_names: &[],
+            arg_sensitivities: &[],
+            end_arg_names: &[],
+            end_arg_sensitivities: &[],
+        });
+
+        start().unwrap();
+        {
+            let _guard = SpanGuard::new(&CALL_SITE, true, Some(time()));
+            record_event(Event::StartSpan(CALL_SITE.id()));
+            record_event(Event::Timestamp(time()));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        for packet in &trace.trace.packet {
+            if let Some(interned) = &packet.interned_data {
+                assert!(interned.source_locations.is_empty());
+            }
+            if let Some(schema::trace_packet::Data::TrackEvent(event)) = &packet.data {
+                assert!(event.source_location_field.is_none());
+            }
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_async_span() {
+        start().unwrap();
+
+        let request = start_async_span!("request", id = 7_u64);
+        let worker = std::thread::spawn(move || {
+            scope!("worker");
+            drop(request);
+            ThreadTraceData::take_current_thread()
+        })
+        .join()
+        .unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_all([ThreadTraceData::take_current_thread(), worker]);
+
+        let descriptors: HashMap<u64, &TrackDescriptor> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                    Some((descriptor.uuid.unwrap(), descriptor))
+                }
+                _ => None,
+            })
+            .collect();
+        let events = track_events(&trace);
+        let async_events: Vec<&&schema::TrackEvent> = events
+            .iter()
+            .filter(|event| descriptors[&event.track_uuid.unwrap()].thread.is_none())
+            .collect();
+        assert_eq!(
+            async_events
+                .iter()
+                .map(|event| event.r#type())
+                .collect::<Vec<_>>(),
+            [
+                schema::track_event::Type::SliceBegin,
+                schema::track_event::Type::SliceEnd
+            ]
+        );
+        assert_eq!(async_events[0].debug_annotations.len(), 1);
+        let track = descriptors[&async_events[0].track_uuid.unwrap()];
+        assert_eq!(track.uuid, async_events[1].track_uuid);
+        assert_eq!(
+            track.static_or_dynamic_name,
+            Some(schema::track_descriptor::StaticOrDynamicName::Name(
+                "request".to_owned()
+            ))
+        );
+        assert!(descriptors[&track.parent_uuid.unwrap()].process.is_some());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_legacy_async_events() {
+        start().unwrap();
+
+        drop(start_async_span!("legacy request", id = 7_u64));
+        let thread = ThreadTraceData::take_current_thread();
+
+        for (mode, num_track_events) in [(AsyncSpanMode::Legacy, 0), (AsyncSpanMode::Both, 2)] {
+            let mut trace = TraceBuilder::new().unwrap();
+            trace.set_async_span_mode(mode).process_thread_data(&thread);
+
+            let events = track_events(&trace);
+            let legacy: Vec<schema::track_event::LegacyEvent> = events
+                .iter()
+                .filter_map(|event| event.legacy_event)
+                .collect();
+            assert_eq!(legacy.len(), 2);
+            assert_eq!(legacy[0].phase, Some(i32::from(b'b')));
+            assert_eq!(legacy[1].phase, Some(i32::from(b'e')));
+            assert_eq!(legacy[0].unscoped_id, legacy[1].unscoped_id);
+            assert!(
+                events
+                    .iter()
+                    .filter(|event| event.legacy_event.is_some())
+                    .all(|event| event.r#type.is_none())
+            );
+            let legacy_begin = events.iter().find(|event| event.legacy_event.is_some());
+            assert_eq!(legacy_begin.unwrap().debug_annotations.len(), 1);
+            assert_eq!(events.len() - legacy.len(), num_track_events);
+            assert_eq!(trace.async_tracks.len(), num_track_events / 2);
+        }
+    }
+
+    #[cfg(all(feature = "enable", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn test_record_cpu() {
+        start().unwrap();
+
+        {
+            let guard = start_span!("pinned");
+            record_cpu(guard.call_site.unwrap());
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let cpu_name_id = trace.debug_annotation_name_ids["cpu"];
+        let annotations: Vec<&DebugAnnotation> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .flat_map(|event| &event.debug_annotations)
+            .collect();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(
+            annotations[0].name_field,
+            Some(schema::debug_annotation::NameField::NameIid(cpu_name_id))
+        );
+        assert!(matches!(
+            annotations[0].value,
+            Some(schema::debug_annotation::Value::UintValue(_))
+        ));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_machine_id() {
+        start().unwrap();
+        {
+            scope!("on each machine");
+        }
+        let thread = ThreadTraceData::take_current_thread();
+
+        let mut options = TraceBuilderOptions::new();
+        options.set_machine_id(1);
+        let mut first = TraceBuilder::with_options(&options).unwrap();
+        first.process_thread_data(&thread);
+        let mut second = TraceBuilder::new().unwrap();
+        second.process_thread_data(&thread).set_machine_id(2);
+        first.merge(second);
+
+        let machine_ids: HashSet<Option<u32>> = first
+            .trace
+            .packet
+            .iter()
+            .map(|packet| packet.machine_id)
+            .collect();
+        assert_eq!(machine_ids, HashSet::from([Some(1), Some(2)]));
+        let num_thread_descriptors = first
+            .trace
+            .packet
+            .iter()
+            .filter(|packet| {
+                matches!(&packet.data, Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                    if descriptor.thread.is_some())
+            })
+            .count();
+        assert_eq!(num_thread_descriptors, 2);
+
+        // Only packets from this builder are re-stamped.
+        first.set_machine_id(3);
+        let machine_ids: HashSet<Option<u32>> = first
+            .trace
+            .packet
+            .iter()
+            .map(|packet| packet.machine_id)
+            .collect();
+        assert_eq!(machine_ids, HashSet::from([Some(3), Some(2)]));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_dynamic_span_names() {
+        start().unwrap();
+        for unit in ["a", "b", "a"] {
+            let _guard = start_span_dynamic!(format!("compile {unit}"), unit);
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let bytes = trace.encode_to_vec();
+        let counts: Vec<(String, u64)> = compare::span_stats(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|(name, stats)| (name, stats.count))
+            .collect();
+        assert_eq!(
+            counts,
+            [("compile a".to_owned(), 2), ("compile b".to_owned(), 1)]
+        );
+        for packet in &trace.trace.packet {
+            if let Some(schema::trace_packet::Data::TrackEvent(event)) = &packet.data
+                && event.r#type() == schema::track_event::Type::SliceBegin
+            {
+                assert_eq!(event.debug_annotations.len(), 1);
+            }
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_assert_traced() {
+        start().unwrap();
+        let len = 5_u64;
+        assert!(assert_traced!(len < 10, len));
+        assert!(!assert_traced!(len < 3, len));
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let instants: Vec<&schema::TrackEvent> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::Instant)
+            .collect();
+        assert_eq!(instants.len(), 1);
+        assert_eq!(
+            instants[0].name_field,
+            Some(schema::track_event::NameField::NameIid(
+                trace.name_ids["assertion failed: len < 3"]
+            ))
+        );
+        assert_eq!(instants[0].debug_annotations.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "assertion failed: 1 + 1 == 3")]
+    fn test_assert_traced_panic() {
+        assert_traced!(@panic 1 + 1 == 3);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_generic_span_names() {
+        fn decode<T: Default>() -> T {
+            let _guard = start_span_dynamic!(std::any::type_name::<T>());
+            scope!(concat!("decode ", stringify!(T)));
+            T::default()
+        }
+
+        start().unwrap();
+        decode::<u32>();
+        decode::<String>();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let bytes = trace.encode_to_vec();
+        let counts: HashMap<String, u64> = compare::span_stats(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|(name, stats)| (name, stats.count))
+            .collect();
+        assert_eq!(
+            counts,
+            HashMap::from([
+                (std::any::type_name::<u32>().to_owned(), 1),
+                (std::any::type_name::<String>().to_owned(), 1),
+                ("decode T".to_owned(), 2),
+            ])
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_flow_from_id() {
+        start().unwrap();
+
+        {
+            scope!("produce", flow = Flow::from_id(1234));
+        }
+        let consumer = std::thread::spawn(|| {
+            {
+                scope!("consume", flow = Flow::from_id(1234));
+            }
+            ThreadTraceData::take_current_thread()
+        })
+        .join()
+        .unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_all([ThreadTraceData::take_current_thread(), consumer]);
+
+        let begin_flow_ids: Vec<&[u64]> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .map(|event| event.flow_ids.as_slice())
+            .collect();
+        assert_eq!(begin_flow_ids, [[1234], [1234]]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_correlation_id() {
+        start().unwrap();
+        {
+            scope!("handle", request_id = CorrelationId(42));
+        }
+        {
+            scope!("secret", request_id: secret = CorrelationId(7));
+        }
+        {
+            scope!("uncorrelated");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .set_max_sensitivity(Sensitivity::Internal, Redaction::Redact)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+        let begins: Vec<(Option<u64>, Option<&schema::debug_annotation::Value>)> =
+            track_events(&trace)
+                .into_iter()
+                .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+                .map(|event| {
+                    (
+                        event.correlation_id,
+                        event
+                            .debug_annotations
+                            .first()
+                            .and_then(|annotation| annotation.value.as_ref()),
+                    )
+                })
+                .collect();
+        use schema::debug_annotation::Value;
+        assert_eq!(
+            begins,
+            [
+                (Some(42), Some(&Value::UintValue(42))),
+                (None, Some(&Value::StringValue(REDACTED.to_owned()))),
+                (None, None),
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_flows() {
+        start().unwrap();
+
+        let flow = Flow::named("request 42");
+        {
+            scope!("send", flow = &flow, size = 10_u64);
+        }
+        {
+            scope!("receive", flow = Flow::named("request 42"));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let begins: Vec<&schema::TrackEvent> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .collect();
+        assert_eq!(begins.len(), 2);
+        for event in begins {
+            assert_eq!(event.flow_ids, [flow.id()]);
+            assert_eq!(
+                event.debug_annotations[0].value,
+                Some(schema::debug_annotation::Value::StringValue(
+                    "request 42".to_owned()
+                ))
+            );
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_collapse_recursion() {
+        fn collapsed(depth: u32) {
+            scope!(@collapse_recursion "collapsed");
+            if depth < 3 {
+                collapsed(depth + 1);
+            }
+        }
+        fn nested(depth: u32) {
+            scope!("nested");
+            if depth < 3 {
+                nested(depth + 1);
+            }
+        }
+        fn indirect(depth: u32) {
+            scope!(@collapse_recursion "indirect");
+            if depth == 0 {
+                scope!("between");
+                indirect(depth + 1);
+            }
+        }
+
+        start().unwrap();
+        collapsed(0);
+        nested(0);
+        indirect(0);
+
+        let thread = ThreadTraceData::take_current_thread();
+        assert!(thread.validate().is_ok());
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&thread);
+
+        let begin_annotations: Vec<Vec<u64>> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::SliceBegin =>
+                {
+                    Some(
+                        event
+                            .debug_annotations
+                            .iter()
+                            .filter_map(|annotation| match annotation.value {
+                                Some(schema::debug_annotation::Value::UintValue(value)) => {
+                                    Some(value)
+                                }
+                                _ => None,
+                            })
+                            .collect(),
+                    )
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            begin_annotations,
+            [
+                vec![4, 4],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![]
+            ]
+        );
+        let num_ends = trace
+            .trace
+            .packet
+            .iter()
+            .filter(|packet| {
+                matches!(&packet.data, Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::SliceEnd)
+            })
+            .count();
+        assert_eq!(num_ends, begin_annotations.len());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_session_data() {
+        start().unwrap();
+        {
+            scope!("before session");
+        }
+        std::thread::sleep(Duration::from_millis(1));
+        let session_start = time();
+        {
+            scope!("during session");
+        }
+        let session_end = time();
+        std::thread::sleep(Duration::from_millis(1));
+        {
+            scope!("after session");
+        }
+
+        let session = SessionData {
+            threads: vec![ThreadTraceData::take_current_thread()],
+            start: session_start,
+            end: session_end,
+        };
+        let bytes = session.into_trace_builder().encode_to_vec();
+        let spans = compare::span_stats(&bytes).unwrap();
+        assert_eq!(spans.keys().collect::<Vec<_>>(), ["during session"]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_take_segment() {
+        start().unwrap();
+        let mut trace = TraceBuilder::new().unwrap();
+        let counter = trace.create_counter_track("Segment counter", CounterUnit::Count, 1, false);
+
+        {
+            scope!("first segment");
+        }
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let first = trace.take_segment();
+        {
+            scope!("second segment");
+        }
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let second = trace.take_segment();
+
+        let second_spans = compare::span_stats(&second).unwrap();
+        assert_eq!(second_spans.keys().collect::<Vec<_>>(), ["second segment"]);
+        assert!(
+            compare::span_stats(&first)
+                .unwrap()
+                .contains_key("first segment")
+        );
+
+        let decoded = schema::Trace::decode(second.as_slice()).unwrap();
+        let descriptor_uuids: Vec<u64> = decoded
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => descriptor.uuid,
+                _ => None,
+            })
+            .collect();
+        assert!(descriptor_uuids.contains(&counter.uuid));
+        for packet in &decoded.packet {
+            if let Some(schema::trace_packet::Data::TrackEvent(event)) = &packet.data {
+                assert!(descriptor_uuids.contains(&event.track_uuid.unwrap()));
+            }
+        }
+        assert_eq!(
+            decoded.packet[0].sequence_flags,
+            Some(schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32)
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_tail_sampling() {
+        start().unwrap();
+        let outer_span = start_span!("outer");
+        {
+            scope!("inner");
+        }
+        let mut events = ThreadTraceData::take_current_thread().events;
+        let Event::StartSpan(outer) = events[0] else {
+            panic!("Expected StartSpan, got {:?}", events[0]);
+        };
+        let num_events = events.len();
+
+        let later = time() + std::time::Duration::from_secs(10);
+        assert!(!tail_sampling::drop_short_span(
+            &mut events,
+            outer,
+            later,
+            std::time::Duration::from_secs(1)
+        ));
+        assert_eq!(events.len(), num_events);
+
+        assert!(tail_sampling::drop_short_span(
+            &mut events,
+            outer,
+            time(),
+            std::time::Duration::from_secs(1)
+        ));
+        assert!(events.is_empty());
+        drop(outer_span);
+        ThreadTraceData::take_current_thread();
+
+        // Spans are kept if removing them would remove anything else.
+        let first = start_span!("first");
+        let second = start_span!("second");
+        let mut events = ThreadTraceData::take_current_thread().events;
+        let Event::StartSpan(first_id) = events[0] else {
+            panic!("Expected StartSpan, got {:?}", events[0]);
+        };
+        assert!(!tail_sampling::drop_short_span(
+            &mut events,
+            first_id,
+            time(),
+            std::time::Duration::from_secs(1)
+        ));
+        drop((first, second));
+        ThreadTraceData::take_current_thread();
+
+        let logged = start_span!("logged");
+        log_event!(LogLevel::Info, "inside");
+        let mut events = ThreadTraceData::take_current_thread().events;
+        let Event::StartSpan(logged_id) = events[0] else {
+            panic!("Expected StartSpan, got {:?}", events[0]);
+        };
+        let num_events = events.len();
+        assert!(!tail_sampling::drop_short_span(
+            &mut events,
+            logged_id,
+            time(),
+            std::time::Duration::from_secs(1)
+        ));
+        assert_eq!(events.len(), num_events);
+        drop(logged);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_process_track() {
+        start().unwrap();
+
+        {
+            scope!("local");
+            scope!(@process "global");
+            scope!(@internal @process "internal global");
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let descriptors: HashMap<u64, &TrackDescriptor> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                    Some((descriptor.uuid.unwrap(), descriptor))
+                }
+                _ => None,
+            })
+            .collect();
+        let on_process_track: Vec<bool> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::SliceBegin =>
+                {
+                    let descriptor = descriptors[&event.track_uuid.unwrap()];
+                    Some(descriptor.process.is_some())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(on_process_track, [false, true, true]);
+        assert_eq!(trace.process_uuids.len(), 1);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_record_panic() {
+        start().unwrap();
+        let location = std::panic::Location::caller();
+        panic_hook::record_panic("oh no", Some(location));
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let args: Vec<Vec<Option<schema::debug_annotation::Value>>> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.log_message.is_some())
+            .map(|event| {
+                event
+                    .debug_annotations
+                    .iter()
+                    .map(|arg| arg.value.clone())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            args,
+            [[
+                Some(schema::debug_annotation::Value::StringValue(
+                    "oh no".to_owned()
+                )),
+                Some(schema::debug_annotation::Value::StringValue(
+                    location.to_string()
+                )),
+            ]]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_log_event() {
+        start().unwrap();
+        {
+            scope!("work");
+            for free_bytes in [1024_u64, 512] {
+                log_event!(LogLevel::Warn, "Disk nearly full", free_bytes);
+            }
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let bodies: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.log_message_body)
+            .map(|body| (body.iid.unwrap(), body.body.as_deref().unwrap()))
+            .collect();
+        let logs: Vec<(&str, i32, u64)> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::Instant =>
+                {
+                    let log = event.log_message.unwrap();
+                    let Some(schema::debug_annotation::Value::UintValue(free_bytes)) =
+                        event.debug_annotations[0].value
+                    else {
+                        panic!("Expected a uint argument");
+                    };
+                    assert!(log.source_location_iid.is_some() == SOURCE_LOCATIONS);
+                    Some((
+                        bodies[&log.body_iid.unwrap()],
+                        log.prio.unwrap(),
+                        free_bytes,
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        let warn = schema::log_message::Priority::PrioWarn as i32;
+        assert_eq!(
+            logs,
+            [
+                ("Disk nearly full", warn, 1024),
+                ("Disk nearly full", warn, 512)
+            ]
+        );
+        assert_eq!(bodies.len(), 1);
+
+        // The body must be interned by the packet of the first message, not a later one.
+        let first_log = trace
+            .trace
+            .packet
+            .iter()
+            .position(|packet| {
+                matches!(&packet.data, Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.log_message.is_some())
+            })
+            .unwrap();
+        assert!(
+            trace.trace.packet[..=first_log]
+                .iter()
+                .filter_map(|packet| packet.interned_data.as_ref())
+                .any(|interned| !interned.log_message_body.is_empty())
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_custom_tracks() {
+        start().unwrap();
+
+        {
+            scope!("local");
+            scope!(@track = "GC" "mark");
+            scope!("fetch index");
+            let _dynamic = start_span_dynamic!(format!("fetch {}", 2));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let network = trace.create_track("Network");
+        trace
+            .route_spans("fetch*", network)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+        let gc = trace.create_track("GC");
+
+        let thread_uuid = trace.thread_uuids.values().next().unwrap().0;
+        let tracks: Vec<(schema::track_event::Type, u64)> = track_events(&trace)
+            .into_iter()
+            .map(|event| (event.r#type(), event.track_uuid.unwrap()))
+            .collect();
+        use schema::track_event::Type;
+        assert_eq!(
+            tracks,
+            [
+                (Type::SliceBegin, thread_uuid),
+                (Type::SliceBegin, gc.uuid),
+                (Type::SliceBegin, network.uuid),
+                (Type::SliceBegin, network.uuid),
+                (Type::SliceEnd, network.uuid),
+                (Type::SliceEnd, network.uuid),
+                (Type::SliceEnd, gc.uuid),
+                (Type::SliceEnd, thread_uuid),
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_record_all() {
+        start().unwrap();
+
+        {
+            let outer = start_span!("outer");
+            {
+                scope!("inner");
+                let columns = ["a".to_owned(), "b".to_owned()];
+                for (index, column) in columns.iter().enumerate() {
+                    outer.record_all(&[(column, index.into())]);
+                }
+            }
+            outer.record_all(&[("cache", true.into()), ("table", "users".into())]);
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let annotation_names: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.debug_annotation_names)
+            .map(|name| (name.iid.unwrap(), name.name.as_deref().unwrap()))
+            .collect();
+        use schema::debug_annotation::Value;
+        let begin_annotations: Vec<Vec<(&str, Value)>> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .map(|event| {
+                event
+                    .debug_annotations
+                    .iter()
+                    .map(|annotation| {
+                        let Some(schema::debug_annotation::NameField::NameIid(iid)) =
+                            annotation.name_field
+                        else {
+                            panic!("Annotation names should be interned");
+                        };
+                        (annotation_names[&iid], annotation.value.clone().unwrap())
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            begin_annotations,
+            [
+                vec![
+                    ("a", Value::UintValue(0)),
+                    ("b", Value::UintValue(1)),
+                    ("cache", Value::BoolValue(true)),
+                    ("table", Value::StringValue("users".to_owned())),
+                ],
+                vec![],
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_format_args() {
+        start().unwrap();
+        let (width, height) = (3, 4);
+        {
+            scope!(
+                "resize",
+                size = format_args!("{width}x{height}"),
+                unit = format_args!("px")
+            );
+        }
+
+        let thread = ThreadTraceData::take_current_thread();
+        assert!(
+            !thread
+                .events
+                .iter()
+                .any(|event| matches!(event, Event::String(_)))
+        );
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&thread);
+        let values = annotation_strings(&trace);
+        assert_eq!(values, ["3x4", "px"]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_current_trace_id() {
+        fn recurse(depth: u32) -> u64 {
+            scope!("recurse");
+            if depth == 0 {
+                current_trace_id().unwrap()
+            } else {
+                recurse(depth - 1)
+            }
+        }
+
+        start().unwrap();
+        assert_eq!(current_trace_id(), None);
+        let first = {
+            scope!("request");
+            let id = current_trace_id().unwrap();
+            {
+                scope!("inner");
+                assert_eq!(current_trace_id(), Some(id));
+            }
+            id
+        };
+        assert_eq!(current_trace_id(), None);
+        let second = {
+            scope!("request");
+            current_trace_id().unwrap()
+        };
+        assert_ne!(first, second);
+        // The ID is attached to the outermost span, not to the innermost one from the same call site.
+        let third = recurse(1);
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let trace_id_name = trace.debug_annotation_name_ids["trace_id"];
+        let annotations: Vec<Vec<&DebugAnnotation>> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .map(|event| event.debug_annotations.iter().collect())
+            .collect();
+        let annotation = |id| DebugAnnotation {
+            name_field: Some(schema::debug_annotation::NameField::NameIid(trace_id_name)),
+            value: Some(schema::debug_annotation::Value::UintValue(id)),
+            ..Default::default()
+        };
+        assert_eq!(
+            annotations,
+            [
+                vec![&annotation(first)],
+                vec![],
+                vec![&annotation(second)],
+                vec![&annotation(third)],
+                vec![],
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_debug_display_args() {
+        start().unwrap();
+        {
+            scope!(
+                "handle",
+                request = DebugArg(Some((7, "get"))),
+                addr = DisplayArg(std::net::Ipv4Addr::LOCALHOST)
+            );
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let values = annotation_values(&trace);
+        let expected = ["Some((7, \"get\"))", "127.0.0.1"]
+            .map(|value| schema::debug_annotation::Value::StringValue(value.to_owned()));
+        assert_eq!(values, expected.iter().collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_display_arg_that_records_spans() {
+        struct Traced;
+
+        impl std::fmt::Display for Traced {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                scope!("format", kind = "nested");
+                f.write_str("traced")
+            }
+        }
+
+        start().unwrap();
+        {
+            scope!("handle", value = DisplayArg(Traced));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        // The span recorded while formatting is discarded, since it started within the arguments.
+        assert!(!trace.name_ids.contains_key("format"));
+        let values = annotation_values(&trace);
+        assert_eq!(
+            values,
+            [&schema::debug_annotation::Value::StringValue(
+                "traced".to_owned()
+            )]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_lazy_args() {
+        start().unwrap();
+        let calls = Cell::new(0);
+        let detail = lazy(|| {
+            calls.set(calls.get() + 1);
+            format!("{:?}", [1, 2])
+        });
+        assert_eq!(calls.get(), 0);
+        {
+            scope!("sort", detail, sizes = ArrayArg([lazy(|| 3_u64)]));
+        }
+        assert_eq!(calls.get(), 1);
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let values: Vec<&schema::DebugAnnotation> = track_events(&trace)
+            .into_iter()
+            .flat_map(|event| &event.debug_annotations)
+            .collect();
+        assert_eq!(
+            values[0].value,
+            Some(schema::debug_annotation::Value::StringValue(
+                "[1, 2]".to_owned()
+            ))
+        );
+        assert_eq!(
+            values[1].array_values[0].value,
+            Some(schema::debug_annotation::Value::UintValue(3))
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_byte_args() {
+        start().unwrap();
+        let hash = [0xde_u8, 0xad, 0x01];
+        {
+            scope!(
+                "hash",
+                hash = &hash[..],
+                key = vec![0x0f_u8],
+                empty = &[][..]
+            );
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let values = annotation_values(&trace);
+        let expected = ["dead01", "0f", ""]
+            .map(|value| schema::debug_annotation::Value::StringValue(value.to_owned()));
+        assert_eq!(values, expected.iter().collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_path_args() {
+        start().unwrap();
+        let path = Path::new("src/lib.rs");
+        {
+            scope!(
+                "compile",
+                path = path,
+                output = path.with_extension("o"),
+                name = path.file_name().unwrap()
+            );
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let values = annotation_values(&trace);
+        let expected = ["src/lib.rs", "src/lib.o", "lib.rs"]
+            .map(|value| schema::debug_annotation::Value::StringValue(value.to_owned()));
+        assert_eq!(values, expected.iter().collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_with_span() {
+        start().unwrap();
+        let squares: Vec<u64> = [2_u64, 3]
+            .iter()
+            .map(|value| with_span!("square", value = *value; value * value))
+            .collect();
+        assert_eq!(squares, [4, 9]);
+        assert_eq!(with_span("answer", || 42), 42);
+        let result = std::panic::catch_unwind(|| with_span("panics", || panic!("expected")));
+        assert!(result.is_err());
+
+        let thread_data = ThreadTraceData::take_current_thread();
+        assert!(thread_data.validate().is_ok());
+        let stats = compare::span_stats(
+            &TraceBuilder::new()
+                .unwrap()
+                .process_thread_data(&thread_data)
+                .encode_to_vec(),
+        )
+        .unwrap();
+        let counts: Vec<(&str, u64)> = stats
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats.count))
+            .collect();
+        assert_eq!(counts, [("answer", 1), ("panics", 1), ("square", 2)]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_end_returns_duration() {
+        start().unwrap();
+        let guard = start_span!("timed");
+        std::thread::sleep(Duration::from_millis(1));
+        let duration = guard.end().unwrap();
+        assert!(duration >= Duration::from_millis(1));
+
+        let rows = 2_u64;
+        let guard = start_span!("with end args", @end rows);
+        assert!(guard.end().is_some());
+
+        let stats = compare::span_stats(
+            &TraceBuilder::new()
+                .unwrap()
+                .process_thread_data(&ThreadTraceData::take_current_thread())
+                .encode_to_vec(),
+        )
+        .unwrap();
+        assert_eq!(stats["timed"].count, 1);
+        assert_eq!(stats["timed"].total_nanos, duration.as_nanos() as u64);
+        assert_eq!(stats["with end args"].count, 1);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_record_arg() {
+        start().unwrap();
+
+        {
+            let guard = start_span!("lookup");
+            {
+                let inner = start_span!("inner");
+                inner.record_arg("rows", 3_u64);
+            }
+            guard.record_arg("hit", false);
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        use schema::debug_annotation::Value;
+        let hit_id = trace.debug_annotation_name_ids["hit"];
+        let rows_id = trace.debug_annotation_name_ids["rows"];
+        let annotations: Vec<(schema::track_event::Type, Vec<(u64, Value)>)> = track_events(&trace)
+            .into_iter()
+            .map(|event| {
+                (
+                    event.r#type(),
+                    event
+                        .debug_annotations
+                        .iter()
+                        .map(|annotation| {
+                            let Some(schema::debug_annotation::NameField::NameIid(iid)) =
+                                annotation.name_field
+                            else {
+                                panic!("Annotation names should be interned");
+                            };
+                            (iid, annotation.value.clone().unwrap())
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        use schema::track_event::Type;
+        assert_eq!(
+            annotations,
+            [
+                (Type::SliceBegin, vec![]),
+                (Type::SliceBegin, vec![]),
+                (Type::SliceEnd, vec![(rows_id, Value::UintValue(3))]),
+                (Type::SliceEnd, vec![(hit_id, Value::BoolValue(false))]),
+            ]
+        );
+        // The name of an annotation on the last packet must still be written.
+        assert!(
+            trace
+                .trace
+                .packet
+                .iter()
+                .filter_map(|packet| packet.interned_data.as_ref())
+                .flat_map(|interned| &interned.debug_annotation_names)
+                .any(|name| name.name.as_deref() == Some("hit"))
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_unit_args() {
+        start().unwrap();
+        {
+            scope!(
+                "units",
+                small = Bytes(12),
+                large = Bytes(3 << 29),
+                short = Micros(250),
+                long = Micros(2_500),
+                full = Percent(87.5),
+                tiny = std::time::Duration::from_nanos(40),
+                elapsed = std::time::Duration::from_micros(12_300)
+            );
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        use schema::debug_annotation::Value;
+        let names: HashMap<u64, &str> = trace
+            .debug_annotation_name_ids
+            .iter()
+            .map(|(name, id)| (*id, name.as_ref()))
+            .collect();
+        let args: Vec<(&str, &Value)> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .flat_map(|event| &event.debug_annotations)
+            .map(|annotation| {
+                let Some(schema::debug_annotation::NameField::NameIid(id)) = annotation.name_field
+                else {
+                    panic!("Annotation names should be interned");
+                };
+                (names[&id], annotation.value.as_ref().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            args,
+            [
+                ("small (B)", &Value::UintValue(12)),
+                ("large (B)", &Value::UintValue(3 << 29)),
+                ("short (µs)", &Value::UintValue(250)),
+                ("long (µs)", &Value::UintValue(2_500)),
+                ("full (%)", &Value::DoubleValue(87.5)),
+                ("tiny (ns)", &Value::UintValue(40)),
+                ("elapsed (ns)", &Value::UintValue(12_300_000)),
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_validate() {
+        start().unwrap();
+        {
+            scope!("balanced", name = "a string");
+        }
+        assert_eq!(
+            ThreadTraceData::take_current_thread().validate(),
+            ValidationReport::default()
+        );
+
+        std::mem::forget(start_span!("leaked"));
+        let mut thread = ThreadTraceData::take_current_thread();
+        thread.events.push(Event::Str {
+            offset: thread.strings.len(),
+            len: 1,
+        });
+        let report = thread.validate();
+        assert_eq!(
+            report.issues,
+            [
+                ValidationIssue::UnclosedSpan {
+                    name: "leaked",
+                    index: 0
+                },
+                ValidationIssue::InvalidString { index: 2 }
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_dynamic_args() {
+        start().unwrap();
+
+        for id in ["1", "2"] {
+            let headers = vec![
+                ("accept".to_owned(), "*/*"),
+                ("x-request-id".to_owned(), id),
+            ];
+            scope!("request", headers = DynamicArgs(headers));
+        }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let annotation_names: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.debug_annotation_names)
+            .map(|name| (name.iid.unwrap(), name.name.as_deref().unwrap()))
+            .collect();
+        assert_eq!(annotation_names.len(), 3);
+
+        use schema::debug_annotation::Value;
+        let entries: Vec<Vec<(&str, Value)>> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::SliceBegin =>
+                {
+                    let [annotation] = event.debug_annotations.as_slice() else {
+                        panic!("Expected a single annotation");
+                    };
+                    Some(
+                        annotation
+                            .dict_entries
+                            .iter()
+                            .map(|entry| {
+                                let Some(schema::debug_annotation::NameField::NameIid(iid)) =
+                                    entry.name_field
+                                else {
+                                    panic!("Dynamic argument names should be interned");
+                                };
+                                (annotation_names[&iid], entry.value.clone().unwrap())
+                            })
+                            .collect(),
+                    )
+                }
+                _ => None,
+            })
+            .collect();
+        let string = |s: &str| Value::StringValue(s.to_owned());
+        assert_eq!(
+            entries,
+            [
+                [("accept", string("*/*")), ("x-request-id", string("1"))],
+                [("accept", string("*/*")), ("x-request-id", string("2"))],
+            ]
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_structured_args() {
+        start().unwrap();
+        scope!(
+            "request",
+            request = StructArg(|entries| {
+                entries
+                    .entry("method", "GET")
+                    .entry(
+                        "shards",
+                        ArrayArg([ArrayArg([1_u64, 2]), ArrayArg([3_u64, 4])]),
+                    )
+                    .entry("headers", DynamicArgs([("accept", "*/*")]));
+            })
+        );
+
+        let mut bytes = Vec::new();
+        ThreadTraceData::take_current_thread()
+            .serialize(&mut bytes)
+            .unwrap();
+        let thread_data = ThreadTraceData::deserialize(&mut bytes.as_slice()).unwrap();
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&thread_data);
+
+        let annotation_names: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .flat_map(|interned| &interned.debug_annotation_names)
+            .map(|name| (name.iid.unwrap(), name.name.as_deref().unwrap()))
+            .collect();
+        fn render(annotation: &DebugAnnotation, names: &HashMap<u64, &str>) -> String {
+            use schema::debug_annotation::Value;
+            if !annotation.dict_entries.is_empty() {
+                let entries: Vec<String> = annotation
+                    .dict_entries
+                    .iter()
+                    .map(|entry| {
+                        let Some(schema::debug_annotation::NameField::NameIid(iid)) =
+                            entry.name_field
+                        else {
+                            panic!("Entry names should be interned");
+                        };
+                        format!("{}: {}", names[&iid], render(entry, names))
+                    })
+                    .collect();
+                return format!("{{{}}}", entries.join(", "));
+            }
+            if !annotation.array_values.is_empty() {
+                let values: Vec<String> = annotation
+                    .array_values
+                    .iter()
+                    .map(|value| render(value, names))
+                    .collect();
+                return format!("[{}]", values.join(", "));
+            }
+            match annotation.value.as_ref().unwrap() {
+                Value::StringValue(value) => value.clone(),
+                Value::UintValue(value) => value.to_string(),
+                other => panic!("Unexpected value {other:?}"),
+            }
+        }
+
+        let rendered: Vec<String> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .map(|event| render(&event.debug_annotations[0], &annotation_names))
+            .collect();
+        assert_eq!(
+            rendered,
+            ["{method: GET, shards: [[1, 2], [3, 4]], headers: {accept: */*}}"]
+        );
+    }
 
     #[cfg(feature = "enable")]
     #[test]
-    fn test_basic_usage() {
+    fn test_spawn_flow() {
+        start().unwrap();
+
+        let child = {
+            scope!("parent");
+            spawn(|| {
+                scope!("child");
+                ThreadTraceData::take_current_thread()
+            })
+        };
+        let child = child.join().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .process_thread_data(&ThreadTraceData::take_current_thread())
+            .process_thread_data(&child);
+
+        let begin_flow_ids: Vec<&[u64]> = track_events(&trace)
+            .into_iter()
+            .filter(|event| event.r#type() == schema::track_event::Type::SliceBegin)
+            .map(|event| event.flow_ids.as_slice())
+            .collect();
+        let [parent, child] = begin_flow_ids.as_slice() else {
+            panic!("Expected two spans, got {begin_flow_ids:?}");
+        };
+        assert_eq!(parent.len(), 1);
+        assert_eq!(parent, child);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_public_interning() {
         start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let name_id = trace.intern_event_name("custom");
+        assert_eq!(trace.intern_event_name("custom"), name_id);
+        let annotation_id = trace.intern_annotation_name("key");
+
         {
-            scope!(
-                "foo",
-                value = 1_u64,
-                foo = 2_i64,
-                baz = "baz",
-                baz_owned = "baz".to_owned()
-            );
-            scope!("bar");
+            scope!("custom", key = 1_u64);
         }
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
 
-        let num_events = EVENTS.with_borrow(|events| events.len());
-        assert_eq!(num_events, 12);
+        let interned: Vec<&schema::InternedData> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| packet.interned_data.as_ref())
+            .collect();
+        let event_names: Vec<_> = interned.iter().flat_map(|i| &i.event_names).collect();
+        assert_eq!(event_names.len(), 1);
+        assert_eq!(event_names[0].iid, Some(name_id));
+        let annotation_names: Vec<_> = interned
+            .iter()
+            .flat_map(|i| &i.debug_annotation_names)
+            .collect();
+        assert_eq!(annotation_names.len(), 1);
+        assert_eq!(annotation_names[0].iid, Some(annotation_id));
+    }
 
-        TraceBuilder::new()
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_merge() {
+        start().unwrap();
+
+        let mut main = TraceBuilder::new().unwrap();
+        {
+            scope!("main");
+        }
+        main.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let other = std::thread::spawn(|| {
+            let mut other = TraceBuilder::new().unwrap();
+            {
+                scope!("other");
+            }
+            other.process_thread_data(&ThreadTraceData::take_current_thread());
+            other
+        })
+        .join()
+        .unwrap();
+
+        let other_sequence_id = other.sequence_id;
+        let num_packets = main.trace.packet.len() + other.trace.packet.len();
+        main.merge(other);
+        assert_eq!(main.trace.packet.len(), num_packets);
+        assert_eq!(main.thread_uuids.len(), 2);
+        assert!(main.trace.packet.iter().any(|packet| {
+            packet.optional_trusted_packet_sequence_id
+                == Some(
+                    schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                        other_sequence_id,
+                    ),
+                )
+        }));
+    }
+
+    #[test]
+    fn test_unique_ids() {
+        let ids: Vec<u64> = (0..100).map(|_| unique_id()).collect();
+        let distinct: std::collections::HashSet<u64> = ids.iter().copied().collect();
+        assert_eq!(distinct.len(), ids.len());
+        assert!(!ids.contains(&0));
+
+        assert_ne!(next_sequence_id(), next_sequence_id());
+    }
+
+    #[cfg(all(not(feature = "fastant"), target_os = "linux"))]
+    #[test]
+    fn test_coarse_realtime() {
+        let coarse = os::coarse_realtime_nanos().unwrap();
+        let precise = os::clock_nanos(BuiltinClock::Realtime).unwrap();
+        // The coarse clock should lag the precise clock by at most a few milliseconds.
+        assert!(precise.abs_diff(coarse) < 100_000_000);
+    }
+
+    #[cfg(all(feature = "enable", target_os = "linux"))]
+    #[test]
+    fn test_set_clock() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.set_clock(BuiltinClock::Boottime).unwrap();
+        {
+            scope!("boot");
+        }
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let boottime = os::clock_nanos(BuiltinClock::Boottime).unwrap();
+        let event = trace
+            .trace
+            .packet
+            .iter()
+            .find(|packet| matches!(packet.data, Some(schema::trace_packet::Data::TrackEvent(_))))
+            .unwrap();
+        assert_eq!(
+            event.timestamp_clock_id,
+            Some(BuiltinClock::Boottime as u32)
+        );
+        // The span was recorded within the last minute in boot time.
+        let timestamp = event.timestamp.unwrap();
+        assert!(timestamp <= boottime && boottime - timestamp < 60_000_000_000);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_sampling_suppresses_nested_spans() {
+        // Changing the global sample interval would affect other tests, so we use the per-thread
+        // sampling state directly.
+        assert!(!sample_span_slow(2));
+        assert!(!sample_span_slow(2));
+        end_suppressed_span();
+        end_suppressed_span();
+        assert!(sample_span_slow(2));
+        assert!(!sample_span_slow(2));
+        end_suppressed_span();
+        assert!(sample_span_slow(2));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_with_options() {
+        start().unwrap();
+
+        let mut options = TraceBuilderOptions::new();
+        options
+            .set_max_sensitivity(Sensitivity::Public, Redaction::Drop)
+            .set_footer(true);
+
+        for _ in 0..2 {
+            {
+                scope!("public");
+                scope!(@secret "secret");
+            }
+            let mut trace = TraceBuilder::with_options(&options).unwrap();
+            trace.process_thread_data(&ThreadTraceData::take_current_thread());
+            let stats = verify_footer(&trace.encode_to_vec()).unwrap();
+            assert_eq!(stats.span_count, 1);
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_control() {
+        start().unwrap();
+
+        let control = control();
+        let mut options = TraceBuilderOptions::new();
+        options.set_footer(true);
+        let path = std::env::temp_dir().join(format!("control-{}.pftrace", std::process::id()));
+        control.set_trace_options(options).set_output_path(&path);
+
+        {
+            scope!("controlled");
+        }
+        let mut trace = control.trace_builder().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        control.write(&trace).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(verify_footer(&bytes).unwrap().span_count, 1);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_write_sharded() {
+        start().unwrap();
+        let request = start_async_span!("sharded request");
+        let worker = std::thread::Builder::new()
+            .name("shard worker".to_owned())
+            .spawn(|| {
+                scope!("worker");
+                ThreadTraceData::take_current_thread()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        {
+            scope!("main");
+            drop(request);
+        }
+        let main = ThreadTraceData::take_current_thread();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_all([main, worker]);
+        let dir = std::env::temp_dir().join(format!("shards-{}", std::process::id()));
+        let shards = trace.write_sharded(&dir).unwrap();
+
+        let names: Vec<Option<&str>> = shards.iter().map(|shard| shard.name.as_deref()).collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&Some("shard worker")));
+        let process_shard = shards.iter().find(|shard| shard.tid.is_none()).unwrap();
+        assert!(process_shard.file_name.starts_with("process-"));
+
+        for shard in &shards {
+            let bytes = std::fs::read(dir.join(&shard.file_name)).unwrap();
+            let decoded = schema::Trace::decode(bytes.as_slice()).unwrap();
+            assert_eq!(decoded.packet.len(), shard.num_packets);
+            let threads: Vec<&schema::ThreadDescriptor> = decoded
+                .packet
+                .iter()
+                .filter_map(|packet| match &packet.data {
+                    Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => {
+                        descriptor.thread.as_ref()
+                    }
+                    _ => None,
+                })
+                .collect();
+            let expected_threads = usize::from(shard.tid.is_some());
+            assert_eq!(threads.len(), expected_threads);
+            assert!(decoded.packet.iter().any(|packet| matches!(
+                &packet.data,
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                    if descriptor.process.is_some()
+            )));
+        }
+        let manifest = std::fs::read_to_string(dir.join("manifest.json")).unwrap();
+        assert!(manifest.contains("\"name\": \"shard worker\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_write_error() {
+        start().unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("missing-{}", std::process::id()))
+            .join("trace.pftrace");
+        let error = TraceBuilder::new()
+            .unwrap()
+            .write_to_file(&path)
+            .unwrap_err();
+        let Error::Write {
+            path: error_path, ..
+        } = &error
+        else {
+            panic!("Expected a write error, got {error:?}");
+        };
+        assert_eq!(*error_path, path);
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        let mut bytes = Vec::new();
+        TraceBuilder::new().unwrap().write_to(&mut bytes).unwrap();
+        assert!(!bytes.is_empty());
+
+        struct Failing;
+        impl TraceOutput for Failing {
+            fn write_trace(&mut self, _trace: &[u8]) -> Result<(), Error> {
+                Err(Error::Output("upload failed".into()))
+            }
+        }
+        let error = TraceBuilder::new()
             .unwrap()
+            .write_to(&mut Failing)
+            .unwrap_err();
+        assert!(matches!(error, Error::Output(_)));
+        assert_eq!(
+            std::error::Error::source(&error).unwrap().to_string(),
+            "upload failed"
+        );
+
+        assert!(matches!(
+            TraceBuilder::import(b"not a trace"),
+            Err(Error::InvalidTrace(_))
+        ));
+    }
+
+    #[cfg(all(feature = "enable", feature = "span-stack"))]
+    #[test]
+    fn test_span_stack() {
+        start().unwrap();
+
+        assert_eq!(current_span_name(), None);
+        {
+            scope!("outer");
+            {
+                scope!("inner");
+                assert_eq!(current_span_name(), Some("inner"));
+                assert_eq!(current_span_depth(), 2);
+            }
+            assert_eq!(current_span_name(), Some("outer"));
+        }
+        assert_eq!(current_span_depth(), 0);
+
+        for id in 0..2 {
+            let _guard = start_span_dynamic!(format!("job {id}"));
+            assert_eq!(current_span_name(), Some(format!("job {id}").as_str()));
+        }
+
+        // Spans started by functions are named at runtime rather than by their shared call site.
+        assert_eq!(with_span("sum", current_span_name), Some("sum"));
+        let job = format!("job {}", 3);
+        assert_eq!(measure(&job, current_span_name), Some("job 3"));
+        ThreadTraceData::take_current_thread();
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_duration_assertions() {
+        start().unwrap();
+        for _ in 0..3 {
+            scope!("budgeted");
+        }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let trace = trace.encode_to_vec();
+
+        assert_span_under!(trace, "budgeted", 10s);
+        assert_total_under!(trace, "budgeted", std::time::Duration::from_secs(10));
+        let over_budget = std::panic::catch_unwind(|| assert_total_under!(trace, "budgeted", 0ns));
+        assert!(over_budget.is_err());
+        let missing = std::panic::catch_unwind(|| assert_span_under!(trace, "missing", 10s));
+        assert!(missing.is_err());
+
+        assert_eq!(
+            parse_duration("1.5ms"),
+            std::time::Duration::from_micros(1500)
+        );
+        assert_eq!(
+            parse_duration("2_000us"),
+            std::time::Duration::from_millis(2)
+        );
+        assert!(std::panic::catch_unwind(|| parse_duration("500")).is_err());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_compare_traces() {
+        start().unwrap();
+
+        let mut traces = Vec::new();
+        for names in [["same", "removed"], ["same", "added"]] {
+            for name in names {
+                scope!("placeholder", name = name);
+            }
+            for _ in 0..2 {
+                scope!("repeated");
+            }
+            let mut trace = TraceBuilder::new().unwrap();
+            trace.process_thread_data(&ThreadTraceData::take_current_thread());
+            traces.push(trace.encode_to_vec());
+        }
+        {
+            scope!("extra");
+        }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let mut after = traces.pop().unwrap();
+        after.extend(trace.encode_to_vec());
+
+        let comparison = compare_traces(&traces[0], &after).unwrap();
+        let summary: Vec<(&str, i64, bool, bool)> = comparison
+            .spans
+            .iter()
+            .map(|span| {
+                (
+                    span.name.as_str(),
+                    span.count_delta(),
+                    span.before.is_some(),
+                    span.after.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("extra", 1, false, true),
+                ("placeholder", 0, true, true),
+                ("repeated", 0, true, true)
+            ]
+        );
+        assert!(comparison.to_string().contains("extra"));
+        let reversed = compare_traces(&after, &traces[0]).unwrap();
+        assert!(reversed.to_string().contains("(removed)"));
+        assert_eq!(reversed.spans[0].count_delta(), -1);
+        assert!(compare_traces(&[0xff], &after).is_err());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_signal_safe_instant() {
+        start().unwrap();
+
+        signal_safe_instant!("signal");
+        signal_safe_instant!("signal");
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_signal_events();
+        let num_instants = trace
+            .trace
+            .packet
+            .iter()
+            .filter(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                    event.r#type() == schema::track_event::Type::Instant
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(num_instants, 2);
+        assert!(trace.name_ids.contains_key("signal"));
+        assert_eq!(trace.thread_uuids.len(), 1);
+
+        // Events are only added to a trace once.
+        let num_packets = trace.trace.packet.len();
+        trace.process_signal_events();
+        assert_eq!(trace.trace.packet.len(), num_packets);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_zero_based_timestamps() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        {
+            scope!("first");
+        }
+        trace
             .process_thread_data(&ThreadTraceData::take_current_thread())
-            .encode_to_vec();
+            .set_zero_based_timestamps(true);
+
+        let decoded = schema::Trace::decode(trace.encode_to_vec().as_slice()).unwrap();
+        let timestamps: Vec<u64> = decoded
+            .packet
+            .iter()
+            .filter_map(|packet| packet.timestamp)
+            .collect();
+        assert_eq!(timestamps.len(), 2);
+        assert_eq!(timestamps[0], 0);
+        assert!(timestamps[1] < 60_000_000_000);
+        assert!(!decoded.packet.iter().any(|packet| matches!(
+            packet.data,
+            Some(schema::trace_packet::Data::ClockSnapshot(_))
+        )));
     }
 
-    #[cfg(not(feature = "enable"))]
+    #[cfg(feature = "enable")]
     #[test]
-    fn test_no_execution_when_disabled() {
-        fn do_not_run() -> u32 {
-            panic!("This should not be called");
+    fn test_align_with() {
+        start().unwrap();
+
+        let anchor = ClockAnchor {
+            local_nanos: 5_000_000_000,
+            ..export_clock_anchor()
+        };
+        {
+            scope!("aligned");
         }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .align_with(anchor)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
 
-        scope!("foo", value = do_not_run());
+        let decoded = schema::Trace::decode(trace.encode_to_vec().as_slice()).unwrap();
+        let timestamps: Vec<u64> = decoded
+            .packet
+            .iter()
+            .filter_map(|packet| packet.timestamp)
+            .collect();
+        assert_eq!(timestamps.len(), 2);
+        assert!(timestamps[0] >= anchor.local_nanos);
+        assert!(timestamps[1] < anchor.local_nanos + 60_000_000_000);
+        assert!(!decoded.packet.iter().any(|packet| matches!(
+            packet.data,
+            Some(schema::trace_packet::Data::ClockSnapshot(_))
+        )));
+
+        // The other timebase can be ahead of the trace's clock by more than the clock's own value.
+        let anchor = ClockAnchor {
+            local_nanos: 1 << 62,
+            ..export_clock_anchor()
+        };
+        {
+            scope!("ahead");
+        }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .align_with(anchor)
+            .process_thread_data(&ThreadTraceData::take_current_thread());
+        let decoded = schema::Trace::decode(trace.encode_to_vec().as_slice()).unwrap();
+        let timestamps: Vec<u64> = decoded
+            .packet
+            .iter()
+            .filter_map(|packet| packet.timestamp)
+            .collect();
+        assert_eq!(timestamps.len(), 2);
+        assert!(timestamps[0] >= anchor.local_nanos);
     }
 
-    /// Try different lengths of string slices to make sure we're able to split them into parts and
-    /// join them back together again.
-    #[test]
-    fn str_encoding() {
-        for l in 0..100 {
-            let string: String = (0..l)
-                .map(|i| char::from_u32('A' as u32 + i).unwrap())
-                .collect();
-            let str_slice = string.as_str();
-            RecordArg::record_arg(str_slice);
-            let events = EVENTS.take();
-            let mut events = events.iter();
-            match convert_next_arg(&mut events) {
-                schema::debug_annotation::Value::StringValue(actual) => {
-                    assert_eq!(actual, string);
-                }
-                other => panic!("Unexpected event: {other:?}"),
-            }
-            assert!(events.next().is_none());
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_call_sites() {
+        start().unwrap();
+
+        for _ in 0..2 {
+            scope!("repeated");
         }
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.intern_call_sites();
+        let num_interned_names = trace.name_ids.len();
+        let thread_data = ThreadTraceData::take_current_thread();
+        let ids: Vec<u32> = thread_data
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::StartSpan(id) | Event::EndSpan(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids.len(), 4);
+        assert!(ids.iter().all(|id| *id == ids[0]));
+
+        // The span's name was interned up-front, so processing the events shouldn't intern it again.
+        trace.process_thread_data(&thread_data);
+        assert_eq!(trace.name_ids.len(), num_interned_names);
+        assert!(trace.name_ids.contains_key("repeated"));
     }
 
     #[cfg(feature = "enable")]
@@ -962,13 +8470,13 @@ mod tests {
         let mut trace = TraceBuilder::new().unwrap();
 
         // Create different types of counter tracks
-        let mut cpu_counter =
+        let cpu_counter =
             trace.create_counter_track("CPU Usage", CounterUnit::Custom("%".to_string()), 1, false);
 
-        let mut memory_counter =
+        let memory_counter =
             trace.create_counter_track("Memory", CounterUnit::SizeBytes, 1024 * 1024, false);
 
-        let mut count_counter = trace.create_counter_track(
+        let count_counter = trace.create_counter_track(
             "Events",
             CounterUnit::Count,
             1,
@@ -986,12 +8494,729 @@ mod tests {
         memory_counter.record_i64(t2, 2048);
         count_counter.record_i64(t2, 50);
 
+        cpu_counter.record_many_f64(&[(t1, 1.0), (t2, 2.0)]);
+        memory_counter.record_many_i64(&[(t1, 1), (t2, 2)]);
+
         // Process the thread data to convert events to trace packets
         let thread_data = ThreadTraceData::take_current_thread();
+        assert_eq!(thread_data.events.len(), 10 * EVENTS_PER_COUNTER);
         trace.process_thread_data(&thread_data);
 
         // Verify we can encode without errors
         let bytes = trace.encode_to_vec();
         assert!(!bytes.is_empty());
     }
+
+    #[cfg(all(feature = "enable", feature = "raw-schema"))]
+    #[test]
+    fn test_from_packets() {
+        start().unwrap();
+        {
+            scope!("work");
+        }
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let num_packets = trace.packets().len();
+        let sequence_id = Some(
+            schema::trace_packet::OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+                trace.sequence_id,
+            ),
+        );
+
+        // The rebuilt trace has only the given packets, not a second clock snapshot of its own.
+        let rebuilt = TraceBuilder::from_packets(trace.into_trace().packet).unwrap();
+        let packets = rebuilt.packets();
+        assert_eq!(packets.len(), num_packets);
+        assert!(
+            packets
+                .iter()
+                .all(|packet| packet.optional_trusted_packet_sequence_id == sequence_id)
+        );
+        let stats = compare::span_stats(&rebuilt.encode_to_vec()).unwrap();
+        assert_eq!(stats["work"].count, 1);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_import_and_filter() {
+        start().unwrap();
+        {
+            scope!("outer");
+            scope!("noise");
+            scope!("inner");
+        }
+        let worker = std::thread::Builder::new()
+            .name("filtered worker".to_owned())
+            .spawn(|| {
+                scope!("outer");
+                ThreadTraceData::take_current_thread()
+            })
+            .unwrap();
+        let worker_data = worker.join().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .process_thread_data(&ThreadTraceData::take_current_thread())
+            .process_thread_data(&worker_data);
+        let bytes = trace.encode_to_vec();
+
+        let mut imported = TraceBuilder::import(&bytes).unwrap();
+        imported
+            .retain_spans(|name| name != "noise")
+            .retain_threads(|_, _, name| name != Some("filtered worker"));
+        let counts: Vec<(String, u64)> = compare::span_stats(&imported.encode_to_vec())
+            .unwrap()
+            .into_iter()
+            .map(|(name, stats)| (name, stats.count))
+            .collect();
+        assert_eq!(counts, [("inner".to_owned(), 1), ("outer".to_owned(), 1)]);
+
+        assert!(TraceBuilder::import(b"not a trace").is_err());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_simulated_time() {
+        start().unwrap();
+        let outer = start_span_at!(1_000, "simulation");
+        let step = start_span_at!(1_500, "step", entity = 7_u64);
+        step.end_at(2_500);
+        let unfinished = start_span_at!(3_000, "unfinished");
+        drop(unfinished);
+        outer.end_at(4_000);
+
+        let thread_data = ThreadTraceData::take_current_thread();
+        assert!(thread_data.validate().is_ok());
+        let mut bytes = Vec::new();
+        thread_data.serialize(&mut bytes).unwrap();
+        let thread_data = ThreadTraceData::deserialize(&mut bytes.as_slice()).unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&thread_data);
+        let timestamps: Vec<u64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter(|packet| matches!(packet.data, Some(schema::trace_packet::Data::TrackEvent(_))))
+            .map(|packet| packet.timestamp.unwrap())
+            .collect();
+        assert_eq!(timestamps, [1_000, 1_500, 2_500, 3_000, 3_000, 4_000]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_counter_macro() {
+        start().unwrap();
+        for depth in [3_usize, 5] {
+            counter!("test queue depth", depth);
+        }
+        counter!("test load", 0.5);
+        counter!("test bytes", u64::MAX);
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        let track_names: HashMap<u64, &str> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(TrackDescriptor {
+                    uuid: Some(uuid),
+                    static_or_dynamic_name:
+                        Some(schema::track_descriptor::StaticOrDynamicName::Name(name)),
+                    counter: Some(_),
+                    ..
+                })) => Some((*uuid, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        let values: Vec<(&str, schema::track_event::CounterValueField)> = track_events(&trace)
+            .into_iter()
+            .filter_map(|event| Some((track_names[&event.track_uuid?], event.counter_value_field?)))
+            .collect();
+        use schema::track_event::CounterValueField;
+        assert_eq!(
+            values,
+            [
+                ("test queue depth", CounterValueField::CounterValue(3)),
+                ("test queue depth", CounterValueField::CounterValue(5)),
+                ("test load", CounterValueField::DoubleCounterValue(0.5)),
+                ("test bytes", CounterValueField::CounterValue(i64::MAX)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_noop_builder() {
+        {
+            scope!("ignored");
+        }
+        let mut trace = TraceBuilder::build(&TraceBuilderOptions::new(), true);
+        let num_packets = trace.trace.packet.len();
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+        assert_eq!(trace.trace.packet.len(), num_packets);
+
+        let path = std::env::temp_dir().join(format!("noop-{}.pftrace", std::process::id()));
+        trace.write_to_file(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_counter_track_options() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let bytes_read = trace.create_counter_track("Bytes read", CounterUnit::SizeBytes, 1, true);
+        trace
+            .set_counter_y_axis_share_key(bytes_read, "io")
+            .record_initial_counter_value(bytes_read, 4096);
+        bytes_read.record_i64(time(), 512);
+        trace.process_thread_data(&ThreadTraceData::take_current_thread());
+
+        let counter = trace
+            .trace
+            .packet
+            .iter()
+            .find_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                    if descriptor.uuid == Some(bytes_read.uuid) =>
+                {
+                    descriptor.counter.clone()
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(counter.y_axis_share_key.as_deref(), Some("io"));
+        assert_eq!(counter.is_incremental, Some(true));
+
+        let values: Vec<schema::track_event::CounterValueField> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.track_uuid == Some(bytes_read.uuid) =>
+                {
+                    event.counter_value_field
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            values,
+            [
+                schema::track_event::CounterValueField::CounterValue(4096),
+                schema::track_event::CounterValueField::CounterValue(512),
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_shared_counter_track() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let counter = trace.create_counter_track("Queue", CounterUnit::Count, 1, false);
+        let threads: Vec<ThreadTraceData> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..3)
+                .map(|i| {
+                    let counter = &counter;
+                    scope.spawn(move || {
+                        counter.record_i64(time(), i);
+                        ThreadTraceData::take_current_thread()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        trace.process_all(threads);
+
+        let mut values: Vec<i64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.track_uuid == Some(counter.uuid) =>
+                {
+                    match event.counter_value_field {
+                        Some(schema::track_event::CounterValueField::CounterValue(value)) => {
+                            Some(value)
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        values.sort_unstable();
+        assert_eq!(values, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_current_thread_shrink_to_fit() {
+        start().unwrap();
+        ThreadTraceData::take_current_thread();
+        current_thread_reserve(1000);
+        {
+            scope!("kept");
+        }
+        let released = current_thread_shrink_to_fit();
+        assert!(released >= (1000 - EVENTS_PER_SPAN) * size_of::<Event>());
+        assert_eq!(current_thread_shrink_to_fit(), 0);
+        assert_eq!(
+            ThreadTraceData::take_current_thread().events.len(),
+            EVENTS_PER_SPAN
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_thread_lifecycle_events() {
+        start().unwrap();
+        let (first, mut last) = std::thread::Builder::new()
+            .name("lifecycle".to_owned())
+            .spawn(|| {
+                scope!("first");
+                let first = ThreadTraceData::take_current_thread();
+                scope!("last");
+                (first, ThreadTraceData::take_current_thread())
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(first.thread_started);
+        assert!(!last.thread_started);
+        // As if the data had been stashed when the thread exited.
+        last.thread_exited = true;
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace
+            .set_thread_lifecycle_events(true)
+            .process_all([first, last]);
+        let name_ids = trace.name_ids.clone();
+        let process_tracks: Vec<u64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                    if descriptor.process.is_some() =>
+                {
+                    descriptor.uuid
+                }
+                _ => None,
+            })
+            .collect();
+        let lifecycle: Vec<(u64, Option<schema::debug_annotation::Value>)> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.r#type() == schema::track_event::Type::Instant =>
+                {
+                    assert!(process_tracks.contains(&event.track_uuid.unwrap()));
+                    match event.name_field {
+                        Some(schema::track_event::NameField::NameIid(id)) => {
+                            Some((id, event.debug_annotations[0].value.clone()))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        let thread_name = Some(schema::debug_annotation::Value::StringValue(
+            "lifecycle".to_owned(),
+        ));
+        assert_eq!(
+            lifecycle,
+            [
+                (name_ids[THREAD_STARTED_EVENT_NAME], thread_name.clone()),
+                (name_ids[THREAD_EXITED_EVENT_NAME], thread_name),
+            ]
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_exited_threads() {
+        start().unwrap();
+        std::thread::Builder::new()
+            .name("exited".to_owned())
+            .spawn(|| {
+                scope!("before exit");
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_exited_threads();
+        let name_id = trace.name_ids["before exit"];
+        let has_span = trace.trace.packet.iter().any(|packet| match &packet.data {
+            Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                event.name_field == Some(schema::track_event::NameField::NameIid(name_id))
+            }
+            _ => false,
+        });
+        assert!(has_span);
+        let has_thread = trace.trace.packet.iter().any(|packet| match &packet.data {
+            Some(schema::trace_packet::Data::TrackDescriptor(descriptor)) => descriptor
+                .thread
+                .as_ref()
+                .is_some_and(|thread| thread.thread_name.as_deref() == Some("exited")),
+            _ => false,
+        });
+        assert!(has_thread);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_ring_buffer_discard_oldest() {
+        start().unwrap();
+        for index in 0..40 {
+            scope!("ring", label = format_args!("span {index}"));
+        }
+        let mut thread = ThreadTraceData::take_current_thread();
+        let strings_len = thread.strings.len();
+        ring_buffer::discard_oldest(&mut thread.events, &mut thread.strings);
+        assert!(thread.strings.len() < strings_len);
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&thread);
+        let labels = annotation_strings(&trace);
+        // Each span has 5 events, so discarding a quarter of them drops the first 10 spans.
+        let expected: Vec<String> = (10..40).map(|index| format!("span {index}")).collect();
+        assert_eq!(labels, expected);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_dropped_events() {
+        start().unwrap();
+        for index in 0..3 {
+            scope!("capped", label = format_args!("span {index}"));
+        }
+        // Stop part-way through the third span, as if it had reached a limit of 12 events.
+        EVENTS.with_borrow_mut(|events| ring_buffer::drop_current_thread_newest(events, 12));
+        let thread = ThreadTraceData::take_current_thread();
+        // The start of the third span is removed too, since it might have been missing arguments.
+        assert_eq!(thread.events.len(), 10);
+        assert_eq!(thread.dropped_events, 5);
+        assert!(thread.first_drop_time.is_some());
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let reports = trace.process_all([thread]);
+        assert_eq!(reports[0].dropped_events, 5);
+        let name_ids = trace.name_ids.clone();
+        let events = track_events(&trace);
+        let is_named = |event: &schema::TrackEvent, name: &str| {
+            event.name_field == Some(schema::track_event::NameField::NameIid(name_ids[name]))
+        };
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| is_named(event, "capped")
+                    && event.r#type() == schema::track_event::Type::SliceBegin)
+                .count(),
+            2
+        );
+        let data_lost = events
+            .iter()
+            .find(|event| is_named(event, DATA_LOST_EVENT_NAME))
+            .unwrap();
+        assert_eq!(data_lost.r#type(), schema::track_event::Type::Instant);
+        assert_eq!(
+            data_lost.debug_annotations[0].value,
+            Some(schema::debug_annotation::Value::UintValue(5))
+        );
+
+        let mut merged = TraceBuilder::new().unwrap();
+        merged.dropped_events = 2;
+        merged.merge(trace);
+        assert_eq!(merged.dropped_events, 7);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_concurrency_counter() {
+        start().unwrap();
+        let record = |spans: &'static [(u64, u64)]| {
+            std::thread::spawn(move || {
+                for (start, end) in spans {
+                    start_span_at!(*start, "compile TU").end_at(*end);
+                    start_span_at!(*start, "link").end_at(*end);
+                }
+                ThreadTraceData::take_current_thread()
+            })
+            .join()
+            .unwrap()
+        };
+        let first = record(&[(2_000, 4_000)]);
+        let second = record(&[(1_000, 3_000), (4_000, 5_000)]);
+
+        let levels = |trace: &TraceBuilder, track: CounterTrack| -> Vec<i64> {
+            trace
+                .finished_trace()
+                .packet
+                .iter()
+                .filter_map(|packet| match &packet.data {
+                    Some(schema::trace_packet::Data::TrackEvent(event))
+                        if event.track_uuid == Some(track.uuid) =>
+                    {
+                        match event.counter_value_field {
+                            Some(schema::track_event::CounterValueField::CounterValue(value)) => {
+                                Some(value)
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let track = trace.add_concurrency_counter("compile TU");
+        trace
+            .process_thread_data(&first)
+            .process_thread_data(&second);
+        // At 4000, one span ends as another starts, so the count doesn't change.
+        assert_eq!(levels(&trace, track), [1, 2, 1, 1, 0]);
+
+        // Threads processed by separate builders are counted together once merged.
+        let mut trace = TraceBuilder::new().unwrap();
+        let track = trace.add_concurrency_counter("compile TU");
+        trace.process_thread_data(&first);
+        let mut other = TraceBuilder::new().unwrap();
+        let other_track = other.add_concurrency_counter("compile TU");
+        other.process_thread_data(&second);
+        trace.merge(other);
+        assert_eq!(levels(&trace, track), [1, 2, 1, 1, 0]);
+        assert!(levels(&trace, other_track).is_empty());
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_split_complete_records() {
+        start().unwrap();
+        for index in 0..3 {
+            scope!("dump", label = format_args!("span {index}"));
+        }
+        let _open = start_span!("dump", label = format_args!("span 3"));
+        let mut thread = ThreadTraceData::take_current_thread();
+        let (handed_events, handed_strings) =
+            dump::split_complete_records(&mut thread.events, &mut thread.strings).unwrap();
+
+        // The start of the open span is the last top-level event, so stays with the thread.
+        assert_eq!(handed_events.len(), 3 * 5);
+        assert_eq!(thread.events.len(), 3);
+        assert_eq!(thread.strings, "span 3");
+        assert!(matches!(thread.events[2], Event::Str { offset: 0, len: 6 }));
+
+        let handed = ThreadTraceData {
+            events: handed_events,
+            strings: handed_strings,
+            ..thread
+        };
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.process_thread_data(&handed);
+        let labels = annotation_strings(&trace);
+        assert_eq!(labels, ["span 0", "span 1", "span 2"]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_memory_budget() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+
+        const BUDGET: usize = 1024 * 1024;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        set_memory_budget(
+            buffered_bytes() + BUDGET,
+            BudgetAction::Callback({
+                let calls = calls.clone();
+                Arc::new(move || {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                })
+            }),
+        );
+        current_thread_reserve(2 * BUDGET / size_of::<Event>());
+        clear_memory_budget();
+        assert_ne!(calls.load(Ordering::Relaxed), 0);
+
+        let before = buffered_bytes();
+        drop(ThreadTraceData::take_current_thread());
+        assert!(buffered_bytes() < before);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_nested_counter_tracks() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let shards = trace.create_track_group("Shards");
+        let group = trace.create_track_group_in(shards, "Shard 0");
+        let counter = trace.create_counter_track_in(group, "Queue", CounterUnit::Count, 1, false);
+
+        let parent_of = |uuid: u64| {
+            trace
+                .trace
+                .packet
+                .iter()
+                .find_map(|packet| match &packet.data {
+                    Some(schema::trace_packet::Data::TrackDescriptor(descriptor))
+                        if descriptor.uuid == Some(uuid) =>
+                    {
+                        Some(descriptor.parent_uuid)
+                    }
+                    _ => None,
+                })
+        };
+        assert_eq!(parent_of(counter.uuid), Some(Some(group.uuid)));
+        assert_eq!(parent_of(group.uuid), Some(Some(shards.uuid)));
+        assert_eq!(parent_of(shards.uuid), Some(None));
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_gauge() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        let gauge = Gauge::new(trace.create_counter_track("Workers", CounterUnit::Count, 1, false));
+
+        {
+            let _a = GaugeGuard::enter(&gauge);
+            let _b = GaugeGuard::enter(&gauge);
+            assert_eq!(gauge.value.load(Ordering::Relaxed), 2);
+        }
+        assert_eq!(gauge.value.load(Ordering::Relaxed), 0);
+
+        let values: Vec<i64> = ThreadTraceData::take_current_thread()
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::CounterI64 { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, [1, 2, 1, 0]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_static_counters() {
+        static HITS: StaticCounter = StaticCounter::new("hits");
+
+        start().unwrap();
+
+        HITS.add(3);
+        HITS.increment();
+        assert_eq!(HITS.value(), 4);
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.flush_static_counters();
+        trace.flush_static_counters();
+
+        let hits_track = trace.static_counter_tracks[&(std::ptr::from_ref(&HITS) as usize)];
+        let num_samples = trace
+            .trace
+            .packet
+            .iter()
+            .filter(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event)) => {
+                    event.track_uuid == Some(hits_track.uuid)
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(num_samples, 2);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_count_only() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        trace.set_record_static_counter_rates(true);
+        let count = || count_only!("counted");
+        count();
+        trace.flush_static_counters();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        count();
+        count();
+        trace.flush_static_counters();
+
+        assert!(ThreadTraceData::take_current_thread().events.is_empty());
+        let counter = STATIC_COUNTERS
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .find(|counter| counter.name() == "counted")
+            .unwrap();
+        assert_eq!(counter.value(), 3);
+
+        let key = std::ptr::from_ref(counter) as usize;
+        let rate_track = trace.static_counter_rate_tracks[&key];
+        let rates: Vec<f64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(event))
+                    if event.track_uuid == Some(rate_track.uuid) =>
+                {
+                    match event.counter_value_field {
+                        Some(schema::track_event::CounterValueField::DoubleCounterValue(rate)) => {
+                            Some(rate)
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rates.len(), 1);
+        assert!(rates[0] > 0.0);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn test_registered_gauge() {
+        start().unwrap();
+
+        let mut trace = TraceBuilder::new().unwrap();
+        register_gauge("answer", || 42.0);
+        sample_now();
+        trace.process_samples();
+
+        let answers: Vec<f64> = trace
+            .trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(schema::trace_packet::Data::TrackEvent(schema::TrackEvent {
+                    counter_value_field:
+                        Some(schema::track_event::CounterValueField::DoubleCounterValue(value)),
+                    ..
+                })) => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert!(answers.contains(&42.0));
+    }
 }
@@ -0,0 +1,87 @@
+//! Log messages recorded by [crate::log_event], shown interleaved with spans on the timeline.
+
+use crate::CallSite;
+use crate::REDACTED;
+use crate::TraceBuilder;
+use crate::schema;
+use crate::schema::log_message::Priority;
+
+/// The severity of a message recorded by [crate::log_event].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub(crate) fn from_u8(value: u8) -> Option<LogLevel> {
+        match value {
+            0 => Some(LogLevel::Trace),
+            1 => Some(LogLevel::Debug),
+            2 => Some(LogLevel::Info),
+            3 => Some(LogLevel::Warn),
+            4 => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    fn priority(self) -> Priority {
+        match self {
+            Self::Trace => Priority::PrioVerbose,
+            Self::Debug => Priority::PrioDebug,
+            Self::Info => Priority::PrioInfo,
+            Self::Warn => Priority::PrioWarn,
+            Self::Error => Priority::PrioError,
+        }
+    }
+}
+
+impl TraceBuilder {
+    /// Makes the instant event in the packet at `index`, which was recorded by `call_site`, into a
+    /// log message with severity `level`.
+    pub(crate) fn add_log_message(
+        &mut self,
+        index: usize,
+        call_site: &'static CallSite,
+        level: LogLevel,
+    ) {
+        let body = match self.redaction_for(call_site.source.sensitivity) {
+            None => call_site.source.name,
+            Some(_) => REDACTED,
+        };
+        let body_iid = self.log_message_body_id(body);
+        if let Some(schema::trace_packet::Data::TrackEvent(track_event)) =
+            &mut self.trace.packet[index].data
+        {
+            let source_location_iid = match track_event.source_location_field {
+                Some(schema::track_event::SourceLocationField::SourceLocationIid(iid)) => Some(iid),
+                _ => None,
+            };
+            track_event.log_message = Some(schema::LogMessage {
+                source_location_iid,
+                body_iid: Some(body_iid),
+                prio: Some(level.priority() as i32),
+            });
+        }
+        self.attach_pending_interned(index);
+    }
+
+    fn log_message_body_id(&mut self, body: &'static str) -> u64 {
+        if let Some(id) = self.log_message_body_ids.get(body) {
+            return *id;
+        }
+        let id = self.log_message_body_ids.len() as u64 + 1;
+        self.pending_interned
+            .get_or_insert_default()
+            .log_message_body
+            .push(schema::LogMessageBody {
+                iid: Some(id),
+                body: Some(body.to_owned()),
+            });
+        self.log_message_body_ids.insert(body, id);
+        id
+    }
+}
@@ -0,0 +1,67 @@
+//! Timing a closure and reporting how long it took to a user-supplied sink when tracing is off.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// A function that's given the name and duration of each [measure] call made while tracing is off.
+type MeasureSink = Arc<dyn Fn(&str, Duration) + Send + Sync>;
+
+static SINK: Mutex<Option<MeasureSink>> = Mutex::new(None);
+
+/// Whether [SINK] is set, so that [measure] doesn't need to take the lock when it isn't.
+static HAS_SINK: AtomicBool = AtomicBool::new(false);
+
+/// Sets the function that [measure] reports to when tracing is off, replacing any previous one.
+/// It's called on the thread that made the measurement, so should be cheap, e.g. recording into a
+/// histogram.
+///
+/// # Example
+///
+/// ```
+/// perfetto_recorder::set_measure_sink(|name, duration| {
+///     println!("{name} took {duration:?}");
+/// });
+/// ```
+pub fn set_measure_sink(sink: impl Fn(&str, Duration) + Send + Sync + 'static) {
+    *SINK.lock().unwrap() = Some(Arc::new(sink));
+    HAS_SINK.store(true, Ordering::Relaxed);
+}
+
+/// Removes the function set by [set_measure_sink].
+pub fn clear_measure_sink() {
+    HAS_SINK.store(false, Ordering::Relaxed);
+    *SINK.lock().unwrap() = None;
+}
+
+/// Runs `work`, returning its result. If tracing is on, this is recorded as a span named `name`, as
+/// for the [with_span](fn@crate::with_span) function, so without a source location. Otherwise,
+/// including when the `enable` feature is off, the event buffer isn't touched and instead `name`
+/// and how long `work` took are passed to the sink set by [set_measure_sink], if any. This lets the
+/// same call sites feed lightweight metrics in builds where full tracing is off.
+///
+/// # Example
+///
+/// ```
+/// let total = perfetto_recorder::measure("sum", || (0..1000_u64).sum::<u64>());
+/// ```
+pub fn measure<R>(name: &str, work: impl FnOnce() -> R) -> R {
+    if crate::is_enabled() {
+        let _guard = crate::start_function_span(name);
+        return work();
+    }
+    if !HAS_SINK.load(Ordering::Relaxed) {
+        return work();
+    }
+    let start = std::time::Instant::now();
+    let result = work();
+    let duration = start.elapsed();
+    // Clone the sink so that we don't hold the lock while calling it.
+    let sink = SINK.lock().unwrap().clone();
+    if let Some(sink) = sink {
+        sink(name, duration);
+    }
+    result
+}
@@ -0,0 +1,176 @@
+//! Configuration for a [TraceBuilder] that can be constructed once and reused.
+
+use crate::AsyncSpanMode;
+use crate::BuiltinClock;
+use crate::ClockAnchor;
+use crate::ClockUnavailable;
+use crate::Error;
+use crate::Redaction;
+use crate::Sensitivity;
+use crate::os;
+
+/// Settings for a [crate::TraceBuilder], which can be stored and used to create builders that are
+/// configured consistently, e.g. one for each snapshot of a long-running process. Each setting
+/// corresponds to the [crate::TraceBuilder] method of the same name.
+///
+/// # Example
+///
+/// ```
+/// # use perfetto_recorder::*;
+/// # if perfetto_recorder::is_enabled() {
+/// let mut options = TraceBuilderOptions::new();
+/// options
+///     .set_clock(BuiltinClock::Monotonic)?
+///     .set_max_sensitivity(Sensitivity::Internal, Redaction::Redact)
+///     .set_footer(true);
+///
+/// let trace = TraceBuilder::with_options(&options)?;
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct TraceBuilderOptions {
+    pub(crate) clock: BuiltinClock,
+    pub(crate) zero_based_timestamps: bool,
+    pub(crate) clock_anchor: Option<ClockAnchor>,
+    pub(crate) thread_lifetime_slices: bool,
+    pub(crate) thread_lifecycle_events: bool,
+    pub(crate) phases_depth: Option<usize>,
+    pub(crate) group_threads: bool,
+    pub(crate) thread_group_patterns: Vec<(String, String)>,
+    pub(crate) record_static_counter_rates: bool,
+    pub(crate) async_span_mode: AsyncSpanMode,
+    pub(crate) concurrency_counters: Vec<String>,
+    pub(crate) footer: bool,
+    pub(crate) packet_defaults: bool,
+    pub(crate) max_sensitivity: Sensitivity,
+    pub(crate) redaction: Redaction,
+    pub(crate) machine_id: Option<u32>,
+}
+
+impl TraceBuilderOptions {
+    /// Creates options with the same settings as [crate::TraceBuilder::new].
+    pub fn new() -> Self {
+        Self {
+            clock: BuiltinClock::Realtime,
+            zero_based_timestamps: false,
+            clock_anchor: None,
+            thread_lifetime_slices: false,
+            thread_lifecycle_events: false,
+            phases_depth: None,
+            group_threads: false,
+            thread_group_patterns: Vec::new(),
+            record_static_counter_rates: false,
+            async_span_mode: AsyncSpanMode::Tracks,
+            concurrency_counters: Vec::new(),
+            footer: false,
+            packet_defaults: false,
+            max_sensitivity: Sensitivity::Secret,
+            redaction: Redaction::Drop,
+            machine_id: None,
+        }
+    }
+
+    /// See [crate::TraceBuilder::set_clock]. Returns an error if the clock isn't available on this
+    /// platform.
+    pub fn set_clock(&mut self, clock: BuiltinClock) -> Result<&mut Self, Error> {
+        if os::clock_nanos(clock).is_none() {
+            return Err(ClockUnavailable(clock).into());
+        }
+        self.clock = clock;
+        Ok(self)
+    }
+
+    /// See [crate::TraceBuilder::set_zero_based_timestamps].
+    pub fn set_zero_based_timestamps(&mut self, zero_based: bool) -> &mut Self {
+        self.zero_based_timestamps = zero_based;
+        self
+    }
+
+    /// See [crate::TraceBuilder::align_with].
+    pub fn align_with(&mut self, anchor: ClockAnchor) -> &mut Self {
+        self.clock_anchor = Some(anchor);
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_thread_lifetime_slices].
+    pub fn set_thread_lifetime_slices(&mut self, enabled: bool) -> &mut Self {
+        self.thread_lifetime_slices = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_thread_lifecycle_events].
+    pub fn set_thread_lifecycle_events(&mut self, enabled: bool) -> &mut Self {
+        self.thread_lifecycle_events = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_phases_track].
+    pub fn set_phases_track(&mut self, max_depth: usize) -> &mut Self {
+        self.phases_depth = Some(max_depth);
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_group_threads].
+    pub fn set_group_threads(&mut self, enabled: bool) -> &mut Self {
+        self.group_threads = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::add_thread_group].
+    pub fn add_thread_group(&mut self, pattern: &str, name: &str) -> &mut Self {
+        self.group_threads = true;
+        self.thread_group_patterns
+            .push((pattern.to_owned(), name.to_owned()));
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_record_static_counter_rates].
+    pub fn set_record_static_counter_rates(&mut self, enabled: bool) -> &mut Self {
+        self.record_static_counter_rates = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_async_span_mode].
+    pub fn set_async_span_mode(&mut self, mode: AsyncSpanMode) -> &mut Self {
+        self.async_span_mode = mode;
+        self
+    }
+
+    /// See [crate::TraceBuilder::add_concurrency_counter].
+    pub fn add_concurrency_counter(&mut self, span_name: &str) -> &mut Self {
+        self.concurrency_counters.push(span_name.to_owned());
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_footer].
+    pub fn set_footer(&mut self, enabled: bool) -> &mut Self {
+        self.footer = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_packet_defaults].
+    pub fn set_packet_defaults(&mut self, enabled: bool) -> &mut Self {
+        self.packet_defaults = enabled;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_max_sensitivity].
+    pub fn set_max_sensitivity(&mut self, max: Sensitivity, redaction: Redaction) -> &mut Self {
+        self.max_sensitivity = max;
+        self.redaction = redaction;
+        self
+    }
+
+    /// See [crate::TraceBuilder::set_machine_id].
+    pub fn set_machine_id(&mut self, machine_id: u32) -> &mut Self {
+        self.machine_id = Some(machine_id);
+        self
+    }
+}
+
+impl Default for TraceBuilderOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
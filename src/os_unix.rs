@@ -13,4 +13,170 @@ impl Pid {
     pub(crate) fn as_i32(self) -> i32 {
         self.0.as_raw()
     }
+
+    pub(crate) fn from_i32(raw: i32) -> Self {
+        Pid(nix::unistd::Pid::from_raw(raw))
+    }
+}
+
+pub(crate) fn clock_nanos(clock: crate::BuiltinClock) -> Option<u64> {
+    use nix::time::ClockId;
+
+    let clock_id = match clock {
+        crate::BuiltinClock::Realtime => ClockId::CLOCK_REALTIME,
+        crate::BuiltinClock::Monotonic => ClockId::CLOCK_MONOTONIC,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        crate::BuiltinClock::Boottime => ClockId::CLOCK_BOOTTIME,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        crate::BuiltinClock::Boottime => return None,
+    };
+    let time = nix::time::clock_gettime(clock_id).ok()?;
+    Some(time.tv_sec() as u64 * 1_000_000_000 + time.tv_nsec() as u64)
+}
+
+/// Returns the realtime clock in nanoseconds, read from a clock that is cheaper to read, but only
+/// updated every few milliseconds. Returns `None` if no such clock is available.
+#[cfg(not(feature = "fastant"))]
+pub(crate) fn coarse_realtime_nanos() -> Option<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let time = nix::time::clock_gettime(nix::time::ClockId::CLOCK_REALTIME_COARSE).ok()?;
+        Some(time.tv_sec() as u64 * 1_000_000_000 + time.tv_nsec() as u64)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        None
+    }
+}
+
+/// Returns the CPU that the current thread is running on, if that can be determined.
+#[cfg(feature = "enable")]
+pub(crate) fn current_cpu() -> Option<u32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // SAFETY: `sched_getcpu` has no preconditions.
+        let cpu = unsafe { libc::sched_getcpu() };
+        u32::try_from(cpu).ok()
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        None
+    }
+}
+
+/// The write end of the pipe that wakes the thread started by [on_termination_signal].
+static SIGNAL_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+/// The signal that woke the thread started by [on_termination_signal].
+static RECEIVED_SIGNAL: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+/// Arranges for `callback` to be called on a background thread when the process receives SIGTERM
+/// or SIGINT. Once `callback` returns, the signal is raised again with its default handler.
+pub(crate) fn on_termination_signal(callback: fn()) -> Result<(), std::io::Error> {
+    use nix::sys::signal::SaFlags;
+    use nix::sys::signal::SigAction;
+    use nix::sys::signal::SigHandler;
+    use nix::sys::signal::SigSet;
+    use nix::sys::signal::Signal;
+    use std::os::fd::IntoRawFd;
+    use std::sync::atomic::Ordering;
+
+    let (read, write) = nix::unistd::pipe()?;
+    SIGNAL_PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+
+    std::thread::Builder::new()
+        .name("perfetto-exit-signal".to_owned())
+        .spawn(move || {
+            let mut buffer = [0];
+            loop {
+                match nix::unistd::read(&read, &mut buffer) {
+                    Ok(1) => break,
+                    Err(nix::errno::Errno::EINTR) => continue,
+                    _ => return,
+                }
+            }
+            callback();
+            let signal = Signal::try_from(RECEIVED_SIGNAL.load(Ordering::Relaxed))
+                .unwrap_or(Signal::SIGTERM);
+            let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+            // SAFETY: Restoring the default handler is always safe.
+            let _ = unsafe { nix::sys::signal::sigaction(signal, &default) };
+            let _ = nix::sys::signal::raise(signal);
+        })?;
+
+    let action = SigAction::new(
+        SigHandler::Handler(handle_termination_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: The handler only does things that are async-signal-safe.
+        unsafe { nix::sys::signal::sigaction(signal, &action) }?;
+    }
+    Ok(())
+}
+
+extern "C" fn handle_termination_signal(signal: i32) {
+    use std::sync::atomic::Ordering;
+
+    RECEIVED_SIGNAL.store(signal, Ordering::Relaxed);
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    // SAFETY: `write` is async-signal-safe and the buffer outlives the call.
+    unsafe { libc::write(fd, [0_u8].as_ptr().cast(), 1) };
+}
+
+/// The write end of the pipe that wakes the thread started by [on_dump_signal].
+static DUMP_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+/// Arranges for `callback` to be called on a background thread each time the process receives
+/// `signal`. Can be called again to also call `callback` for another signal.
+pub(crate) fn on_dump_signal(
+    signal: crate::DumpSignal,
+    callback: fn(),
+) -> Result<(), std::io::Error> {
+    use nix::sys::signal::SaFlags;
+    use nix::sys::signal::SigAction;
+    use nix::sys::signal::SigHandler;
+    use nix::sys::signal::SigSet;
+    use nix::sys::signal::Signal;
+    use std::os::fd::IntoRawFd;
+    use std::sync::atomic::Ordering;
+
+    if DUMP_PIPE.load(Ordering::Relaxed) == -1 {
+        let (read, write) = nix::unistd::pipe()?;
+        std::thread::Builder::new()
+            .name("perfetto-dump-signal".to_owned())
+            .spawn(move || {
+                let mut buffer = [0];
+                loop {
+                    match nix::unistd::read(&read, &mut buffer) {
+                        Ok(1) => callback(),
+                        Err(nix::errno::Errno::EINTR) => continue,
+                        _ => return,
+                    }
+                }
+            })?;
+        DUMP_PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+    }
+
+    let signal = match signal {
+        crate::DumpSignal::Usr1 => Signal::SIGUSR1,
+        crate::DumpSignal::Usr2 => Signal::SIGUSR2,
+    };
+    let action = SigAction::new(
+        SigHandler::Handler(handle_dump_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: The handler only does things that are async-signal-safe.
+    unsafe { nix::sys::signal::sigaction(signal, &action) }?;
+    Ok(())
+}
+
+extern "C" fn handle_dump_signal(_signal: i32) {
+    use std::sync::atomic::Ordering;
+
+    let fd = DUMP_PIPE.load(Ordering::Relaxed);
+    // SAFETY: `write` is async-signal-safe and the buffer outlives the call.
+    unsafe { libc::write(fd, [0_u8].as_ptr().cast(), 1) };
 }
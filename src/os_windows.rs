@@ -13,4 +13,47 @@ impl Pid {
     pub(crate) fn as_i32(self) -> i32 {
         self.0 as i32
     }
+
+    pub(crate) fn from_i32(raw: i32) -> Self {
+        Pid(raw as u32)
+    }
+}
+
+pub(crate) fn clock_nanos(clock: crate::BuiltinClock) -> Option<u64> {
+    match clock {
+        crate::BuiltinClock::Realtime => Some(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()?
+                .as_nanos() as u64,
+        ),
+        crate::BuiltinClock::Monotonic | crate::BuiltinClock::Boottime => None,
+    }
+}
+
+#[cfg(not(feature = "fastant"))]
+pub(crate) fn coarse_realtime_nanos() -> Option<u64> {
+    None
+}
+
+/// Returns the CPU that the current thread is running on.
+#[cfg(feature = "enable")]
+pub(crate) fn current_cpu() -> Option<u32> {
+    Some(unsafe { windows_sys::Win32::System::Threading::GetCurrentProcessorNumber() })
+}
+
+/// Termination signals aren't handled on Windows, so `callback` is never called.
+pub(crate) fn on_termination_signal(_callback: fn()) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Signals don't exist on Windows, so dumps can't be triggered by them.
+pub(crate) fn on_dump_signal(
+    _signal: crate::DumpSignal,
+    _callback: fn(),
+) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Signal-triggered trace dumps are only supported on unix",
+    ))
 }
@@ -0,0 +1,86 @@
+//! Destinations that encoded traces can be written to.
+
+use crate::Error;
+use crate::TraceBuilder;
+use std::path::PathBuf;
+
+/// Somewhere that a finished trace can be written, such as a file, an in-memory buffer or an upload
+/// to object storage. Implement this to send traces directly to wherever they're kept, without
+/// going via a temporary file, then pass it to [TraceBuilder::write_to].
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::Error;
+/// use perfetto_recorder::TraceOutput;
+///
+/// struct Upload {
+///     url: String,
+/// }
+///
+/// impl TraceOutput for Upload {
+///     fn write_trace(&mut self, trace: &[u8]) -> Result<(), Error> {
+///         // An HTTP client would be used here.
+///         if self.url.is_empty() {
+///             return Err(Error::Output("No URL configured".into()));
+///         }
+///         println!("Uploading {} bytes to {}", trace.len(), self.url);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait TraceOutput {
+    /// Writes `trace`, which is a complete encoded trace. Errors that don't come from this crate
+    /// can be returned as [Error::Output].
+    fn write_trace(&mut self, trace: &[u8]) -> Result<(), Error>;
+}
+
+/// Writes traces to a file, replacing it if it already exists. Used by
+/// [TraceBuilder::write_to_file].
+#[derive(Debug, Clone)]
+pub struct FileOutput {
+    path: PathBuf,
+}
+
+impl FileOutput {
+    /// Creates an output that writes to `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl TraceOutput for FileOutput {
+    fn write_trace(&mut self, trace: &[u8]) -> Result<(), Error> {
+        std::fs::write(&self.path, trace).map_err(|source| Error::Write {
+            path: self.path.clone(),
+            source,
+        })
+    }
+}
+
+/// Appends traces to the vector.
+impl TraceOutput for Vec<u8> {
+    fn write_trace(&mut self, trace: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(trace);
+        Ok(())
+    }
+}
+
+impl TraceBuilder {
+    /// Encodes the trace and writes it to `output`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// let mut bytes = Vec::new();
+    /// TraceBuilder::try_new().write_to(&mut bytes)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn write_to(&self, output: &mut dyn TraceOutput) -> Result<(), Error> {
+        if self.noop {
+            return Ok(());
+        }
+        output.write_trace(&self.encode_to_vec())
+    }
+}
@@ -0,0 +1,240 @@
+//! Writing the fields that consecutive packets share once, as packet defaults, rather than in each
+//! packet.
+
+use crate::TraceBuilder;
+use crate::schema;
+use crate::schema::TracePacket;
+use crate::schema::trace_packet::Data;
+use crate::schema::trace_packet::OptionalTrustedPacketSequenceId;
+
+/// How many consecutive track events need to be on the same track for it to be made the default.
+/// Changing the default track costs about as much as writing the track's UUID twice.
+const MIN_TRACK_RUN: usize = 3;
+
+impl TraceBuilder {
+    /// When enabled, the clock ID of timestamps and the track of runs of track events, e.g. the
+    /// spans of a thread, are written once per sequence as packet defaults, rather than in each
+    /// packet. Perfetto fills them back in when loading the trace. Since most packets are track
+    /// events that only hold a timestamp and an interned name, this makes traces substantially
+    /// smaller and quicker to encode. See `examples/benchmark.rs`.
+    ///
+    /// Traces written this way are read as usual by [TraceBuilder::import] and
+    /// [crate::compare_traces]. Other tools that read packets one at a time need to support packet
+    /// defaults.
+    pub fn set_packet_defaults(&mut self, enabled: bool) -> &mut Self {
+        self.packet_defaults = enabled;
+        self
+    }
+}
+
+/// The defaults that are in effect for a packet sequence.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+struct Defaults {
+    clock_id: Option<u32>,
+    track_uuid: Option<u64>,
+}
+
+impl Defaults {
+    fn from_schema(defaults: &schema::TracePacketDefaults) -> Defaults {
+        Defaults {
+            clock_id: defaults.timestamp_clock_id,
+            track_uuid: defaults
+                .track_event_defaults
+                .and_then(|defaults| defaults.track_uuid),
+        }
+    }
+
+    fn to_schema(self) -> schema::TracePacketDefaults {
+        schema::TracePacketDefaults {
+            timestamp_clock_id: self.clock_id,
+            track_event_defaults: self
+                .track_uuid
+                .map(|track_uuid| schema::TrackEventDefaults {
+                    track_uuid: Some(track_uuid),
+                }),
+        }
+    }
+}
+
+/// How a packet is changed to make use of packet defaults.
+#[derive(Default)]
+struct Edit {
+    /// New defaults for the packet's sequence. The packet itself keeps all of its fields, so that
+    /// it doesn't matter whether readers apply defaults to the packet that sets them.
+    defaults: Option<schema::TracePacketDefaults>,
+    omit_clock_id: bool,
+    omit_track_uuid: bool,
+}
+
+impl Edit {
+    fn is_empty(&self) -> bool {
+        self.defaults.is_none() && !self.omit_clock_id && !self.omit_track_uuid
+    }
+
+    fn apply(self, packet: &mut TracePacket) {
+        if self.omit_clock_id {
+            packet.timestamp_clock_id = None;
+        }
+        if self.omit_track_uuid
+            && let Some(Data::TrackEvent(event)) = &mut packet.data
+        {
+            event.track_uuid = None;
+        }
+        if self.defaults.is_some() {
+            packet.trace_packet_defaults = self.defaults;
+        }
+    }
+}
+
+/// Decides how each packet of a trace is changed, keeping track of the defaults of each sequence.
+#[derive(Default)]
+struct Planner {
+    sequences: Vec<(u32, Defaults)>,
+}
+
+impl Planner {
+    fn edit(&mut self, packets: &[TracePacket], index: usize) -> Edit {
+        let packet = &packets[index];
+        let Some(sequence) = sequence_id(packet) else {
+            return Edit::default();
+        };
+        let defaults = defaults_for(&mut self.sequences, sequence);
+        // Readers might clear the defaults after applying any that are set by the same packet, so
+        // we wait for the next packet before setting them.
+        if clears_incremental_state(packet) {
+            *defaults = Defaults::default();
+            return Edit::default();
+        }
+        if let Some(given) = &packet.trace_packet_defaults {
+            *defaults = Defaults::from_schema(given);
+            return Edit::default();
+        }
+
+        let clock_id = packet.timestamp.and(packet.timestamp_clock_id);
+        let track_uuid = track_uuid(packet);
+        let mut wanted = *defaults;
+        if wanted.clock_id.is_none() {
+            wanted.clock_id = clock_id;
+        }
+        if let Some(track_uuid) = track_uuid
+            && wanted.track_uuid != Some(track_uuid)
+            && run_length(packets, index) >= MIN_TRACK_RUN
+        {
+            wanted.track_uuid = Some(track_uuid);
+        }
+        if wanted != *defaults {
+            *defaults = wanted;
+            return Edit {
+                defaults: Some(wanted.to_schema()),
+                ..Edit::default()
+            };
+        }
+        Edit {
+            defaults: None,
+            omit_clock_id: clock_id.is_some() && clock_id == defaults.clock_id,
+            omit_track_uuid: track_uuid.is_some() && track_uuid == defaults.track_uuid,
+        }
+    }
+}
+
+/// Changes `packets` to make use of packet defaults.
+pub(crate) fn apply_packet_defaults(packets: &mut [TracePacket]) {
+    let mut planner = Planner::default();
+    for index in 0..packets.len() {
+        let edit = planner.edit(packets, index);
+        edit.apply(&mut packets[index]);
+    }
+}
+
+/// Encodes `trace` as if [apply_packet_defaults] had been applied to it, without needing a copy of
+/// the whole trace.
+pub(crate) fn encode_with_packet_defaults(trace: &schema::Trace) -> Vec<u8> {
+    let mut planner = Planner::default();
+    let mut bytes = Vec::new();
+    for (index, packet) in trace.packet.iter().enumerate() {
+        let edit = planner.edit(&trace.packet, index);
+        if edit.is_empty() {
+            prost::encoding::message::encode(1, packet, &mut bytes);
+            continue;
+        }
+        // Most packets that are changed are track events that hold little more than a timestamp
+        // and a name, so they're cheap to copy.
+        let mut copy = packet.clone();
+        edit.apply(&mut copy);
+        prost::encoding::message::encode(1, &copy, &mut bytes);
+    }
+    bytes
+}
+
+/// Fills in the fields of `packets` that were left unset in favour of packet defaults, so that
+/// each packet can be read on its own.
+pub(crate) fn resolve_packet_defaults(packets: &mut [TracePacket]) {
+    let mut sequences: Vec<(u32, Defaults)> = Vec::new();
+    for packet in packets {
+        let Some(sequence) = sequence_id(packet) else {
+            continue;
+        };
+        let defaults = defaults_for(&mut sequences, sequence);
+        if clears_incremental_state(packet) {
+            *defaults = Defaults::default();
+        }
+        if let Some(given) = packet.trace_packet_defaults.take() {
+            *defaults = Defaults::from_schema(&given);
+        }
+        if packet.timestamp.is_some() && packet.timestamp_clock_id.is_none() {
+            packet.timestamp_clock_id = defaults.clock_id;
+        }
+        if let Some(Data::TrackEvent(event)) = &mut packet.data
+            && event.track_uuid.is_none()
+        {
+            event.track_uuid = defaults.track_uuid;
+        }
+    }
+}
+
+/// Returns how many track events on the same track and sequence start at `index`, counting up to
+/// [MIN_TRACK_RUN].
+fn run_length(packets: &[TracePacket], index: usize) -> usize {
+    let first = &packets[index];
+    1 + packets[index + 1..]
+        .iter()
+        .take(MIN_TRACK_RUN - 1)
+        .take_while(|packet| {
+            track_uuid(packet) == track_uuid(first)
+                && sequence_id(packet) == sequence_id(first)
+                && !clears_incremental_state(packet)
+                && packet.trace_packet_defaults.is_none()
+        })
+        .count()
+}
+
+/// Returns the defaults of `sequence`, adding it to `sequences` if it isn't there yet. Traces
+/// usually only have one sequence per builder that was merged, so a short list is quicker than a
+/// map.
+fn defaults_for(sequences: &mut Vec<(u32, Defaults)>, sequence: u32) -> &mut Defaults {
+    match sequences.iter().position(|(id, _)| *id == sequence) {
+        Some(position) => &mut sequences[position].1,
+        None => {
+            sequences.push((sequence, Defaults::default()));
+            &mut sequences.last_mut().unwrap().1
+        }
+    }
+}
+
+fn sequence_id(packet: &TracePacket) -> Option<u32> {
+    packet
+        .optional_trusted_packet_sequence_id
+        .map(|OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(id)| id)
+}
+
+fn track_uuid(packet: &TracePacket) -> Option<u64> {
+    match &packet.data {
+        Some(Data::TrackEvent(event)) => event.track_uuid,
+        _ => None,
+    }
+}
+
+fn clears_incremental_state(packet: &TracePacket) -> bool {
+    let cleared = schema::trace_packet::SequenceFlags::SeqIncrementalStateCleared as u32;
+    packet.sequence_flags.unwrap_or_default() & cleared != 0
+}
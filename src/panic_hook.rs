@@ -0,0 +1,123 @@
+//! Writing a trace when the process panics.
+
+use crate::EVENTS;
+use crate::FIRST_EVENT_TIME;
+use crate::LogLevel;
+use crate::STRINGS;
+use crate::ThreadTraceData;
+use crate::Timestamp;
+use crate::dump;
+use crate::exit;
+use crate::os;
+use crate::time;
+use std::panic::Location;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Where the trace is written when the process panics. `None` if [install_panic_hook] hasn't been
+/// called.
+static PANIC_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Set once the trace has been written for a panic, so that we only write it once.
+static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Arranges for a trace to be written to `path` when a thread panics, so that the traces of
+/// crashing runs, which are usually the most interesting ones, aren't lost. A "panic" log event
+/// with the panic's message and location is added to the panicking thread's track. The trace
+/// includes that thread's data and that of threads that have exited, and is built using the
+/// options set by [crate::Control::set_trace_options].
+///
+/// The panic might be caught, e.g. by [std::panic::catch_unwind] or a thread pool, and the process
+/// carry on, so the trace is written from copies of the data, which is left where it was, except
+/// that running threads hand over their events as for [crate::install_dump_handler]. Those events
+/// are then added by [crate::TraceBuilder::process_exited_threads] rather than
+/// [crate::ThreadTraceData::take_current_thread]. Signal-safe events and samples can only be taken
+/// once, so they aren't included.
+///
+/// The trace is only written for the first panic, so that a later panic, e.g. one caused by the
+/// first, doesn't replace it. The existing panic hook, which usually prints the panic message, is
+/// called first. Calling this again changes the path.
+///
+/// # Example
+///
+/// ```no_run
+/// perfetto_recorder::start()?;
+/// perfetto_recorder::install_panic_hook("crash.pftrace");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn install_panic_hook(path: impl Into<PathBuf>) {
+    let previous = PANIC_PATH.lock().unwrap().replace(path.into());
+    if previous.is_some() {
+        return;
+    }
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous_hook(info);
+        let Some(path) = PANIC_PATH.lock().unwrap().clone() else {
+            return;
+        };
+        if WRITTEN.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // If the panic happened while the current thread was recording an event, e.g. in the
+        // `Display` implementation of an argument, then its events are borrowed and we mustn't
+        // touch them, since panicking again would abort.
+        let recording = EVENTS
+            .try_with(|events| events.try_borrow_mut().is_err())
+            .unwrap_or(true)
+            || STRINGS
+                .try_with(|strings| strings.try_borrow_mut().is_err())
+                .unwrap_or(true);
+        if !recording {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+            record_panic(message, info.location());
+        }
+        // This can hand over some of the current thread's events too, so we copy what's left of
+        // them afterwards.
+        dump::collect_running_threads();
+        let mut threads = exit::copy_stashed();
+        if !recording {
+            threads.push(copy_current_thread());
+        }
+        exit::write_trace(&path, |trace| {
+            trace.process_all(threads);
+        });
+    }));
+}
+
+/// Returns a copy of the current thread's data, leaving its events where they are.
+fn copy_current_thread() -> ThreadTraceData {
+    ThreadTraceData {
+        events: EVENTS.with_borrow(Vec::clone),
+        strings: STRINGS.with_borrow(String::clone),
+        pid: os::getpid(),
+        tid: os::gettid(),
+        thread_name: std::thread::current().name().map(str::to_owned),
+        first_event_time: FIRST_EVENT_TIME.get().map(Timestamp::Instant),
+        taken_time: Timestamp::Instant(time()),
+        dropped_events: 0,
+        first_drop_time: None,
+        thread_started: false,
+        thread_exited: false,
+        session: 0,
+    }
+}
+
+/// Records a log event on the current thread for a panic with `message` at `location`.
+pub(crate) fn record_panic(message: &str, location: Option<&Location>) {
+    crate::log_event!(
+        LogLevel::Error,
+        "panic",
+        message,
+        location = location
+            .map(|location| location.to_string())
+            .unwrap_or_default(),
+    );
+}
@@ -0,0 +1,313 @@
+//! Bounding how many events each thread buffers, and counting the events that are dropped as a
+//! result.
+
+use crate::Event;
+use crate::Instant;
+use crate::STRINGS;
+use crate::time;
+use std::cell::Cell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// The maximum number of events that each thread keeps, or `usize::MAX` if there's no limit.
+static MAX_EVENTS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The number of events above which [enforce_limit] takes its slow path. The same as [MAX_EVENTS],
+/// except while threads are being asked to hand over their events, when it's zero.
+static CHECK_ABOVE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Whether threads are being asked to hand over their events. See [request_handover].
+static HANDOVER_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// What happens once a thread has more than [MAX_EVENTS] events, as an [OverflowPolicy].
+static POLICY: AtomicU8 = AtomicU8::new(OverflowPolicy::DropOldest as u8);
+
+thread_local! {
+    /// Set while the current thread's events are temporarily replaced by ones that must be kept
+    /// whole, so the limit doesn't apply.
+    static LIMIT_SUSPENDED: Cell<bool> = const { Cell::new(false) };
+
+    /// The events that the current thread has dropped since its events were last taken.
+    static DROPS: Cell<Drops> = const {
+        Cell::new(Drops {
+            events: 0,
+            first_time: None,
+            strings_len: None,
+            dropped_newest: false,
+            discarded_oldest: 0,
+        })
+    };
+}
+
+/// How each thread's events are buffered until they're taken by
+/// [crate::ThreadTraceData::take_current_thread]. Set with [set_buffer_mode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BufferMode {
+    /// All events are kept, however many there are.
+    #[default]
+    Unbounded,
+    /// Each thread keeps at most `events` events. Once it has more, its oldest events are
+    /// discarded, so that it holds just its most recent activity. This suits long-running servers
+    /// that only want a trace of what happened shortly before it was taken. Spans whose start has
+    /// been discarded are incomplete, so viewers will ignore their ends. Equivalent to
+    /// [BufferMode::Capped] with [OverflowPolicy::DropOldest].
+    RingBuffer { events: usize },
+    /// Each thread keeps at most `events` events, with `policy` deciding what happens once it has
+    /// more. Dropped events are counted and shown in the trace as a "data lost" instant event on
+    /// the thread's track.
+    Capped {
+        events: usize,
+        policy: OverflowPolicy,
+    },
+}
+
+/// What a thread does once it has recorded as many events as [BufferMode::Capped] allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Discard the oldest events, as for [BufferMode::RingBuffer].
+    #[default]
+    DropOldest = 0,
+    /// Keep the events that were recorded first and drop any further events until the thread's
+    /// events are taken. Spans that were open when the limit was reached won't have an end.
+    DropNewest = 1,
+    /// Panic in builds with debug assertions, so that tests notice when the limit is too small.
+    /// Otherwise, and for events recorded while the thread is already panicking, e.g. the ends of
+    /// spans whose guards are dropped by the panic, the same as [OverflowPolicy::DropNewest].
+    PanicInDebug = 2,
+}
+
+impl OverflowPolicy {
+    fn from_u8(value: u8) -> OverflowPolicy {
+        match value {
+            1 => OverflowPolicy::DropNewest,
+            2 => OverflowPolicy::PanicInDebug,
+            _ => OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// The events that a thread dropped because of its buffer mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Drops {
+    /// How many events were dropped.
+    pub(crate) events: u64,
+    /// When the first event was dropped.
+    pub(crate) first_time: Option<Instant>,
+    /// The length of the thread's strings when it started dropping its newest events. Strings added
+    /// after that belong to dropped events.
+    strings_len: Option<usize>,
+    /// Whether the newest events were dropped, in which case the last kept event might be missing
+    /// some of its arguments.
+    dropped_newest: bool,
+    /// How many of the dropped events were discarded from the start of the thread's events.
+    discarded_oldest: u64,
+}
+
+impl Drops {
+    fn add(&mut self, events: usize) {
+        self.events += events as u64;
+        self.first_time.get_or_insert_with(time);
+    }
+}
+
+/// Sets how each thread's events are buffered. Applies to all threads, including those that have
+/// already recorded events, from the next event that they record.
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::BufferMode;
+/// use perfetto_recorder::OverflowPolicy;
+///
+/// perfetto_recorder::set_buffer_mode(BufferMode::Capped {
+///     events: 1_000_000,
+///     policy: OverflowPolicy::DropNewest,
+/// });
+/// ```
+pub fn set_buffer_mode(mode: BufferMode) {
+    let (max_events, policy) = match mode {
+        BufferMode::Unbounded => (usize::MAX, OverflowPolicy::DropOldest),
+        BufferMode::RingBuffer { events } => (events, OverflowPolicy::DropOldest),
+        BufferMode::Capped { events, policy } => (events, policy),
+    };
+    POLICY.store(policy as u8, Ordering::Relaxed);
+    MAX_EVENTS.store(max_events, Ordering::Relaxed);
+    if !HANDOVER_REQUESTED.load(Ordering::Relaxed) {
+        CHECK_ABOVE.store(max_events, Ordering::Relaxed);
+    }
+}
+
+/// Sets whether [enforce_limit] should tell each thread that records an event to hand over its
+/// events.
+pub(crate) fn request_handover(requested: bool) {
+    HANDOVER_REQUESTED.store(requested, Ordering::Relaxed);
+    let check_above = if requested {
+        0
+    } else {
+        MAX_EVENTS.load(Ordering::Relaxed)
+    };
+    CHECK_ABOVE.store(check_above, Ordering::Relaxed);
+}
+
+/// Drops some of the current thread's `events` if there are more than the buffer mode allows.
+/// Returns whether the thread should hand over its events for a dump, which it can only do once
+/// `events` is no longer borrowed.
+#[inline(always)]
+pub(crate) fn enforce_limit(events: &mut Vec<Event>) -> bool {
+    events.len() > CHECK_ABOVE.load(Ordering::Relaxed) && handle_overflow(events)
+}
+
+#[cold]
+fn handle_overflow(events: &mut Vec<Event>) -> bool {
+    if LIMIT_SUSPENDED.get() {
+        return false;
+    }
+    let max_events = MAX_EVENTS.load(Ordering::Relaxed);
+    if events.len() > max_events {
+        match OverflowPolicy::from_u8(POLICY.load(Ordering::Relaxed)) {
+            OverflowPolicy::DropOldest => discard_current_thread_oldest(events),
+            // Panicking while unwinding would abort, which happens if the panic below drops the
+            // guards of spans that are still open, since their ends go over the limit again.
+            OverflowPolicy::PanicInDebug if cfg!(debug_assertions) && !std::thread::panicking() => {
+                events.truncate(max_events);
+                panic!(
+                    "A thread recorded more than {max_events} events, the limit set by \
+                     perfetto_recorder::set_buffer_mode"
+                );
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::PanicInDebug => {
+                drop_current_thread_newest(events, max_events)
+            }
+        }
+    }
+    HANDOVER_REQUESTED.load(Ordering::Relaxed)
+}
+
+fn discard_current_thread_oldest(events: &mut Vec<Event>) {
+    STRINGS.with(|strings| {
+        // Panicking here would lose the thread's events, so if the strings are already borrowed,
+        // we try again when the next event is recorded.
+        if let Ok(mut strings) = strings.try_borrow_mut() {
+            let discarded = discard_oldest(events, &mut strings);
+            update_drops(|drops| {
+                drops.add(discarded);
+                drops.discarded_oldest += discarded as u64;
+                // The strings have moved.
+                drops.strings_len = None;
+            });
+        }
+    });
+}
+
+pub(crate) fn drop_current_thread_newest(events: &mut Vec<Event>, max_events: usize) {
+    let dropped = events.len() - max_events;
+    events.truncate(max_events);
+    update_drops(|drops| {
+        drops.add(dropped);
+        drops.dropped_newest = true;
+        STRINGS.with(|strings| {
+            if let Ok(mut strings) = strings.try_borrow_mut() {
+                let len = *drops.strings_len.get_or_insert_with(|| strings_end(events));
+                strings.truncate(len);
+            }
+        });
+    });
+}
+
+fn update_drops(update: impl FnOnce(&mut Drops)) {
+    let mut drops = DROPS.get();
+    update(&mut drops);
+    DROPS.set(drops);
+}
+
+/// Returns the length of the strings that are referred to by `events`.
+fn strings_end(events: &[Event]) -> usize {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Str { offset, len } => Some(offset + len),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns how many events the current thread has discarded from the start of its events since
+/// they were last taken, so that positions within them can be adjusted.
+pub(crate) fn discarded_oldest() -> u64 {
+    DROPS.get().discarded_oldest
+}
+
+/// Returns whether the current thread has dropped its newest events, so is ignoring any further
+/// events until its events are taken.
+pub(crate) fn dropping_newest() -> bool {
+    DROPS.get().dropped_newest
+}
+
+/// Takes the record of what the current thread dropped since this was last called. If its newest
+/// events were dropped, the last of its `events` is removed too, since it might be missing some of
+/// its arguments.
+pub(crate) fn take_drops(events: &mut Vec<Event>) -> Drops {
+    // Thread locals might already have been destroyed if the thread is exiting.
+    let mut drops = DROPS.try_with(Cell::take).unwrap_or_default();
+    if drops.dropped_newest {
+        let start = (0..events.len())
+            .rev()
+            .find(|index| is_timestamp(events.get(index + 1)))
+            .unwrap_or(0);
+        drops.events += (events.len() - start) as u64;
+        events.truncate(start);
+    }
+    drops
+}
+
+/// Sets whether the limit is ignored for the current thread's events, returning the previous
+/// setting.
+pub(crate) fn suspend_limit(suspended: bool) -> bool {
+    LIMIT_SUSPENDED.replace(suspended)
+}
+
+/// Swaps the current thread's record of dropped events with `drops`, for when its events are
+/// temporarily replaced.
+pub(crate) fn replace_drops(drops: Drops) -> Drops {
+    DROPS.replace(drops)
+}
+
+pub(crate) fn is_timestamp(event: Option<&Event>) -> bool {
+    matches!(
+        event,
+        Some(Event::Timestamp(_) | Event::UnixTimestamp(_) | Event::SimulatedTimestamp(_))
+    )
+}
+
+/// Discards roughly the oldest quarter of `events`, together with the contents of any `strings`
+/// that they referred to, and returns how many events were discarded. Discarding a quarter at a
+/// time means that the cost of moving the remaining events is spread over many recorded events.
+pub(crate) fn discard_oldest(events: &mut Vec<Event>, strings: &mut String) -> usize {
+    // Only top-level events are followed by a timestamp, so we cut just before one of those, which
+    // leaves whole events.
+    let Some(cut) =
+        ((events.len() / 4).max(1)..events.len()).find(|index| is_timestamp(events.get(index + 1)))
+    else {
+        return 0;
+    };
+    events.drain(..cut);
+
+    let first_string = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Str { offset, .. } => Some(*offset),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(strings.len());
+    strings.drain(..first_string);
+    for event in events {
+        if let Event::Str { offset, .. } = event {
+            *offset -= first_string;
+        }
+    }
+    cut
+}
@@ -0,0 +1,186 @@
+//! Periodic sampling of application state into counter tracks.
+
+use crate::CounterTrack;
+use crate::CounterUnit;
+use crate::Instant;
+use crate::STATIC_COUNTERS;
+use crate::StaticCounter;
+use crate::TraceBuilder;
+use crate::is_enabled;
+use crate::schema::track_event::CounterValueField;
+use crate::time;
+use crate::watchpoint;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+struct RegisteredGauge {
+    name: String,
+    poll: Box<dyn Fn() -> f64 + Send>,
+}
+
+enum SampleSource {
+    /// An index into `GAUGES`.
+    Gauge(usize),
+    StaticCounter(&'static StaticCounter),
+}
+
+struct Sample {
+    source: SampleSource,
+    timestamp: Instant,
+    value: CounterValueField,
+}
+
+static GAUGES: Mutex<Vec<RegisteredGauge>> = Mutex::new(Vec::new());
+
+/// Samples that have been taken, but not yet processed by a [TraceBuilder].
+static SAMPLES: Mutex<Vec<Sample>> = Mutex::new(Vec::new());
+
+/// Registers a closure that will be polled each time samples are taken, either by a [Sampler] or by
+/// [sample_now]. Each gauge gets its own counter track named `name`.
+///
+/// The closure is called while holding an internal lock, so it must not call back into
+/// [register_gauge] or [sample_now].
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::Mutex;
+///
+/// let queue = Arc::new(Mutex::new(Vec::<u32>::new()));
+/// perfetto_recorder::register_gauge("queue depth", {
+///     let queue = queue.clone();
+///     move || queue.lock().unwrap().len() as f64
+/// });
+/// ```
+pub fn register_gauge(name: impl Into<String>, poll: impl Fn() -> f64 + Send + 'static) {
+    GAUGES.lock().unwrap().push(RegisteredGauge {
+        name: name.into(),
+        poll: Box::new(poll),
+    });
+}
+
+/// Polls all registered gauges and reads all [StaticCounter]s that have been used, storing the
+/// results until they're processed by [TraceBuilder::process_samples]. Does nothing if recording
+/// isn't enabled.
+pub fn sample_now() {
+    if !is_enabled() {
+        return;
+    }
+
+    let timestamp = time();
+    let mut samples = Vec::new();
+    let mut callbacks = Vec::new();
+
+    for (index, gauge) in GAUGES.lock().unwrap().iter().enumerate() {
+        let value = (gauge.poll)();
+        callbacks.extend(watchpoint::check(&gauge.name, timestamp, value));
+        samples.push(Sample {
+            source: SampleSource::Gauge(index),
+            timestamp,
+            value: CounterValueField::DoubleCounterValue(value),
+        });
+    }
+
+    for counter in STATIC_COUNTERS.lock().unwrap().iter() {
+        let value = counter.value();
+        callbacks.extend(watchpoint::check(counter.name, timestamp, value as f64));
+        samples.push(Sample {
+            source: SampleSource::StaticCounter(counter),
+            timestamp,
+            value: CounterValueField::CounterValue(value),
+        });
+    }
+
+    SAMPLES.lock().unwrap().append(&mut samples);
+
+    for callback in callbacks {
+        callback();
+    }
+}
+
+/// A background thread that periodically calls [sample_now]. The thread is stopped when this is
+/// dropped.
+pub struct Sampler {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Starts a background thread that samples all registered gauges and static counters every
+/// `interval`.
+pub fn start_sampler(interval: Duration) -> Sampler {
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = std::thread::Builder::new()
+        .name("perfetto-sampler".to_owned())
+        .spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    sample_now();
+                    std::thread::park_timeout(interval);
+                }
+            }
+        })
+        .expect("Failed to spawn sampler thread");
+
+    Sampler {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+impl Sampler {
+    /// Stops the sampler thread and waits for it to exit.
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl TraceBuilder {
+    /// Adds all samples that have been taken since the last call to this function to the trace,
+    /// together with markers for any watchpoints that they triggered.
+    pub fn process_samples(&mut self) -> &mut Self {
+        let samples = std::mem::take(&mut *SAMPLES.lock().unwrap());
+
+        for sample in samples {
+            let timestamp = self.clock_nanos(sample.timestamp);
+            match sample.source {
+                SampleSource::Gauge(index) => {
+                    let track = self.gauge_track(index);
+                    self.emit_counter_value(track.uuid, timestamp, sample.value);
+                }
+                SampleSource::StaticCounter(counter) => {
+                    self.emit_static_counter_value(counter, timestamp, sample.value);
+                }
+            }
+        }
+        self.process_watchpoint_markers();
+
+        self
+    }
+
+    fn gauge_track(&mut self, index: usize) -> CounterTrack {
+        if let Some(track) = self.gauge_tracks.get(&index) {
+            return *track;
+        }
+        let name = GAUGES.lock().unwrap()[index].name.clone();
+        let track = self.create_counter_track(name, CounterUnit::Unspecified, 1, false);
+        self.gauge_tracks.insert(index, track);
+        track
+    }
+}
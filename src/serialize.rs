@@ -0,0 +1,724 @@
+//! A compact binary format for [ThreadTraceData], so that it can be sent to another process, e.g.
+//! from worker processes to a coordinator that builds a single trace.
+
+use crate::CALL_SITES;
+use crate::CallSite;
+use crate::Event;
+use crate::LogLevel;
+use crate::Sensitivity;
+use crate::SourceInfo;
+use crate::ThreadTraceData;
+use crate::TimeAnchor;
+use crate::Timestamp;
+use crate::Unit;
+use crate::os;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 4] = b"PFRT";
+/// Incremented whenever the format changes, so that data written by another version of this crate
+/// is rejected rather than misread.
+const VERSION: u8 = 13;
+
+const START_SPAN: u8 = 0;
+const END_SPAN: u8 = 1;
+const TIMESTAMP: u8 = 2;
+const BOOL: u8 = 3;
+const U64: u8 = 4;
+const I64: u8 = 5;
+const F64: u8 = 6;
+const STRING: u8 = 7;
+const STR: u8 = 8;
+const COUNTER_I64: u8 = 9;
+const COUNTER_F64: u8 = 10;
+const FLOW: u8 = 11;
+const NAMED_FLOW: u8 = 12;
+const DICT_START: u8 = 13;
+const DICT_END: u8 = 14;
+const SPAWNED_THREAD: u8 = 15;
+const SPAWNED_BY: u8 = 16;
+const ANNOTATIONS: u8 = 17;
+const UNIT: u8 = 18;
+const ASYNC_START: u8 = 19;
+const ASYNC_END: u8 = 20;
+const FAILED_ASSERTION: u8 = 21;
+const END_ANNOTATIONS: u8 = 22;
+const SIMULATED_TIMESTAMP: u8 = 23;
+const ARRAY_START: u8 = 24;
+const CORRELATION_ID: u8 = 25;
+const LOG: u8 = 26;
+const ROOT_ANNOTATIONS: u8 = 27;
+
+/// Identifies a deserialized call site by its contents.
+#[derive(PartialEq, Eq, Hash)]
+struct CallSiteKey {
+    name: String,
+    file: String,
+    line: u32,
+    sensitivity: Sensitivity,
+    process_track: bool,
+    category: Option<String>,
+    track: Option<String>,
+    collapse_recursion: bool,
+    dynamic_name: bool,
+    arg_names: Vec<String>,
+    arg_sensitivities: Vec<Sensitivity>,
+    end_arg_names: Vec<String>,
+    end_arg_sensitivities: Vec<Sensitivity>,
+}
+
+/// Call sites that have been read from serialized data. These are leaked, since call sites need to
+/// be `'static`, so we reuse them when the same call site is read again.
+static DESERIALIZED_CALL_SITES: Mutex<Option<HashMap<CallSiteKey, &'static CallSite>>> =
+    Mutex::new(None);
+
+impl ThreadTraceData {
+    /// Writes the trace data to `writer` in a compact binary format that can be read back with
+    /// [ThreadTraceData::deserialize], possibly in another process. To reduce the size further, wrap
+    /// `writer` in a compressor.
+    ///
+    /// Counter values refer to counter tracks that were created by a [crate::TraceBuilder] in this
+    /// process, so they're only useful if that builder's trace is also included in the final trace.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if perfetto_recorder::is_enabled() {
+    /// let mut bytes = Vec::new();
+    /// ThreadTraceData::take_current_thread().serialize(&mut bytes)?;
+    ///
+    /// // Possibly in another process.
+    /// let thread_data = ThreadTraceData::deserialize(&mut bytes.as_slice())?;
+    /// TraceBuilder::new()?.process_thread_data(&thread_data);
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn serialize(&self, writer: &mut impl Write) -> Result<(), Error> {
+        let anchor = TimeAnchor::new();
+        let unix_nanos = |timestamp: Timestamp| match timestamp {
+            Timestamp::Instant(instant) => anchor.unix_nanos(instant),
+            Timestamp::UnixNanos(nanos) | Timestamp::Simulated(nanos) => nanos,
+        };
+
+        writer.write_all(MAGIC)?;
+        write_u8(writer, VERSION)?;
+        write_u32(writer, self.pid.as_i32() as u32)?;
+        write_u32(writer, self.tid.as_i32() as u32)?;
+        write_optional_str(writer, self.thread_name.as_deref())?;
+        match self.first_event_time {
+            Some(timestamp) => {
+                write_u8(writer, 1)?;
+                write_u64(writer, unix_nanos(timestamp))?;
+            }
+            None => write_u8(writer, 0)?,
+        }
+        write_u64(writer, unix_nanos(self.taken_time))?;
+        write_u64(writer, self.dropped_events)?;
+        match self.first_drop_time {
+            Some(timestamp) => {
+                write_u8(writer, 1)?;
+                write_u64(writer, unix_nanos(timestamp))?;
+            }
+            None => write_u8(writer, 0)?,
+        }
+        write_u8(
+            writer,
+            u8::from(self.thread_started) | u8::from(self.thread_exited) << 1,
+        )?;
+
+        let mut call_site_ids: Vec<u32> = self
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Event::StartSpan(id)
+                | Event::EndSpan(id)
+                | Event::Annotations(id)
+                | Event::EndAnnotations(id)
+                | Event::RootAnnotations(id)
+                | Event::AsyncStart { id, .. }
+                | Event::AsyncEnd { id, .. }
+                | Event::FailedAssertion(id)
+                | Event::Log { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        call_site_ids.sort_unstable();
+        call_site_ids.dedup();
+        let call_sites = CALL_SITES.lock().unwrap().clone();
+        write_u32(writer, call_site_ids.len() as u32)?;
+        for id in call_site_ids {
+            let source = &call_sites[id as usize - 1].source;
+            write_u32(writer, id)?;
+            write_str(writer, source.name)?;
+            write_str(writer, source.file)?;
+            write_u32(writer, source.line)?;
+            write_u8(writer, source.sensitivity as u8)?;
+            write_u8(writer, source.process_track as u8)?;
+            write_optional_str(writer, source.category)?;
+            write_optional_str(writer, source.track)?;
+            write_u8(writer, source.collapse_recursion as u8)?;
+            write_u8(writer, source.dynamic_name as u8)?;
+            write_strs(writer, source.arg_names)?;
+            write_sensitivities(writer, source.arg_sensitivities)?;
+            write_strs(writer, source.end_arg_names)?;
+            write_sensitivities(writer, source.end_arg_sensitivities)?;
+        }
+
+        write_u64(writer, self.events.len() as u64)?;
+        for event in &self.events {
+            match event {
+                Event::StartSpan(id) => {
+                    write_u8(writer, START_SPAN)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::EndSpan(id) => {
+                    write_u8(writer, END_SPAN)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::Timestamp(instant) => {
+                    write_u8(writer, TIMESTAMP)?;
+                    write_u64(writer, anchor.unix_nanos(*instant))?;
+                }
+                Event::UnixTimestamp(nanos) => {
+                    write_u8(writer, TIMESTAMP)?;
+                    write_u64(writer, *nanos)?;
+                }
+                Event::SimulatedTimestamp(nanos) => {
+                    write_u8(writer, SIMULATED_TIMESTAMP)?;
+                    write_u64(writer, *nanos)?;
+                }
+                Event::Bool(value) => {
+                    write_u8(writer, BOOL)?;
+                    write_u8(writer, *value as u8)?;
+                }
+                Event::U64(value) => {
+                    write_u8(writer, U64)?;
+                    write_u64(writer, *value)?;
+                }
+                Event::I64(value) => {
+                    write_u8(writer, I64)?;
+                    write_u64(writer, *value as u64)?;
+                }
+                Event::F64(value) => {
+                    write_u8(writer, F64)?;
+                    write_u64(writer, value.to_bits())?;
+                }
+                Event::String(value) => {
+                    write_u8(writer, STRING)?;
+                    write_str(writer, value)?;
+                }
+                Event::Str { offset, len } => {
+                    write_u8(writer, STR)?;
+                    write_str(writer, &self.strings[*offset..*offset + len])?;
+                }
+                Event::CounterI64 { uuid, value } => {
+                    write_u8(writer, COUNTER_I64)?;
+                    write_u64(writer, *uuid)?;
+                    write_u64(writer, *value as u64)?;
+                }
+                Event::CounterF64 { uuid, value } => {
+                    write_u8(writer, COUNTER_F64)?;
+                    write_u64(writer, *uuid)?;
+                    write_u64(writer, value.to_bits())?;
+                }
+                Event::Flow(id) => {
+                    write_u8(writer, FLOW)?;
+                    write_u64(writer, *id)?;
+                }
+                Event::NamedFlow(id) => {
+                    write_u8(writer, NAMED_FLOW)?;
+                    write_u64(writer, *id)?;
+                }
+                Event::CorrelationId(id) => {
+                    write_u8(writer, CORRELATION_ID)?;
+                    write_u64(writer, *id)?;
+                }
+                Event::DictStart => write_u8(writer, DICT_START)?,
+                Event::ArrayStart => write_u8(writer, ARRAY_START)?,
+                Event::DictEnd => write_u8(writer, DICT_END)?,
+                Event::SpawnedThread(id) => {
+                    write_u8(writer, SPAWNED_THREAD)?;
+                    write_u64(writer, *id)?;
+                }
+                Event::SpawnedBy(id) => {
+                    write_u8(writer, SPAWNED_BY)?;
+                    write_u64(writer, *id)?;
+                }
+                Event::Annotations(id) => {
+                    write_u8(writer, ANNOTATIONS)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::Unit(unit) => {
+                    write_u8(writer, UNIT)?;
+                    write_u8(writer, *unit as u8)?;
+                }
+                Event::AsyncStart { id, track } => {
+                    write_u8(writer, ASYNC_START)?;
+                    write_u32(writer, *id)?;
+                    write_u64(writer, *track)?;
+                }
+                Event::AsyncEnd { id, track } => {
+                    write_u8(writer, ASYNC_END)?;
+                    write_u32(writer, *id)?;
+                    write_u64(writer, *track)?;
+                }
+                Event::FailedAssertion(id) => {
+                    write_u8(writer, FAILED_ASSERTION)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::EndAnnotations(id) => {
+                    write_u8(writer, END_ANNOTATIONS)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::RootAnnotations(id) => {
+                    write_u8(writer, ROOT_ANNOTATIONS)?;
+                    write_u32(writer, *id)?;
+                }
+                Event::Log { id, level } => {
+                    write_u8(writer, LOG)?;
+                    write_u32(writer, *id)?;
+                    write_u8(writer, *level as u8)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads trace data that was written by [ThreadTraceData::serialize]. Returns an error if the
+    /// data is truncated or corrupt, including if its events aren't laid out as they're recorded, so
+    /// that data from another process can't cause processing to panic.
+    pub fn deserialize(reader: &mut impl Read) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not serialized thread trace data"));
+        }
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported thread trace data version {version}"
+            )));
+        }
+
+        let pid = os::Pid::from_i32(read_u32(reader)? as i32);
+        let tid = os::Pid::from_i32(read_u32(reader)? as i32);
+        let thread_name = read_optional_string(reader)?;
+        let first_event_time = match read_u8(reader)? {
+            0 => None,
+            _ => Some(Timestamp::UnixNanos(read_u64(reader)?)),
+        };
+        let taken_time = Timestamp::UnixNanos(read_u64(reader)?);
+        let dropped_events = read_u64(reader)?;
+        let first_drop_time = match read_u8(reader)? {
+            0 => None,
+            _ => Some(Timestamp::UnixNanos(read_u64(reader)?)),
+        };
+        let lifecycle = read_u8(reader)?;
+
+        let num_call_sites = read_u32(reader)?;
+        let mut call_site_ids = HashMap::new();
+        let mut call_sites = HashMap::new();
+        for _ in 0..num_call_sites {
+            let id = read_u32(reader)?;
+            let key = CallSiteKey {
+                name: read_string(reader)?,
+                file: read_string(reader)?,
+                line: read_u32(reader)?,
+                sensitivity: read_sensitivity(reader)?,
+                process_track: read_u8(reader)? != 0,
+                category: read_optional_string(reader)?,
+                track: read_optional_string(reader)?,
+                collapse_recursion: read_u8(reader)? != 0,
+                dynamic_name: read_u8(reader)? != 0,
+                arg_names: read_strings(reader)?,
+                arg_sensitivities: read_sensitivities(reader)?,
+                end_arg_names: read_strings(reader)?,
+                end_arg_sensitivities: read_sensitivities(reader)?,
+            };
+            if key.arg_names.len() != key.arg_sensitivities.len()
+                || key.end_arg_names.len() != key.end_arg_sensitivities.len()
+            {
+                return Err(invalid_data("Mismatched argument sensitivities"));
+            }
+            let call_site = deserialized_call_site(key);
+            call_site_ids.insert(id, call_site.id());
+            call_sites.insert(call_site.id(), call_site);
+        }
+        let call_site_id = |id: u32| {
+            call_site_ids
+                .get(&id)
+                .copied()
+                .ok_or_else(|| invalid_data(format!("Unknown call site {id}")))
+        };
+
+        let num_events = read_u64(reader)?;
+        let mut events = Vec::new();
+        let mut strings = String::new();
+        for _ in 0..num_events {
+            let event = match read_u8(reader)? {
+                START_SPAN => Event::StartSpan(call_site_id(read_u32(reader)?)?),
+                END_SPAN => Event::EndSpan(call_site_id(read_u32(reader)?)?),
+                TIMESTAMP => Event::UnixTimestamp(read_u64(reader)?),
+                SIMULATED_TIMESTAMP => Event::SimulatedTimestamp(read_u64(reader)?),
+                BOOL => Event::Bool(read_u8(reader)? != 0),
+                U64 => Event::U64(read_u64(reader)?),
+                I64 => Event::I64(read_u64(reader)? as i64),
+                F64 => Event::F64(f64::from_bits(read_u64(reader)?)),
+                STRING => Event::String(read_string(reader)?),
+                STR => {
+                    let value = read_string(reader)?;
+                    let offset = strings.len();
+                    strings.push_str(&value);
+                    Event::Str {
+                        offset,
+                        len: value.len(),
+                    }
+                }
+                COUNTER_I64 => Event::CounterI64 {
+                    uuid: read_u64(reader)?,
+                    value: read_u64(reader)? as i64,
+                },
+                COUNTER_F64 => Event::CounterF64 {
+                    uuid: read_u64(reader)?,
+                    value: f64::from_bits(read_u64(reader)?),
+                },
+                FLOW => Event::Flow(read_u64(reader)?),
+                NAMED_FLOW => Event::NamedFlow(read_u64(reader)?),
+                CORRELATION_ID => Event::CorrelationId(read_u64(reader)?),
+                DICT_START => Event::DictStart,
+                ARRAY_START => Event::ArrayStart,
+                DICT_END => Event::DictEnd,
+                SPAWNED_THREAD => Event::SpawnedThread(read_u64(reader)?),
+                SPAWNED_BY => Event::SpawnedBy(read_u64(reader)?),
+                ANNOTATIONS => Event::Annotations(call_site_id(read_u32(reader)?)?),
+                UNIT => {
+                    let unit = read_u8(reader)?;
+                    Event::Unit(
+                        Unit::from_u8(unit)
+                            .ok_or_else(|| invalid_data(format!("Unknown unit {unit}")))?,
+                    )
+                }
+                ASYNC_START => Event::AsyncStart {
+                    id: call_site_id(read_u32(reader)?)?,
+                    track: read_u64(reader)?,
+                },
+                ASYNC_END => Event::AsyncEnd {
+                    id: call_site_id(read_u32(reader)?)?,
+                    track: read_u64(reader)?,
+                },
+                FAILED_ASSERTION => Event::FailedAssertion(call_site_id(read_u32(reader)?)?),
+                END_ANNOTATIONS => Event::EndAnnotations(call_site_id(read_u32(reader)?)?),
+                ROOT_ANNOTATIONS => Event::RootAnnotations(call_site_id(read_u32(reader)?)?),
+                LOG => {
+                    let id = call_site_id(read_u32(reader)?)?;
+                    let level = read_u8(reader)?;
+                    Event::Log {
+                        id,
+                        level: LogLevel::from_u8(level)
+                            .ok_or_else(|| invalid_data(format!("Unknown log level {level}")))?,
+                    }
+                }
+                other => return Err(invalid_data(format!("Unknown event type {other}"))),
+            };
+            events.push(event);
+        }
+        EventChecker {
+            events: events.iter(),
+            strings: &strings,
+            call_sites: &call_sites,
+        }
+        .check()?;
+
+        Ok(Self {
+            events,
+            strings,
+            pid,
+            tid,
+            thread_name,
+            first_event_time,
+            taken_time,
+            dropped_events,
+            first_drop_time,
+            thread_started: lifecycle & 1 != 0,
+            thread_exited: lifecycle & 2 != 0,
+            session: 0,
+        })
+    }
+}
+
+/// Checks that deserialized events are laid out as [crate::TraceBuilder] expects, so that
+/// processing them can't panic. Events recorded by this process are trusted, but serialized data
+/// can come from anywhere, so is checked when it's read.
+struct EventChecker<'a> {
+    events: std::slice::Iter<'a, Event>,
+    strings: &'a str,
+    /// The call sites that the events refer to, by ID.
+    call_sites: &'a HashMap<u32, &'static CallSite>,
+}
+
+impl EventChecker<'_> {
+    fn check(&mut self) -> Result<(), Error> {
+        while let Some(event) = self.events.next() {
+            match event {
+                Event::StartSpan(id) | Event::AsyncStart { id, .. } => {
+                    let source = &self.call_sites[id].source;
+                    self.timestamp()?;
+                    if source.dynamic_name {
+                        self.string()?;
+                    }
+                    self.args(source.arg_names.len())?;
+                }
+                Event::EndSpan(id) | Event::AsyncEnd { id, .. } => {
+                    self.timestamp()?;
+                    self.args(self.call_sites[id].source.end_arg_names.len())?;
+                }
+                Event::FailedAssertion(id) | Event::Log { id, .. } => {
+                    self.timestamp()?;
+                    self.args(self.call_sites[id].source.arg_names.len())?;
+                }
+                Event::CounterI64 { .. } | Event::CounterF64 { .. } => self.timestamp()?,
+                Event::Annotations(_) | Event::EndAnnotations(_) | Event::RootAnnotations(_) => {
+                    while !self.at_dict_end() {
+                        self.string()?;
+                        self.value()?;
+                    }
+                    self.events.next();
+                }
+                Event::SpawnedThread(_) | Event::SpawnedBy(_) => {}
+                other => return Err(unexpected(Some(other))),
+            }
+        }
+        Ok(())
+    }
+
+    fn timestamp(&mut self) -> Result<(), Error> {
+        match self.events.next() {
+            Some(Event::UnixTimestamp(_) | Event::SimulatedTimestamp(_)) => Ok(()),
+            _ => Err(invalid_data("Event isn't followed by a timestamp")),
+        }
+    }
+
+    fn args(&mut self, count: usize) -> Result<(), Error> {
+        for _ in 0..count {
+            self.structured_arg()?;
+        }
+        Ok(())
+    }
+
+    /// Checks an argument, which might be a dictionary or array, or linked to a flow.
+    fn structured_arg(&mut self) -> Result<(), Error> {
+        match self.events.as_slice().first() {
+            Some(Event::DictStart) => {
+                self.events.next();
+                while !self.at_dict_end() {
+                    self.string()?;
+                    self.structured_arg()?;
+                }
+                self.events.next();
+            }
+            Some(Event::ArrayStart) => {
+                self.events.next();
+                while !self.at_dict_end() {
+                    self.structured_arg()?;
+                }
+                self.events.next();
+            }
+            Some(Event::Flow(_) | Event::CorrelationId(_)) => {
+                self.events.next();
+            }
+            Some(Event::NamedFlow(_)) => {
+                self.events.next();
+                self.value()?;
+            }
+            _ => self.value()?,
+        }
+        Ok(())
+    }
+
+    /// Returns whether the next event ends a dictionary or array. Running out of events doesn't,
+    /// so that reading the next entry fails.
+    fn at_dict_end(&self) -> bool {
+        matches!(self.events.as_slice().first(), Some(Event::DictEnd))
+    }
+
+    /// Checks a value that must be a string, such as a name.
+    fn string(&mut self) -> Result<(), Error> {
+        match self.events.as_slice().first() {
+            Some(Event::String(_) | Event::Str { .. }) => self.value(),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn value(&mut self) -> Result<(), Error> {
+        match self.events.next() {
+            Some(Event::Bool(_) | Event::U64(_) | Event::I64(_) | Event::F64(_)) => Ok(()),
+            Some(Event::String(_)) => Ok(()),
+            Some(Event::Str { offset, len }) => {
+                if offset
+                    .checked_add(*len)
+                    .and_then(|end| self.strings.get(*offset..end))
+                    .is_none()
+                {
+                    return Err(invalid_data("String argument is out of range"));
+                }
+                Ok(())
+            }
+            Some(Event::Unit(_)) => match self.events.next() {
+                Some(Event::U64(_) | Event::I64(_) | Event::F64(_)) => Ok(()),
+                other => Err(unexpected(other)),
+            },
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+fn unexpected(event: Option<&Event>) -> Error {
+    match event {
+        Some(event) => invalid_data(format!("Unexpected event {event:?}")),
+        None => invalid_data("Events ended unexpectedly"),
+    }
+}
+
+/// Returns a call site with the supplied name, location, arguments and sensitivities, creating it if
+/// this is the first time we've seen it.
+fn deserialized_call_site(key: CallSiteKey) -> &'static CallSite {
+    let mut call_sites = DESERIALIZED_CALL_SITES.lock().unwrap();
+    let call_sites = call_sites.get_or_insert_default();
+    if let Some(call_site) = call_sites.get(&key) {
+        return call_site;
+    }
+
+    fn leak_strs(strings: &[String]) -> &'static [&'static str] {
+        Vec::leak(strings.iter().map(|s| &*String::leak(s.clone())).collect())
+    }
+
+    let call_site = Box::leak(Box::new(CallSite::new(SourceInfo {
+        name: String::leak(key.name.clone()),
+        file: String::leak(key.file.clone()),
+        line: key.line,
+        sensitivity: key.sensitivity,
+        process_track: key.process_track,
+        category: key
+            .category
+            .clone()
+            .map(|category| &*String::leak(category)),
+        track: key.track.clone().map(|track| &*String::leak(track)),
+        collapse_recursion: key.collapse_recursion,
+        dynamic_name: key.dynamic_name,
+        arg_names: leak_strs(&key.arg_names),
+        arg_sensitivities: Vec::leak(key.arg_sensitivities.clone()),
+        end_arg_names: leak_strs(&key.end_arg_names),
+        end_arg_sensitivities: Vec::leak(key.end_arg_sensitivities.clone()),
+    })));
+    call_sites.insert(key, call_site);
+    call_site
+}
+
+fn invalid_data(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> Result<(), Error> {
+    writer.write_all(&[value])
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> Result<(), Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> Result<(), Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_str(writer: &mut impl Write, value: &str) -> Result<(), Error> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn write_optional_str(writer: &mut impl Write, value: Option<&str>) -> Result<(), Error> {
+    match value {
+        Some(value) => {
+            write_u8(writer, 1)?;
+            write_str(writer, value)
+        }
+        None => write_u8(writer, 0),
+    }
+}
+
+fn write_strs(writer: &mut impl Write, values: &[&str]) -> Result<(), Error> {
+    write_u32(writer, values.len() as u32)?;
+    for value in values {
+        write_str(writer, value)?;
+    }
+    Ok(())
+}
+
+fn write_sensitivities(writer: &mut impl Write, values: &[Sensitivity]) -> Result<(), Error> {
+    write_u32(writer, values.len() as u32)?;
+    for value in values {
+        write_u8(writer, *value as u8)?;
+    }
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8, Error> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String, Error> {
+    let len = read_u32(reader)?;
+    let mut bytes = Vec::new();
+    // Use `take` rather than allocating `len` bytes up-front, so that corrupt data can't cause us to
+    // allocate lots of memory.
+    Read::take(&mut *reader, len.into()).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("Invalid UTF-8"))
+}
+
+fn read_optional_string(reader: &mut impl Read) -> Result<Option<String>, Error> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        _ => read_string(reader).map(Some),
+    }
+}
+
+fn read_strings(reader: &mut impl Read) -> Result<Vec<String>, Error> {
+    let len = read_u32(reader)?;
+    (0..len).map(|_| read_string(reader)).collect()
+}
+
+fn read_sensitivity(reader: &mut impl Read) -> Result<Sensitivity, Error> {
+    match read_u8(reader)? {
+        0 => Ok(Sensitivity::Public),
+        1 => Ok(Sensitivity::Internal),
+        2 => Ok(Sensitivity::Secret),
+        other => Err(invalid_data(format!("Unknown sensitivity {other}"))),
+    }
+}
+
+fn read_sensitivities(reader: &mut impl Read) -> Result<Vec<Sensitivity>, Error> {
+    let len = read_u32(reader)?;
+    (0..len).map(|_| read_sensitivity(reader)).collect()
+}
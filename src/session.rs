@@ -0,0 +1,501 @@
+//! Configuring a recording session, optionally from a Perfetto `TraceConfig`, so that existing
+//! Perfetto config files can select what this crate records.
+
+use crate::BudgetAction;
+use crate::CallSite;
+use crate::Error;
+use crate::Event;
+use crate::Instant;
+use crate::Sensitivity;
+use crate::SourceInfo;
+use crate::ThreadTraceData;
+use crate::TraceBuilder;
+use crate::TracingDisabledAtBuildTime;
+use crate::control;
+use crate::dump;
+use crate::exit;
+use crate::pattern_matches;
+use crate::record_event;
+use crate::resume;
+use crate::ring_buffer;
+use crate::schema;
+use crate::set_memory_budget;
+use crate::start;
+use crate::stop_recording;
+use crate::time;
+use prost::Message;
+use std::cell::Cell;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The name of the Perfetto data source that our events correspond to.
+const TRACK_EVENT_DATA_SOURCE: &str = "track_event";
+
+/// Incremented each time a session is started with [start_with_config] or [start_session].
+static SESSION: AtomicU32 = AtomicU32::new(0);
+
+/// How many threads still have events buffered from before the current session started. Until
+/// they've all handed their events over, each thread is asked to when it next records an event, so
+/// that the session's events are kept apart from earlier ones.
+static STALE_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Incremented each time the category filter changes. Zero means that no filter has been set, so all
+/// categories are enabled.
+static FILTER_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// The largest filter generation. Generations need to fit in a call site's cached state alongside
+/// the answer, so they wrap around to 1 after this, rather than to 0, which would disable the
+/// filter.
+pub(crate) const MAX_FILTER_GENERATION: u32 = u32::MAX >> 1;
+
+/// When the session started by [start_session] began, or `None` if there isn't one.
+static SESSION_START: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// The categories that are enabled in the current session.
+static CATEGORY_FILTER: RwLock<CategoryFilter> = RwLock::new(CategoryFilter {
+    enabled: Vec::new(),
+    disabled: Vec::new(),
+});
+
+/// The call site of the span recorded when [SessionConfig::set_lifetime_span] is enabled.
+static LIFETIME_CALL_SITE: CallSite = CallSite::new(SourceInfo {
+    name: "process lifetime",
+    file: "",
+    line: 0,
+    sensitivity: Sensitivity::Public,
+    process_track: false,
+    category: None,
+    track: None,
+    collapse_recursion: false,
+    dynamic_name: false,
+    arg_names: &[],
+    arg_sensitivities: &[],
+    end_arg_names: &[],
+    end_arg_sensitivities: &[],
+});
+
+thread_local! {
+    /// Whether the current thread has a lifetime span that hasn't yet been ended.
+    static LIFETIME_SPAN_OPEN: Cell<bool> = const { Cell::new(false) };
+
+    /// How many spans, counting the lifetime span, were left open by the events that have already
+    /// been taken from the current thread's buffer since the lifetime span was started.
+    static LIFETIME_SPAN_DEPTH: Cell<isize> = const { Cell::new(0) };
+
+    /// The session during which the current thread's buffered events were recorded.
+    static BUFFER_SESSION: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Configuration for a recording session started by [start_with_config].
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::SessionConfig;
+///
+/// let mut config = SessionConfig::new();
+/// config
+///     .enable_category("db*")
+///     .disable_category("db.verbose")
+///     .set_buffer_size(64 * 1024 * 1024)
+///     .set_duration(std::time::Duration::from_secs(10));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    enabled_categories: Vec<String>,
+    disabled_categories: Vec<String>,
+    buffer_size: Option<usize>,
+    duration: Option<Duration>,
+    lifetime_span: bool,
+}
+
+/// Returned by [SessionConfig::from_trace_config] if the supplied bytes aren't a valid
+/// `TraceConfig`.
+#[derive(Debug)]
+pub struct InvalidTraceConfig;
+
+struct CategoryFilter {
+    enabled: Vec<String>,
+    disabled: Vec<String>,
+}
+
+impl SessionConfig {
+    /// Creates a configuration that records all categories, with no limit on buffer size or
+    /// duration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a configuration from an encoded Perfetto `TraceConfig` proto, as used to configure
+    /// the Perfetto SDK. The enabled and disabled categories are taken from the `track_event` data
+    /// source, the buffer size is the total size of all buffers and the duration is `duration_ms`.
+    /// Other settings are ignored.
+    pub fn from_trace_config(bytes: &[u8]) -> Result<Self, InvalidTraceConfig> {
+        let trace_config = schema::TraceConfig::decode(bytes).map_err(|_| InvalidTraceConfig)?;
+
+        let mut config = SessionConfig::new();
+        for data_source in trace_config.data_sources {
+            let Some(data_source_config) = data_source.config else {
+                continue;
+            };
+            if data_source_config.name.as_deref() != Some(TRACK_EVENT_DATA_SOURCE) {
+                continue;
+            }
+            if let Some(track_event_config) = data_source_config.track_event_config {
+                config
+                    .enabled_categories
+                    .extend(track_event_config.enabled_categories);
+                config
+                    .disabled_categories
+                    .extend(track_event_config.disabled_categories);
+            }
+        }
+        let buffer_size_kb: u64 = trace_config
+            .buffers
+            .iter()
+            .filter_map(|buffer| buffer.size_kb)
+            .map(u64::from)
+            .sum();
+        if buffer_size_kb != 0 {
+            config.buffer_size = Some((buffer_size_kb * 1024) as usize);
+        }
+        if let Some(duration_ms) = trace_config.duration_ms
+            && duration_ms != 0
+        {
+            config.duration = Some(Duration::from_millis(duration_ms.into()));
+        }
+        Ok(config)
+    }
+
+    /// Enables spans whose category matches `pattern`. A pattern ending in `*` matches all
+    /// categories that start with the rest of the pattern. If no categories are enabled, then all
+    /// categories that aren't disabled are recorded.
+    pub fn enable_category(&mut self, pattern: impl Into<String>) -> &mut Self {
+        self.enabled_categories.push(pattern.into());
+        self
+    }
+
+    /// Disables spans whose category matches `pattern`, even if it's also enabled. Patterns are as
+    /// for [SessionConfig::enable_category].
+    pub fn disable_category(&mut self, pattern: impl Into<String>) -> &mut Self {
+        self.disabled_categories.push(pattern.into());
+        self
+    }
+
+    /// Stops recording once recorded events use more than `bytes` of memory. See
+    /// [crate::set_memory_budget].
+    pub fn set_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.buffer_size = Some(bytes);
+        self
+    }
+
+    /// Stops recording once `duration` has passed since the session was started.
+    pub fn set_duration(&mut self, duration: Duration) -> &mut Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// When enabled, [start_with_config] opens a span named "process lifetime" on the calling
+    /// thread, which should normally be the main thread. The span is ended when that thread's data
+    /// is taken by [crate::ThreadTraceData::take_current_thread], so it contains all of the thread's
+    /// other spans and its duration is the total runtime that was traced. If other spans on the
+    /// thread are still open at that point, it's instead ended the next time the thread's data is
+    /// taken without any open.
+    pub fn set_lifetime_span(&mut self, enabled: bool) -> &mut Self {
+        self.lifetime_span = enabled;
+        self
+    }
+}
+
+/// Enables recording in the same way as [start], but only records spans in the categories enabled by
+/// `config`, and stops recording once the buffer size or duration in `config` is exceeded. The
+/// category settings replace those of any previous session.
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::SessionConfig;
+///
+/// # fn trace_config_bytes() -> Vec<u8> { Vec::new() }
+/// let config = SessionConfig::from_trace_config(&trace_config_bytes())?;
+/// let _ = perfetto_recorder::start_with_config(&config);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn start_with_config(config: &SessionConfig) -> Result<(), TracingDisabledAtBuildTime> {
+    if !cfg!(feature = "enable") {
+        return Err(TracingDisabledAtBuildTime);
+    }
+
+    set_categories(
+        config.enabled_categories.clone(),
+        config.disabled_categories.clone(),
+    );
+    let session = SESSION.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if let Some(buffer_size) = config.buffer_size {
+        set_memory_budget(buffer_size, BudgetAction::Stop);
+    }
+    if let Some(duration) = config.duration {
+        std::thread::Builder::new()
+            .name("perfetto-session-timer".to_owned())
+            .spawn(move || {
+                std::thread::sleep(duration);
+                // Don't stop a session that was started after this one.
+                if SESSION.load(Ordering::Relaxed) == session {
+                    stop_recording();
+                }
+            })
+            .expect("Failed to spawn session timer thread");
+    }
+
+    start()?;
+    if config.lifetime_span {
+        start_lifetime_span();
+    }
+    Ok(())
+}
+
+/// Starts recording a session that's ended by [end_session], which returns the data recorded
+/// during it. This allows several separate windows, e.g. one per benchmark iteration, to each be
+/// written as their own trace. Recording is enabled as for [start] and the category settings of any
+/// previous session are kept.
+///
+/// # Example
+///
+/// ```
+/// # if perfetto_recorder::start().is_ok() {
+/// for iteration in 0..3 {
+///     perfetto_recorder::start_session()?;
+///     {
+///         perfetto_recorder::scope!("iteration", iteration);
+///     }
+///     let session = perfetto_recorder::end_session();
+///     session.write_to_file(std::env::temp_dir().join(format!("iteration-{iteration}.pftrace")))?;
+/// }
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn start_session() -> Result<(), TracingDisabledAtBuildTime> {
+    start()?;
+    SESSION.fetch_add(1, Ordering::Relaxed);
+    *SESSION_START.lock().unwrap() = Some(time());
+    let stale_buffers = exit::buffering_threads();
+    STALE_BUFFERS.store(stale_buffers, Ordering::Relaxed);
+    if stale_buffers != 0 {
+        dump::request_handovers();
+    }
+    Ok(())
+}
+
+/// Ends the session started by [start_session], disables recording and returns the data that was
+/// recorded during the session. This includes the data of the calling thread, of threads that
+/// exited during the session and of other threads that record an event shortly after it ends, as
+/// for [crate::install_dump_handler]. Each thread's events are kept apart by the session that they
+/// were recorded in, so data from before the session, e.g. of threads that exited before it
+/// started, is left for [TraceBuilder::process_exited_threads], and data that a thread hands over
+/// late is never attributed to a later session. Returns empty data if no session was started.
+pub fn end_session() -> SessionData {
+    let end = time();
+    let Some(start) = SESSION_START.lock().unwrap().take() else {
+        return SessionData {
+            threads: Vec::new(),
+            start: end,
+            end,
+        };
+    };
+    // Running threads only hand over their events when they record another one, so recording stays
+    // on, or is resumed if it's paused, until they've had a chance to.
+    resume();
+    dump::collect_running_threads();
+    STALE_BUFFERS.store(0, Ordering::Relaxed);
+    stop_recording();
+    let session = SESSION.load(Ordering::Relaxed);
+    let mut threads = exit::take_stashed_if(|thread| thread.session == session);
+    let current = ThreadTraceData::take_current_thread();
+    if current.session == session && !current.events.is_empty() {
+        threads.push(current);
+    } else if !current.events.is_empty() {
+        exit::stash(current);
+    }
+    SessionData {
+        threads,
+        start,
+        end,
+    }
+}
+
+/// The data recorded during a session. Returned by [end_session].
+pub struct SessionData {
+    pub(crate) threads: Vec<ThreadTraceData>,
+    pub(crate) start: Instant,
+    pub(crate) end: Instant,
+}
+
+impl SessionData {
+    /// Returns the data of each thread that recorded events during the session. It may also
+    /// include events recorded before or after the session.
+    pub fn threads(&self) -> &[ThreadTraceData] {
+        &self.threads
+    }
+
+    /// Returns when the session started.
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Returns when the session ended.
+    pub fn end(&self) -> Instant {
+        self.end
+    }
+
+    /// Builds the session's trace, using the options set by [crate::Control::set_trace_options].
+    /// Events outside the session are removed and spans that cross its start or end are clipped,
+    /// as for [TraceBuilder::restrict_to].
+    pub fn into_trace_builder(self) -> TraceBuilder {
+        // Recording is disabled once a session ends, so we can't use `TraceBuilder::with_options`.
+        let mut trace = TraceBuilder::build(&control().trace_options(), false);
+        trace.process_all(self.threads);
+        trace.restrict_to(self.start, self.end);
+        trace
+    }
+
+    /// Writes the session's trace to `path`. See [SessionData::into_trace_builder].
+    pub fn write_to_file(self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.into_trace_builder().write_to_file(path)
+    }
+}
+
+/// Called when the current thread records its first events, which belong to the current session.
+pub(crate) fn start_buffer() {
+    BUFFER_SESSION.set(SESSION.load(Ordering::Relaxed));
+}
+
+/// Returns the session during which the current thread's buffered events were recorded, for when
+/// they're being taken. Events that are recorded afterwards belong to the current session.
+pub(crate) fn take_buffer_session() -> u32 {
+    let current = SESSION.load(Ordering::Relaxed);
+    let session = BUFFER_SESSION
+        .try_with(|session| session.replace(current))
+        .unwrap_or(current);
+    if session != current
+        && STALE_BUFFERS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+            count.checked_sub(1)
+        }) == Ok(1)
+    {
+        ring_buffer::request_handover(false);
+    }
+    session
+}
+
+/// Opens a lifetime span on the current thread, unless it already has one that's open.
+pub(crate) fn start_lifetime_span() {
+    if LIFETIME_SPAN_OPEN.replace(true) {
+        return;
+    }
+    // Spans that are already open were started outside the lifetime span.
+    LIFETIME_SPAN_DEPTH.set(-crate::EVENTS.with_borrow(|events| open_spans(events)));
+    record_event(Event::StartSpan(LIFETIME_CALL_SITE.id()));
+    record_event(Event::Timestamp(time()));
+}
+
+/// Ends the current thread's lifetime span, if it has one that's open, before all of the thread's
+/// buffered events are taken. If spans inside it are still open, ending it now would end it before
+/// them, so it's instead ended the next time the thread's events are taken without any open.
+pub(crate) fn end_lifetime_span() {
+    if !LIFETIME_SPAN_OPEN.get() {
+        return;
+    }
+    let depth = LIFETIME_SPAN_DEPTH.get() + crate::EVENTS.with_borrow(|events| open_spans(events));
+    if depth > 1 {
+        LIFETIME_SPAN_DEPTH.set(depth);
+        return;
+    }
+    LIFETIME_SPAN_OPEN.set(false);
+    record_event(Event::EndSpan(LIFETIME_CALL_SITE.id()));
+    record_event(Event::Timestamp(time()));
+}
+
+/// Called when `events` are taken from the current thread's buffer while leaving the rest, so that
+/// spans they start or end are still counted when deciding whether the lifetime span can be ended.
+pub(crate) fn lifetime_events_taken(events: &[Event]) {
+    if LIFETIME_SPAN_OPEN.get() {
+        LIFETIME_SPAN_DEPTH.set(LIFETIME_SPAN_DEPTH.get() + open_spans(events));
+    }
+}
+
+/// Returns how many more spans `events` start than they end.
+fn open_spans(events: &[Event]) -> isize {
+    events
+        .iter()
+        .map(|event| match event {
+            Event::StartSpan(_) => 1,
+            Event::EndSpan(_) => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Replaces the category filter. Call sites pick up the new filter the next time they start a span.
+pub(crate) fn set_categories(enabled: Vec<String>, disabled: Vec<String>) {
+    *CATEGORY_FILTER.write().unwrap() = CategoryFilter { enabled, disabled };
+    // This must happen after we've updated the filter, so that call sites that see the new
+    // generation also see the new filter.
+    let _ = FILTER_GENERATION.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |generation| {
+        Some(next_filter_generation(generation))
+    });
+}
+
+pub(crate) fn next_filter_generation(generation: u32) -> u32 {
+    if generation >= MAX_FILTER_GENERATION {
+        1
+    } else {
+        generation + 1
+    }
+}
+
+/// Returns whether `category` is enabled by the current filter. `state` caches the answer for a
+/// particular call site. It holds the filter generation for which it was computed, shifted left by
+/// one, with the answer in the lowest bit.
+#[inline(always)]
+pub(crate) fn category_enabled(category: &'static str, state: &AtomicU32) -> bool {
+    let generation = FILTER_GENERATION.load(Ordering::Relaxed);
+    if generation == 0 {
+        return true;
+    }
+    let cached = state.load(Ordering::Relaxed);
+    if cached >> 1 == generation {
+        return cached & 1 != 0;
+    }
+    category_enabled_slow(category, state, generation)
+}
+
+#[cold]
+fn category_enabled_slow(category: &str, state: &AtomicU32, generation: u32) -> bool {
+    let enabled = CATEGORY_FILTER.read().unwrap().is_enabled(category);
+    state.store(generation << 1 | enabled as u32, Ordering::Relaxed);
+    enabled
+}
+
+impl CategoryFilter {
+    fn is_enabled(&self, category: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| pattern_matches(pattern, category))
+        };
+        (self.enabled.is_empty() || matches(&self.enabled)) && !matches(&self.disabled)
+    }
+}
+
+impl std::error::Error for InvalidTraceConfig {}
+
+impl std::fmt::Display for InvalidTraceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid Perfetto TraceConfig")
+    }
+}
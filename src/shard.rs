@@ -0,0 +1,261 @@
+//! Splitting a trace into a file per thread.
+
+use crate::Error;
+use crate::FileOutput;
+use crate::TraceBuilder;
+use crate::TraceOutput;
+use crate::merge_interned;
+use crate::packet_defaults;
+use crate::schema;
+use crate::schema::trace_packet::Data;
+use crate::schema::trace_packet::OptionalTrustedPacketSequenceId;
+use prost::Message;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The name of the manifest written by [TraceBuilder::write_sharded].
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// A file written by [TraceBuilder::write_sharded].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceShard {
+    /// The name of the file within the directory.
+    pub file_name: String,
+    /// The process that the shard's events were recorded by, if known.
+    pub pid: Option<i32>,
+    /// The thread that the shard's events were recorded by, or `None` for the shard of a process's
+    /// own tracks, such as counters and async spans.
+    pub tid: Option<i32>,
+    /// The name of the thread, or of the process for the shard of a process's own tracks.
+    pub name: Option<String>,
+    /// The number of packets in the shard, including the descriptors and clock snapshots that each
+    /// shard needs in order to be read on its own.
+    pub num_packets: usize,
+}
+
+/// The thread or process that a track belongs to, identified by the UUID of its descriptor, or
+/// `None` for tracks that don't belong to either.
+type Owner = Option<u64>;
+
+impl TraceBuilder {
+    /// Writes the trace to `dir` as a separate file for each thread, so that analysts can open just
+    /// the threads that they're interested in and tools can process the files in parallel. Tracks
+    /// that belong to a process rather than one of its threads, such as counters and async spans,
+    /// are written to a file for the process. Each file is a complete trace that includes the
+    /// descriptors of its tracks. A `manifest.json` listing the files is written alongside them.
+    /// The directory is created if it doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use perfetto_recorder::*;
+    /// # if start().is_ok() {
+    /// scope!("work");
+    /// let mut trace = TraceBuilder::new()?;
+    /// trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    /// for shard in trace.write_sharded(std::env::temp_dir().join("shards"))? {
+    ///     println!("{}: {:?}", shard.file_name, shard.name);
+    /// }
+    /// # }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn write_sharded(&self, dir: impl AsRef<Path>) -> Result<Vec<TraceShard>, Error> {
+        if self.noop {
+            return Ok(Vec::new());
+        }
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(|source| Error::Write {
+            path: dir.to_owned(),
+            source,
+        })?;
+
+        let trace = self.finished_trace();
+        let descriptors: HashMap<u64, &schema::TrackDescriptor> = trace
+            .packet
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                Some(Data::TrackDescriptor(descriptor)) => Some((descriptor.uuid?, descriptor)),
+                _ => None,
+            })
+            .collect();
+        let owner_of = |track: u64| -> Owner {
+            let mut uuid = track;
+            loop {
+                let descriptor = descriptors.get(&uuid)?;
+                if descriptor.thread.is_some() || descriptor.process.is_some() {
+                    return Some(uuid);
+                }
+                uuid = descriptor.parent_uuid?;
+            }
+        };
+
+        // The owner of each packet's track, or `None` if the packet isn't associated with a track,
+        // e.g. clock snapshots, in which case it's included in every shard.
+        let packet_owners: Vec<Option<Owner>> = trace
+            .packet
+            .iter()
+            .map(|packet| match &packet.data {
+                Some(Data::TrackDescriptor(descriptor)) => descriptor.uuid.map(owner_of),
+                Some(Data::TrackEvent(event)) => event.track_uuid.map(owner_of),
+                _ => None,
+            })
+            .collect();
+        let mut owners: Vec<Owner> = Vec::new();
+        for (packet, owner) in trace.packet.iter().zip(&packet_owners) {
+            if let (Some(Data::TrackEvent(_)), Some(owner)) = (&packet.data, owner)
+                && !owners.contains(owner)
+            {
+                owners.push(*owner);
+            }
+        }
+
+        let mut shards = Vec::new();
+        let mut file_names = HashSet::new();
+        for owner in owners {
+            // A thread's shard also needs the descriptor of its process, which is found by its pid
+            // rather than being the thread's parent.
+            let mut ancestors: HashSet<u64> =
+                std::iter::successors(owner, |uuid| descriptors.get(uuid)?.parent_uuid).collect();
+            let thread_pid = owner
+                .and_then(|uuid| descriptors.get(&uuid)?.thread.as_ref())
+                .map(|thread| thread.pid);
+            if let Some(pid) = thread_pid {
+                ancestors.extend(descriptors.iter().filter_map(|(uuid, descriptor)| {
+                    let process = descriptor.process.as_ref()?;
+                    (process.pid == pid).then_some(*uuid)
+                }));
+            }
+            let keep = trace
+                .packet
+                .iter()
+                .zip(&packet_owners)
+                .map(|(packet, packet_owner)| match packet_owner {
+                    None => true,
+                    Some(packet_owner) => {
+                        *packet_owner == owner
+                            || matches!(&packet.data, Some(Data::TrackDescriptor(descriptor))
+                                if descriptor.uuid.is_some_and(|uuid| ancestors.contains(&uuid)))
+                    }
+                });
+            let mut shard_trace = schema::Trace {
+                packet: select_packets(&trace.packet, keep),
+            };
+            if self.packet_defaults {
+                packet_defaults::apply_packet_defaults(&mut shard_trace.packet);
+            }
+
+            let mut shard = shard_info(owner.and_then(|uuid| descriptors.get(&uuid).copied()));
+            shard.num_packets = shard_trace.packet.len();
+            // Threads can share IDs when traces from several machines have been merged.
+            let stem = shard.file_name.clone();
+            let mut suffix = 1;
+            while !file_names.insert(shard.file_name.clone()) {
+                suffix += 1;
+                shard.file_name = format!("{stem}-{suffix}");
+            }
+            shard.file_name.push_str(".pftrace");
+
+            let mut bytes = shard_trace.encode_to_vec();
+            if self.footer {
+                self.append_footer(&shard_trace, &mut bytes);
+            }
+            FileOutput::new(dir.join(&shard.file_name)).write_trace(&bytes)?;
+            shards.push(shard);
+        }
+
+        FileOutput::new(dir.join(MANIFEST_FILE_NAME)).write_trace(manifest(&shards).as_bytes())?;
+        Ok(shards)
+    }
+}
+
+/// Describes the shard for the thread or process with `descriptor`. The file name is returned
+/// without an extension.
+fn shard_info(descriptor: Option<&schema::TrackDescriptor>) -> TraceShard {
+    let mut shard = TraceShard {
+        file_name: "other".to_owned(),
+        pid: None,
+        tid: None,
+        name: None,
+        num_packets: 0,
+    };
+    if let Some(thread) = descriptor.and_then(|descriptor| descriptor.thread.as_ref()) {
+        shard.pid = thread.pid;
+        shard.tid = thread.tid;
+        shard.name = thread.thread_name.clone();
+        shard.file_name = format!(
+            "thread-{}-{}",
+            thread.pid.unwrap_or_default(),
+            thread.tid.unwrap_or_default()
+        );
+    } else if let Some(process) = descriptor.and_then(|descriptor| descriptor.process.as_ref()) {
+        shard.pid = process.pid;
+        shard.name = process.process_name.clone();
+        shard.file_name = format!("process-{}", process.pid.unwrap_or_default());
+    }
+    shard
+}
+
+/// Returns copies of the `packets` for which `keep` is true. As in
+/// [TraceBuilder::retain_packets], interned data from packets that aren't kept is moved to the next
+/// packet that is kept in the same sequence.
+fn select_packets(
+    packets: &[schema::TracePacket],
+    keep: impl Iterator<Item = bool>,
+) -> Vec<schema::TracePacket> {
+    let mut carried: HashMap<Option<OptionalTrustedPacketSequenceId>, schema::InternedData> =
+        HashMap::new();
+    let mut selected = Vec::new();
+    for (packet, keep) in packets.iter().zip(keep) {
+        let sequence = packet.optional_trusted_packet_sequence_id;
+        if !keep {
+            if let Some(interned) = &packet.interned_data {
+                let mut pending = carried.remove(&sequence);
+                merge_interned(&mut pending, interned.clone());
+                carried.insert(sequence, pending.unwrap_or_default());
+            }
+            continue;
+        }
+        let mut packet = packet.clone();
+        if let Some(pending) = carried.remove(&sequence) {
+            merge_interned(&mut packet.interned_data, pending);
+        }
+        selected.push(packet);
+    }
+    selected
+}
+
+/// Returns the JSON manifest that lists `shards`.
+fn manifest(shards: &[TraceShard]) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_owned());
+    let entries: Vec<String> = shards
+        .iter()
+        .map(|shard| {
+            format!(
+                "    {{\"file\": {}, \"pid\": {}, \"tid\": {}, \"name\": {}, \"packets\": {}}}",
+                json_string(&shard.file_name),
+                optional(shard.pid.map(|pid| pid.to_string())),
+                optional(shard.tid.map(|tid| tid.to_string())),
+                optional(shard.name.as_deref().map(json_string)),
+                shard.num_packets,
+            )
+        })
+        .collect();
+    format!("{{\n  \"shards\": [\n{}\n  ]\n}}\n", entries.join(",\n"))
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
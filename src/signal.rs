@@ -0,0 +1,128 @@
+//! Recording of instant events from contexts such as signal handlers, where we can't allocate or
+//! take locks.
+
+use crate::BuiltinClock;
+use crate::CallSite;
+use crate::TraceBuilder;
+use crate::is_enabled;
+use crate::os;
+use crate::schema;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// The maximum number of signal-safe events that can be pending at once. Events recorded while this
+/// many are pending are discarded.
+pub const SIGNAL_EVENT_CAPACITY: usize = 256;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    call_site: AtomicPtr<CallSite>,
+    tid: AtomicI32,
+    unix_nanos: AtomicU64,
+}
+
+static SLOTS: [Slot; SIGNAL_EVENT_CAPACITY] = [const {
+    Slot {
+        state: AtomicU8::new(EMPTY),
+        call_site: AtomicPtr::new(std::ptr::null_mut()),
+        tid: AtomicI32::new(0),
+        unix_nanos: AtomicU64::new(0),
+    }
+}; SIGNAL_EVENT_CAPACITY];
+
+/// The number of slots that have ever been claimed. Used to spread writes across the slots.
+static CLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of events that have been discarded because all slots were full.
+static DISCARDED: AtomicU64 = AtomicU64::new(0);
+
+/// Records an instant event without allocating or locking, so that it's safe to call from a signal
+/// handler. The event is stored in a fixed-size, process-wide buffer until it's added to a trace by
+/// [TraceBuilder::process_signal_events]. If the buffer is full, the event is discarded.
+///
+/// Use via the [crate::signal_safe_instant] macro.
+#[doc(hidden)]
+pub fn record_instant_signal_safe(call_site: &'static CallSite) {
+    if !is_enabled() {
+        return;
+    }
+    let Some(unix_nanos) = os::clock_nanos(BuiltinClock::Realtime) else {
+        return;
+    };
+
+    let start = CLAIMED.fetch_add(1, Ordering::Relaxed);
+    for offset in 0..SIGNAL_EVENT_CAPACITY {
+        let slot = &SLOTS[(start + offset) % SIGNAL_EVENT_CAPACITY];
+        if slot
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.call_site
+                .store(std::ptr::from_ref(call_site).cast_mut(), Ordering::Relaxed);
+            slot.tid.store(os::gettid().as_i32(), Ordering::Relaxed);
+            slot.unix_nanos.store(unix_nanos, Ordering::Relaxed);
+            slot.state.store(READY, Ordering::Release);
+            return;
+        }
+    }
+    DISCARDED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of events that have been discarded because too many were pending.
+pub(crate) fn discarded_events() -> u64 {
+    DISCARDED.load(Ordering::Relaxed)
+}
+
+impl TraceBuilder {
+    /// Adds all events recorded by [crate::signal_safe_instant] since the last call to this function
+    /// to the trace. Each event is placed on the track of the thread that recorded it. If the
+    /// thread's data hasn't already been processed, the track will be created without a name.
+    pub fn process_signal_events(&mut self) -> &mut Self {
+        let pid = os::getpid();
+
+        for slot in &SLOTS {
+            if slot.state.load(Ordering::Acquire) != READY {
+                continue;
+            }
+            let call_site = slot.call_site.load(Ordering::Relaxed);
+            let tid = os::Pid::from_i32(slot.tid.load(Ordering::Relaxed));
+            let unix_nanos = slot.unix_nanos.load(Ordering::Relaxed);
+            slot.state.store(EMPTY, Ordering::Release);
+
+            // SAFETY: The pointer was created from a `&'static CallSite` by
+            // `record_instant_signal_safe`.
+            let call_site = unsafe { &*call_site };
+            // Registration takes a lock, so couldn't be done from the signal handler.
+            call_site.id();
+
+            let thread_uuid = self.thread_uuid_for(pid, tid, None);
+            let (name_id, source_location_id) = self.call_site_iids(call_site);
+            let mut track_event = schema::TrackEvent {
+                track_uuid: Some(thread_uuid.0),
+                name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+                source_location_field: source_location_id
+                    .map(schema::track_event::SourceLocationField::SourceLocationIid),
+                ..Default::default()
+            };
+            track_event.set_type(schema::track_event::Type::Instant);
+
+            self.add_packet(schema::TracePacket {
+                timestamp: Some(self.unix_nanos_to_clock(unix_nanos)),
+                timestamp_clock_id: Some(self.clock as u32),
+                data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+                ..Default::default()
+            });
+        }
+
+        self
+    }
+}
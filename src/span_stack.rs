@@ -0,0 +1,85 @@
+//! Tracking of the spans that are open on each thread, so that other code, such as logging, can
+//! tell which span it's running in.
+
+use std::cell::RefCell;
+#[cfg(feature = "enable")]
+use std::collections::HashSet;
+#[cfg(feature = "enable")]
+use std::sync::Mutex;
+
+thread_local! {
+    /// The names of the recorded spans that are open on this thread, innermost last.
+    static SPAN_STACK: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+
+    /// The names from [NAMES] that this thread has used, so that it doesn't need to take the lock
+    /// each time it starts a span with one of them.
+    #[cfg(feature = "enable")]
+    static THREAD_NAMES: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+/// The names of spans that were only known at runtime. Each distinct name is leaked once, which is
+/// fine since, as for the names interned in traces, they should come from a limited set.
+#[cfg(feature = "enable")]
+static NAMES: Mutex<Option<HashSet<&'static str>>> = Mutex::new(None);
+
+/// Returns the name of the innermost span that is open on the current thread, or `None` if no spans
+/// are open. Only spans whose start was recorded are tracked. Requires the `span-stack` feature.
+///
+/// For spans whose names are computed at runtime, e.g. by [crate::start_span_dynamic], this is the
+/// recorded name. Each distinct such name is kept for the rest of the process.
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// fn log(message: &str) {
+///     let span = perfetto_recorder::current_span_name().unwrap_or("-");
+///     eprintln!("[{span}] {message}");
+/// }
+///
+/// scope!("Load config");
+/// log("Reading file");
+/// ```
+pub fn current_span_name() -> Option<&'static str> {
+    SPAN_STACK.with_borrow(|stack| stack.last().copied())
+}
+
+/// Returns the number of spans that are open on the current thread. Only spans whose start was
+/// recorded are counted. Requires the `span-stack` feature.
+pub fn current_span_depth() -> usize {
+    SPAN_STACK.with_borrow(|stack| stack.len())
+}
+
+/// Records that a span named `name` has started on the current thread.
+#[cfg(feature = "enable")]
+pub(crate) fn push(name: &'static str) {
+    SPAN_STACK.with_borrow_mut(|stack| stack.push(name));
+}
+
+/// Returns a copy of `name` that lives for the rest of the process, for a span whose name was only
+/// known at runtime.
+#[cfg(feature = "enable")]
+pub(crate) fn intern(name: &str) -> &'static str {
+    if let Some(name) = THREAD_NAMES.with_borrow(|names| names.get(name).copied()) {
+        return name;
+    }
+    let mut names = NAMES.lock().unwrap();
+    let names = names.get_or_insert_default();
+    let name = match names.get(name) {
+        Some(name) => *name,
+        None => {
+            let name: &'static str = name.to_owned().leak();
+            names.insert(name);
+            name
+        }
+    };
+    THREAD_NAMES.with_borrow_mut(|names| names.insert(name));
+    name
+}
+
+/// Records that the innermost span on the current thread has ended.
+#[cfg(feature = "enable")]
+pub(crate) fn pop() {
+    SPAN_STACK.with_borrow_mut(|stack| stack.pop());
+}
@@ -0,0 +1,53 @@
+//! Spawning threads such that the spawning span is linked to the new thread's first span.
+
+use crate::Event;
+use crate::is_enabled;
+use crate::record_event;
+use crate::unique_id;
+use std::thread::JoinHandle;
+
+/// Like [std::thread::spawn], but if a span is open on the current thread, it's linked by a flow to
+/// the first span recorded on the new thread. This makes hand-offs between threads show up in the
+/// Perfetto UI without having to pass a [crate::Flow] to the new thread.
+///
+/// The link is only made if the open span started in the same [crate::ThreadTraceData] as the
+/// spawn.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::scope;
+///
+/// scope!("Start workers");
+/// let worker = perfetto_recorder::spawn(|| {
+///     scope!("Work");
+/// });
+/// worker.join().unwrap();
+/// ```
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with(std::thread::Builder::new(), f).expect("failed to spawn thread")
+}
+
+/// Like [spawn], but uses the supplied [std::thread::Builder], which allows the thread to be named.
+pub fn spawn_with<F, T>(builder: std::thread::Builder, f: F) -> std::io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let flow_id = is_enabled().then(unique_id);
+    if let Some(flow_id) = flow_id {
+        record_event(Event::SpawnedThread(flow_id));
+    }
+    builder.spawn(move || {
+        if let Some(flow_id) = flow_id
+            && is_enabled()
+        {
+            record_event(Event::SpawnedBy(flow_id));
+        }
+        f()
+    })
+}
@@ -0,0 +1,183 @@
+//! Dropping most short spans while keeping every slow one.
+
+#[cfg(feature = "enable")]
+use crate::EVENTS;
+#[cfg(feature = "enable")]
+use crate::Event;
+#[cfg(feature = "enable")]
+use crate::Instant;
+#[cfg(feature = "enable")]
+use crate::RNG;
+use crate::StaticCounter;
+#[cfg(feature = "enable")]
+use rand::RngCore;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Spans shorter than this many nanoseconds may be dropped. Zero if tail sampling is disabled.
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The bits of the `f64` probability with which short spans are dropped.
+static DROP_PROBABILITY: AtomicU64 = AtomicU64::new(0);
+
+/// Counts the spans that ended while tail sampling was enabled and were kept.
+static KEPT: StaticCounter = StaticCounter::new("tail sampling kept spans");
+
+/// Counts the spans that ended while tail sampling was enabled and were dropped.
+static DROPPED: StaticCounter = StaticCounter::new("tail sampling dropped spans");
+
+/// How far back through a thread's events we look for the start of a span that is ending. Spans
+/// whose start is further back than this contain lots of events, so are kept.
+#[cfg(feature = "enable")]
+const MAX_LOOKBACK: usize = 1024;
+
+/// Drops spans that took less than `threshold` with probability `drop_probability`, while always
+/// keeping slower spans. This yields small traces that still contain every outlier. Spans are
+/// recorded as usual, then when a short span ends, its events, including any spans nested within
+/// it, are removed from the thread's buffer. Short spans are kept if anything else was recorded
+/// while they were open, e.g. a log message or an asynchronous span.
+///
+/// The number of spans kept and dropped are counted by the static counters `tail sampling kept
+/// spans` and `tail sampling dropped spans`, so are included in traces like other static counters.
+/// See [tail_sampling_counts].
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// perfetto_recorder::set_tail_sampling(Duration::from_millis(1), 0.99);
+/// ```
+pub fn set_tail_sampling(threshold: Duration, drop_probability: f64) {
+    DROP_PROBABILITY.store(
+        drop_probability.clamp(0.0, 1.0).to_bits(),
+        Ordering::Relaxed,
+    );
+    THRESHOLD_NANOS.store(
+        u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+/// Stops dropping short spans. See [set_tail_sampling].
+pub fn clear_tail_sampling() {
+    THRESHOLD_NANOS.store(0, Ordering::Relaxed);
+}
+
+/// Returns the number of spans that have been kept and dropped by tail sampling, in that order.
+pub fn tail_sampling_counts() -> (i64, i64) {
+    (KEPT.value(), DROPPED.value())
+}
+
+/// Called when a span that was recorded on the current thread is about to end at `now`. Returns
+/// whether the span was dropped, in which case its events have been removed and its end shouldn't
+/// be recorded.
+#[cfg(feature = "enable")]
+#[inline(always)]
+pub(crate) fn drop_if_short(call_site_id: u32, now: Instant) -> bool {
+    let threshold = THRESHOLD_NANOS.load(Ordering::Relaxed);
+    threshold != 0 && drop_if_short_slow(call_site_id, now, threshold)
+}
+
+#[cfg(feature = "enable")]
+#[cold]
+fn drop_if_short_slow(call_site_id: u32, now: Instant, threshold: u64) -> bool {
+    let probability = f64::from_bits(DROP_PROBABILITY.load(Ordering::Relaxed));
+    // Use the top 53 bits, which is all that fits in the mantissa of an `f64`.
+    let random = RNG.with_borrow_mut(|rng| rng.next_u64() >> 11) as f64 / (1_u64 << 53) as f64;
+    let dropped = random < probability
+        && EVENTS.with_borrow_mut(|events| {
+            drop_short_span(events, call_site_id, now, Duration::from_nanos(threshold))
+        });
+    if dropped {
+        DROPPED.increment();
+    } else {
+        KEPT.increment();
+    }
+    dropped
+}
+
+/// Removes the events of the innermost open span from `call_site_id` if it started less than
+/// `threshold` before `now`. Returns whether the span was removed.
+#[cfg(feature = "enable")]
+pub(crate) fn drop_short_span(
+    events: &mut Vec<Event>,
+    call_site_id: u32,
+    now: Instant,
+    threshold: Duration,
+) -> bool {
+    let Some(start) = find_start(events, call_site_id) else {
+        return false;
+    };
+    let Some(Event::Timestamp(start_time)) = events.get(start + 1) else {
+        return false;
+    };
+    #[cfg(not(feature = "fastant"))]
+    let duration = now.duration_since(*start_time).unwrap_or_default();
+    #[cfg(feature = "fastant")]
+    let duration = now.duration_since(*start_time);
+    if duration >= threshold || !only_nested_spans(&events[start..]) {
+        return false;
+    }
+    events.truncate(start);
+    true
+}
+
+/// Returns whether `events`, which start with the start of a span that's ending, only hold that
+/// span and the spans nested within it, together with their arguments and timestamps. Otherwise
+/// removing the span would also remove something else, e.g. the start of a span that ends after it,
+/// a counter sample or an annotation on an outer span, so it's kept.
+#[cfg(feature = "enable")]
+fn only_nested_spans(events: &[Event]) -> bool {
+    let mut open = Vec::new();
+    for event in events {
+        match event {
+            Event::StartSpan(id) => open.push(*id),
+            Event::EndSpan(id) => {
+                if open.pop() != Some(*id) {
+                    return false;
+                }
+            }
+            Event::Annotations(id) | Event::EndAnnotations(id) | Event::RootAnnotations(id) => {
+                if !open.contains(id) {
+                    return false;
+                }
+            }
+            Event::Timestamp(_)
+            | Event::UnixTimestamp(_)
+            | Event::SimulatedTimestamp(_)
+            | Event::Bool(_)
+            | Event::U64(_)
+            | Event::I64(_)
+            | Event::F64(_)
+            | Event::String(_)
+            | Event::Str { .. }
+            | Event::DictStart
+            | Event::ArrayStart
+            | Event::DictEnd
+            | Event::Unit(_) => {}
+            _ => return false,
+        }
+    }
+    open.len() == 1
+}
+
+/// Returns the index of the start of the innermost span from `call_site_id` that hasn't ended.
+#[cfg(feature = "enable")]
+fn find_start(events: &[Event], call_site_id: u32) -> Option<usize> {
+    let mut depth = 0;
+    for (index, event) in events.iter().enumerate().rev().take(MAX_LOOKBACK) {
+        match event {
+            Event::EndSpan(id) if *id == call_site_id => depth += 1,
+            Event::StartSpan(id) if *id == call_site_id => {
+                if depth == 0 {
+                    return Some(index);
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,102 @@
+//! Argument wrappers that give a value a unit. The value is recorded as a number, so that it can
+//! still be compared and aggregated, and the unit is added to the argument's name.
+
+use crate::Event;
+use crate::RecordArg;
+use crate::record_event;
+use std::time::Duration;
+
+/// A span argument that is a number of bytes. Shown with ` (B)` added to its name, e.g.
+/// `size (B)`.
+///
+/// Example usage:
+///
+/// ```
+/// use perfetto_recorder::Bytes;
+/// use perfetto_recorder::Micros;
+/// use perfetto_recorder::Percent;
+/// use perfetto_recorder::scope;
+///
+/// scope!(
+///     "write block",
+///     size = Bytes(1536),
+///     budget = Micros(250),
+///     full = Percent(87.5)
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes(pub u64);
+
+/// A span argument that is a duration in microseconds. Shown with ` (µs)` added to its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Micros(pub u64);
+
+/// A span argument that is a percentage, where 100 is the whole. Shown with ` (%)` added to its
+/// name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percent(pub f64);
+
+/// The unit of the argument value that follows an [Event::Unit].
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Bytes = 1,
+    Micros = 2,
+    Percent = 3,
+    Nanos = 4,
+}
+
+impl RecordArg for Bytes {
+    fn record_arg(self) {
+        record_event(Event::Unit(Unit::Bytes));
+        self.0.record_arg();
+    }
+}
+
+impl RecordArg for Micros {
+    fn record_arg(self) {
+        record_event(Event::Unit(Unit::Micros));
+        self.0.record_arg();
+    }
+}
+
+impl RecordArg for Percent {
+    fn record_arg(self) {
+        record_event(Event::Unit(Unit::Percent));
+        self.0.record_arg();
+    }
+}
+
+/// Recorded in nanoseconds, with ` (ns)` added to the argument's name. Durations longer than about
+/// 584 years are clamped.
+impl RecordArg for Duration {
+    fn record_arg(self) {
+        record_event(Event::Unit(Unit::Nanos));
+        u64::try_from(self.as_nanos())
+            .unwrap_or(u64::MAX)
+            .record_arg();
+    }
+}
+
+impl Unit {
+    pub(crate) fn from_u8(value: u8) -> Option<Unit> {
+        match value {
+            1 => Some(Unit::Bytes),
+            2 => Some(Unit::Micros),
+            3 => Some(Unit::Percent),
+            4 => Some(Unit::Nanos),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of an argument called `name` whose value is in this unit.
+    pub(crate) fn annotation_name(self, name: &str) -> String {
+        let symbol = match self {
+            Unit::Bytes => "B",
+            Unit::Micros => "µs",
+            Unit::Percent => "%",
+            Unit::Nanos => "ns",
+        };
+        format!("{name} ({symbol})")
+    }
+}
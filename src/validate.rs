@@ -0,0 +1,246 @@
+//! Checking that recorded trace data is well-formed.
+
+use crate::CALL_SITES;
+use crate::Event;
+use crate::ThreadTraceData;
+use crate::Timestamp;
+
+/// The result of [ThreadTraceData::validate].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    /// Each problem that was found, in the order that the events were recorded.
+    pub issues: Vec<ValidationIssue>,
+}
+
+/// A problem found by [ThreadTraceData::validate]. `index` is the position of the offending event
+/// within the thread's data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    /// A span was still open when the data was taken. This is expected if the data was taken from
+    /// within a span, but otherwise suggests that a span guard was leaked, e.g. by
+    /// [std::mem::forget].
+    UnclosedSpan { name: &'static str, index: usize },
+
+    /// A span ended without a matching start.
+    UnmatchedEnd { name: &'static str, index: usize },
+
+    /// A span ended while a different span was innermost, so the spans don't nest.
+    MismatchedEnd {
+        expected: &'static str,
+        found: &'static str,
+        index: usize,
+    },
+
+    /// An event that should be followed by a timestamp wasn't.
+    MissingTimestamp { index: usize },
+
+    /// A timestamp was earlier than the one before it.
+    TimestampWentBackwards { index: usize },
+
+    /// A string argument referred to bytes outside of the thread's string data, or not on a
+    /// character boundary.
+    InvalidString { index: usize },
+
+    /// An argument with dynamic names, or a set of annotations, wasn't terminated.
+    UnterminatedDict { index: usize },
+
+    /// The end of an argument with dynamic names was found without a matching start.
+    UnmatchedDictEnd { index: usize },
+}
+
+impl ValidationReport {
+    /// Returns whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl ThreadTraceData {
+    /// Checks that the data is well-formed: that spans begin and end in pairs and nest properly,
+    /// that timestamps don't go backwards and that string arguments are intact. This is intended
+    /// for tests, e.g. to catch span guards that are leaked by a refactor, so isn't optimised for
+    /// speed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use perfetto_recorder::ThreadTraceData;
+    /// use perfetto_recorder::scope;
+    ///
+    /// {
+    ///     scope!("work");
+    /// }
+    /// let report = ThreadTraceData::take_current_thread().validate();
+    /// assert!(report.is_ok(), "{:?}", report.issues);
+    /// ```
+    pub fn validate(&self) -> ValidationReport {
+        let call_sites = CALL_SITES.lock().unwrap().clone();
+        let name = |id: u32| {
+            (id as usize)
+                .checked_sub(1)
+                .and_then(|index| call_sites.get(index))
+                .map_or("<unknown>", |call_site| call_site.source.name)
+        };
+
+        let mut issues = Vec::new();
+        let mut open_spans: Vec<(u32, usize)> = Vec::new();
+        let mut open_dicts: Vec<usize> = Vec::new();
+        let mut previous_timestamp: Option<Timestamp> = None;
+        // The index of the event that must be followed by a timestamp, if any.
+        let mut needs_timestamp = None;
+
+        for (index, event) in self.events.iter().enumerate() {
+            let timestamp = match event {
+                Event::Timestamp(instant) => Some(Timestamp::Instant(*instant)),
+                Event::UnixTimestamp(nanos) => Some(Timestamp::UnixNanos(*nanos)),
+                Event::SimulatedTimestamp(nanos) => Some(Timestamp::Simulated(*nanos)),
+                _ => None,
+            };
+            if let Some(needed_by) = needs_timestamp.take()
+                && timestamp.is_none()
+            {
+                issues.push(ValidationIssue::MissingTimestamp { index: needed_by });
+            }
+            if let Some(timestamp) = timestamp {
+                let went_backwards = match (previous_timestamp, timestamp) {
+                    (Some(Timestamp::Instant(previous)), Timestamp::Instant(current)) => {
+                        current < previous
+                    }
+                    (Some(Timestamp::UnixNanos(previous)), Timestamp::UnixNanos(current)) => {
+                        current < previous
+                    }
+                    (Some(Timestamp::Simulated(previous)), Timestamp::Simulated(current)) => {
+                        current < previous
+                    }
+                    _ => false,
+                };
+                if went_backwards {
+                    issues.push(ValidationIssue::TimestampWentBackwards { index });
+                }
+                previous_timestamp = Some(timestamp);
+                continue;
+            }
+
+            match event {
+                Event::StartSpan(id) => {
+                    open_spans.push((*id, index));
+                    needs_timestamp = Some(index);
+                }
+                Event::EndSpan(id) => {
+                    match open_spans.pop() {
+                        None => issues.push(ValidationIssue::UnmatchedEnd {
+                            name: name(*id),
+                            index,
+                        }),
+                        Some((open, _)) if open != *id => {
+                            issues.push(ValidationIssue::MismatchedEnd {
+                                expected: name(open),
+                                found: name(*id),
+                                index,
+                            })
+                        }
+                        Some(_) => {}
+                    }
+                    needs_timestamp = Some(index);
+                }
+                Event::CounterI64 { .. }
+                | Event::CounterF64 { .. }
+                | Event::AsyncStart { .. }
+                | Event::AsyncEnd { .. }
+                | Event::FailedAssertion(_)
+                | Event::Log { .. } => {
+                    needs_timestamp = Some(index);
+                }
+                Event::Str { offset, len } if self.strings.get(*offset..offset + len).is_none() => {
+                    issues.push(ValidationIssue::InvalidString { index });
+                }
+                Event::DictStart
+                | Event::ArrayStart
+                | Event::Annotations(_)
+                | Event::EndAnnotations(_)
+                | Event::RootAnnotations(_) => open_dicts.push(index),
+                Event::DictEnd if open_dicts.pop().is_none() => {
+                    issues.push(ValidationIssue::UnmatchedDictEnd { index });
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(needed_by) = needs_timestamp {
+            issues.push(ValidationIssue::MissingTimestamp { index: needed_by });
+        }
+        for index in open_dicts {
+            issues.push(ValidationIssue::UnterminatedDict { index });
+        }
+        for (id, index) in open_spans {
+            issues.push(ValidationIssue::UnclosedSpan {
+                name: name(id),
+                index,
+            });
+        }
+        issues.sort_by_key(ValidationIssue::index);
+
+        ValidationReport { issues }
+    }
+}
+
+impl ValidationIssue {
+    /// Returns the position of the offending event within the thread's data.
+    pub fn index(&self) -> usize {
+        match self {
+            ValidationIssue::UnclosedSpan { index, .. }
+            | ValidationIssue::UnmatchedEnd { index, .. }
+            | ValidationIssue::MismatchedEnd { index, .. }
+            | ValidationIssue::MissingTimestamp { index }
+            | ValidationIssue::TimestampWentBackwards { index }
+            | ValidationIssue::InvalidString { index }
+            | ValidationIssue::UnterminatedDict { index }
+            | ValidationIssue::UnmatchedDictEnd { index } => *index,
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationIssue::UnclosedSpan { name, index } => {
+                write!(f, "Span `{name}` started at event {index} was never ended")
+            }
+            ValidationIssue::UnmatchedEnd { name, index } => {
+                write!(f, "Span `{name}` ended at event {index} was never started")
+            }
+            ValidationIssue::MismatchedEnd {
+                expected,
+                found,
+                index,
+            } => write!(
+                f,
+                "Span `{found}` ended at event {index} while span `{expected}` was innermost"
+            ),
+            ValidationIssue::MissingTimestamp { index } => {
+                write!(f, "Event {index} isn't followed by a timestamp")
+            }
+            ValidationIssue::TimestampWentBackwards { index } => {
+                write!(
+                    f,
+                    "Timestamp at event {index} is earlier than the previous one"
+                )
+            }
+            ValidationIssue::InvalidString { index } => {
+                write!(f, "String at event {index} refers to invalid string data")
+            }
+            ValidationIssue::UnterminatedDict { index } => {
+                write!(
+                    f,
+                    "Dynamic arguments started at event {index} were never ended"
+                )
+            }
+            ValidationIssue::UnmatchedDictEnd { index } => {
+                write!(
+                    f,
+                    "Dynamic arguments ended at event {index} were never started"
+                )
+            }
+        }
+    }
+}
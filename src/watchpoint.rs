@@ -0,0 +1,152 @@
+//! Watchpoints that mark the trace when a sampled counter crosses a threshold.
+
+use crate::Instant;
+use crate::TraceBuilder;
+use crate::os;
+use crate::schema;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// The condition under which a watchpoint added by [add_watchpoint] triggers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    /// Triggers when the value goes from being at or below the threshold to being above it.
+    Above(f64),
+
+    /// Triggers when the value goes from being at or above the threshold to being below it.
+    Below(f64),
+}
+
+/// What to do, in addition to adding a marker to the trace, when a watchpoint triggers.
+#[derive(Clone)]
+pub enum WatchpointAction {
+    /// Only add a marker.
+    Marker,
+
+    /// Call the supplied function after the marker has been added. Typically this would arrange for
+    /// each thread's events to be taken and written out, so that a snapshot of what led up to the
+    /// anomaly is kept. The function is called on whichever thread took the samples.
+    Callback(Arc<dyn Fn() + Send + Sync>),
+}
+
+struct Watchpoint {
+    counter: String,
+    threshold: Threshold,
+    action: WatchpointAction,
+    /// Whether the most recent sample was past the threshold.
+    triggered: bool,
+}
+
+/// A marker for a watchpoint that triggered.
+struct Marker {
+    name: String,
+    timestamp: Instant,
+    value: f64,
+}
+
+static WATCHPOINTS: Mutex<Vec<Watchpoint>> = Mutex::new(Vec::new());
+
+/// Markers that have been recorded, but not yet processed by a [TraceBuilder].
+static MARKERS: Mutex<Vec<Marker>> = Mutex::new(Vec::new());
+
+/// Adds a watchpoint on the gauge registered by [crate::register_gauge] or the
+/// [crate::StaticCounter] named `counter`. Each time a sample of the counter crosses `threshold`,
+/// an instant event is added to the process track at the time of the sample, then `action` is
+/// taken. Watchpoints are checked when samples are taken, either by a [crate::Sampler] or by
+/// [crate::sample_now].
+///
+/// # Example
+///
+/// ```
+/// use perfetto_recorder::Threshold;
+/// use perfetto_recorder::WatchpointAction;
+///
+/// perfetto_recorder::add_watchpoint("rss", Threshold::Above(2e9), WatchpointAction::Marker);
+/// ```
+pub fn add_watchpoint(counter: impl Into<String>, threshold: Threshold, action: WatchpointAction) {
+    WATCHPOINTS.lock().unwrap().push(Watchpoint {
+        counter: counter.into(),
+        threshold,
+        action,
+        triggered: false,
+    });
+}
+
+/// Removes all watchpoints added by [add_watchpoint].
+pub fn clear_watchpoints() {
+    WATCHPOINTS.lock().unwrap().clear();
+}
+
+/// Checks a sample of `counter` against all watchpoints on it. Returns the callbacks of any
+/// watchpoints that triggered, which the caller should call once it no longer holds any locks.
+pub(crate) fn check(
+    counter: &str,
+    timestamp: Instant,
+    value: f64,
+) -> Vec<Arc<dyn Fn() + Send + Sync>> {
+    let mut callbacks = Vec::new();
+    let mut watchpoints = WATCHPOINTS.lock().unwrap();
+    for watchpoint in watchpoints.iter_mut() {
+        if watchpoint.counter != counter {
+            continue;
+        }
+        let past_threshold = match watchpoint.threshold {
+            Threshold::Above(threshold) => value > threshold,
+            Threshold::Below(threshold) => value < threshold,
+        };
+        let crossed = past_threshold && !watchpoint.triggered;
+        watchpoint.triggered = past_threshold;
+        if !crossed {
+            continue;
+        }
+        let name = match watchpoint.threshold {
+            Threshold::Above(threshold) => format!("{counter} > {threshold}"),
+            Threshold::Below(threshold) => format!("{counter} < {threshold}"),
+        };
+        MARKERS.lock().unwrap().push(Marker {
+            name,
+            timestamp,
+            value,
+        });
+        if let WatchpointAction::Callback(callback) = &watchpoint.action {
+            callbacks.push(callback.clone());
+        }
+    }
+    callbacks
+}
+
+impl TraceBuilder {
+    /// Adds markers for any watchpoints that have triggered since this was last called.
+    pub(crate) fn process_watchpoint_markers(&mut self) {
+        let markers = std::mem::take(&mut *MARKERS.lock().unwrap());
+        if markers.is_empty() {
+            return;
+        }
+
+        let track_uuid = self.process_uuid(os::getpid());
+        for marker in markers {
+            let name_id = self.name_id(Cow::Owned(marker.name));
+            let mut track_event = schema::TrackEvent {
+                track_uuid: Some(track_uuid.0),
+                name_field: Some(schema::track_event::NameField::NameIid(name_id)),
+                debug_annotations: vec![schema::DebugAnnotation {
+                    name_field: Some(schema::debug_annotation::NameField::Name(
+                        "value".to_owned(),
+                    )),
+                    value: Some(schema::debug_annotation::Value::DoubleValue(marker.value)),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            track_event.set_type(schema::track_event::Type::Instant);
+
+            self.add_packet(schema::TracePacket {
+                timestamp: Some(self.clock_nanos(marker.timestamp)),
+                timestamp_clock_id: Some(self.clock as u32),
+                data: Some(schema::trace_packet::Data::TrackEvent(track_event)),
+                ..Default::default()
+            });
+        }
+    }
+}
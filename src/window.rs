@@ -0,0 +1,159 @@
+//! Restricting a trace to a window of time.
+
+use crate::Instant;
+use crate::TraceBuilder;
+use crate::merge_interned;
+use crate::schema;
+use crate::schema::trace_packet::Data;
+use crate::schema::trace_packet::OptionalTrustedPacketSequenceId;
+use crate::schema::track_event::Type;
+use std::collections::HashMap;
+
+impl TraceBuilder {
+    /// Removes everything from the trace that lies outside the window from `start` to `end`. Spans
+    /// that cross the edge of the window are shortened to fit within it and given a `clipped`
+    /// annotation. Each counter's value at the start of the window is retained. This is useful for
+    /// cutting a small trace around an incident out of a large recording.
+    ///
+    /// This only affects data that has already been added to the trace, so should be called after
+    /// all thread data has been processed.
+    pub fn restrict_to(&mut self, start: Instant, end: Instant) -> &mut Self {
+        let start = self.clock_nanos(start);
+        let end = self.clock_nanos(end);
+        self.restrict_to_timestamps(start, end)
+    }
+
+    /// Like [TraceBuilder::restrict_to], but `start` and `end` are timestamps as they appear in the
+    /// trace, e.g. one loaded by [TraceBuilder::import].
+    pub fn restrict_to_timestamps(&mut self, start: u64, end: u64) -> &mut Self {
+        let window = start..=end;
+
+        let mut by_track: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, packet) in self.trace.packet.iter().enumerate() {
+            if let Some(Data::TrackEvent(event)) = &packet.data
+                && packet.timestamp.is_some()
+            {
+                by_track
+                    .entry(event.track_uuid.unwrap_or_default())
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        let packets = &self.trace.packet;
+        let timestamp = |index: usize| packets[index].timestamp.unwrap_or_default();
+        let mut keep = vec![true; packets.len()];
+        let mut clip_to = Vec::new();
+        let mut clipped_spans = Vec::new();
+
+        for indices in by_track.values_mut() {
+            indices.sort_by_key(|index| timestamp(*index));
+            let mut open_spans = Vec::new();
+            let mut counter_before_start = None;
+
+            for &index in indices.iter() {
+                let Some(Data::TrackEvent(event)) = &packets[index].data else {
+                    continue;
+                };
+                let kind = event.r#type.and_then(|kind| Type::try_from(kind).ok());
+                match kind {
+                    Some(Type::SliceBegin) => open_spans.push(index),
+                    Some(Type::SliceEnd) => {
+                        let Some(begin) = open_spans.pop() else {
+                            keep[index] = window.contains(&timestamp(index));
+                            continue;
+                        };
+                        if timestamp(index) < start || timestamp(begin) > end {
+                            keep[begin] = false;
+                            keep[index] = false;
+                            continue;
+                        }
+                        if timestamp(begin) < start {
+                            clip_to.push((begin, start));
+                            clipped_spans.push(begin);
+                        }
+                        if timestamp(index) > end {
+                            clip_to.push((index, end));
+                            if !clipped_spans.contains(&begin) {
+                                clipped_spans.push(begin);
+                            }
+                        }
+                    }
+                    Some(Type::Counter) if timestamp(index) < start => {
+                        // Only the most recent value before the window is needed.
+                        if let Some(previous) = counter_before_start.replace(index) {
+                            keep[previous] = false;
+                        }
+                    }
+                    _ => keep[index] = window.contains(&timestamp(index)),
+                }
+            }
+
+            if let Some(index) = counter_before_start {
+                clip_to.push((index, start));
+            }
+
+            // Spans that never ended.
+            for begin in open_spans {
+                if timestamp(begin) > end {
+                    keep[begin] = false;
+                } else if timestamp(begin) < start {
+                    clip_to.push((begin, start));
+                    clipped_spans.push(begin);
+                }
+            }
+        }
+
+        for (index, timestamp) in clip_to {
+            self.trace.packet[index].timestamp = Some(timestamp);
+        }
+        for index in clipped_spans {
+            if let Some(Data::TrackEvent(event)) = &mut self.trace.packet[index].data {
+                event.debug_annotations.push(schema::DebugAnnotation {
+                    name_field: Some(schema::debug_annotation::NameField::Name(
+                        "clipped".to_owned(),
+                    )),
+                    value: Some(schema::debug_annotation::Value::BoolValue(true)),
+                    ..Default::default()
+                });
+            }
+        }
+
+        self.retain_packets(keep)
+    }
+
+    /// Removes the packets for which `keep` is false.
+    pub(crate) fn retain_packets(&mut self, keep: Vec<bool>) -> &mut Self {
+        // Packets that we remove might carry interned data that later packets in the same sequence
+        // depend on, so we move it to the next packet that we keep.
+        let mut carried: HashMap<Option<OptionalTrustedPacketSequenceId>, schema::InternedData> =
+            HashMap::new();
+        let packets = std::mem::take(&mut self.trace.packet);
+        for (mut packet, keep) in packets.into_iter().zip(keep) {
+            let sequence = packet.optional_trusted_packet_sequence_id;
+            if !keep {
+                if let Some(interned) = packet.interned_data {
+                    let mut pending = carried.remove(&sequence);
+                    merge_interned(&mut pending, interned);
+                    carried.insert(sequence, pending.unwrap_or_default());
+                }
+                continue;
+            }
+            if let Some(pending) = carried.remove(&sequence) {
+                merge_interned(&mut packet.interned_data, pending);
+            }
+            self.trace.packet.push(packet);
+        }
+
+        // Interned data that no remaining packet in our sequence needed might be needed by packets
+        // that we add later.
+        let own_sequence = Some(OptionalTrustedPacketSequenceId::TrustedPacketSequenceId(
+            self.sequence_id,
+        ));
+        if let Some(interned) = carried.remove(&own_sequence) {
+            merge_interned(&mut self.pending_interned, interned);
+        }
+
+        self
+    }
+}
@@ -0,0 +1,108 @@
+//! Writing a trace out as threads' data arrives, rather than holding all of it in memory.
+
+use crate::Error;
+use crate::ThreadTraceData;
+use crate::TraceBuilder;
+use crate::footer::FooterState;
+use std::io::Write;
+
+/// Encodes the data of each thread as it arrives and writes it straight to `W`, e.g. a file, rather
+/// than keeping the whole trace in memory until it's written, as a [TraceBuilder] does. Only the
+/// tracks and interned strings are kept between writes, so memory use doesn't grow with the length
+/// of the recording. The output is a single trace. Everything written so far can be loaded even if
+/// [TraceWriter::finish] is never called, e.g. because the process was killed.
+///
+/// Packets are encoded by a [TraceBuilder], so its settings apply. Zero-based timestamps are
+/// relative to the earliest packet of the first write, so earlier packets that are written later
+/// are clamped at zero. Concurrency counters only take account of the spans of each write.
+///
+/// # Example
+///
+/// ```no_run
+/// use perfetto_recorder::*;
+///
+/// start()?;
+/// let file = std::io::BufWriter::new(std::fs::File::create("long.pftrace")?);
+/// let mut writer = TraceWriter::new(file)?;
+/// for batch in 0..3 {
+///     let thread = std::thread::spawn(move || {
+///         {
+///             scope!("batch", batch);
+///         }
+///         ThreadTraceData::take_current_thread()
+///     });
+///     writer.write_thread_data(&thread.join().unwrap())?;
+/// }
+/// writer.finish()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct TraceWriter<W: Write> {
+    builder: TraceBuilder,
+    output: W,
+    /// What has been written so far, for the footer.
+    totals: FooterState,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Creates a writer that writes to `output`, with a builder created by [TraceBuilder::new].
+    pub fn new(output: W) -> Result<Self, Error> {
+        Ok(Self::with_builder(TraceBuilder::new()?, output))
+    }
+
+    /// Creates a writer that writes to `output` using `builder`, e.g. one created by
+    /// [TraceBuilder::with_options]. Any packets that `builder` already has are written by the
+    /// first write.
+    pub fn with_builder(builder: TraceBuilder, output: W) -> Self {
+        Self {
+            builder,
+            output,
+            totals: FooterState::default(),
+        }
+    }
+
+    /// Adds `thread`'s data to the trace and writes it to the output.
+    pub fn write_thread_data(&mut self, thread: &ThreadTraceData) -> Result<&mut Self, Error> {
+        self.builder.process_thread_data(thread);
+        self.write_pending()
+    }
+
+    /// Returns the builder, e.g. to add counters or the data of threads that have exited. Whatever
+    /// is added is written by the next call to [TraceWriter::write_thread_data] or
+    /// [TraceWriter::flush].
+    pub fn builder(&mut self) -> &mut TraceBuilder {
+        &mut self.builder
+    }
+
+    /// Writes anything that has been added via [TraceWriter::builder], then flushes the output.
+    pub fn flush(&mut self) -> Result<&mut Self, Error> {
+        self.write_pending()?;
+        self.output.flush().map_err(output_error)?;
+        Ok(self)
+    }
+
+    /// Writes anything that's left, followed by a footer if [TraceBuilder::set_footer] is
+    /// enabled, then flushes the output and returns it.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_pending()?;
+        if self.builder.footer && !self.builder.noop {
+            let mut footer = Vec::new();
+            self.builder.append_footer_for(&self.totals, &mut footer);
+            self.output.write_all(&footer).map_err(output_error)?;
+        }
+        self.output.flush().map_err(output_error)?;
+        Ok(self.output)
+    }
+
+    fn write_pending(&mut self) -> Result<&mut Self, Error> {
+        if self.builder.noop {
+            return Ok(self);
+        }
+        let bytes = self.builder.take_packets(&mut self.totals);
+        self.output.write_all(&bytes).map_err(output_error)?;
+        Ok(self)
+    }
+}
+
+fn output_error(error: std::io::Error) -> Error {
+    Error::Output(Box::new(error))
+}
@@ -0,0 +1,39 @@
+//! Runs in its own process, since the buffer mode applies to every thread.
+
+#![cfg(feature = "enable")]
+
+use perfetto_recorder::BufferMode;
+use perfetto_recorder::OverflowPolicy;
+use perfetto_recorder::ThreadTraceData;
+use perfetto_recorder::TraceBuilder;
+use perfetto_recorder::scope;
+
+#[test]
+fn panic_in_debug_with_open_spans() {
+    perfetto_recorder::start().unwrap();
+    perfetto_recorder::set_buffer_mode(BufferMode::Capped {
+        events: 50,
+        policy: OverflowPolicy::PanicInDebug,
+    });
+    // The ends of the open spans are recorded while unwinding, so mustn't panic again.
+    let result = std::panic::catch_unwind(|| {
+        scope!("outer");
+        for _ in 0..100 {
+            scope!("inner");
+        }
+    });
+    assert_eq!(result.is_err(), cfg!(debug_assertions));
+
+    let thread = ThreadTraceData::take_current_thread();
+    let mut trace = TraceBuilder::new().unwrap();
+    trace.process_thread_data(&thread);
+
+    // The limit doesn't apply to the arguments of spans added by the builder.
+    let track = trace.create_track_group("GPU");
+    let now = perfetto_recorder::time();
+    trace.record_span_at("draw", track, now, now, |args| {
+        for index in 0..100_u64 {
+            args.entry("index", index);
+        }
+    });
+}
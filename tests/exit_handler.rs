@@ -0,0 +1,53 @@
+//! Runs in its own process, since the exit handler applies to the whole process. The test then
+//! runs itself again in a child process, so that the trace is written by a real exit.
+
+#![cfg(feature = "enable")]
+
+use perfetto_recorder::compare_traces;
+use perfetto_recorder::scope;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Set in the child process to the path that it should write its trace to.
+const TRACE_PATH_VAR: &str = "PERFETTO_RECORDER_EXIT_TRACE";
+
+#[test]
+fn exit_handler_writes_trace() {
+    if let Some(path) = std::env::var_os(TRACE_PATH_VAR) {
+        record_and_exit(PathBuf::from(path));
+    }
+
+    let path = std::env::temp_dir().join(format!("exit-{}.pftrace", std::process::id()));
+    let status = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "exit_handler_writes_trace"])
+        .env(TRACE_PATH_VAR, &path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let names: Vec<String> = compare_traces(&bytes, &bytes)
+        .unwrap()
+        .spans
+        .into_iter()
+        .map(|span| span.name)
+        .collect();
+    assert!(names.iter().any(|name| name == "before exit"), "{names:?}");
+}
+
+fn record_and_exit(path: PathBuf) -> ! {
+    perfetto_recorder::start().unwrap();
+    let replaced = std::env::temp_dir().join(format!("replaced-{}.pftrace", std::process::id()));
+    perfetto_recorder::write_on_exit(&replaced).unwrap();
+    perfetto_recorder::install_exit_handler(path).unwrap();
+    std::thread::Builder::new()
+        .name("exiting".to_owned())
+        .spawn(|| {
+            scope!("before exit");
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    std::process::exit(0);
+}
@@ -0,0 +1,38 @@
+//! Runs in its own process, since the panic hook applies to every thread.
+
+#![cfg(feature = "enable")]
+
+use perfetto_recorder::ThreadTraceData;
+use perfetto_recorder::TraceBuilder;
+use perfetto_recorder::compare_traces;
+use perfetto_recorder::scope;
+
+fn span_names(bytes: &[u8]) -> Vec<String> {
+    compare_traces(bytes, bytes)
+        .unwrap()
+        .spans
+        .into_iter()
+        .map(|span| span.name)
+        .collect()
+}
+
+#[test]
+fn caught_panic_leaves_data_in_place() {
+    let path = std::env::temp_dir().join(format!("panic-hook-{}.pftrace", std::process::id()));
+    perfetto_recorder::start().unwrap();
+    perfetto_recorder::install_panic_hook(&path);
+    {
+        scope!("before panic");
+    }
+    let result = std::panic::catch_unwind(|| panic!("caught"));
+    assert!(result.is_err());
+
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(span_names(&written), ["before panic"]);
+
+    // The panic was caught, so the thread still has its data.
+    let mut trace = TraceBuilder::new().unwrap();
+    trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    assert_eq!(span_names(&trace.encode_to_vec()), ["before panic"]);
+}
@@ -0,0 +1,37 @@
+//! Runs in its own process, since pausing applies to every thread.
+
+#![cfg(feature = "enable")]
+
+use perfetto_recorder::ThreadTraceData;
+use perfetto_recorder::TraceBuilder;
+use perfetto_recorder::compare_traces;
+use perfetto_recorder::start_async_span;
+use perfetto_recorder::start_span;
+use perfetto_recorder::start_span_at;
+
+#[test]
+fn spans_started_before_pause_record_their_end() {
+    perfetto_recorder::start().unwrap();
+    let span = start_span!("thread span");
+    let async_span = start_async_span!("async span");
+    let simulated = start_span_at!(1_000, "simulated span");
+    perfetto_recorder::pause();
+    let not_recorded = start_span!("started while paused");
+    drop(not_recorded);
+    drop(span);
+    drop(async_span);
+    simulated.end_at(2_000);
+    perfetto_recorder::resume();
+
+    let mut trace = TraceBuilder::new().unwrap();
+    trace.process_thread_data(&ThreadTraceData::take_current_thread());
+    let bytes = trace.encode_to_vec();
+    let mut names: Vec<String> = compare_traces(&bytes, &bytes)
+        .unwrap()
+        .spans
+        .into_iter()
+        .map(|span| span.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["async span", "simulated span", "thread span"]);
+}
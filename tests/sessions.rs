@@ -0,0 +1,104 @@
+//! Runs in its own process, since sessions start and stop recording on every thread.
+
+#![cfg(feature = "enable")]
+
+use perfetto_recorder::TraceBuilder;
+use perfetto_recorder::compare_traces;
+use perfetto_recorder::scope;
+use perfetto_recorder::start_span;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
+
+fn span_names(trace: &TraceBuilder) -> Vec<String> {
+    let bytes = trace.encode_to_vec();
+    compare_traces(&bytes, &bytes)
+        .unwrap()
+        .spans
+        .into_iter()
+        .map(|span| span.name)
+        .collect()
+}
+
+#[test]
+fn sessions_keep_their_own_events() {
+    perfetto_recorder::start().unwrap();
+    std::thread::spawn(|| {
+        scope!("exited before");
+    })
+    .join()
+    .unwrap();
+
+    let (to_worker, from_main) = mpsc::channel::<()>();
+    let (to_main, from_worker) = mpsc::channel::<()>();
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            {
+                scope!("worker before");
+            }
+            from_main.recv().unwrap();
+            {
+                scope!("worker during 1");
+            }
+            to_main.send(()).unwrap();
+            // Stays idle while the first session ends, so keeps its events.
+            from_main.recv().unwrap();
+            while !stop.load(Ordering::Relaxed) {
+                scope!("worker during 2");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    });
+    {
+        scope!("main before");
+    }
+
+    perfetto_recorder::start_session().unwrap();
+    {
+        scope!("main during 1");
+    }
+    to_worker.send(()).unwrap();
+    from_worker.recv().unwrap();
+    let first = perfetto_recorder::end_session();
+    assert_eq!(span_names(&first.into_trace_builder()), ["main during 1"]);
+
+    perfetto_recorder::start_session().unwrap();
+    {
+        scope!("main during 2");
+    }
+    to_worker.send(()).unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    let second = perfetto_recorder::end_session();
+    stop.store(true, Ordering::Relaxed);
+    worker.join().unwrap();
+    let mut names = span_names(&second.into_trace_builder());
+    names.sort();
+    assert_eq!(names, ["main during 2", "worker during 2"]);
+
+    // Events from outside the sessions weren't taken by them.
+    perfetto_recorder::start().unwrap();
+    let mut rest = TraceBuilder::new().unwrap();
+    rest.process_exited_threads();
+    let names = span_names(&rest);
+    for name in [
+        "exited before",
+        "worker before",
+        "main before",
+        "worker during 1",
+    ] {
+        assert!(
+            names.iter().any(|n| n == name),
+            "{name} missing from {names:?}"
+        );
+    }
+
+    // A span that's still running when its session ends still reports how long it lasted.
+    perfetto_recorder::start_session().unwrap();
+    let span = start_span!("spans the end");
+    perfetto_recorder::end_session();
+    assert!(span.end().is_some());
+}
@@ -0,0 +1,63 @@
+//! Runs in its own process, since samples and watchpoint markers are taken from the process-wide
+//! sampler, so would be stolen by other tests that sample gauges.
+
+#![cfg(all(feature = "enable", feature = "raw-schema"))]
+
+use perfetto_recorder::Threshold;
+use perfetto_recorder::TraceBuilder;
+use perfetto_recorder::WatchpointAction;
+use perfetto_recorder::add_watchpoint;
+use perfetto_recorder::register_gauge;
+use perfetto_recorder::sample_now;
+use perfetto_recorder::schema;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+#[test]
+fn watchpoints_fire_when_crossing_threshold() {
+    perfetto_recorder::start().unwrap();
+
+    let rss = Arc::new(AtomicU64::new(0));
+    register_gauge("test watchpoint rss", {
+        let rss = rss.clone();
+        move || rss.load(Ordering::Relaxed) as f64
+    });
+    let num_callbacks = Arc::new(AtomicUsize::new(0));
+    add_watchpoint(
+        "test watchpoint rss",
+        Threshold::Above(10.0),
+        WatchpointAction::Callback({
+            let num_callbacks = num_callbacks.clone();
+            Arc::new(move || {
+                num_callbacks.fetch_add(1, Ordering::Relaxed);
+            })
+        }),
+    );
+
+    let mut trace = TraceBuilder::new().unwrap();
+    for value in [5, 20, 30, 5, 40] {
+        rss.store(value, Ordering::Relaxed);
+        sample_now();
+    }
+    trace.process_samples();
+
+    assert_eq!(num_callbacks.load(Ordering::Relaxed), 2);
+    let markers: Vec<f64> = trace
+        .packets()
+        .iter()
+        .filter_map(|packet| match &packet.data {
+            Some(schema::trace_packet::Data::TrackEvent(event))
+                if event.r#type() == schema::track_event::Type::Instant =>
+            {
+                match event.debug_annotations[0].value {
+                    Some(schema::debug_annotation::Value::DoubleValue(value)) => Some(value),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(markers, [20.0, 40.0]);
+}